@@ -0,0 +1,254 @@
+use crate::config::Config;
+use crate::log;
+#[cfg(feature = "shell")]
+use crate::shell;
+use crate::{Task, TaskType};
+use redis::aio::MultiplexedConnection;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// --- Audit Trail ---
+/// Prefix of the Redis stream audit entries are appended to; each worker
+/// writes `mcp::audit::<worker_id>`, so it is the only writer of its chain.
+pub const AUDIT_STREAM: &str = "mcp::audit";
+/// `prev_hash` of the first entry in a fresh trail.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+/// How much of `AUDIT_FILE` is read at a time, from the end, to find its
+/// last entry.
+const TAIL_CHUNK: u64 = 64 * 1024;
+
+/// Hashes of the last entry written to each sink, loaded from the sink on
+/// first use so its chain continues across restarts. The stream and the
+/// file are chained separately, so an entry only one of them took doesn't
+/// break the other's chain. Held across each write so entries land in chain
+/// order.
+static HEADS: tokio::sync::Mutex<Heads> = tokio::sync::Mutex::const_new(Heads {
+    stream: None,
+    file: None,
+});
+
+struct Heads {
+    stream: Option<String>,
+    file: Option<String>,
+}
+
+/// An audited execution: its `start` entry is written by [`Entry::begin`]
+/// and its `end` entry by [`Entry::end`].
+pub struct Entry<'a> {
+    task: &'a Task,
+    config: &'a Config,
+    conn: Option<MultiplexedConnection>,
+    argv: Option<Vec<String>>,
+    started_at: String,
+}
+
+impl<'a> Entry<'a> {
+    /// Writes the `start` entry. A task whose start can't be recorded in any
+    /// sink doesn't run, so nothing executes unaudited.
+    pub async fn begin(
+        task: &'a Task,
+        config: &'a Config,
+        conn: Option<MultiplexedConnection>,
+    ) -> Result<Entry<'a>, String> {
+        let entry = Entry {
+            task,
+            config,
+            argv: argv(task, config),
+            conn,
+            started_at: chrono::Utc::now().to_rfc3339(),
+        };
+        let fields = entry.fields("start");
+        if let Err(e) = entry.append(fields).await {
+            return Err(format!("audit: failed to record start: {}", e));
+        }
+        Ok(entry)
+    }
+
+    /// Writes the `end` entry with the exit code and outcome.
+    pub async fn end(self, output: &Result<String, String>, exit_code: Option<i32>) {
+        let mut fields = self.fields("end");
+        fields.insert(
+            "ended_at".to_string(),
+            chrono::Utc::now().to_rfc3339().into(),
+        );
+        fields.insert("exit_code".to_string(), exit_code.into());
+        fields.insert(
+            "status".to_string(),
+            if output.is_ok() { "SUCCESS" } else { "ERROR" }.into(),
+        );
+        if let Err(e) = self.append(fields).await {
+            log(&format!(
+                "[ERROR] audit: failed to record end of task {}: {}",
+                self.task.id, e
+            ));
+        }
+    }
+
+    fn fields(&self, phase: &str) -> serde_json::Map<String, serde_json::Value> {
+        let entry = serde_json::json!({
+            "phase": phase,
+            "worker_id": self.config.worker_id,
+            "task_id": self.task.id,
+            "correlation_id": self.task.correlation_id(),
+            "task_type": self.task.task_type.reported(),
+            "target_host": self.task.target_host,
+            "argv": self.argv,
+            "started_at": self.started_at,
+        });
+        match entry {
+            serde_json::Value::Object(fields) => fields,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Chains the entry to the previous one in each sink and appends it to
+    /// the stream and/or `AUDIT_FILE`. Fails only if no sink took the entry.
+    async fn append(
+        &self,
+        fields: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<(), String> {
+        let mut heads = HEADS.lock().await;
+        let mut errors = Vec::new();
+        let mut written = false;
+        if let Some(mut conn) = self.conn.clone() {
+            let stream = stream_key(self.config);
+            let head = match heads.stream.take() {
+                Some(hash) => Ok(hash),
+                None => stream_head(&mut conn, &stream).await,
+            };
+            let added = match head {
+                Ok(prev_hash) => {
+                    let (line, hash) = chain(&fields, &prev_hash);
+                    let added: redis::RedisResult<String> = redis::cmd("XADD")
+                        .arg(&stream)
+                        .arg("*")
+                        .arg("entry")
+                        .arg(&line)
+                        .arg("hash")
+                        .arg(&hash)
+                        .query_async(&mut conn)
+                        .await;
+                    // Keep the old head on failure so the next entry still
+                    // chains to it.
+                    heads.stream = Some(if added.is_ok() { hash } else { prev_hash });
+                    added.map(drop).map_err(|e| e.to_string())
+                }
+                Err(e) => Err(e),
+            };
+            match added {
+                Ok(()) => written = true,
+                Err(e) => errors.push(format!("{}: {}", stream, e)),
+            }
+        }
+        if let Some(path) = &self.config.audit_file {
+            let prev_hash = heads.file.take().unwrap_or_else(|| file_head(path));
+            let (line, hash) = chain(&fields, &prev_hash);
+            let appended = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+            heads.file = Some(if appended.is_ok() { hash } else { prev_hash });
+            match appended {
+                Ok(()) => written = true,
+                Err(e) => errors.push(format!("{}: {}", path, e)),
+            }
+        }
+
+        if written {
+            Ok(())
+        } else {
+            if errors.is_empty() {
+                errors.push("no Redis connection and no AUDIT_FILE".to_string());
+            }
+            Err(errors.join("; "))
+        }
+    }
+}
+
+/// This worker's audit stream.
+fn stream_key(config: &Config) -> String {
+    format!("{}::{}", AUDIT_STREAM, config.worker_id)
+}
+
+/// The entry as a line chained to `prev_hash`, and its hash.
+fn chain(fields: &serde_json::Map<String, serde_json::Value>, prev_hash: &str) -> (String, String) {
+    let mut fields = fields.clone();
+    fields.insert("prev_hash".to_string(), prev_hash.into());
+    let body = serde_json::Value::Object(fields.clone()).to_string();
+    let hash = hex::encode(Sha256::digest(format!("{}{}", prev_hash, body)));
+    fields.insert("hash".to_string(), hash.clone().into());
+    (serde_json::Value::Object(fields).to_string(), hash)
+}
+
+/// The exact argv the task runs, where the worker knows it up front.
+#[cfg_attr(not(feature = "shell"), allow(unused_variables))]
+fn argv(task: &Task, config: &Config) -> Option<Vec<String>> {
+    let details = &task.details;
+    match task.task_type {
+        #[cfg(feature = "shell")]
+        TaskType::SHELL => shell::argv(details, config).ok(),
+        TaskType::SYSTEMD => Some(vec![
+            "systemctl".to_string(),
+            details["action"].as_str()?.to_string(),
+            "--".to_string(),
+            details["unit"].as_str()?.trim().to_string(),
+        ]),
+        _ => None,
+    }
+}
+
+/// The hash of the newest entry in `stream`, or the genesis hash when it's
+/// empty.
+async fn stream_head(conn: &mut MultiplexedConnection, stream: &str) -> Result<String, String> {
+    let newest: Vec<(String, HashMap<String, String>)> = redis::cmd("XREVRANGE")
+        .arg(stream)
+        .arg("+")
+        .arg("-")
+        .arg("COUNT")
+        .arg(1)
+        .query_async(conn)
+        .await
+        .map_err(|e| format!("failed to read the head: {}", e))?;
+    Ok(newest
+        .into_iter()
+        .next()
+        .and_then(|(_, fields)| fields.get("hash").cloned())
+        .unwrap_or_else(|| GENESIS_HASH.to_string()))
+}
+
+/// The hash of the last entry in `AUDIT_FILE`, or the genesis hash when it
+/// has none.
+fn file_head(path: &str) -> String {
+    last_line(path)
+        .and_then(|line| {
+            let entry: serde_json::Value = serde_json::from_str(&line).ok()?;
+            entry["hash"].as_str().map(str::to_string)
+        })
+        .unwrap_or_else(|| GENESIS_HASH.to_string())
+}
+
+/// The last non-empty line of the file at `path`, read backwards from the
+/// end in [`TAIL_CHUNK`]s so a long trail isn't read whole.
+fn last_line(path: &str) -> Option<String> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    let mut tail: Vec<u8> = Vec::new();
+    let mut start = len;
+    while start > 0 {
+        let read_from = start.saturating_sub(TAIL_CHUNK);
+        let mut chunk = vec![0; (start - read_from) as usize];
+        file.seek(SeekFrom::Start(read_from)).ok()?;
+        file.read_exact(&mut chunk).ok()?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        start = read_from;
+        let trimmed = tail.trim_ascii_end();
+        if let Some(newline) = trimmed.iter().rposition(|b| *b == b'\n') {
+            return Some(String::from_utf8_lossy(&trimmed[newline + 1..]).into_owned());
+        }
+    }
+    let trimmed = tail.trim_ascii_end();
+    (!trimmed.is_empty()).then(|| String::from_utf8_lossy(trimmed).into_owned())
+}
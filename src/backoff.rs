@@ -0,0 +1,59 @@
+use rand::Rng;
+use std::time::Duration;
+
+// --- Backoff Jitter ---
+/// Spreads `delay` randomly by up to ±`pct` percent so a fleet of workers
+/// that lost Redis together doesn't retry in lockstep. `pct` is capped at 100.
+pub fn jitter(delay: Duration, pct: u32) -> Duration {
+    let pct = pct.min(100);
+    if pct == 0 {
+        return delay;
+    }
+    let spread = pct as f64 / 100.0;
+    let factor = rand::thread_rng().gen_range(1.0 - spread..=1.0 + spread);
+    delay.mul_f64(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The smallest and largest of many jittered `delay`s.
+    fn range(delay: Duration, pct: u32) -> (Duration, Duration) {
+        let samples: Vec<Duration> = (0..2000).map(|_| jitter(delay, pct)).collect();
+        (
+            *samples.iter().min().unwrap(),
+            *samples.iter().max().unwrap(),
+        )
+    }
+
+    #[test]
+    fn stays_within_the_spread() {
+        let delay = Duration::from_secs(10);
+        let (min, max) = range(delay, 20);
+        assert!(min >= Duration::from_secs(8), "{:?}", min);
+        assert!(max <= Duration::from_secs(12), "{:?}", max);
+        // Actually spread, both ways.
+        assert!(min < Duration::from_millis(9500), "{:?}", min);
+        assert!(max > Duration::from_millis(10500), "{:?}", max);
+    }
+
+    #[test]
+    fn caps_the_spread_at_100_percent() {
+        let delay = Duration::from_secs(1);
+        for pct in [100, 101, 250, u32::MAX] {
+            let (min, max) = range(delay, pct);
+            assert!(max <= Duration::from_secs(2), "{}: {:?}", pct, max);
+            assert!(min < Duration::from_millis(200), "{}: {:?}", pct, min);
+        }
+    }
+
+    #[test]
+    fn leaves_delays_alone_without_a_spread() {
+        assert_eq!(
+            range(Duration::from_millis(1500), 0),
+            (Duration::from_millis(1500), Duration::from_millis(1500))
+        );
+        assert_eq!(range(Duration::ZERO, 50), (Duration::ZERO, Duration::ZERO));
+    }
+}
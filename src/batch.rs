@@ -0,0 +1,255 @@
+use crate::config::Config;
+use crate::exit;
+use crate::handlers;
+use crate::log;
+use crate::output::OutputEncoding;
+use crate::policy;
+use crate::progress::Progress;
+use crate::{execute_task, Task, TaskType};
+use regex::Regex;
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Matches `{{steps[N].output}}` placeholders.
+static STEP_OUTPUT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{\s*steps\[(\d+)\]\.output\s*\}\}").unwrap());
+
+// --- Batch Execution ---
+/// Longest step output kept in the report; the full output is still used for
+/// templating.
+const MAX_STEP_OUTPUT_BYTES: usize = 4096;
+/// Stands in for step output past `MAX_BATCH_OUTPUT_BYTES`.
+const OMITTED_OUTPUT: &str = "[omitted: batch output limit reached]";
+
+/// What one BATCH step did.
+#[derive(Serialize, Debug)]
+pub struct BatchStepResult {
+    pub index: usize,
+    pub task_type: String,
+    /// The step's command (`command` plus `args`, or a SYSTEMD `action` and
+    /// `unit`), for finding it in the batch without reading its details.
+    pub command: String,
+    pub optional: bool,
+    /// `SUCCESS` or `ERROR`.
+    pub status: &'static str,
+    /// Exit code of the last process the step ran, if it ran one.
+    pub exit_code: Option<i32>,
+    /// How `output` is encoded on success: `text` or `base64`.
+    pub output_encoding: &'static str,
+    pub duration_ms: u64,
+    /// The step's output on success or its error, cut at
+    /// `MAX_STEP_OUTPUT_BYTES`, or `[omitted: ...]` once the batch's
+    /// `MAX_BATCH_OUTPUT_BYTES` is used up.
+    pub output: String,
+    pub truncated: bool,
+}
+
+/// Runs `details.steps` (each `{task_type, details, optional}`) in order and
+/// reports them as `{"status", "failed_step", "steps": [BatchStepResult, ...]}`.
+/// The batch stops at the first failed step unless it's `optional`, and its
+/// status is `success` only if every non-optional step succeeded. Once the
+/// reported outputs reach `MAX_BATCH_OUTPUT_BYTES`, later steps still run but
+/// their output is omitted and `output_limit_reached` is set. With
+/// `details.templating` set, string values in a step's details may reference
+/// earlier steps as `{{steps[N].output}}`; without it placeholders are passed
+/// through untouched. Each step starting is reported to the batch's progress
+/// list, at the share of steps already run.
+pub fn execute<'a>(
+    task: &'a Task,
+    config: &'a Config,
+    conn: Option<redis::aio::MultiplexedConnection>,
+    cancel: &'a CancellationToken,
+) -> Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>> {
+    // Boxed because steps recurse back into `execute_task`.
+    Box::pin(async move {
+        let steps = task.details["steps"]
+            .as_array()
+            .ok_or("BATCH task requires a 'steps' array")?;
+        let templating = task.details["templating"].as_bool().unwrap_or(false);
+
+        let mut outputs: Vec<String> = Vec::new();
+        let mut report: Vec<BatchStepResult> = Vec::new();
+        let mut failed_step = None;
+        let mut output_bytes = 0;
+        let limit = config.max_batch_output_bytes;
+        let mut progress = Progress::new(conn.clone(), &task.id);
+        progress.phase("batch");
+        for (index, step) in steps.iter().enumerate() {
+            let mut step_task: Task = serde_json::from_value(serde_json::json!({
+                "id": format!("{}#{}", task.id, index),
+                "target_host": "",
+                "task_type": step["task_type"],
+                "details": step["details"],
+            }))
+            .map_err(|e| format!("BATCH step {} is invalid: {}", index, e))?;
+            step_task.correlation_id = task.correlation_id.clone();
+            if step_task.task_type == TaskType::BATCH {
+                return Err(format!("BATCH step {} cannot itself be a BATCH", index));
+            }
+            let optional = match &step["optional"] {
+                serde_json::Value::Null => false,
+                value => value
+                    .as_bool()
+                    .ok_or_else(|| format!("BATCH step {} 'optional' must be a bool", index))?,
+            };
+            if templating {
+                substitute(&mut step_task.details, &outputs)
+                    .map_err(|e| format!("BATCH step {}: {}", index, e))?;
+                // The batch was checked before templating filled this in.
+                policy::check(&step_task).map_err(|e| format!("BATCH step {}: {}", index, e))?;
+            }
+
+            log(&format!("Running BATCH {} step {}", task.id, index));
+            progress
+                .update(
+                    Some(index as f64 * 100.0 / steps.len() as f64),
+                    &format!(
+                        "Step {} of {}: {} {}",
+                        index + 1,
+                        steps.len(),
+                        step_task.task_type.as_str(),
+                        summary(&step_task)
+                    ),
+                )
+                .await;
+            let started = Instant::now();
+            let (result, captured) = exit::capture(execute_task(
+                handlers::builtin,
+                &step_task,
+                config,
+                conn.clone(),
+                cancel,
+            ))
+            .await;
+            let (status, output) = match result {
+                Ok(output) => ("SUCCESS", output),
+                Err(e) => ("ERROR", e),
+            };
+            let (shown, truncated) = if limit > 0 && output_bytes >= limit {
+                (OMITTED_OUTPUT, true)
+            } else {
+                let (shown, truncated) = truncate(&output, MAX_STEP_OUTPUT_BYTES);
+                output_bytes += shown.len();
+                (shown, truncated)
+            };
+            report.push(BatchStepResult {
+                index,
+                task_type: step_task.task_type.reported(),
+                command: summary(&step_task),
+                optional,
+                status,
+                exit_code: captured.exit_code,
+                output_encoding: captured.output_encoding(),
+                duration_ms: started.elapsed().as_millis() as u64,
+                output: shown.to_string(),
+                truncated,
+            });
+            outputs.push(output);
+            if status == "ERROR" && !optional {
+                failed_step = Some(index);
+                break;
+            }
+        }
+
+        if failed_step.is_none() {
+            progress
+                .update(Some(100.0), &format!("Ran {} steps", steps.len()))
+                .await;
+        }
+        // The report itself is text, whatever its steps' outputs were.
+        exit::record_output_encoding(OutputEncoding::Text);
+        let body = serde_json::json!({
+            "status": if failed_step.is_none() { "success" } else { "failed" },
+            "failed_step": failed_step,
+            "output_limit_reached": limit > 0 && output_bytes >= limit,
+            "steps": report,
+        })
+        .to_string();
+        match failed_step {
+            None => Ok(body),
+            Some(_) => Err(body),
+        }
+    })
+}
+
+/// A one-line description of what the step runs.
+fn summary(task: &Task) -> String {
+    let details = &task.details;
+    if task.task_type == TaskType::SYSTEMD {
+        return format!(
+            "{} {}",
+            details["action"].as_str().unwrap_or(""),
+            details["unit"].as_str().unwrap_or("")
+        );
+    }
+    if task.task_type == TaskType::HTTP {
+        return format!(
+            "{} {}",
+            details["method"]
+                .as_str()
+                .unwrap_or("GET")
+                .to_ascii_uppercase(),
+            details["url"].as_str().unwrap_or("")
+        );
+    }
+    let mut words: Vec<&str> = details["command"].as_str().into_iter().collect();
+    if let Some(args) = details["args"].as_array() {
+        words.extend(args.iter().filter_map(|arg| arg.as_str()));
+    }
+    words.join(" ")
+}
+
+/// `text` cut to at most `limit` bytes on a char boundary, and whether
+/// anything was cut.
+pub fn truncate(text: &str, limit: usize) -> (&str, bool) {
+    if text.len() <= limit {
+        return (text, false);
+    }
+    let mut end = limit;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    (&text[..end], true)
+}
+
+/// Replaces step-output placeholders in every string inside `value`.
+fn substitute(value: &mut serde_json::Value, outputs: &[String]) -> Result<(), String> {
+    match value {
+        serde_json::Value::String(s) => {
+            let mut missing = None;
+            let replaced = STEP_OUTPUT.replace_all(s, |caps: &regex::Captures| {
+                let referenced = caps[1].parse::<usize>().ok();
+                match referenced.and_then(|i| outputs.get(i)) {
+                    Some(output) => output.clone(),
+                    None => {
+                        missing = Some(caps[0].to_string());
+                        String::new()
+                    }
+                }
+            });
+            if let Some(placeholder) = missing {
+                return Err(format!(
+                    "{} refers to a step that hasn't run yet",
+                    placeholder
+                ));
+            }
+            *s = replaced.into_owned();
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                substitute(item, outputs)?;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for item in map.values_mut() {
+                substitute(item, outputs)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use crate::backoff;
+use crate::config::Config;
+use crate::log;
+use hmac::{Hmac, Mac};
+use redis::AsyncCommands;
+use sha2::Sha256;
+use tokio::time::{self, Duration};
+
+/// Where undeliverable callbacks are recorded when `CALLBACK_RECORD_FAILURES` is set.
+pub const CALLBACK_FAILURES_KEY: &str = "mcp::callback_failures";
+/// Header carrying `sha256=<hex HMAC of the body>` when `CALLBACK_SECRET` is set.
+const SIGNATURE_HEADER: &str = "X-MCP-Signature";
+/// Delay before the first retry; doubles after each failed attempt.
+const RETRY_BACKOFF_START: Duration = Duration::from_secs(1);
+
+// --- Result Callbacks ---
+/// POSTs a finished task's result JSON to its `callback_url` in the
+/// background, retrying up to `CALLBACK_RETRIES` times. Delivery failures are
+/// logged (and optionally recorded) but never change the task's outcome.
+pub fn spawn(
+    config: &Config,
+    conn: Option<redis::aio::MultiplexedConnection>,
+    url: &str,
+    body: serde_json::Value,
+) {
+    let config = config.clone();
+    let url = url.to_string();
+    tokio::spawn(async move {
+        let task_id = body["id"].as_str().unwrap_or_default().to_string();
+        let body = body.to_string();
+        if let Err(e) = deliver(&config, &url, &body).await {
+            log(&format!(
+                "[ERROR] Callback for task {} to {} failed: {}",
+                task_id, url, e
+            ));
+            if let (true, Some(mut conn)) = (config.callback_record_failures, conn) {
+                record_failure(&mut conn, &task_id, &url, &e).await;
+            }
+        }
+    });
+}
+
+async fn deliver(config: &Config, url: &str, body: &str) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.callback_timeout_secs))
+        .build()
+        .map_err(|e| format!("HTTP client setup failed: {}", e))?;
+
+    let mut delay = RETRY_BACKOFF_START;
+    let mut attempt = 0;
+    loop {
+        let mut request = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        if let Some(secret) = &config.callback_secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret, body));
+        }
+        let error = match request.send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => format!("HTTP {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+        if attempt >= config.callback_retries {
+            return Err(format!("{} (after {} attempts)", error, attempt + 1));
+        }
+        attempt += 1;
+        time::sleep(backoff::jitter(delay, config.backoff_jitter_pct)).await;
+        delay *= 2;
+    }
+}
+
+fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn record_failure(
+    conn: &mut redis::aio::MultiplexedConnection,
+    task_id: &str,
+    url: &str,
+    error: &str,
+) {
+    let entry = serde_json::json!({
+        "task_id": task_id,
+        "url": url,
+        "error": error,
+        "failed_at": chrono::Utc::now().to_rfc3339(),
+    });
+    if let Err(e) = conn
+        .rpush::<_, _, ()>(CALLBACK_FAILURES_KEY, entry.to_string())
+        .await
+    {
+        log(&format!(
+            "[ERROR] Failed to record callback failure for task {}: {}",
+            task_id, e
+        ));
+    }
+}
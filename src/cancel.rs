@@ -0,0 +1,169 @@
+use crate::log;
+use crate::Task;
+use redis::AsyncCommands;
+use std::future::Future;
+use std::sync::LazyLock;
+use tokio::time::{self, Duration};
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+// --- Task Cancellation ---
+/// Cancelled on the first SIGTERM or SIGINT: listeners stop popping.
+static DRAINING: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+/// Root of every task's token, cancelled once the drain period is over (or
+/// on a second signal).
+static SHUTDOWN: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+/// How often a running task's `mcp::cancel::<id>` key is checked.
+const CANCEL_KEY_POLL: Duration = Duration::from_secs(1);
+
+/// Whether a shutdown has started; running tasks may still be finishing.
+pub fn draining() -> bool {
+    DRAINING.is_cancelled()
+}
+
+/// Whether running tasks have been told to stop.
+pub fn shutting_down() -> bool {
+    SHUTDOWN.is_cancelled()
+}
+
+/// Spawns a background task that starts a graceful shutdown on the first
+/// SIGTERM or SIGINT: listeners stop popping, running tasks get up to
+/// `drain` to finish, and whatever is still running then is cancelled. A
+/// second signal cancels them right away.
+pub fn watch_shutdown_signals(drain: Duration) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut sigterm = match signal(SignalKind::terminate()) {
+                Ok(s) => s,
+                Err(e) => {
+                    log(&format!("[ERROR] Failed to install SIGTERM handler: {}", e));
+                    return;
+                }
+            };
+            tokio::select! {
+                _ = sigterm.recv() => log("SIGTERM received; shutting down."),
+                _ = tokio::signal::ctrl_c() => log("SIGINT received; shutting down."),
+            }
+            DRAINING.cancel();
+            if !drain.is_zero() {
+                log(&format!(
+                    "Draining running tasks for up to {:?}; signal again to stop them now.",
+                    drain
+                ));
+                tokio::select! {
+                    _ = time::sleep(drain) => log("Drain period over; stopping running tasks."),
+                    _ = sigterm.recv() => log("SIGTERM received again; stopping running tasks."),
+                    _ = tokio::signal::ctrl_c() => log("SIGINT received again; stopping running tasks."),
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            log("SIGINT received; shutting down.");
+            DRAINING.cancel();
+            if !drain.is_zero() {
+                tokio::select! {
+                    _ = time::sleep(drain) => log("Drain period over; stopping running tasks."),
+                    _ = tokio::signal::ctrl_c() => log("SIGINT received again; stopping running tasks."),
+                }
+            }
+        }
+        SHUTDOWN.cancel();
+    });
+}
+
+pub fn cancel_key(task_id: &str) -> String {
+    format!("mcp::cancel::{}", task_id)
+}
+
+/// The token a task runs under: cancelled on shutdown, at the task's
+/// deadline, or once `mcp::cancel::<id>` is set (when there's a connection to
+/// check it with). Container cancellations cancel it through the running
+/// registry. The guard stops the watcher when the task is done.
+pub fn for_task(
+    task: &Task,
+    conn: Option<redis::aio::MultiplexedConnection>,
+) -> (CancellationToken, DropGuard) {
+    let token = SHUTDOWN.child_token();
+    let deadline = task.time_left().ok().flatten();
+    let key = cancel_key(&task.id);
+    let watched = token.clone();
+    tokio::spawn(async move {
+        let expired = async {
+            match deadline {
+                Some(left) => time::sleep(left).await,
+                None => std::future::pending().await,
+            }
+        };
+        let requested = async {
+            let Some(mut conn) = conn else {
+                return std::future::pending().await;
+            };
+            let mut poll = time::interval(CANCEL_KEY_POLL);
+            loop {
+                poll.tick().await;
+                if conn.exists(&key).await.unwrap_or(false) {
+                    return;
+                }
+            }
+        };
+        tokio::select! {
+            _ = watched.cancelled() => return,
+            _ = expired => {}
+            _ = requested => {}
+        }
+        watched.cancel();
+    });
+    let guard = token.clone().drop_guard();
+    (token, guard)
+}
+
+/// Why a cancelled task stopped: `shutdown`, `deadline_exceeded` or
+/// `cancelled` (by its cancel key or container).
+pub fn reason(task: &Task) -> &'static str {
+    if shutting_down() {
+        "shutdown"
+    } else if task.past_deadline() {
+        "deadline_exceeded"
+    } else {
+        "cancelled"
+    }
+}
+
+/// The result status for a task that failed after `token` was cancelled, in
+/// place of `ERROR`: `INTERRUPTED` by shutdown, `CANCELLED` by its cancel key
+/// or container. A missed deadline stays an `ERROR`.
+pub fn stopped_status(token: &CancellationToken, task: &Task) -> Option<&'static str> {
+    if !token.is_cancelled() {
+        return None;
+    }
+    match reason(task) {
+        "shutdown" => Some("INTERRUPTED"),
+        "cancelled" => Some("CANCELLED"),
+        _ => None,
+    }
+}
+
+/// The error result of a task stopped by its token.
+pub fn error(task: &Task) -> String {
+    serde_json::json!({"status": reason(task), "deadline": task.deadline}).to_string()
+}
+
+/// Runs `work` unless `token` is cancelled first, in which case `work` is
+/// dropped (killing any `kill_on_drop` child) and the task fails with
+/// [`error`]. Handlers that stop their children themselves don't go through
+/// this, so they can still report what the child did.
+pub async fn run_until_cancelled(
+    token: &CancellationToken,
+    task: &Task,
+    work: impl Future<Output = Result<String, String>>,
+) -> Result<String, String> {
+    tokio::select! {
+        output = work => output,
+        _ = token.cancelled() => Err(error(task)),
+    }
+}
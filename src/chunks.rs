@@ -0,0 +1,164 @@
+use crate::config::Config;
+use crate::log;
+use crate::Task;
+
+/// Pushed after the last chunk so consumers know the stream is complete.
+pub const END_MARKER: &str = "__END__";
+/// Prefix of the Redis Stream a task's live output is appended to.
+pub const LIVE_KEY_PREFIX: &str = "mcp::stream::";
+
+/// Which pipe a line of output came from.
+#[derive(Clone, Copy)]
+#[cfg_attr(not(any(feature = "docker", feature = "shell")), allow(dead_code))]
+pub enum Fd {
+    Stdout,
+    Stderr,
+}
+
+impl Fd {
+    fn as_str(self) -> &'static str {
+        match self {
+            Fd::Stdout => "stdout",
+            Fd::Stderr => "stderr",
+        }
+    }
+}
+
+// --- Streamed Results ---
+/// Output chunks for a `stream_result` task, RPUSHed to
+/// `<result key>::chunks` as they're produced so consumers can BLPOP them in
+/// order. Executors that produce output incrementally (SHELL, `build_image`)
+/// push raw lines as they arrive; for anything else the final output is
+/// pushed as a single chunk. The list always ends with `__END__`; success or
+/// failure is still read from the result key.
+///
+/// With `stream_output` (or `OUTPUT_STREAMS`) the same lines, and a SHELL
+/// command's stderr, are also appended to the Redis Stream
+/// `mcp::stream::<id>` as `fd`/`line` entries, so a subscriber that joins
+/// late can replay from the start. Its last entry is `event=end` with the
+/// task's status.
+pub struct ResultStream {
+    conn: redis::aio::MultiplexedConnection,
+    key: Option<String>,
+    live: Option<LiveStream>,
+    ttl_secs: u64,
+    chunks: usize,
+}
+
+impl ResultStream {
+    /// `None` unless the task asked for streaming (or `OUTPUT_STREAMS` is
+    /// set) and Redis is available.
+    pub fn for_task(
+        task: &Task,
+        config: &Config,
+        conn: Option<redis::aio::MultiplexedConnection>,
+    ) -> Option<Self> {
+        let live = task.stream_output || config.output_streams;
+        if !task.stream_result && !live {
+            return None;
+        }
+        let conn = conn?;
+        Some(ResultStream {
+            key: task
+                .stream_result
+                .then(|| format!("{}{}::chunks", config.result_key_prefix, task.id)),
+            live: live.then(|| LiveStream {
+                conn: conn.clone(),
+                key: format!("{}{}", LIVE_KEY_PREFIX, task.id),
+                ttl_secs: config.result_ttl_secs,
+                max_len: config.output_stream_max_len,
+            }),
+            conn,
+            ttl_secs: config.result_ttl_secs,
+            chunks: 0,
+        })
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
+
+    /// The live stream alone, for a reader that runs apart from the one
+    /// holding this (e.g. a background stderr reader).
+    #[cfg_attr(not(feature = "shell"), allow(dead_code))]
+    pub fn live(&self) -> Option<LiveStream> {
+        self.live.clone()
+    }
+
+    /// Pushes a chunk of stdout.
+    pub async fn push(&mut self, chunk: &str) {
+        self.push_from(Fd::Stdout, chunk).await;
+    }
+
+    pub async fn push_from(&mut self, fd: Fd, chunk: &str) {
+        self.chunks += 1;
+        if let Some(live) = self.live.as_mut() {
+            live.append(fd, chunk).await;
+        }
+        self.push_to_list(chunk).await;
+    }
+
+    /// Pushes the end marker, and ends the live stream with `status`.
+    pub async fn finish(mut self, status: &str) {
+        if let Some(live) = self.live.as_mut() {
+            live.add(&[("event", "end"), ("status", status)]).await;
+        }
+        self.push_to_list(END_MARKER).await;
+    }
+
+    async fn push_to_list(&mut self, chunk: &str) {
+        let Some(key) = &self.key else { return };
+        let pushed: redis::RedisResult<()> = redis::pipe()
+            .rpush(key, chunk)
+            .ignore()
+            .expire(key, self.ttl_secs as i64)
+            .ignore()
+            .query_async(&mut self.conn)
+            .await;
+        if let Err(e) = pushed {
+            log(&format!(
+                "[ERROR] Failed to push result chunk to {}: {}",
+                key, e
+            ));
+        }
+    }
+}
+
+/// The `mcp::stream::<id>` half of a [`ResultStream`], trimmed to about
+/// `OUTPUT_STREAM_MAX_LEN` entries and expiring with the result.
+#[derive(Clone)]
+pub struct LiveStream {
+    conn: redis::aio::MultiplexedConnection,
+    key: String,
+    ttl_secs: u64,
+    max_len: usize,
+}
+
+impl LiveStream {
+    pub async fn append(&mut self, fd: Fd, line: &str) {
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        self.add(&[("fd", fd.as_str()), ("line", line)]).await;
+    }
+
+    async fn add(&mut self, fields: &[(&str, &str)]) {
+        let added: redis::RedisResult<()> = redis::pipe()
+            .cmd("XADD")
+            .arg(&self.key)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(self.max_len)
+            .arg("*")
+            .arg(fields)
+            .ignore()
+            .expire(&self.key, self.ttl_secs as i64)
+            .ignore()
+            .query_async(&mut self.conn)
+            .await;
+        if let Err(e) = added {
+            log(&format!(
+                "[ERROR] Failed to append output to {}: {}",
+                self.key, e
+            ));
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use crate::chunks::ResultStream;
+use crate::config::Config;
+use crate::details::string_list;
+use crate::docker;
+use crate::log;
+use crate::output::OutputOptions;
+use crate::paths;
+use crate::progress::{Progress, Tracker};
+use crate::Task;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Directory under `COMPOSE_DIR` inline compose files are written to, one
+/// subdirectory per project.
+const INLINE_DIR: &str = ".mcp-inline";
+
+// --- Docker Compose ---
+/// The compose project a task addresses: `details.project`, else
+/// `mcp-<task id>`, and the compose file to run it with.
+struct Project {
+    name: String,
+    /// The project directory, or the directory an inline file was written to.
+    dir: PathBuf,
+    /// Set for an inline `details.compose_yaml`.
+    file: Option<PathBuf>,
+}
+
+impl Project {
+    /// Resolves `details.project_dir` (relative to `COMPOSE_DIR`, which it
+    /// may not leave) or, for `compose_up`, writes `details.compose_yaml`
+    /// under `COMPOSE_DIR/.mcp-inline/<project>/` once [`check_inline`]
+    /// passes it. Other commands use the file the last `compose_up` wrote.
+    /// Without `COMPOSE_DIR` compose commands are disabled.
+    async fn from_task(task: &Task, config: &Config, command: &str) -> Result<Self, String> {
+        let base_dir = config
+            .compose_dir
+            .as_deref()
+            .ok_or("Compose commands are disabled on this worker (set COMPOSE_DIR)")?;
+        let details = &task.details;
+        let name = match &details["project"] {
+            serde_json::Value::Null => project_name(&task.id),
+            serde_json::Value::String(name) if valid_project_name(name) => name.clone(),
+            _ => {
+                return Err(format!(
+                    "{} 'project' must be lower-case letters, digits, '-' and '_'",
+                    command
+                ))
+            }
+        };
+        match (&details["project_dir"], &details["compose_yaml"]) {
+            (serde_json::Value::String(dir), serde_json::Value::Null) => {
+                let dir = paths::confine(
+                    base_dir,
+                    "COMPOSE_DIR",
+                    &Path::new(base_dir).join(dir),
+                    true,
+                )?;
+                if !dir.is_dir() {
+                    return Err(format!(
+                        "Compose project is not a directory: {}",
+                        dir.display()
+                    ));
+                }
+                Ok(Project {
+                    name,
+                    dir,
+                    file: None,
+                })
+            }
+            (serde_json::Value::Null, serde_json::Value::String(yaml)) => {
+                let dir = Path::new(base_dir).join(INLINE_DIR).join(&name);
+                let file = dir.join("compose.yaml");
+                if command != "compose_up" {
+                    // Compose finds the project's containers by name alone.
+                    let file = file.is_file().then_some(file);
+                    return Ok(Project { name, dir, file });
+                }
+                tokio::fs::create_dir_all(&dir)
+                    .await
+                    .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+                // Written beside the current file, which it only replaces
+                // once checked.
+                let draft_file = file.with_extension("yaml.new");
+                let draft = Project {
+                    name,
+                    dir,
+                    file: Some(draft_file.clone()),
+                };
+                tokio::fs::write(&draft_file, yaml)
+                    .await
+                    .map_err(|e| format!("Failed to write {}: {}", draft_file.display(), e))?;
+                let checked = match draft.output(&["config", "--format", "json"]).await {
+                    Ok(output) if output.status.success() => {
+                        check_inline(&String::from_utf8_lossy(&output.stdout), config)
+                    }
+                    Ok(output) => Err(docker::docker_failure(&output)),
+                    Err(e) => Err(e),
+                };
+                let installed = match checked {
+                    Ok(()) => tokio::fs::rename(&draft_file, &file)
+                        .await
+                        .map_err(|e| format!("Failed to write {}: {}", file.display(), e)),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = installed {
+                    tokio::fs::remove_file(&draft_file).await.ok();
+                    return Err(e);
+                }
+                Ok(Project {
+                    file: Some(file),
+                    ..draft
+                })
+            }
+            (serde_json::Value::Null, serde_json::Value::Null) => Err(format!(
+                "{} requires a string 'project_dir' or 'compose_yaml'",
+                command
+            )),
+            _ => Err(format!(
+                "{} takes either a string 'project_dir' or 'compose_yaml'",
+                command
+            )),
+        }
+    }
+
+    /// `docker compose -p <name> [-f <file>] <args>`, run in the project
+    /// directory.
+    fn command(&self, args: &[&str]) -> tokio::process::Command {
+        let mut cmd = docker::docker_command(None, &["compose"]);
+        cmd.arg("--project-name").arg(&self.name);
+        if let Some(file) = &self.file {
+            cmd.arg("--file").arg(file);
+        }
+        cmd.args(args).current_dir(&self.dir);
+        cmd
+    }
+
+    async fn output(&self, args: &[&str]) -> Result<std::process::Output, String> {
+        self.command(args)
+            .output()
+            .await
+            .map_err(|e| format!("Failed to execute docker command: {}", e))
+    }
+
+    /// Every service container of the project, stopped ones included.
+    async fn services(&self) -> Result<Vec<serde_json::Value>, String> {
+        let output = self.output(&["ps", "--all", "--format", "json"]).await?;
+        if !output.status.success() {
+            return Err(docker::docker_failure(&output));
+        }
+        parse_ps(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// The names of the services the project defines.
+    async fn service_names(&self) -> Result<Vec<String>, String> {
+        let output = self.output(&["config", "--services"]).await?;
+        if !output.status.success() {
+            return Err(docker::docker_failure(&output));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn result(&self, services: Vec<serde_json::Value>) -> String {
+        serde_json::json!({ "project": self.name, "services": services }).to_string()
+    }
+}
+
+/// Checks an inline compose file, as resolved by `docker compose config
+/// --format json`, against what `run_container` allows: bind mounts (and
+/// volumes the local driver binds to a host path) only from inside
+/// `DOCKER_MOUNT_DIR`, and no privileged containers or host devices, which
+/// would reach past it.
+fn check_inline(resolved: &str, config: &Config) -> Result<(), String> {
+    let resolved: serde_json::Value = serde_json::from_str(resolved)
+        .map_err(|e| format!("Unexpected docker compose config output: {}", e))?;
+    let bind = |source: &str, owner: &str| -> Result<(), String> {
+        let allowed_dir = config.docker_mount_dir.as_deref().ok_or_else(|| {
+            format!(
+                "{} binds {}, but bind mounts are disabled on this worker (set DOCKER_MOUNT_DIR)",
+                owner, source
+            )
+        })?;
+        paths::confine(allowed_dir, "DOCKER_MOUNT_DIR", Path::new(source), true)
+            .map(drop)
+            .map_err(|e| format!("{}: {}", owner, e))
+    };
+    let services = resolved["services"].as_object().into_iter().flatten();
+    for (name, service) in services {
+        let owner = format!("Service '{}'", name);
+        if service["privileged"].as_bool() == Some(true) {
+            return Err(format!("{} may not be privileged", owner));
+        }
+        if service["devices"].as_array().is_some_and(|d| !d.is_empty()) {
+            return Err(format!("{} may not use host devices", owner));
+        }
+        for volume in service["volumes"].as_array().into_iter().flatten() {
+            if volume["type"] == "bind" {
+                bind(volume["source"].as_str().unwrap_or_default(), &owner)?;
+            }
+        }
+    }
+    let volumes = resolved["volumes"].as_object().into_iter().flatten();
+    for (name, volume) in volumes {
+        if let Some(device) = volume["driver_opts"]["device"].as_str() {
+            bind(device, &format!("Volume '{}'", name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Compose project names allow lower-case letters, digits, `-` and `_`,
+/// starting with a letter or digit.
+fn valid_project_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_".contains(c))
+}
+
+fn project_name(task_id: &str) -> String {
+    let id: String = task_id
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("mcp-{}", id)
+}
+
+/// Reads `docker compose ps --format json`, a JSON array in older compose
+/// releases and one object per line in newer ones, into
+/// `{"service","container","state","health","status","exit_code"}` entries.
+fn parse_ps(stdout: &str) -> Result<Vec<serde_json::Value>, String> {
+    let rows: Vec<serde_json::Value> = match stdout.trim_start().starts_with('[') {
+        true => serde_json::from_str(stdout)
+            .map_err(|e| format!("Unexpected docker compose ps output: {}", e))?,
+        false => stdout
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str(line)
+                    .map_err(|e| format!("Unexpected docker compose ps output '{}': {}", line, e))
+            })
+            .collect::<Result<_, _>>()?,
+    };
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let health = row["Health"].as_str().filter(|h| !h.is_empty());
+            serde_json::json!({
+                "service": row["Service"],
+                "container": row["Name"],
+                "state": row["State"],
+                "health": health,
+                "status": row["Status"],
+                "exit_code": row["ExitCode"],
+            })
+        })
+        .collect())
+}
+
+/// `docker compose up --detach`, optionally for just `details.services`
+/// and with `build`/`pull` (`always`, `missing`, `never`) flags, streaming
+/// compose's output to the task's progress list and stream. Progress goes
+/// through `pull`, `build` and `start` phases, the last at the share of
+/// services whose containers have started. Returns the per-service status
+/// once it is up.
+pub async fn up(
+    task: &Task,
+    config: &Config,
+    progress: Progress,
+    stream: Option<&mut ResultStream>,
+    cancel: &CancellationToken,
+    grace: Duration,
+) -> Result<String, String> {
+    let project = Project::from_task(task, config, "compose_up").await?;
+    let details = &task.details;
+    let services = string_list(&details["services"], "services")?;
+    let mut args = vec!["up", "--detach"];
+    if details["build"].as_bool().unwrap_or(false) {
+        args.push("--build");
+    }
+    match details["pull"].as_str() {
+        None => {}
+        Some(policy @ ("always" | "missing" | "never")) => args.extend(["--pull", policy]),
+        Some(other) => return Err(format!("Unsupported compose_up 'pull' policy: '{}'", other)),
+    }
+    if details["remove_orphans"].as_bool().unwrap_or(false) {
+        args.push("--remove-orphans");
+    }
+    args.push("--");
+    args.extend(services.iter().map(String::as_str));
+
+    log(&format!(
+        "Executing docker compose up for project {} in {}",
+        project.name,
+        project.dir.display()
+    ));
+    let total = match services.len() {
+        0 => project.service_names().await.map_or(0, |names| names.len()),
+        named => named,
+    };
+    let mut progress = progress.tracking(UpTracker {
+        services: total,
+        started: HashSet::new(),
+    });
+    docker::run_streaming(project.command(&args), &mut progress, stream, cancel, grace).await?;
+    progress
+        .update(Some(100.0), &format!("Project {} is up", project.name))
+        .await;
+    Ok(project.result(project.services().await?))
+}
+
+/// Reads `docker compose up` output: image pulls, builds, then containers
+/// starting, counted against `services`.
+struct UpTracker {
+    services: usize,
+    started: HashSet<String>,
+}
+
+impl Tracker for UpTracker {
+    fn observe(&mut self, line: &str) -> (Option<&'static str>, Option<f64>) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["Container", name, "Started" | "Running" | "Healthy", ..] => {
+                self.started.insert(name.to_string());
+                let percent = (self.services > 0)
+                    .then(|| self.started.len() as f64 * 100.0 / self.services as f64);
+                (Some("start"), percent)
+            }
+            ["Container", ..] => (Some("start"), None),
+            [.., "Pulling" | "Pulled"] => (Some("pull"), None),
+            [.., "Building" | "Built"] => (Some("build"), None),
+            [first, ..] if first.starts_with('#') => (Some("build"), None),
+            _ => (None, None),
+        }
+    }
+}
+
+/// `docker compose down`, also removing named volumes with
+/// `details.volumes` and orphans with `details.remove_orphans`. An inline
+/// project's compose file is deleted afterwards.
+pub async fn down(task: &Task, config: &Config) -> Result<String, String> {
+    let project = Project::from_task(task, config, "compose_down").await?;
+    let mut args = vec!["down"];
+    if task.details["volumes"].as_bool().unwrap_or(false) {
+        args.push("--volumes");
+    }
+    if task.details["remove_orphans"].as_bool().unwrap_or(false) {
+        args.push("--remove-orphans");
+    }
+    log(&format!(
+        "Executing docker compose down for project {}",
+        project.name
+    ));
+    let output = project.output(&args).await?;
+    if !output.status.success() {
+        return Err(docker::docker_failure(&output));
+    }
+    if project.file.is_some() {
+        if let Err(e) = tokio::fs::remove_dir_all(&project.dir).await {
+            log(&format!(
+                "[WARN] Failed to remove {}: {}",
+                project.dir.display(),
+                e
+            ));
+        }
+    }
+    Ok(project.result(project.services().await?))
+}
+
+/// The per-service status of the project.
+pub async fn ps(task: &Task, config: &Config) -> Result<String, String> {
+    let project = Project::from_task(task, config, "compose_ps").await?;
+    log(&format!(
+        "Executing docker compose ps for project {}",
+        project.name
+    ));
+    Ok(project.result(project.services().await?))
+}
+
+/// The last `details.tail` lines (default 100) of the project's logs, or of
+/// just `details.services`, rendered with the task's output options.
+pub async fn logs(
+    task: &Task,
+    config: &Config,
+    output_options: &OutputOptions,
+) -> Result<String, String> {
+    let project = Project::from_task(task, config, "compose_logs").await?;
+    let tail = match &task.details["tail"] {
+        serde_json::Value::Null => 100,
+        value => value
+            .as_u64()
+            .ok_or("compose_logs 'tail' must be a non-negative integer")?,
+    }
+    .to_string();
+    let services = string_list(&task.details["services"], "services")?;
+    let mut args = vec!["logs", "--no-color", "--timestamps", "--tail", &tail, "--"];
+    args.extend(services.iter().map(String::as_str));
+    log(&format!(
+        "Executing docker compose logs for project {}",
+        project.name
+    ));
+    let output = project.output(&args).await?;
+    if !output.status.success() {
+        return Err(docker::docker_failure(&output));
+    }
+    Ok(output_options.render(&output.stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A config confining bind mounts to a fresh directory holding `data/`.
+    fn config(name: &str) -> Config {
+        let dir = std::env::temp_dir().join(format!("mcp-compose-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        let mut config = Config::for_tests();
+        config.docker_mount_dir = Some(dir.display().to_string());
+        config
+    }
+
+    fn check(resolved: serde_json::Value, config: &Config) -> Result<(), String> {
+        check_inline(&resolved.to_string(), config)
+    }
+
+    fn service(volume: serde_json::Value) -> serde_json::Value {
+        json!({"services": {"web": {"image": "nginx", "volumes": [volume]}}})
+    }
+
+    #[test]
+    fn allows_named_volumes_and_binds_inside_the_mount_dir() {
+        let config = config("inside");
+        let data = format!("{}/data", config.docker_mount_dir.as_deref().unwrap());
+        let named = service(json!({"type": "volume", "source": "cache", "target": "/c"}));
+        assert_eq!(check(named.clone(), &Config::for_tests()), Ok(()));
+        let bound = service(json!({"type": "bind", "source": data, "target": "/d"}));
+        assert_eq!(check(bound, &config), Ok(()));
+        assert_eq!(check(json!({"name": "empty"}), &config), Ok(()));
+    }
+
+    #[test]
+    fn refuses_binds_outside_the_mount_dir() {
+        let config = config("outside");
+        let dir = config.docker_mount_dir.clone().unwrap();
+        let bind =
+            |source: &str| service(json!({"type": "bind", "source": source, "target": "/x"}));
+
+        assert_eq!(
+            check(bind("/etc"), &Config::for_tests()),
+            Err("Service 'web' binds /etc, but bind mounts are disabled on this worker (set DOCKER_MOUNT_DIR)".to_string())
+        );
+        let escape = check(bind(&format!("{}/data/../..", dir)), &config).unwrap_err();
+        assert!(
+            escape.starts_with("Service 'web': Host path "),
+            "{}",
+            escape
+        );
+        assert!(escape.contains("is outside DOCKER_MOUNT_DIR"), "{}", escape);
+
+        // The local driver can bind a "named" volume to any host path.
+        let volume = json!({
+            "services": {"web": {"image": "nginx"}},
+            "volumes": {"sneaky": {"driver_opts": {"type": "none", "o": "bind", "device": "/etc"}}},
+        });
+        let refused = check(volume, &config).unwrap_err();
+        assert!(
+            refused.starts_with("Volume 'sneaky': Host path /etc is outside"),
+            "{}",
+            refused
+        );
+    }
+
+    #[test]
+    fn refuses_privileged_services_and_host_devices() {
+        let config = config("privileged");
+        assert_eq!(
+            check(json!({"services": {"web": {"privileged": true}}}), &config),
+            Err("Service 'web' may not be privileged".to_string())
+        );
+        let devices = json!({"services": {"web": {"devices": [{"source": "/dev/sda", "target": "/dev/sda"}]}}});
+        assert_eq!(
+            check(devices, &config),
+            Err("Service 'web' may not use host devices".to_string())
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use crate::config::Config;
+use crate::log;
+use crate::routing;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// --- Concurrency Limits ---
+/// Caps on how many tasks run at once: a global cap from
+/// `MAX_TOTAL_CONCURRENCY` spanning every task type, per-queue caps from
+/// `QUEUE_CONCURRENCY` and per-type caps from `TASK_TYPE_CONCURRENCY`
+/// (`SHELL=2,DOCKER=4`). Unset or 0 means unlimited.
+struct Limits {
+    total: Option<Arc<Semaphore>>,
+    per_queue: HashMap<String, Arc<Semaphore>>,
+    per_type: HashMap<String, Arc<Semaphore>>,
+}
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Builds the semaphores from the config. Only the first call has an effect.
+pub fn init(config: &Config) {
+    let semaphore = |permits: usize| (permits > 0).then(|| Arc::new(Semaphore::new(permits)));
+    let limits = Limits {
+        total: semaphore(config.max_total_concurrency),
+        per_queue: config
+            .queue_concurrency
+            .iter()
+            .filter_map(|(queue, &permits)| Some((queue.clone(), semaphore(permits)?)))
+            .collect(),
+        per_type: config
+            .task_type_concurrency
+            .iter()
+            .filter_map(|(task_type, &permits)| {
+                Some((task_type.to_ascii_uppercase(), semaphore(permits)?))
+            })
+            .collect(),
+    };
+    if limits.total.is_some() || !limits.per_queue.is_empty() || !limits.per_type.is_empty() {
+        log(&format!(
+            "Concurrency limits: total={}, per queue={:?}, per type={:?}",
+            config.max_total_concurrency, config.queue_concurrency, config.task_type_concurrency
+        ));
+    }
+    LIMITS.set(limits).ok();
+}
+
+/// A global permit reserved before popping, so a worker with no free
+/// capacity leaves tasks in Redis for other workers instead of pulling them
+/// and waiting internally.
+pub struct Slot {
+    total: Option<OwnedSemaphorePermit>,
+}
+
+/// The permits held while a task runs; released on drop.
+pub struct Permits {
+    _total: Option<OwnedSemaphorePermit>,
+    _per_queue: Option<OwnedSemaphorePermit>,
+    _per_type: Option<OwnedSemaphorePermit>,
+}
+
+/// Waits for a free global permit. Listeners call this before every pop.
+pub async fn reserve() -> Slot {
+    let total = match LIMITS.get().and_then(|limits| limits.total.as_ref()) {
+        Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+        None => None,
+    };
+    Slot { total }
+}
+
+impl Slot {
+    /// Waits for the per-queue and per-type permits a top-level task of
+    /// `task_type` popped from `queue` needs. Permits are always taken
+    /// global first, then per queue, then per type, so two tasks can never
+    /// each hold the permit the other is waiting for. BATCH steps run under
+    /// their batch's permits and don't acquire their own.
+    pub async fn acquire(self, queue: &str, task_type: &str) -> Permits {
+        let per_queue = match LIMITS
+            .get()
+            .and_then(|limits| limits.per_queue.get(routing::base_queue(queue)))
+        {
+            Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        let per_type = match LIMITS
+            .get()
+            .and_then(|limits| limits.per_type.get(task_type))
+        {
+            Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        Permits {
+            _total: self.total,
+            _per_queue: per_queue,
+            _per_type: per_type,
+        }
+    }
+}
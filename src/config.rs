@@ -0,0 +1,653 @@
+use crate::config_file;
+use crate::encryption::ResultCipher;
+use crate::envelope::Envelope;
+use crate::logging::{Level, LogFormat};
+use crate::redact::REDACTED;
+use crate::result::{ResultStorage, RESULT_TTL_SECS};
+use crate::routing;
+use crate::secrets;
+use crate::serializer::ResultFormat;
+use serde::{Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// --- Worker Configuration ---
+/// Serializes with secrets redacted, as published to the config key.
+#[derive(Serialize, Debug, Clone)]
+// Executor settings go unread when that executor is compiled out.
+#[cfg_attr(not(all(feature = "docker", feature = "shell")), allow(dead_code))]
+pub struct Config {
+    #[serde(serialize_with = "redact_userinfo")]
+    pub redis_host: String,
+    /// Sentinel addresses (`host:port`); when set, `redis_host` is ignored
+    /// and the master is resolved through them.
+    #[serde(serialize_with = "redact_userinfo_list")]
+    pub redis_sentinels: Vec<String>,
+    pub redis_master_name: String,
+    /// ACL user, from `REDIS_USERNAME`; overrides one in `REDIS_HOST`.
+    pub redis_username: Option<String>,
+    /// From `REDIS_PASSWORD`; overrides one in `REDIS_HOST`.
+    #[serde(serialize_with = "redact")]
+    pub redis_password: Option<String>,
+    /// Connects with TLS (`rediss://`), from `REDIS_TLS`. A `rediss://`
+    /// `REDIS_HOST` turns it on by itself.
+    pub redis_tls: bool,
+    /// Skips verifying the server certificate, from `REDIS_TLS_INSECURE`.
+    pub redis_tls_insecure: bool,
+    /// Logical database this config's connections use, from `REDIS_DB`.
+    pub redis_db: i64,
+    /// Databases to serve, from `REDIS_DBS`; with more than one, a set of
+    /// listeners runs per database. The first is the worker's home database.
+    pub redis_dbs: Vec<i64>,
+    /// How long startup keeps retrying the first Redis connection.
+    pub redis_connect_timeout_secs: u64,
+    /// Seconds between health-check PINGs; 0 disables them.
+    pub redis_ping_interval_secs: u64,
+    /// Cap on the doubling wait between attempts to re-establish a lost
+    /// connection, from `REDIS_RECONNECT_MAX_SECS`.
+    pub redis_reconnect_max_secs: u64,
+    /// Random ±percentage applied to reconnect and error backoff delays.
+    pub backoff_jitter_pct: u32,
+    /// Bound on establishing a Redis connection; 0 is unbounded.
+    pub redis_connection_timeout_secs: u64,
+    /// Bound on waiting for a Redis reply; 0 is unbounded.
+    pub redis_response_timeout_secs: u64,
+    /// Connections in the Redis pool; 1 keeps a single multiplexed connection.
+    pub redis_pool_size: usize,
+    /// Largest task payload accepted from a queue; bigger ones are dead-lettered.
+    pub max_task_bytes: usize,
+    /// Cumulative step output a BATCH result keeps before later steps' output
+    /// is omitted, from `MAX_BATCH_OUTPUT_BYTES`; 0 is unlimited.
+    pub max_batch_output_bytes: usize,
+    /// Bytes of output a task's result keeps, and each of a shell child's
+    /// stdout and stderr, before the rest is dropped with a truncation
+    /// marker, from `MAX_OUTPUT_BYTES`; 0 is unlimited.
+    pub max_output_bytes: usize,
+    /// Memory a shell child or `run_container` container may use, from
+    /// `TASK_MEMORY_MB`; 0 is unlimited.
+    pub task_memory_mb: u64,
+    /// CPUs a shell child or `run_container` container may use, from
+    /// `TASK_CPUS`; 0 is unlimited. Shell children need `TASK_CGROUP_DIR`.
+    pub task_cpus: f64,
+    /// CPU seconds a shell child may use before SIGXCPU, from
+    /// `TASK_CPU_SECS`; 0 is unlimited.
+    pub task_cpu_secs: u64,
+    /// Niceness shell children run at, from `TASK_NICE` (0 to 19).
+    pub task_nice: i32,
+    /// Delegated cgroup v2 directory each shell child gets its own group
+    /// under, from `TASK_CGROUP_DIR`; unset caps memory with `RLIMIT_AS`.
+    pub task_cgroup_dir: Option<String>,
+    /// How many times a dead-lettered entry may be replayed before it stays put.
+    pub max_replays: u64,
+    /// How many times a `requeue_on_error` task is pushed back before its
+    /// error result is written, unless it sets `details.max_retries`.
+    pub max_requeue: u64,
+    /// First delay before retrying a failed task, doubled on each attempt.
+    pub retry_backoff_secs: f64,
+    /// Cap on the delay between retries.
+    pub retry_backoff_max_secs: f64,
+    /// Unique id of this worker, used in per-worker Redis keys.
+    pub worker_id: String,
+    /// Identity matched against `Task.target_host`.
+    pub worker_host: String,
+    /// Placement reported in the worker info, from `WORKER_REGION`/`WORKER_ZONE`.
+    pub worker_region: Option<String>,
+    pub worker_zone: Option<String>,
+    /// Labels matched against `Task.target_selector`, from `WORKER_LABELS`.
+    pub worker_labels: BTreeMap<String, String>,
+    /// Default result TTL, from `RESULT_TTL_SECS`.
+    pub result_ttl_secs: u64,
+    /// Per-queue TTLs, from `RESULT_TTL_OVERRIDES` (`queue=secs,...`).
+    pub result_ttl_overrides: HashMap<String, u64>,
+    /// Default result key prefix, from `RESULT_KEY_PREFIX`.
+    pub result_key_prefix: String,
+    /// Per-queue key prefixes, from `RESULT_KEY_PREFIX_OVERRIDES` (`queue=prefix,...`).
+    pub result_key_prefix_overrides: HashMap<String, String>,
+    /// `RESULT_STORAGE=hash` writes results as hashes instead of strings.
+    pub result_storage: ResultStorage,
+    /// Encoding of string results, from `RESULT_FORMAT` (`json`, the default,
+    /// `msgpack`, or `legacy` for the old `SUCCESS: ...` strings).
+    pub result_format: ResultFormat,
+    /// Encrypts stored results when `RESULT_ENCRYPTION_KEY` is set. Loaded
+    /// by `main` so an invalid key stops startup.
+    #[serde(serialize_with = "redact")]
+    pub result_cipher: Option<Arc<ResultCipher>>,
+    /// Looks up `secret://<name>` references in `details.env` when
+    /// `SECRETS_PROVIDER` is set. Loaded by `main` so a bad setting stops
+    /// startup.
+    #[serde(serialize_with = "redact")]
+    pub secrets: Option<Arc<secrets::Provider>>,
+    /// Gzips a string result, or a hash result's `output`, larger than
+    /// `RESULT_COMPRESS_BYTES` (default 64 KiB); 0 never compresses.
+    pub result_compress_bytes: usize,
+    /// Splits a string result larger than `RESULT_CHUNK_BYTES` (default
+    /// 512 KiB) across `<result key>::<n>` keys behind a manifest; 0 keeps
+    /// results in one key.
+    pub result_chunk_bytes: usize,
+    /// Honors `mcp::touch::<id>` requests to extend a result's TTL.
+    pub result_touch: bool,
+    /// Streams every task's output to `mcp::stream::<id>`, from `OUTPUT_STREAMS`.
+    pub output_streams: bool,
+    /// Approximate cap on a live output stream's entries, from
+    /// `OUTPUT_STREAM_MAX_LEN`.
+    pub output_stream_max_len: usize,
+    /// Writes `mcp::ack::<id>` before a task runs, from `WRITE_ACK`.
+    pub write_ack: bool,
+    /// Restores last-writer-wins result writes instead of `SET NX`.
+    pub result_overwrite: bool,
+    /// How long a task id is remembered under `mcp::seen::<id>`, so a task
+    /// pushed twice runs once, from `DEDUP_TTL_SECS`; 0 runs every delivery.
+    pub dedup_ttl_secs: u64,
+    /// Writes the startup recovery report to `mcp::recovery::<worker_id>`,
+    /// listing what was found in `mcp::processing::<worker_id>::<queue>`.
+    pub recovery_report: bool,
+    /// Signs result callbacks with HMAC-SHA256 when set, from `CALLBACK_SECRET`.
+    #[serde(serialize_with = "redact")]
+    pub callback_secret: Option<String>,
+    /// Per-attempt timeout for result callbacks.
+    pub callback_timeout_secs: u64,
+    /// Retries after a failed callback attempt.
+    pub callback_retries: u32,
+    /// Records undeliverable callbacks in `mcp::callback_failures`.
+    pub callback_record_failures: bool,
+    /// Timeout of an HTTP task that doesn't set `details.timeout_secs`.
+    pub http_task_timeout_secs: u64,
+    /// Response body an HTTP task keeps before the rest is dropped.
+    pub http_max_body_bytes: usize,
+    /// Requires every queued task to be HMAC-signed with this secret, from
+    /// `MCP_TASK_SECRET` (see [`crate::signing::verify`]).
+    #[serde(serialize_with = "redact")]
+    pub task_secret: Option<String>,
+    /// How far a signed task's `signed_at` may be from now, from
+    /// `MCP_TASK_MAX_AGE_SECS`.
+    pub task_max_age_secs: u64,
+    /// Worker variables shell tasks may inherit, from `ENV_ALLOWLIST`; when
+    /// set (even empty) the child environment starts out cleared.
+    pub env_allowlist: Option<Vec<String>>,
+    /// Command prepended to every shell task's argv, e.g. `firejail --quiet`.
+    pub shell_wrapper: String,
+    /// Sends the docker commands the Engine API backend covers through the
+    /// CLI instead, from `DOCKER_BACKEND=cli` (default `api`).
+    pub docker_cli: bool,
+    /// Docker-compatible CLI to invoke, from `DOCKER_BIN` (default `docker`).
+    pub docker_bin: String,
+    /// Docker commands this worker will run, from `DOCKER_ALLOWED_COMMANDS`;
+    /// empty allows all of them.
+    pub docker_allowed_commands: Vec<String>,
+    /// Extra flags per docker subcommand, from `DOCKER_EXTRA_ARGS`
+    /// (`ps=--no-trunc,build=--pull`); split like shell words when used.
+    pub docker_extra_args: BTreeMap<String, String>,
+    /// How many hosts a `details.hosts` docker fan-out queries at once, from
+    /// `DOCKER_FANOUT_CONCURRENCY`.
+    pub docker_fanout_concurrency: usize,
+    /// Image of the warm containers docker `exec` tasks run in, from
+    /// `RUNNER_IMAGE`; unset disables the pool.
+    pub runner_image: Option<String>,
+    /// Most runners kept at once, from `RUNNER_POOL_SIZE`.
+    pub runner_pool_size: usize,
+    /// How long a runner may sit idle before it's removed, from
+    /// `RUNNER_IDLE_TTL_SECS`.
+    pub runner_idle_ttl_secs: u64,
+    /// Host directory `copy_to_container`/`copy_from_container` are confined
+    /// to, from `DOCKER_COPY_DIR`; unset disables both commands.
+    pub docker_copy_dir: Option<String>,
+    /// Host directory `run_container` bind mounts are confined to, from
+    /// `DOCKER_MOUNT_DIR`; unset allows only named volumes.
+    pub docker_mount_dir: Option<String>,
+    /// Directory compose projects (`details.project_dir`) and inline compose
+    /// files live in, from `COMPOSE_DIR`; unset disables the compose commands.
+    pub compose_dir: Option<String>,
+    /// Directory FILE tasks are confined to, from `FILE_BASE_DIR`; unset
+    /// disables FILE tasks.
+    pub file_base_dir: Option<String>,
+    /// Largest file a FILE `download` writes, from `FILE_MAX_DOWNLOAD_BYTES`.
+    pub file_max_download_bytes: u64,
+    /// Largest file a FILE `upload` returns inline (not streamed), from
+    /// `FILE_MAX_UPLOAD_BYTES`.
+    pub file_max_upload_bytes: u64,
+    /// Enables the privileged SYSTEMD task type and its queue.
+    pub allow_systemd: bool,
+    /// Lets a CONTROL `restart_worker` task re-exec the worker, from
+    /// `ALLOW_SELF_RESTART`.
+    pub allow_self_restart: bool,
+    /// Records every execution in the hash-chained audit trail, from `AUDIT`.
+    pub audit: bool,
+    /// File the audit trail is also appended to, from `AUDIT_FILE`.
+    pub audit_file: Option<String>,
+    /// SQLite database every finished task is recorded in, from
+    /// `HISTORY_DB`; unset keeps no history.
+    pub history_db: Option<String>,
+    /// Bytes of output kept per history row, from `HISTORY_OUTPUT_BYTES`.
+    pub history_output_bytes: usize,
+    /// Days history rows are kept, from `HISTORY_RETENTION_DAYS`; 0 keeps
+    /// them forever.
+    pub history_retention_days: u64,
+    /// Wire format of queued tasks, from `ENVELOPE` (`native` or `jsonrpc`).
+    pub envelope: Envelope,
+    /// `TASK_TYPE_CASE=lower` reports task types in lower case (`shell`)
+    /// instead of upper case (`SHELL`).
+    pub lowercase_task_types: bool,
+    /// Queues to consume, from `QUEUES` plus any named in `QUEUE_TASK_TYPES`.
+    /// With `PRIORITY_QUEUES` each queue also has `::high` and `::low` twins,
+    /// and with `HOST_QUEUES` each `mcp::tasks::<kind>` queue is preceded by
+    /// `mcp::tasks::<host>::<kind>`.
+    pub queues: Vec<String>,
+    /// The task types a queue runs, from `QUEUE_TASK_TYPES`
+    /// (`queue=SHELL|HTTP,...`); other types popped from it are refused.
+    /// Queues it doesn't name run any type.
+    pub queue_task_types: HashMap<String, Vec<String>>,
+    /// Per-queue caps on tasks running at once, from `QUEUE_CONCURRENCY`
+    /// (`queue=n,...`), on top of the global and per-type caps.
+    pub queue_concurrency: HashMap<String, usize>,
+    /// Per-queue time limits, from `QUEUE_TIMEOUT_SECS` (`queue=secs,...`):
+    /// a task without a `deadline` gets one that long after it starts.
+    pub queue_timeout_secs: HashMap<String, u64>,
+    /// `QUEUE_FAIRNESS=rotate` rotates the pop order instead of strict priority.
+    pub queue_rotate: bool,
+    /// `PRIORITY_QUEUES` serves each queue's `::high` twin before it and its
+    /// `::low` twin after it, and re-queues tasks by their `priority`.
+    pub priority_queues: bool,
+    /// `TARGET_MISMATCH=reject` dead-letters tasks addressed to another host
+    /// instead of re-queueing them.
+    pub reject_mismatched: bool,
+    /// How long a task may keep being re-queued because no worker it reaches
+    /// is its target before it is dead-lettered, from
+    /// `TARGET_MISMATCH_MAX_AGE_SECS` (default 3600); 0 re-queues it forever.
+    pub mismatch_max_age_secs: u64,
+    /// How long a shutdown waits for running tasks before cancelling them,
+    /// from `SHUTDOWN_DRAIN_SECS`; 0 cancels them right away.
+    pub shutdown_drain_secs: u64,
+    /// Tasks each listener runs at once, from `MAX_CONCURRENT_TASKS`
+    /// (default 1, one after another).
+    pub max_concurrent_tasks: usize,
+    /// Cap on tasks running at once across all types; 0 is unlimited.
+    pub max_total_concurrency: usize,
+    /// Per-type caps from `TASK_TYPE_CONCURRENCY` (`SHELL=2,DOCKER=4`).
+    pub task_type_concurrency: HashMap<String, usize>,
+    /// `LISTENERS=per-queue` runs a dedicated listener loop per queue.
+    pub listener_per_queue: bool,
+    /// Runtime after which a task is logged as slow; 0 disables the warning.
+    pub slow_task_secs: u64,
+    /// How long a timed-out or cancelled child gets between SIGTERM and
+    /// SIGKILL, from `KILL_GRACE_SECS`; 0 SIGKILLs right away.
+    pub kill_grace_secs: u64,
+    /// Log lines buffered for the background writer before new ones are
+    /// dropped, from `LOG_BUFFER_LINES`.
+    pub log_buffer_lines: usize,
+    /// Address the embedded HTTP server (`/metrics`, `/healthz`, `/readyz`)
+    /// listens on, from `HTTP_LISTEN` (e.g. `0.0.0.0:9100`); unset leaves
+    /// it off.
+    pub http_listen: Option<String>,
+    /// How long `/healthz` tolerates a listener loop with neither a
+    /// completed pop nor a heartbeat, from `HEALTH_STUCK_SECS`; 0 never
+    /// reports it stuck.
+    pub health_stuck_secs: u64,
+    /// Where log lines are appended besides stdout, from `LOG_FILE`; empty
+    /// logs to stdout only.
+    pub log_file: String,
+    /// Lines below `LOG_LEVEL` (`error`, `warn`, `info`, `debug`) are skipped.
+    pub log_level: Level,
+    /// `LOG_FORMAT=json` writes one JSON object per line.
+    pub log_format: LogFormat,
+    /// Size at which the log file is rotated, from `LOG_ROTATE_BYTES`; 0
+    /// never rotates on size.
+    pub log_rotate_bytes: u64,
+    /// Rotates the log file daily, from `LOG_ROTATE_DAILY`.
+    pub log_rotate_daily: bool,
+    /// Rotated log files kept, from `LOG_ROTATE_KEEP`.
+    pub log_rotate_keep: usize,
+    /// Seconds between `STATS` log summaries; 0 disables them.
+    pub stats_interval_secs: u64,
+    /// Seconds between refreshes of `mcp::worker::<id>`; 0 publishes it once.
+    pub heartbeat_interval_secs: u64,
+    /// Expiry of `mcp::worker::<id>`, from `HEARTBEAT_TTL_SECS`; 0 never
+    /// expires it.
+    pub heartbeat_ttl_secs: u64,
+}
+
+impl Config {
+    /// Reads every setting from its env var, falling back to the config file
+    /// (see [`config_file::load`]) and then the default.
+    pub fn from_env() -> Self {
+        let worker_host = var("WORKER_HOST").unwrap_or_else(|_| system_hostname());
+        let allow_systemd = env_bool("ALLOW_SYSTEMD", false);
+        let mut queues = env_list("QUEUES");
+        if queues.is_empty() {
+            // Only queues for executors compiled into this binary.
+            if cfg!(feature = "shell") {
+                queues.push("mcp::tasks::shell".to_string());
+            }
+            if cfg!(feature = "docker") {
+                queues.push("mcp::tasks::docker".to_string());
+            }
+            // Privileged queues are only consumed when explicitly enabled.
+            if allow_systemd {
+                queues.push("mcp::tasks::systemd".to_string());
+            }
+        }
+        let queue_task_types: HashMap<String, Vec<String>> =
+            parse_labels(&env_or("QUEUE_TASK_TYPES", ""))
+                .into_iter()
+                .map(|(queue, types)| {
+                    let types = types
+                        .split('|')
+                        .map(|t| t.trim().to_ascii_uppercase())
+                        .filter(|t| !t.is_empty())
+                        .collect();
+                    (queue, types)
+                })
+                .collect();
+        let mut mapped: Vec<&String> = queue_task_types
+            .keys()
+            .filter(|queue| !queues.contains(queue))
+            .collect();
+        mapped.sort();
+        queues.extend(mapped.into_iter().cloned());
+        // Every high priority queue goes before any normal one, and so on.
+        let priority_queues = env_bool("PRIORITY_QUEUES", false);
+        if priority_queues {
+            queues = routing::Priority::ALL
+                .iter()
+                .flat_map(|&priority| {
+                    queues
+                        .iter()
+                        .map(move |queue| routing::priority_queue(queue, priority))
+                })
+                .collect();
+        }
+        // Host-scoped queues go first: work addressed to this host alone.
+        if env_bool("HOST_QUEUES", false) {
+            queues = queues
+                .iter()
+                .flat_map(|queue| {
+                    routing::host_queue(queue, &worker_host)
+                        .into_iter()
+                        .chain([queue.clone()])
+                })
+                .collect();
+        }
+        let redis_dbs: Vec<i64> = env_list("REDIS_DBS")
+            .iter()
+            .filter_map(|db| db.parse().ok())
+            .collect();
+        Config {
+            redis_db: redis_dbs
+                .first()
+                .copied()
+                .unwrap_or_else(|| env_parse("REDIS_DB", 0)),
+            redis_dbs,
+            redis_host: env_or("REDIS_HOST", "127.0.0.1"),
+            redis_sentinels: env_list("REDIS_SENTINELS"),
+            redis_master_name: env_or("REDIS_MASTER_NAME", "mymaster"),
+            redis_username: var("REDIS_USERNAME").ok().filter(|v| !v.is_empty()),
+            redis_password: var("REDIS_PASSWORD").ok().filter(|v| !v.is_empty()),
+            redis_tls: env_bool("REDIS_TLS", false),
+            redis_tls_insecure: env_bool("REDIS_TLS_INSECURE", false),
+            redis_connect_timeout_secs: env_parse("REDIS_CONNECT_TIMEOUT", 60),
+            redis_ping_interval_secs: env_parse("REDIS_PING_INTERVAL_SECS", 30),
+            redis_reconnect_max_secs: env_parse("REDIS_RECONNECT_MAX_SECS", 30).max(1),
+            backoff_jitter_pct: env_parse("BACKOFF_JITTER_PCT", 20),
+            redis_connection_timeout_secs: env_parse("REDIS_CONNECTION_TIMEOUT", 10),
+            redis_response_timeout_secs: env_parse("REDIS_RESPONSE_TIMEOUT", 0),
+            redis_pool_size: env_parse("REDIS_POOL_SIZE", 1),
+            max_task_bytes: env_parse("MAX_TASK_BYTES", 1024 * 1024),
+            max_batch_output_bytes: env_parse("MAX_BATCH_OUTPUT_BYTES", 1024 * 1024),
+            max_output_bytes: env_parse("MAX_OUTPUT_BYTES", 16 * 1024 * 1024),
+            task_memory_mb: env_parse("TASK_MEMORY_MB", 0),
+            task_cpus: env_parse("TASK_CPUS", 0.0),
+            task_cpu_secs: env_parse("TASK_CPU_SECS", 0),
+            task_nice: env_parse("TASK_NICE", 0).clamp(0, 19),
+            task_cgroup_dir: var("TASK_CGROUP_DIR").ok().filter(|v| !v.is_empty()),
+            max_replays: env_parse("MAX_REPLAYS", 3),
+            max_requeue: env_parse("MAX_REQUEUE", 3),
+            retry_backoff_secs: env_parse("RETRY_BACKOFF_SECS", 1.0),
+            retry_backoff_max_secs: env_parse("RETRY_BACKOFF_MAX_SECS", 300.0),
+            worker_id: var("WORKER_ID").unwrap_or_else(|_| worker_host.clone()),
+            worker_host,
+            worker_region: var("WORKER_REGION").ok().filter(|v| !v.is_empty()),
+            worker_zone: var("WORKER_ZONE").ok().filter(|v| !v.is_empty()),
+            worker_labels: parse_labels(&env_or("WORKER_LABELS", "")),
+            result_ttl_secs: env_parse("RESULT_TTL_SECS", RESULT_TTL_SECS),
+            result_ttl_overrides: parse_labels(&env_or("RESULT_TTL_OVERRIDES", ""))
+                .into_iter()
+                .filter_map(|(queue, ttl)| Some((queue, ttl.parse().ok()?)))
+                .collect(),
+            result_key_prefix: env_or("RESULT_KEY_PREFIX", "mcp::result::"),
+            result_key_prefix_overrides: parse_labels(&env_or("RESULT_KEY_PREFIX_OVERRIDES", ""))
+                .into_iter()
+                .collect(),
+            result_storage: ResultStorage::from_env_value(&env_or("RESULT_STORAGE", "string")),
+            result_format: ResultFormat::from_env_value(&env_or("RESULT_FORMAT", "json")),
+            result_cipher: None,
+            secrets: None,
+            result_compress_bytes: env_parse("RESULT_COMPRESS_BYTES", 64 * 1024),
+            result_chunk_bytes: env_parse("RESULT_CHUNK_BYTES", 512 * 1024),
+            result_touch: env_bool("RESULT_TOUCH", false),
+            output_streams: env_bool("OUTPUT_STREAMS", false),
+            output_stream_max_len: env_parse("OUTPUT_STREAM_MAX_LEN", 10_000),
+            write_ack: env_bool("WRITE_ACK", false),
+            result_overwrite: env_bool("RESULT_OVERWRITE", false),
+            dedup_ttl_secs: env_parse("DEDUP_TTL_SECS", RESULT_TTL_SECS),
+            recovery_report: env_bool("RECOVERY_REPORT", false),
+            callback_secret: var("CALLBACK_SECRET").ok().filter(|v| !v.is_empty()),
+            callback_timeout_secs: env_parse("CALLBACK_TIMEOUT_SECS", 10),
+            callback_retries: env_parse("CALLBACK_RETRIES", 3),
+            callback_record_failures: env_bool("CALLBACK_RECORD_FAILURES", false),
+            http_task_timeout_secs: env_parse("HTTP_TASK_TIMEOUT_SECS", 30),
+            http_max_body_bytes: env_parse("HTTP_MAX_BODY_BYTES", 64 * 1024),
+            task_secret: var("MCP_TASK_SECRET").ok().filter(|v| !v.is_empty()),
+            task_max_age_secs: env_parse("MCP_TASK_MAX_AGE_SECS", 300),
+            env_allowlist: var("ENV_ALLOWLIST").ok().map(|_| env_list("ENV_ALLOWLIST")),
+            shell_wrapper: env_or("SHELL_WRAPPER", ""),
+            docker_cli: env_or("DOCKER_BACKEND", "api") == "cli",
+            docker_bin: env_or("DOCKER_BIN", "docker"),
+            docker_allowed_commands: env_list("DOCKER_ALLOWED_COMMANDS"),
+            docker_extra_args: parse_labels(&env_or("DOCKER_EXTRA_ARGS", "")),
+            docker_fanout_concurrency: env_parse("DOCKER_FANOUT_CONCURRENCY", 4).max(1),
+            runner_image: var("RUNNER_IMAGE").ok().filter(|v| !v.is_empty()),
+            runner_pool_size: env_parse("RUNNER_POOL_SIZE", 2).max(1),
+            runner_idle_ttl_secs: env_parse("RUNNER_IDLE_TTL_SECS", 300),
+            docker_copy_dir: var("DOCKER_COPY_DIR").ok().filter(|v| !v.is_empty()),
+            docker_mount_dir: var("DOCKER_MOUNT_DIR").ok().filter(|v| !v.is_empty()),
+            compose_dir: var("COMPOSE_DIR").ok().filter(|v| !v.is_empty()),
+            file_base_dir: var("FILE_BASE_DIR").ok().filter(|v| !v.is_empty()),
+            file_max_download_bytes: env_parse("FILE_MAX_DOWNLOAD_BYTES", 100 * 1024 * 1024),
+            file_max_upload_bytes: env_parse("FILE_MAX_UPLOAD_BYTES", 10 * 1024 * 1024),
+            allow_systemd,
+            allow_self_restart: env_bool("ALLOW_SELF_RESTART", false),
+            audit: env_bool("AUDIT", false),
+            audit_file: var("AUDIT_FILE").ok().filter(|v| !v.is_empty()),
+            history_db: var("HISTORY_DB").ok().filter(|v| !v.is_empty()),
+            history_output_bytes: env_parse("HISTORY_OUTPUT_BYTES", 4096),
+            history_retention_days: env_parse("HISTORY_RETENTION_DAYS", 30),
+            envelope: Envelope::from_env_value(&env_or("ENVELOPE", "native")),
+            lowercase_task_types: env_or("TASK_TYPE_CASE", "upper").eq_ignore_ascii_case("lower"),
+            queues,
+            queue_task_types,
+            queue_concurrency: parse_labels(&env_or("QUEUE_CONCURRENCY", ""))
+                .into_iter()
+                .filter_map(|(queue, limit)| Some((queue, limit.parse().ok()?)))
+                .collect(),
+            queue_timeout_secs: parse_labels(&env_or("QUEUE_TIMEOUT_SECS", ""))
+                .into_iter()
+                .filter_map(|(queue, secs)| Some((queue, secs.parse().ok()?)))
+                .filter(|&(_, secs)| secs > 0)
+                .collect(),
+            queue_rotate: env_or("QUEUE_FAIRNESS", "priority") == "rotate",
+            priority_queues,
+            reject_mismatched: env_or("TARGET_MISMATCH", "requeue") == "reject",
+            mismatch_max_age_secs: env_parse("TARGET_MISMATCH_MAX_AGE_SECS", 3600),
+            shutdown_drain_secs: env_parse("SHUTDOWN_DRAIN_SECS", 30),
+            max_concurrent_tasks: env_parse("MAX_CONCURRENT_TASKS", 1).max(1),
+            max_total_concurrency: env_parse("MAX_TOTAL_CONCURRENCY", 0),
+            task_type_concurrency: parse_labels(&env_or("TASK_TYPE_CONCURRENCY", ""))
+                .into_iter()
+                .filter_map(|(task_type, limit)| Some((task_type, limit.parse().ok()?)))
+                .collect(),
+            listener_per_queue: env_or("LISTENERS", "single") == "per-queue",
+            slow_task_secs: env_parse("SLOW_TASK_SECS", 0),
+            kill_grace_secs: env_parse("KILL_GRACE_SECS", 10),
+            stats_interval_secs: env_parse("STATS_INTERVAL_SECS", 60),
+            heartbeat_interval_secs: env_parse("HEARTBEAT_INTERVAL_SECS", 10),
+            heartbeat_ttl_secs: env_parse("HEARTBEAT_TTL_SECS", 30),
+            log_buffer_lines: env_parse("LOG_BUFFER_LINES", 10_000),
+            http_listen: var("HTTP_LISTEN").ok().filter(|v| !v.is_empty()),
+            health_stuck_secs: env_parse("HEALTH_STUCK_SECS", 120),
+            log_file: env_or("LOG_FILE", "mcp-worker.log"),
+            log_level: Level::from_env_value(&env_or("LOG_LEVEL", "info")),
+            log_format: LogFormat::from_env_value(&env_or("LOG_FORMAT", "text")),
+            log_rotate_bytes: env_parse("LOG_ROTATE_BYTES", 0),
+            log_rotate_daily: env_bool("LOG_ROTATE_DAILY", false),
+            log_rotate_keep: env_parse("LOG_ROTATE_KEEP", 5),
+        }
+    }
+
+    /// The result TTL for tasks popped from `queue` (or one of its priority
+    /// twins).
+    pub fn result_ttl_for(&self, queue: &str) -> u64 {
+        self.result_ttl_overrides
+            .get(routing::base_queue(queue))
+            .copied()
+            .unwrap_or(self.result_ttl_secs)
+    }
+
+    /// The task types `queue` (or one of its priority twins) runs, if it is
+    /// limited to some.
+    pub fn task_types_for(&self, queue: &str) -> Option<&[String]> {
+        self.queue_task_types
+            .get(routing::base_queue(queue))
+            .map(Vec::as_slice)
+    }
+
+    /// The time limit for tasks popped from `queue` (or one of its priority
+    /// twins) that don't set a deadline.
+    pub fn timeout_for(&self, queue: &str) -> Option<u64> {
+        self.queue_timeout_secs
+            .get(routing::base_queue(queue))
+            .copied()
+    }
+
+    /// The result key prefix for tasks popped from `queue` (or one of its
+    /// priority twins).
+    pub fn result_key_prefix_for(&self, queue: &str) -> &str {
+        self.result_key_prefix_overrides
+            .get(routing::base_queue(queue))
+            .unwrap_or(&self.result_key_prefix)
+    }
+
+    /// The result key for a task popped from `queue`.
+    pub fn result_key_for(&self, queue: &str, task_id: &str) -> String {
+        format!("{}{}", self.result_key_prefix_for(queue), task_id)
+    }
+}
+
+/// A set secret becomes `***`; an unset one stays `null`.
+fn redact<T, S: Serializer>(secret: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    secret.as_ref().map(|_| REDACTED).serialize(serializer)
+}
+
+/// Hides the `user:password@` part of a Redis address.
+fn redact_userinfo<S: Serializer>(address: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    without_userinfo(address).serialize(serializer)
+}
+
+fn redact_userinfo_list<S: Serializer>(
+    addresses: &[String],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(addresses.iter().map(|address| without_userinfo(address)))
+}
+
+fn without_userinfo(address: &str) -> String {
+    match address.rsplit_once('@') {
+        Some((_, host)) => format!("{}@{}", REDACTED, host),
+        None => address.to_string(),
+    }
+}
+
+/// `key` from the environment, or else from the config file.
+pub fn var(key: &str) -> Result<String, env::VarError> {
+    #[cfg(test)]
+    if let Some(settings) = TEST_SETTINGS.with(|settings| settings.borrow().clone()) {
+        return settings
+            .get(key)
+            .map(|value| value.to_string())
+            .ok_or(env::VarError::NotPresent);
+    }
+    env::var(key).or_else(|e| config_file::setting(key).ok_or(e))
+}
+
+#[cfg(test)]
+thread_local! {
+    /// While set, [`var`] reads these settings instead of the environment
+    /// and the config file.
+    static TEST_SETTINGS: std::cell::RefCell<Option<HashMap<&'static str, &'static str>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+#[cfg(test)]
+impl Config {
+    /// The defaults for worker `test-worker` on host `test-host`, whatever
+    /// the environment or a config file say, so tests don't depend on them.
+    pub fn for_tests() -> Self {
+        let settings = HashMap::from([("WORKER_HOST", "test-host"), ("WORKER_ID", "test-worker")]);
+        TEST_SETTINGS.with(|current| *current.borrow_mut() = Some(settings));
+        let config = Config::from_env();
+        TEST_SETTINGS.with(|current| *current.borrow_mut() = None);
+        config
+    }
+}
+
+fn env_or(key: &str, default: &str) -> String {
+    var(key).unwrap_or_else(|_| default.to_string())
+}
+
+fn env_parse<T: FromStr>(key: &str, default: T) -> T {
+    var(key)
+        .ok()
+        .and_then(|v| v.trim().parse::<T>().ok())
+        .unwrap_or(default)
+}
+
+/// Reads a comma-separated list, skipping empty entries.
+fn env_list(key: &str) -> Vec<String> {
+    env_or(key, "")
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn env_bool(key: &str, default: bool) -> bool {
+    match var(key) {
+        Ok(v) => matches!(
+            v.trim().to_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        ),
+        Err(_) => default,
+    }
+}
+
+fn system_hostname() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Parses a `key=value,key=value` list. A bare `key` is stored with an empty value.
+pub fn parse_labels(raw: &str) -> BTreeMap<String, String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((k, v)) => (k.trim().to_string(), v.trim().to_string()),
+            None => (pair.to_string(), String::new()),
+        })
+        .collect()
+}
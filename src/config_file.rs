@@ -0,0 +1,484 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+// --- Config File ---
+/// Settings read from the config file, keyed by the env var each one stands
+/// in for. Empty until [`load`] runs.
+static SETTINGS: OnceLock<HashMap<String, String>> = OnceLock::new();
+/// `[defaults.<TYPE>]` tables from the config file.
+static TASK_DEFAULTS: OnceLock<HashMap<String, Map<String, Value>>> = OnceLock::new();
+/// The `[policy]` table from the config file.
+static POLICY: OnceLock<Map<String, Value>> = OnceLock::new();
+/// The `[ssh]` table from the config file.
+static SSH: OnceLock<Map<String, Value>> = OnceLock::new();
+
+/// The config file named by `--config <path>` in `args`, or else by
+/// `MCP_CONFIG`.
+pub fn path(args: &[String]) -> Result<Option<String>, String> {
+    if let Some(pos) = args.iter().position(|a| a == "--config") {
+        return match args.get(pos + 1) {
+            Some(path) => Ok(Some(path.clone())),
+            None => Err("--config needs a path".to_string()),
+        };
+    }
+    Ok(std::env::var("MCP_CONFIG").ok().filter(|v| !v.is_empty()))
+}
+
+/// Reads the TOML file at `path` so [`crate::config::Config::from_env`] falls
+/// back to it for anything the environment doesn't set. A key stands in for
+/// the env var named by its table and key in upper case, so
+///
+/// ```toml
+/// queues = ["mcp::tasks::shell"]   # QUEUES
+/// max_concurrent_tasks = 4         # MAX_CONCURRENT_TASKS
+///
+/// [redis]
+/// host = "redis://10.0.0.5/"       # REDIS_HOST
+/// db = 2                           # REDIS_DB
+/// username = "worker"              # REDIS_USERNAME
+/// password = "s3cret"              # REDIS_PASSWORD
+/// tls = true                       # REDIS_TLS
+/// tls_insecure = false             # REDIS_TLS_INSECURE
+///
+/// [result]
+/// ttl_secs = 600                   # RESULT_TTL_SECS
+///
+/// [log]
+/// file = "/var/log/mcp-worker.log" # LOG_FILE
+/// level = "debug"                  # LOG_LEVEL
+///
+/// [defaults.SHELL]
+/// timeout_secs = 30
+///
+/// [policy.shell]
+/// binaries = ["df", "uptime"]
+/// ```
+///
+/// Arrays become comma-separated lists. `[defaults.<TYPE>]` tables are
+/// instead `details` fields given to tasks of that type that don't set them,
+/// and the `[policy]` and `[ssh]` tables are kept whole for
+/// [`crate::policy::init`] and [`crate::ssh::init`].
+/// Only the first call has an effect.
+pub fn load(path: &str) -> Result<(), String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let mut root = parse(&contents).map_err(|e| format!("{}: {}", path, e))?;
+    let mut defaults = HashMap::new();
+    if let Some(Value::Object(tables)) = root.remove("defaults") {
+        for (task_type, fields) in tables {
+            let Value::Object(fields) = fields else {
+                return Err(format!("{}: defaults.{} must be a table", path, task_type));
+            };
+            defaults.insert(task_type.to_ascii_uppercase(), fields);
+        }
+    }
+    let policy = match root.remove("policy") {
+        None => Map::new(),
+        Some(Value::Object(policy)) => policy,
+        Some(_) => return Err(format!("{}: policy must be a table", path)),
+    };
+    let ssh = match root.remove("ssh") {
+        None => Map::new(),
+        Some(Value::Object(ssh)) => ssh,
+        Some(_) => return Err(format!("{}: ssh must be a table", path)),
+    };
+    let mut settings = HashMap::new();
+    flatten("", &root, &mut settings);
+    SETTINGS.set(settings).ok();
+    TASK_DEFAULTS.set(defaults).ok();
+    POLICY.set(policy).ok();
+    SSH.set(ssh).ok();
+    Ok(())
+}
+
+/// The file's value for the env var `key`.
+pub fn setting(key: &str) -> Option<String> {
+    SETTINGS.get()?.get(key).cloned()
+}
+
+/// The `details` defaults for tasks of `task_type`.
+pub fn task_defaults(task_type: &str) -> Option<&'static Map<String, Value>> {
+    TASK_DEFAULTS.get()?.get(task_type)
+}
+
+/// The `[policy]` table, if the config file has one.
+pub fn policy() -> Option<&'static Map<String, Value>> {
+    POLICY.get().filter(|policy| !policy.is_empty())
+}
+
+/// The `[ssh]` table, if the config file has one.
+pub fn ssh() -> Option<&'static Map<String, Value>> {
+    SSH.get().filter(|ssh| !ssh.is_empty())
+}
+
+fn flatten(prefix: &str, table: &Map<String, Value>, out: &mut HashMap<String, String>) {
+    for (key, value) in table {
+        let name = match prefix {
+            "" => key.to_ascii_uppercase(),
+            _ => format!("{}_{}", prefix, key.to_ascii_uppercase()),
+        };
+        match value {
+            Value::Object(table) => flatten(&name, table, out),
+            Value::Array(items) => {
+                let items: Vec<String> = items.iter().map(scalar).collect();
+                out.insert(name, items.join(","));
+            }
+            value => {
+                out.insert(name, scalar(value));
+            }
+        }
+    }
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Parses the subset of TOML a config file needs: `[table]` headers
+/// (dotted for nesting), `key = value` pairs, and strings, integers,
+/// floats, booleans and (possibly multi-line) arrays of them.
+fn parse(contents: &str) -> Result<Map<String, Value>, String> {
+    let mut root = Map::new();
+    let mut table: Vec<String> = Vec::new();
+    let mut lines = contents.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let at = |e: String| format!("line {}: {}", index + 1, e);
+        let line = strip_comment(line).trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header
+                .strip_suffix(']')
+                .ok_or_else(|| at("unterminated table header".to_string()))?;
+            table = split_unquoted(header, '.')
+                .into_iter()
+                .map(|part| unquote_key(part.trim()))
+                .collect::<Result<_, _>>()
+                .map_err(at)?;
+            table_at(&mut root, &table).map_err(at)?;
+            continue;
+        }
+        let (key, value) = unquoted(&line, '=')
+            .first()
+            .map(|&eq| (&line[..eq], &line[eq + 1..]))
+            .ok_or_else(|| at("expected `key = value`".to_string()))?;
+        let key = unquote_key(key.trim()).map_err(at)?;
+        let mut value = value.trim().to_string();
+        // An array may continue over several lines until its brackets close.
+        while value.starts_with('[') && !brackets_closed(&value) {
+            let (_, next) = lines
+                .next()
+                .ok_or_else(|| at("unterminated array".to_string()))?;
+            value.push(' ');
+            value.push_str(strip_comment(next).trim());
+        }
+        let (value, rest) = parse_value(&value).map_err(at)?;
+        if !rest.trim().is_empty() {
+            return Err(at(format!("unexpected `{}` after the value", rest.trim())));
+        }
+        let target = table_at(&mut root, &table).map_err(at)?;
+        if target.insert(key.clone(), value).is_some() {
+            return Err(at(format!("duplicate key `{}`", key)));
+        }
+    }
+    Ok(root)
+}
+
+/// The table at `path`, created (with its parents) if missing.
+fn table_at<'a>(
+    root: &'a mut Map<String, Value>,
+    path: &[String],
+) -> Result<&'a mut Map<String, Value>, String> {
+    let mut table = root;
+    for part in path {
+        let entry = table
+            .entry(part.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        table = match entry {
+            Value::Object(next) => next,
+            _ => return Err(format!("`{}` is already a value, not a table", part)),
+        };
+    }
+    Ok(table)
+}
+
+fn unquote_key(key: &str) -> Result<String, String> {
+    if key.starts_with('"') || key.starts_with('\'') {
+        return match parse_value(key)? {
+            (Value::String(s), "") => Ok(s),
+            _ => Err(format!("invalid key `{}`", key)),
+        };
+    }
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    match bare {
+        true => Ok(key.to_string()),
+        false => Err(format!("invalid key `{}`", key)),
+    }
+}
+
+/// `line` up to a `#` that isn't inside a string.
+fn strip_comment(line: &str) -> &str {
+    unquoted(line, '#').first().map_or(line, |&i| &line[..i])
+}
+
+/// Byte offsets of every `sep` in `text` that isn't inside a string, so a
+/// quoted key may contain `.`, `=` or `#`.
+fn unquoted(text: &str, sep: char) -> Vec<usize> {
+    let mut found = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, c) if c == sep => found.push(i),
+            _ => {}
+        }
+        escaped = false;
+    }
+    found
+}
+
+/// `text` split at each `sep` outside a string.
+fn split_unquoted(text: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for i in unquoted(text, sep) {
+        parts.push(&text[start..i]);
+        start = i + sep.len_utf8();
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn brackets_closed(value: &str) -> bool {
+    let mut depth = 0i32;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in value.chars() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            _ => {}
+        }
+        escaped = false;
+    }
+    depth <= 0
+}
+
+/// Parses one value off the front of `input`, returning it and the rest.
+fn parse_value(input: &str) -> Result<(Value, &str), String> {
+    let input = input.trim_start();
+    if let Some(rest) = input.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                '\\' => value.push(match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    other => {
+                        return Err(format!("unsupported escape `\\{}`", other.unwrap_or(' ')))
+                    }
+                }),
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+    if let Some(rest) = input.strip_prefix('\'') {
+        let end = rest
+            .find('\'')
+            .ok_or_else(|| "unterminated string".to_string())?;
+        return Ok((Value::String(rest[..end].to_string()), &rest[end + 1..]));
+    }
+    if let Some(mut rest) = input.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("expected `,` or `]` in array".to_string());
+            }
+        }
+    }
+    let end = input
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(input.len());
+    let (token, rest) = input.split_at(end);
+    let value = match token {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => {
+            let digits = token.replace('_', "");
+            if let Ok(n) = digits.parse::<i64>() {
+                Value::from(n)
+            } else if let Some(n) = digits.parse::<f64>().ok().filter(|n| n.is_finite()) {
+                Value::from(n)
+            } else {
+                return Err(format!("unsupported value `{}`", token));
+            }
+        }
+    };
+    Ok((value, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_tables_keys_and_values() {
+        let parsed = parse(
+            r#"
+            # comment
+            queues = ["a", "b"]   # trailing comment
+            max = 1_000
+            ratio = 0.5
+            enabled = true
+
+            [redis]
+            host = "redis://h/#not-a-comment"
+            db = -2
+
+            [defaults.SHELL]
+            timeout_secs = 30
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            Value::Object(parsed),
+            json!({
+                "queues": ["a", "b"],
+                "max": 1000,
+                "ratio": 0.5,
+                "enabled": true,
+                "redis": {"host": "redis://h/#not-a-comment", "db": -2},
+                "defaults": {"SHELL": {"timeout_secs": 30}},
+            })
+        );
+    }
+
+    #[test]
+    fn parses_strings() {
+        let parsed = parse(
+            r#"
+            basic = "tab\there \"quoted\" back\\slash"
+            literal = 'C:\path\n'
+            empty = ""
+            "#,
+        )
+        .unwrap();
+        assert_eq!(parsed["basic"], "tab\there \"quoted\" back\\slash");
+        assert_eq!(parsed["literal"], "C:\\path\\n");
+        assert_eq!(parsed["empty"], "");
+    }
+
+    #[test]
+    fn parses_multi_line_and_nested_arrays() {
+        let parsed = parse(
+            r#"
+            binaries = [
+                "df",      # disk
+                "uptime",
+            ]
+            nested = [[1, 2], ["]"]]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(parsed["binaries"], json!(["df", "uptime"]));
+        assert_eq!(parsed["nested"], json!([[1, 2], ["]"]]));
+    }
+
+    #[test]
+    fn quoted_keys_may_hold_separators() {
+        let parsed = parse(
+            r#"
+            "a.b=c#d" = 1
+            [policy."mcp::tasks.shell"]
+            'x=y' = "z"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(parsed["a.b=c#d"], 1);
+        assert_eq!(parsed["policy"]["mcp::tasks.shell"]["x=y"], "z");
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let error = |contents: &str| parse(contents).unwrap_err();
+        assert_eq!(error("key"), "line 1: expected `key = value`");
+        assert_eq!(error("[table"), "line 1: unterminated table header");
+        assert_eq!(error("a = 1\na = 2"), "line 2: duplicate key `a`");
+        assert_eq!(error("a b = 1"), "line 1: invalid key `a b`");
+        assert_eq!(error("a = \"open"), "line 1: unterminated string");
+        assert_eq!(error("a = [1,\n2"), "line 1: unterminated array");
+        assert_eq!(error("a = 1 2"), "line 1: unexpected `2` after the value");
+        assert_eq!(error("a = nope"), "line 1: unsupported value `nope`");
+        assert_eq!(error("a = \"\\q\""), "line 1: unsupported escape `\\q`");
+        assert_eq!(
+            error("a = 1\n[a]"),
+            "line 2: `a` is already a value, not a table"
+        );
+    }
+
+    #[test]
+    fn flattens_into_env_var_names() {
+        let parsed = parse(
+            r#"
+            queues = ["a", "b"]
+            [redis]
+            db = 2
+            username = "worker"
+            password = "s3cret"
+            tls = true
+            tls_insecure = false
+            [result]
+            ttl_secs = 600
+            [log]
+            level = "debug"
+            "#,
+        )
+        .unwrap();
+        let mut settings = HashMap::new();
+        flatten("", &parsed, &mut settings);
+        assert_eq!(settings["QUEUES"], "a,b");
+        assert_eq!(settings["REDIS_DB"], "2");
+        assert_eq!(settings["REDIS_USERNAME"], "worker");
+        assert_eq!(settings["REDIS_PASSWORD"], "s3cret");
+        assert_eq!(settings["REDIS_TLS"], "true");
+        assert_eq!(settings["REDIS_TLS_INSECURE"], "false");
+        assert_eq!(settings["RESULT_TTL_SECS"], "600");
+        assert_eq!(settings["LOG_LEVEL"], "debug");
+    }
+}
@@ -0,0 +1,207 @@
+use crate::backoff;
+use crate::config::Config;
+use crate::listener;
+use crate::log;
+use crate::sentinel;
+use redis::aio::MultiplexedConnection;
+use std::ops::{Deref, DerefMut};
+use tokio::time::{self, Duration, Instant};
+
+// --- Redis Connections ---
+/// The connections the worker talks to Redis through. With a pool size of 1
+/// everything shares one multiplexed connection; larger pools give the
+/// blocking pop loop its own connection so a stalled pop can't back up
+/// result writes, which borrow from the pool instead.
+#[derive(Clone)]
+pub enum Connections {
+    Single(MultiplexedConnection),
+    Pooled {
+        listener: MultiplexedConnection,
+        pool: deadpool_redis::Pool,
+    },
+}
+
+/// A connection borrowed for a short command such as a result write.
+pub enum Writer {
+    Shared(MultiplexedConnection),
+    Pooled(deadpool_redis::Connection),
+}
+
+/// First and largest delay between initial connection attempts.
+const CONNECT_BACKOFF_START: Duration = Duration::from_millis(500);
+const CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+/// Floor for the listener's response timeout: the longest blocking pop plus slack.
+const LISTENER_MIN_RESPONSE_TIMEOUT: Duration =
+    Duration::from_secs(listener::POP_TIMEOUT_SECS as u64 + 5);
+
+fn timeout_or_max(secs: u64) -> Duration {
+    match secs {
+        0 => Duration::MAX,
+        secs => Duration::from_secs(secs),
+    }
+}
+
+/// The URL to connect to `host` with: a bare `host:port` (optionally with
+/// `user:password@`) or a full `redis://`/`rediss://` URL, upgraded to TLS by
+/// `REDIS_TLS`, with `REDIS_USERNAME`/`REDIS_PASSWORD` and the database
+/// filled in.
+fn redis_url(host: &str, config: &Config) -> Result<String, String> {
+    let raw = match host.contains("://") {
+        true => host.to_string(),
+        false => format!("redis://{}", host),
+    };
+    let invalid = |e: &dyn std::fmt::Display| format!("Invalid Redis address: {}", e);
+    let mut url = reqwest::Url::parse(&raw).map_err(|e| invalid(&e))?;
+    if !matches!(url.scheme(), "redis" | "rediss") {
+        return Err(invalid(&format!("unsupported scheme '{}'", url.scheme())));
+    }
+    if config.redis_tls {
+        url.set_scheme("rediss")
+            .map_err(|_| invalid(&"can't switch to rediss://"))?;
+    }
+    if let Some(username) = &config.redis_username {
+        url.set_username(username)
+            .map_err(|_| invalid(&"can't set a username"))?;
+    }
+    if let Some(password) = &config.redis_password {
+        url.set_password(Some(password))
+            .map_err(|_| invalid(&"can't set a password"))?;
+    }
+    url.set_path(&format!("/{}", config.redis_db));
+    if url.scheme() == "rediss" && config.redis_tls_insecure {
+        url.set_fragment(Some("insecure"));
+    }
+    Ok(url.to_string())
+}
+
+impl Connections {
+    /// Like [`Connections::open`], but keeps retrying with capped exponential
+    /// backoff (jittered by `BACKOFF_JITTER_PCT`) for up to
+    /// `REDIS_CONNECT_TIMEOUT` so the worker can start before Redis is up.
+    pub async fn open_with_retry(config: &Config) -> Result<Self, String> {
+        let deadline = Instant::now() + Duration::from_secs(config.redis_connect_timeout_secs);
+        let mut delay = CONNECT_BACKOFF_START;
+        let mut attempt = 1;
+
+        loop {
+            match Connections::open(config).await {
+                Ok(conns) => return Ok(conns),
+                Err(e) if Instant::now() < deadline => {
+                    let wait = backoff::jitter(delay, config.backoff_jitter_pct)
+                        .min(deadline - Instant::now());
+                    log(&format!(
+                        "Redis connection attempt {} failed: {}. Retrying in {:?}...",
+                        attempt, e, wait
+                    ));
+                    time::sleep(wait).await;
+                    delay = (delay * 2).min(CONNECT_BACKOFF_MAX);
+                    attempt += 1;
+                }
+                Err(e) => return Err(format!("{} (gave up after {} attempts)", e, attempt)),
+            }
+        }
+    }
+
+    /// Resolves the Redis address (through Sentinel when configured) and
+    /// connects to it.
+    pub async fn open(config: &Config) -> Result<Self, String> {
+        let host = if config.redis_sentinels.is_empty() {
+            config.redis_host.clone()
+        } else {
+            sentinel::resolve_master(&config.redis_sentinels, &config.redis_master_name).await?
+        };
+        Connections::connect(&redis_url(&host, config)?, config).await
+    }
+
+    /// Connects with `REDIS_CONNECTION_TIMEOUT` and `REDIS_RESPONSE_TIMEOUT`
+    /// applied (0 leaves either unbounded), so a partition fails operations
+    /// and triggers a reconnect instead of hanging. TCP keepalive comes from
+    /// the redis crate's `keep-alive` feature. The listener connection's
+    /// response timeout is kept above the BLPOP wait so idle pops don't trip it.
+    async fn connect(redis_url: &str, config: &Config) -> Result<Self, String> {
+        let client = redis::Client::open(redis_url)
+            .map_err(|e| format!("Redis client creation failed: {}", e))?;
+        let connection_timeout = timeout_or_max(config.redis_connection_timeout_secs);
+        let response_timeout = match config.redis_response_timeout_secs {
+            0 => Duration::MAX,
+            secs => Duration::from_secs(secs).max(LISTENER_MIN_RESPONSE_TIMEOUT),
+        };
+        let listener = client
+            .get_multiplexed_async_connection_with_timeouts(response_timeout, connection_timeout)
+            .await
+            .map_err(|e| format!("Failed to get multiplexed Redis connection: {}", e))?;
+
+        if config.redis_pool_size <= 1 {
+            return Ok(Connections::Single(listener));
+        }
+
+        let mut pool = deadpool_redis::PoolConfig::new(config.redis_pool_size);
+        if config.redis_connection_timeout_secs > 0 {
+            pool.timeouts.create = Some(connection_timeout);
+        }
+        let pool_config = deadpool_redis::Config {
+            pool: Some(pool),
+            ..deadpool_redis::Config::from_url(redis_url)
+        };
+        let pool = pool_config
+            .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+            .map_err(|e| format!("Redis pool creation failed: {}", e))?;
+
+        Ok(Connections::Pooled { listener, pool })
+    }
+
+    /// The connection reserved for the blocking queue pop.
+    pub fn listener(&mut self) -> &mut MultiplexedConnection {
+        match self {
+            Connections::Single(conn) => conn,
+            Connections::Pooled { listener, .. } => listener,
+        }
+    }
+
+    /// A handle on the listener connection for work that runs between pops,
+    /// such as progress updates during task execution.
+    pub fn shared(&self) -> MultiplexedConnection {
+        match self {
+            Connections::Single(conn) => conn.clone(),
+            Connections::Pooled { listener, .. } => listener.clone(),
+        }
+    }
+
+    /// Borrows a connection for writes. If the pool is exhausted or broken the
+    /// write falls back to the listener connection rather than being dropped.
+    pub async fn writer(&self) -> Writer {
+        match self {
+            Connections::Single(conn) => Writer::Shared(conn.clone()),
+            Connections::Pooled { listener, pool } => match pool.get().await {
+                Ok(conn) => Writer::Pooled(conn),
+                Err(e) => {
+                    log(&format!(
+                        "[ERROR] Failed to borrow pooled Redis connection, using listener: {}",
+                        e
+                    ));
+                    Writer::Shared(listener.clone())
+                }
+            },
+        }
+    }
+}
+
+impl Deref for Writer {
+    type Target = MultiplexedConnection;
+
+    fn deref(&self) -> &MultiplexedConnection {
+        match self {
+            Writer::Shared(conn) => conn,
+            Writer::Pooled(conn) => conn,
+        }
+    }
+}
+
+impl DerefMut for Writer {
+    fn deref_mut(&mut self) -> &mut MultiplexedConnection {
+        match self {
+            Writer::Shared(conn) => conn,
+            Writer::Pooled(conn) => conn,
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::config::Config;
+use crate::health;
+use crate::log;
+use crate::Task;
+use redis::AsyncCommands;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// --- Pause / Resume ---
+/// Whether the worker is currently consuming tasks. Toggled by SIGUSR2 or by
+/// setting `mcp::control::<worker_id>` to `paused`/`active`.
+#[derive(Clone, Default)]
+pub struct PauseState {
+    paused: Arc<AtomicBool>,
+}
+
+impl PauseState {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn set(&self, paused: bool, source: &str) {
+        health::set_paused(paused);
+        if self.paused.swap(paused, Ordering::SeqCst) != paused {
+            let state = if paused { "PAUSED" } else { "ACTIVE" };
+            log(&format!("Worker {} via {}.", state, source));
+        }
+    }
+
+    fn toggle(&self, source: &str) {
+        self.set(!self.is_paused(), source);
+    }
+}
+
+pub fn control_key(worker_id: &str) -> String {
+    format!("mcp::control::{}", worker_id)
+}
+
+/// Spawns a background task that flips the pause state on every SIGUSR2.
+#[cfg(unix)]
+pub fn watch_signal(state: PauseState) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigusr2 = match signal(SignalKind::user_defined2()) {
+        Ok(s) => s,
+        Err(e) => {
+            log(&format!("[ERROR] Failed to install SIGUSR2 handler: {}", e));
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while sigusr2.recv().await.is_some() {
+            state.toggle("SIGUSR2");
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn watch_signal(_state: PauseState) {}
+
+/// Applies the worker's control key. Only changes of the key are applied, so
+/// a stale `active` value doesn't undo a later SIGUSR2 pause.
+pub async fn poll_control_key(
+    conn: &mut redis::aio::MultiplexedConnection,
+    worker_id: &str,
+    state: &PauseState,
+    last_seen: &mut Option<String>,
+) {
+    let value: Option<String> = match conn.get(control_key(worker_id)).await {
+        Ok(v) => v,
+        Err(e) => {
+            log(&format!("[ERROR] Failed to read control key: {}", e));
+            return;
+        }
+    };
+    if value == *last_seen {
+        return;
+    }
+
+    match value.as_deref() {
+        Some("paused") => state.set(true, "control key"),
+        Some("active") => state.set(false, "control key"),
+        Some(other) => log(&format!("[ERROR] Unknown control value: {}", other)),
+        None => {}
+    }
+    *last_seen = value;
+}
+
+// --- Self Restart ---
+/// Set by `restart_worker`; listeners stop popping and return once it is.
+static RESTART: AtomicBool = AtomicBool::new(false);
+
+pub fn restart_requested() -> bool {
+    RESTART.load(Ordering::SeqCst)
+}
+
+/// Runs a CONTROL task. `restart_worker` (behind `ALLOW_SELF_RESTART`)
+/// only schedules the restart: the listeners finish their in-flight tasks
+/// and return, and `main` then re-execs the binary.
+pub fn execute(task: &Task, config: &Config) -> Result<String, String> {
+    let action = task.details["action"]
+        .as_str()
+        .ok_or("CONTROL task requires a string 'action'")?;
+    match action {
+        "restart_worker" => {
+            if !config.allow_self_restart {
+                return Err(
+                    "restart_worker is disabled on this worker (set ALLOW_SELF_RESTART)"
+                        .to_string(),
+                );
+            }
+            if !RESTART.swap(true, Ordering::SeqCst) {
+                log(&format!(
+                    "Restart requested by task {}; draining in-flight tasks.",
+                    task.id
+                ));
+            }
+            Ok(serde_json::json!({
+                "action": action,
+                "worker_id": config.worker_id,
+                "status": "restart scheduled",
+            })
+            .to_string())
+        }
+        other => Err(format!(
+            "Unsupported control action '{}'. Allowed: restart_worker",
+            other
+        )),
+    }
+}
+
+/// Replaces the process with a fresh copy of the binary, looked up like
+/// `execvp` from the original `argv[0]`, with the original arguments and
+/// environment. Only returns on failure.
+#[cfg(unix)]
+pub fn reexec() -> String {
+    use std::os::unix::process::CommandExt;
+
+    let mut args = std::env::args_os();
+    let Some(program) = args.next() else {
+        return "cannot restart: argv[0] is missing".to_string();
+    };
+    let e = std::process::Command::new(&program).args(args).exec();
+    format!("Failed to re-exec {:?}: {}", program, e)
+}
+
+#[cfg(not(unix))]
+pub fn reexec() -> String {
+    "restart_worker is only supported on Unix".to_string()
+}
@@ -0,0 +1,238 @@
+use crate::config::Config;
+use crate::log;
+use crate::queue::TaskQueue;
+use crate::retry::Failure;
+use crate::signing;
+use crate::stats::{self, STATS};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+pub const DEADLETTER_KEY: &str = "mcp::deadletter";
+
+// --- Structs ---
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeadLetter {
+    pub queue: String,
+    pub payload: String,
+    pub reason: String,
+    #[serde(default)]
+    pub replay_count: u64,
+    /// Every failed attempt of a task that used up its retries.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<Failure>,
+}
+
+/// Pushes a payload that could not be processed onto the dead-letter queue,
+/// remembering the queue it came from so it can be replayed later.
+pub async fn dead_letter<Q: TaskQueue>(backend: &mut Q, queue: &str, payload: &str, reason: &str) {
+    dead_letter_failed(backend, queue, payload, reason, Vec::new()).await;
+}
+
+/// Like [`dead_letter`], for a task that failed on every attempt; `errors`
+/// is its history.
+pub async fn dead_letter_failed<Q: TaskQueue>(
+    backend: &mut Q,
+    queue: &str,
+    payload: &str,
+    reason: &str,
+    errors: Vec<Failure>,
+) {
+    stats::incr(&STATS.dead_lettered);
+    let entry = DeadLetter {
+        queue: queue.to_string(),
+        payload: payload.to_string(),
+        reason: reason.to_string(),
+        replay_count: replay_count_of(payload),
+        errors,
+    };
+
+    let entry_json = match serde_json::to_string(&entry) {
+        Ok(s) => s,
+        Err(e) => {
+            log(&format!(
+                "[ERROR] Failed to serialize dead-letter entry: {}",
+                e
+            ));
+            return;
+        }
+    };
+
+    match backend.push(DEADLETTER_KEY, &entry_json).await {
+        Ok(()) => log(&format!("Payload from {} dead-lettered: {}", queue, reason)),
+        Err(e) => log(&format!("[ERROR] Failed to dead-letter payload: {}", e)),
+    }
+}
+
+/// Moves dead-lettered entries back onto their original queues, oldest first,
+/// with their `attempts` reset so a retried task gets its retries again, and
+/// re-signed when tasks are signed.
+/// Entries that were already replayed `max_replays` times, or whose payload is
+/// not a JSON object we can tag with a `replay_count`, stay in the dead-letter queue.
+/// So do payloads signature verification refused and, with `MCP_TASK_SECRET`,
+/// ones whose own signature doesn't match, so a replay never signs a task
+/// that wasn't signed to begin with.
+pub async fn replay(
+    conn: &mut redis::aio::MultiplexedConnection,
+    limit: Option<usize>,
+    config: &Config,
+) {
+    let max_replays = config.max_replays;
+    log(&format!(
+        "Replaying dead-letter queue {} (limit: {:?}, max replays: {})",
+        DEADLETTER_KEY, limit, max_replays
+    ));
+
+    let mut replayed = 0;
+    let mut kept = Vec::new();
+
+    while limit.is_none_or(|n| replayed < n) {
+        let raw: Option<String> = match conn.lpop(DEADLETTER_KEY, None).await {
+            Ok(r) => r,
+            Err(e) => {
+                log(&format!("[ERROR] Redis Error during replay: {:?}", e));
+                break;
+            }
+        };
+        let Some(raw) = raw else { break };
+
+        let entry = match serde_json::from_str::<DeadLetter>(&raw) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log(&format!("[ERROR] Unreadable dead-letter entry kept: {}", e));
+                kept.push(raw);
+                continue;
+            }
+        };
+
+        match prepare_replay(&entry, config) {
+            Ok(payload) => match conn.rpush::<_, _, ()>(&entry.queue, payload).await {
+                Ok(()) => replayed += 1,
+                Err(e) => {
+                    log(&format!(
+                        "[ERROR] Failed to replay onto {}: {}",
+                        entry.queue, e
+                    ));
+                    kept.push(raw);
+                }
+            },
+            Err(reason) => {
+                log(&format!(
+                    "Dead-letter entry from {} kept: {}",
+                    entry.queue, reason
+                ));
+                kept.push(raw);
+            }
+        }
+    }
+
+    // Put kept entries back at the head in their original order.
+    for raw in kept.into_iter().rev() {
+        if let Err(e) = conn.lpush::<_, _, ()>(DEADLETTER_KEY, raw).await {
+            log(&format!(
+                "[ERROR] Failed to restore dead-letter entry: {}",
+                e
+            ));
+        }
+    }
+
+    log(&format!("Replayed {} dead-letter entries.", replayed));
+}
+
+fn prepare_replay(entry: &DeadLetter, config: &Config) -> Result<String, String> {
+    if entry.replay_count >= config.max_replays {
+        return Err(format!("already replayed {} times", entry.replay_count));
+    }
+    if entry.reason.contains(signing::REJECTED) {
+        return Err(format!("task {}", signing::REJECTED));
+    }
+
+    let mut value = serde_json::from_str::<serde_json::Value>(&entry.payload)
+        .map_err(|e| format!("payload is not valid JSON: {}", e))?;
+    let obj = value
+        .as_object_mut()
+        .ok_or_else(|| "payload is not a JSON object".to_string())?;
+    signing::check_signature(obj, config)?;
+    obj.insert("replay_count".to_string(), (entry.replay_count + 1).into());
+    if obj.contains_key("attempts") {
+        obj.insert("attempts".to_string(), 0.into());
+    }
+    signing::resign(obj, config);
+
+    Ok(value.to_string())
+}
+
+fn replay_count_of(payload: &str) -> u64 {
+    serde_json::from_str::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|v| v.get("replay_count")?.as_u64())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn config() -> Config {
+        let mut config = Config::for_tests();
+        config.task_secret = Some("k3y".to_string());
+        config
+    }
+
+    fn entry(payload: Value, reason: &str) -> DeadLetter {
+        DeadLetter {
+            queue: "mcp::tasks::shell".to_string(),
+            payload: payload.to_string(),
+            reason: reason.to_string(),
+            replay_count: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    fn signed(config: &Config) -> Value {
+        let mut fields = json!({"id": "t1", "task_type": "STATUS", "attempts": 2})
+            .as_object()
+            .cloned()
+            .unwrap();
+        signing::resign(&mut fields, config);
+        Value::Object(fields)
+    }
+
+    #[test]
+    fn replays_a_signed_task_signed_afresh() {
+        let config = config();
+        let original = signed(&config);
+        let payload = prepare_replay(&entry(original.clone(), "retries exhausted"), &config);
+        let Ok(Value::Object(fields)) = payload.map(|p| serde_json::from_str(&p).unwrap()) else {
+            panic!("not replayed");
+        };
+        assert_eq!(fields["attempts"], 0);
+        assert_eq!(fields["replay_count"], 1);
+        assert_ne!(fields["nonce"], original["nonce"]);
+        assert_eq!(signing::check_signature(&fields, &config), Ok(()));
+    }
+
+    #[test]
+    fn keeps_forged_and_refused_tasks() {
+        let config = config();
+        let forged = json!({"id": "t1", "task_type": "SHELL", "details": {"command": "id"}});
+        assert_eq!(
+            prepare_replay(&entry(forged, "retries exhausted"), &config),
+            Err("task is not signed".to_string())
+        );
+
+        let mut tampered = signed(&config);
+        tampered["task_type"] = "SHELL".into();
+        assert_eq!(
+            prepare_replay(&entry(tampered, "retries exhausted"), &config),
+            Err("signature does not match".to_string())
+        );
+
+        // Refused at the listener, if only for reusing its nonce.
+        let reason = format!("Task t1 {}: nonce 'n' was already used", signing::REJECTED);
+        assert_eq!(
+            prepare_replay(&entry(signed(&config), &reason), &config),
+            Err(format!("task {}", signing::REJECTED))
+        );
+    }
+}
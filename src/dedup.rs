@@ -0,0 +1,224 @@
+use crate::config::Config;
+use crate::envelope::Envelope;
+use crate::packing;
+use crate::serializer::ResultFormat;
+use crate::Task;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use redis::AsyncCommands;
+
+// --- Duplicate Deliveries ---
+/// A task id that was already delivered.
+pub struct Duplicate {
+    pub result_key: String,
+    /// The earlier delivery's `(status, output)`, once it has finished and
+    /// its result can be read back: a hash result, or an unencrypted JSON or
+    /// legacy string (or JSON-RPC response).
+    pub cached: Option<(String, String)>,
+}
+
+fn seen_key(task_id: &str) -> String {
+    format!("mcp::seen::{}", task_id)
+}
+
+/// How [`check`] treats a delivery.
+#[derive(Debug, PartialEq)]
+enum Claim {
+    /// Runs without touching the claim: dedup is off, or the task is a
+    /// retry or a dead-letter replay.
+    Skip,
+    /// Runs, taking over the claim from whoever held it: a forced rerun.
+    Take,
+    /// Claims the id unless already claimed, else is a duplicate.
+    Check,
+}
+
+fn claim(task: &Task, config: &Config) -> Claim {
+    if config.dedup_ttl_secs == 0 || task.attempts > 0 || task.replay_count > 0 {
+        Claim::Skip
+    } else if task.force {
+        Claim::Take
+    } else {
+        Claim::Check
+    }
+}
+
+/// Claims `task`'s id under `mcp::seen::<id>` for `DEDUP_TTL_SECS`, so a
+/// producer retrying its push doesn't get the task run twice. Returns the
+/// earlier delivery when the id was already claimed or already has a
+/// result. Tasks re-queued for a retry or replayed from the dead-letter
+/// queue run regardless, as does a task with `force` set, and this worker
+/// may run an id it claimed itself (a task recovered after a crash).
+pub async fn check(
+    conn: &mut redis::aio::MultiplexedConnection,
+    config: &Config,
+    queue_name: &str,
+    task: &Task,
+) -> Result<Option<Duplicate>, String> {
+    let key = seen_key(&task.id);
+    match claim(task, config) {
+        Claim::Skip => return Ok(None),
+        Claim::Take => {
+            conn.set_ex::<_, _, ()>(&key, &config.worker_id, config.dedup_ttl_secs)
+                .await
+                .map_err(|e| format!("Failed to claim task id: {}", e))?;
+            return Ok(None);
+        }
+        Claim::Check => {}
+    }
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(&key)
+        .arg(&config.worker_id)
+        .arg("NX")
+        .arg("EX")
+        .arg(config.dedup_ttl_secs)
+        .query_async(conn)
+        .await
+        .map_err(|e| format!("Failed to claim task id: {}", e))?;
+    if claimed.is_none() {
+        let owner: Option<String> = conn
+            .get(&key)
+            .await
+            .map_err(|e| format!("Failed to read task id claim: {}", e))?;
+        if owner.as_deref() == Some(config.worker_id.as_str()) {
+            return Ok(None);
+        }
+    }
+    let result_key = config.result_key_for(queue_name, &task.id);
+    let failed = |e: redis::RedisError| format!("Failed to read {}: {}", result_key, e);
+    let kind: String = redis::cmd("TYPE")
+        .arg(&result_key)
+        .query_async(conn)
+        .await
+        .map_err(failed)?;
+    let cached = match kind.as_str() {
+        // Claimed just now and not yet finished anywhere: a first delivery.
+        "none" if claimed.is_some() => return Ok(None),
+        "hash" => {
+            let (status, output, encoding): (Option<String>, Option<String>, Option<String>) =
+                redis::cmd("HMGET")
+                    .arg(&result_key)
+                    .arg("status")
+                    .arg("output")
+                    .arg("output_encoding")
+                    .query_async(conn)
+                    .await
+                    .map_err(failed)?;
+            let mut output = output.unwrap_or_default();
+            // A sealed output is passed on as stored.
+            if encoding.is_some_and(|e| e.ends_with("gzip")) && config.result_cipher.is_none() {
+                output = gunzip_text(&output).unwrap_or(output);
+            }
+            status.map(|status| (status, output))
+        }
+        "string" if config.result_cipher.is_none() => {
+            let value: Vec<u8> = conn.get(&result_key).await.map_err(failed)?;
+            let value = match packing::manifest(&value) {
+                Some(manifest) => read_chunks(conn, &result_key, &manifest).await,
+                None => Some(value),
+            };
+            value.and_then(|value| parse_result(&String::from_utf8_lossy(&value), config))
+        }
+        _ => None,
+    };
+    Ok(Some(Duplicate { result_key, cached }))
+}
+
+/// Reassembles a result stored in chunks; `None` when a chunk has expired
+/// or doesn't match the manifest.
+async fn read_chunks(
+    conn: &mut redis::aio::MultiplexedConnection,
+    result_key: &str,
+    manifest: &serde_json::Value,
+) -> Option<Vec<u8>> {
+    let count = manifest["chunks"].as_u64()? as usize;
+    let keys: Vec<String> = (0..count)
+        .map(|n| packing::chunk_key(result_key, n))
+        .collect();
+    let chunks: Vec<Option<Vec<u8>>> =
+        redis::cmd("MGET").arg(&keys).query_async(conn).await.ok()?;
+    let chunks = chunks.into_iter().collect::<Option<Vec<_>>>()?;
+    packing::reassemble(manifest, chunks).ok()
+}
+
+fn gunzip_text(output: &str) -> Option<String> {
+    let gzipped = BASE64.decode(output).ok()?;
+    String::from_utf8(packing::gunzip(&gzipped).ok()?).ok()
+}
+
+/// Reads the status and output back out of a string result.
+fn parse_result(value: &str, config: &Config) -> Option<(String, String)> {
+    if config.envelope == Envelope::JsonRpc {
+        let response: serde_json::Value = serde_json::from_str(value).ok()?;
+        return match &response["error"] {
+            serde_json::Value::Null => {
+                Some(("SUCCESS".to_string(), output_text(&response["result"])))
+            }
+            error => Some(("ERROR".to_string(), output_text(&error["message"]))),
+        };
+    }
+    match config.result_format {
+        ResultFormat::Json => {
+            let result: serde_json::Value = serde_json::from_str(value).ok()?;
+            let status = result["status"].as_str()?.to_string();
+            let output = match &result["stdout"] {
+                serde_json::Value::Null => &result["error"],
+                stdout => stdout,
+            };
+            Some((status, output_text(output)))
+        }
+        ResultFormat::Legacy => {
+            let (status, output) = value.split_once(": ")?;
+            Some((status.to_string(), output.to_string()))
+        }
+        ResultFormat::MessagePack => None,
+    }
+}
+
+fn output_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn task(fields: serde_json::Value) -> Task {
+        let mut task =
+            json!({"id": "t1", "task_type": "STATUS", "target_host": "*", "details": {}});
+        task.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(task).unwrap()
+    }
+
+    #[test]
+    fn retries_replays_and_forced_runs_are_exempt() {
+        let mut config = Config::for_tests();
+        config.dedup_ttl_secs = 600;
+        let cases = [
+            (json!({}), Claim::Check),
+            (json!({"attempts": 1}), Claim::Skip),
+            (json!({"attempts": 3, "force": true}), Claim::Skip),
+            (json!({"replay_count": 1}), Claim::Skip),
+            (json!({"force": true}), Claim::Take),
+            (json!({"force": false}), Claim::Check),
+        ];
+        for (fields, expected) in cases {
+            assert_eq!(
+                claim(&task(fields.clone()), &config),
+                expected,
+                "{}",
+                fields
+            );
+        }
+        config.dedup_ttl_secs = 0;
+        assert_eq!(claim(&task(json!({})), &config), Claim::Skip);
+        assert_eq!(claim(&task(json!({"force": true})), &config), Claim::Skip);
+    }
+}
@@ -0,0 +1,32 @@
+// --- Task Detail Helpers ---
+/// Reads an optional object of string values from the task details.
+pub fn string_map(value: &serde_json::Value, field: &str) -> Result<Vec<(String, String)>, String> {
+    match value {
+        serde_json::Value::Null => Ok(Vec::new()),
+        serde_json::Value::Object(map) => map
+            .iter()
+            .map(|(key, item)| {
+                item.as_str()
+                    .map(|v| (key.clone(), v.to_string()))
+                    .ok_or_else(|| format!("'{}.{}' must be a string", field, key))
+            })
+            .collect(),
+        _ => Err(format!("'{}' must be an object of strings", field)),
+    }
+}
+
+/// Reads an optional array of strings from the task details.
+pub fn string_list(value: &serde_json::Value, field: &str) -> Result<Vec<String>, String> {
+    match value {
+        serde_json::Value::Null => Ok(Vec::new()),
+        serde_json::Value::Array(items) => items
+            .iter()
+            .map(|item| {
+                item.as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("'{}' must only contain strings", field))
+            })
+            .collect(),
+        _ => Err(format!("'{}' must be an array of strings", field)),
+    }
+}
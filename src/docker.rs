@@ -0,0 +1,1433 @@
+use crate::cancel;
+use crate::chunks::{Fd, ResultStream};
+use crate::compose;
+use crate::config::Config;
+use crate::details::{string_list, string_map};
+use crate::engine;
+use crate::exit;
+use crate::limits::Limits;
+use crate::log;
+use crate::output::OutputOptions;
+use crate::paths;
+use crate::progress::{Progress, Tracker};
+use crate::runners;
+use crate::running;
+use crate::secrets;
+use crate::Task;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Commands that stop their own child when the task is cancelled; the rest
+/// are dropped, killing the docker CLI call in flight.
+const SELF_CANCELLING: &[&str] = &[
+    "build_image",
+    "pull_image",
+    "wait_healthy",
+    "follow_logs",
+    "exec",
+    "compose_up",
+];
+/// Lines of output kept to explain a failed streaming command.
+const ERROR_TAIL_LINES: usize = 20;
+/// Defaults for `wait_healthy` when the task doesn't set them.
+const DEFAULT_WAIT_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 2;
+/// How long `follow_logs` runs when the task gives no `duration_secs`.
+const DEFAULT_FOLLOW_SECS: u64 = 60;
+/// Labels `run_container` puts on every container it starts.
+pub const MANAGED_BY_LABEL: &str = "managed-by";
+pub const MANAGED_BY: &str = "mcp-worker";
+const TASK_ID_LABEL: &str = "mcp-task-id";
+/// Approximate cap on entries kept in each `mcp::logs::<container>` stream.
+const LOG_STREAM_MAXLEN: usize = 10_000;
+
+/// Docker subcommands that take a second word (`system df`, `image ls`).
+const MANAGEMENT_COMMANDS: [&str; 11] = [
+    "builder",
+    "buildx",
+    "compose",
+    "container",
+    "context",
+    "image",
+    "manifest",
+    "network",
+    "plugin",
+    "system",
+    "volume",
+];
+/// Characters a shell would act on; harmless in argv, but worth flagging in
+/// operator-supplied flags.
+const SHELL_METACHARACTERS: &[char] = &[';', '|', '&', '$', '`', '>', '<', '(', ')', '\n'];
+
+/// The docker-compatible CLI to run, from `DOCKER_BIN` (e.g. `podman`).
+static DOCKER_BIN: OnceLock<String> = OnceLock::new();
+/// Flags added after each docker subcommand, from `DOCKER_EXTRA_ARGS`.
+static EXTRA_ARGS: OnceLock<HashMap<String, Vec<String>>> = OnceLock::new();
+
+/// Logs the docker backend, records `DOCKER_BIN` and logs which binary it
+/// resolves to, warning when it can't be found or isn't executable, and loads
+/// `DOCKER_EXTRA_ARGS`. Only the first call has an effect.
+pub fn init(config: &Config) {
+    if !config.docker_cli {
+        log("Using the Docker Engine API; commands it doesn't cover use the docker CLI");
+    }
+    let bin = config.docker_bin.clone();
+    match resolve_executable(&bin) {
+        Some(path) => log(&format!("Using docker CLI: {} ({})", bin, path.display())),
+        None => log(&format!(
+            "[WARN] DOCKER_BIN {} is not an executable file or on PATH; DOCKER tasks will fail",
+            bin
+        )),
+    }
+    DOCKER_BIN.set(bin).ok();
+    EXTRA_ARGS.set(extra_args(&config.docker_extra_args)).ok();
+}
+
+/// Splits each subcommand's extra flags like a shell would (without running
+/// one) and logs them, warning about shell metacharacters since operators
+/// may expect a shell to interpret them.
+fn extra_args(raw: &BTreeMap<String, String>) -> HashMap<String, Vec<String>> {
+    let mut extra = HashMap::new();
+    for (subcommand, flags) in raw {
+        let Some(args) = shlex::split(flags) else {
+            log(&format!(
+                "[ERROR] DOCKER_EXTRA_ARGS for '{}' has unbalanced quoting, ignoring: {}",
+                subcommand, flags
+            ));
+            continue;
+        };
+        if args.iter().any(|arg| arg.contains(SHELL_METACHARACTERS)) {
+            log(&format!(
+                "[WARN] DOCKER_EXTRA_ARGS for '{}' contains shell metacharacters; they are passed to docker literally: {:?}",
+                subcommand, args
+            ));
+        }
+        log(&format!("Extra args for docker {}: {:?}", subcommand, args));
+        extra.insert(subcommand.clone(), args);
+    }
+    extra
+}
+
+/// Finds `bin` directly when it contains a path separator, otherwise on `PATH`.
+fn resolve_executable(bin: &str) -> Option<std::path::PathBuf> {
+    let is_executable = |path: &Path| {
+        let Ok(meta) = std::fs::metadata(path) else {
+            return false;
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            meta.is_file() && meta.permissions().mode() & 0o111 != 0
+        }
+        #[cfg(not(unix))]
+        {
+            meta.is_file()
+        }
+    };
+    if bin.contains(std::path::MAIN_SEPARATOR) {
+        let path = Path::new(bin);
+        return is_executable(path).then(|| path.to_path_buf());
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(bin))
+        .find(|path| is_executable(path))
+}
+
+/// A docker invocation of `subcommand` (`["ps"]`, `["system", "df"]`),
+/// against `host` when set, followed by any `DOCKER_EXTRA_ARGS` for it.
+pub fn docker_command(host: Option<&str>, subcommand: &[&str]) -> tokio::process::Command {
+    let mut cmd = tokio::process::Command::new(DOCKER_BIN.get().map_or("docker", String::as_str));
+    if let Some(host) = host {
+        cmd.arg("-H").arg(host);
+    }
+    cmd.args(subcommand).kill_on_drop(true);
+    if let Some(extra) = EXTRA_ARGS.get().and_then(|e| e.get(&subcommand.join(" "))) {
+        cmd.args(extra);
+    }
+    cmd
+}
+
+/// Matches a docker command against its handlers. The error for an unknown
+/// command lists the supported ones, taken from the arms themselves so the
+/// list can't drift from what's implemented.
+macro_rules! dispatch {
+    ($command:expr, { $($name:literal => $handler:expr,)+ }) => {
+        match $command {
+            $($name => $handler,)+
+            other => Err(format!(
+                "Unsupported Docker command '{}'. Supported: {}",
+                other,
+                [$($name),+].join(", ")
+            )),
+        }
+    };
+}
+
+pub async fn execute(
+    task: &Task,
+    config: &Config,
+    conn: Option<redis::aio::MultiplexedConnection>,
+    stream: Option<&mut ResultStream>,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    let command = match task.details.get("command") {
+        None | Some(serde_json::Value::Null) => {
+            return Err("Docker task is missing the 'command' field".to_string())
+        }
+        Some(serde_json::Value::String(command)) => command.as_str(),
+        Some(other) => return Err(format!("Docker 'command' must be a string, got: {}", other)),
+    };
+    // An empty allowlist means every implemented command may run.
+    if !config.docker_allowed_commands.is_empty()
+        && !config.docker_allowed_commands.iter().any(|c| c == command)
+    {
+        return Err(format!(
+            "Docker command '{}' is not allowed on this worker (DOCKER_ALLOWED_COMMANDS)",
+            command
+        ));
+    }
+    let output_options = OutputOptions::from_details(&task.details)?;
+    let grace = Duration::from_secs(config.kill_grace_secs);
+    let handled = async {
+        dispatch!(command, {
+            "list_containers" => list_containers(task, config, &output_options).await,
+            "inspect_container" => inspect_container(task, config).await,
+            "build_image" => {
+                let progress = Progress::new(conn, &task.id);
+                build_image(task, progress, stream, cancel, grace).await
+            },
+            "start_container" => change_state(task, config, Lifecycle::Start).await,
+            "stop_container" => change_state(task, config, Lifecycle::Stop).await,
+            "restart_container" => change_state(task, config, Lifecycle::Restart).await,
+            "pull_image" => {
+                let progress = Progress::new(conn, &task.id).tracking(PullTracker::default());
+                pull_image(task, config, progress, stream, cancel, grace).await
+            },
+            "run_container" => run_container(task, config).await,
+            "exec" => runners::exec(task, config, &output_options, cancel, grace).await,
+            "list_managed" => list_managed(&output_options).await,
+            "wait_healthy" => wait_healthy(task, cancel).await,
+            "disk_usage" => disk_usage(task).await,
+            "copy_to_container" => copy_files(task, config, CopyDirection::ToContainer).await,
+            "copy_from_container" => copy_files(task, config, CopyDirection::FromContainer).await,
+            "follow_logs" => {
+                let mut conn = conn.ok_or("follow_logs needs a Redis connection for its stream")?;
+                follow_logs(task, &mut conn, cancel, grace).await
+            },
+            "compose_up" => {
+                let progress = Progress::new(conn, &task.id);
+                compose::up(task, config, progress, stream, cancel, grace).await
+            },
+            "compose_down" => compose::down(task, config).await,
+            "compose_ps" => compose::ps(task, config).await,
+            "compose_logs" => compose::logs(task, config, &output_options).await,
+        })
+    };
+    if SELF_CANCELLING.contains(&command) {
+        handled.await
+    } else {
+        cancel::run_until_cancelled(cancel, task, handled).await
+    }
+}
+
+/// Lists every container as JSON lines, once per entry of `details.hosts`
+/// when given. A custom `format` template is rendered by `docker ps -a`,
+/// so it always goes through the CLI.
+async fn list_containers(
+    task: &Task,
+    config: &Config,
+    output_options: &OutputOptions,
+) -> Result<String, String> {
+    let format = match task.details.get("format") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(serde_json::Value::String(f)) if f.trim().is_empty() => {
+            return Err("list_containers 'format' must not be empty".to_string())
+        }
+        Some(serde_json::Value::String(f)) if f == "json" => None,
+        Some(serde_json::Value::String(f)) => {
+            let hosts = docker_hosts(&task.details)?;
+            check_format(hosts.as_ref().and_then(|h| h.first()), f).await?;
+            Some(f.clone())
+        }
+        Some(other) => {
+            return Err(format!(
+                "list_containers 'format' must be a string, got: {}",
+                other
+            ))
+        }
+    };
+    let use_cli = config.docker_cli || format.is_some();
+    let format = format.unwrap_or_else(|| "{{json .}}".to_string());
+
+    let list = move |host: Option<String>| {
+        let format = format.clone();
+        let output_options = output_options.clone();
+        async move {
+            if !use_cli {
+                log(&format!(
+                    "Listing containers through the Docker API{}",
+                    on_host(&host)
+                ));
+                let lines = engine::list_containers(host.as_deref()).await?;
+                return Ok(output_options.render(&lines));
+            }
+            log(&format!(
+                "Executing docker ps -a --format '{}'{}",
+                format,
+                on_host(&host)
+            ));
+            let output =
+                docker_output_on(host.as_deref(), &["ps", "-a", "--format", &format]).await?;
+            if output.status.success() {
+                Ok(output_options.render(&output.stdout))
+            } else {
+                Err(docker_failure(&output))
+            }
+        }
+    };
+    match docker_hosts(&task.details)? {
+        Some(hosts) => Ok(fan_out(hosts, config.docker_fanout_concurrency, list).await),
+        None => list(None).await,
+    }
+}
+
+/// Inspects `details.container`, once per entry of `details.hosts` when
+/// given, and returns the `docker inspect` JSON array.
+async fn inspect_container(task: &Task, config: &Config) -> Result<String, String> {
+    let container = container_id(&task.details, "inspect_container")?;
+    let use_cli = config.docker_cli;
+    let inspect = move |host: Option<String>| {
+        let container = container.clone();
+        async move {
+            if !use_cli {
+                log(&format!(
+                    "Inspecting {} through the Docker API{}",
+                    container,
+                    on_host(&host)
+                ));
+                return engine::inspect_container(host.as_deref(), &container).await;
+            }
+            log(&format!(
+                "Executing docker inspect {}{}",
+                container,
+                on_host(&host)
+            ));
+            let output = docker_output_on(host.as_deref(), &["inspect", "--", &container]).await?;
+            if output.status.success() {
+                Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+            } else {
+                Err(docker_failure(&output))
+            }
+        }
+    };
+    match docker_hosts(&task.details)? {
+        Some(hosts) => Ok(fan_out(hosts, config.docker_fanout_concurrency, inspect).await),
+        None => inspect(None).await,
+    }
+}
+
+/// Reads the optional `details.hosts` list of docker daemons (`DOCKER_HOST`
+/// values such as `tcp://10.0.0.5:2375` or `ssh://ops@web-1`; the Engine API
+/// backend only reaches `unix://`, `tcp://` and `http://` ones).
+fn docker_hosts(details: &serde_json::Value) -> Result<Option<Vec<String>>, String> {
+    match &details["hosts"] {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::Array(items) if !items.is_empty() => items
+            .iter()
+            .map(|item| match item.as_str().map(str::trim) {
+                Some(host) if !host.is_empty() => Ok(host.to_string()),
+                _ => Err("'hosts' must only contain non-empty strings".to_string()),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some),
+        _ => Err("'hosts' must be a non-empty array of strings".to_string()),
+    }
+}
+
+fn on_host(host: &Option<String>) -> String {
+    host.as_ref()
+        .map(|h| format!(" on {}", h))
+        .unwrap_or_default()
+}
+
+/// Runs `run` against every host with at most `limit` in flight and merges
+/// the results into `{"<host>": {"output": ...} | {"error": ...}}`. A failing
+/// host is reported in place and doesn't fail the others.
+async fn fan_out<F, Fut>(hosts: Vec<String>, limit: usize, run: F) -> String
+where
+    F: Fn(Option<String>) -> Fut,
+    Fut: std::future::Future<Output = Result<String, String>> + Send + 'static,
+{
+    let permits = Arc::new(Semaphore::new(limit));
+    let mut running = JoinSet::new();
+    for host in hosts {
+        let permits = Arc::clone(&permits);
+        let query = run(Some(host.clone()));
+        running.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            (host, query.await)
+        });
+    }
+
+    let mut merged = serde_json::Map::new();
+    while let Some(joined) = running.join_next().await {
+        match joined {
+            Ok((host, Ok(output))) => {
+                merged.insert(host, serde_json::json!({ "output": output }));
+            }
+            Ok((host, Err(e))) => {
+                merged.insert(host, serde_json::json!({ "error": e }));
+            }
+            Err(e) => log(&format!("[ERROR] Docker host query failed: {}", e)),
+        }
+    }
+    serde_json::Value::Object(merged).to_string()
+}
+
+/// Validates a custom Go template by running it against a filter that
+/// matches no containers, so a bad template fails before the real listing.
+/// Failures unrelated to the template only produce a warning.
+async fn check_format(host: Option<&String>, format: &str) -> Result<(), String> {
+    let output = docker_output_on(
+        host.map(String::as_str),
+        &[
+            "ps",
+            "-a",
+            "--filter",
+            "id=0000000000000000",
+            "--format",
+            format,
+        ],
+    )
+    .await?;
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("template") {
+        Err(format!(
+            "Invalid docker format template '{}': {}",
+            format,
+            stderr.trim()
+        ))
+    } else {
+        log(&format!(
+            "[WARN] Could not validate docker format template '{}': {}",
+            format,
+            stderr.trim()
+        ));
+        Ok(())
+    }
+}
+
+/// A `run_container` request, validated the same way for either backend.
+pub struct RunSpec {
+    pub image: String,
+    pub name: Option<String>,
+    pub env: Vec<(String, String)>,
+    pub labels: Vec<(String, String)>,
+    pub mounts: Vec<MountSpec>,
+    pub ports: Vec<PortSpec>,
+    /// The container's command; empty keeps the image's default.
+    pub args: Vec<String>,
+    /// `--memory`, from the task's [`Limits`].
+    pub memory_bytes: Option<i64>,
+    /// `--cpus` in billionths of a CPU, from the task's [`Limits`].
+    pub nano_cpus: Option<i64>,
+}
+
+/// One entry of `details.mounts`.
+pub struct MountSpec {
+    /// A host path inside `DOCKER_MOUNT_DIR` when `bind`, else a volume name.
+    pub source: String,
+    pub target: String,
+    pub read_only: bool,
+    pub bind: bool,
+}
+
+/// One entry of `details.ports`.
+pub struct PortSpec {
+    /// `<port>/<protocol>` inside the container, e.g. `80/tcp`.
+    pub container: String,
+    pub host_ip: Option<String>,
+    pub host_port: String,
+}
+
+impl RunSpec {
+    fn from_task(task: &Task, config: &Config) -> Result<Self, String> {
+        let details = &task.details;
+        let image = image_name(details, "run_container")?;
+        let mut labels = string_map(&details["labels"], "labels")?;
+        if let Some((key, _)) = labels
+            .iter()
+            .find(|(key, _)| key.is_empty() || key.contains('='))
+        {
+            return Err(format!("Invalid label key: '{}'", key));
+        }
+        labels.retain(|(key, _)| key != MANAGED_BY_LABEL && key != TASK_ID_LABEL);
+        labels.push((MANAGED_BY_LABEL.to_string(), MANAGED_BY.to_string()));
+        labels.push((TASK_ID_LABEL.to_string(), task.id.clone()));
+        let limits = Limits::from_task(details, config)?;
+
+        Ok(RunSpec {
+            image,
+            name: details["name"].as_str().map(str::to_string),
+            env: string_map(&details["env"], "env")?,
+            labels,
+            mounts: mounts(details, config)?,
+            ports: ports(details)?,
+            args: string_list(&details["args"], "args")?,
+            memory_bytes: limits.memory_bytes(),
+            nano_cpus: limits.nano_cpus(),
+        })
+    }
+}
+
+/// Reads `details.mounts`: objects with a `source` (an absolute host path
+/// for a bind mount, otherwise a volume name), an absolute `target` and an
+/// optional `read_only`. Bind sources must resolve inside `DOCKER_MOUNT_DIR`
+/// so tasks can't hand a container arbitrary host files; without it only
+/// volumes can be mounted.
+fn mounts(details: &serde_json::Value, config: &Config) -> Result<Vec<MountSpec>, String> {
+    let items = match &details["mounts"] {
+        serde_json::Value::Null => return Ok(Vec::new()),
+        serde_json::Value::Array(items) => items,
+        _ => return Err("'mounts' must be an array of objects".to_string()),
+    };
+    items
+        .iter()
+        .map(|item| {
+            let source = item["source"]
+                .as_str()
+                .filter(|s| !s.trim().is_empty())
+                .ok_or("each mount requires a string 'source'")?;
+            let target = item["target"]
+                .as_str()
+                .filter(|t| t.starts_with('/'))
+                .ok_or("each mount requires an absolute 'target' path")?;
+            // `docker run --mount` takes comma-separated fields.
+            if source.contains(',') || target.contains(',') {
+                return Err(format!("Mount paths must not contain ',': {}", source));
+            }
+            let read_only = match &item["read_only"] {
+                serde_json::Value::Null => false,
+                serde_json::Value::Bool(read_only) => *read_only,
+                _ => return Err("mount 'read_only' must be a boolean".to_string()),
+            };
+            let bind = source.starts_with('/');
+            let source = if bind {
+                let allowed_dir = config
+                    .docker_mount_dir
+                    .as_deref()
+                    .ok_or("Bind mounts are disabled on this worker (set DOCKER_MOUNT_DIR)")?;
+                paths::confine(allowed_dir, "DOCKER_MOUNT_DIR", Path::new(source), true)?
+                    .display()
+                    .to_string()
+            } else if source
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+                && !source.starts_with(['.', '-'])
+            {
+                source.to_string()
+            } else {
+                return Err(format!("Invalid volume name: '{}'", source));
+            };
+            Ok(MountSpec {
+                source,
+                target: target.to_string(),
+                read_only,
+                bind,
+            })
+        })
+        .collect()
+}
+
+/// Reads `details.ports`, an object from container port (`80`, `53/udp`) to
+/// host port (`8080`, `127.0.0.1:8080`).
+fn ports(details: &serde_json::Value) -> Result<Vec<PortSpec>, String> {
+    let map = match &details["ports"] {
+        serde_json::Value::Null => return Ok(Vec::new()),
+        serde_json::Value::Object(map) => map,
+        _ => return Err("'ports' must be an object of container port to host port".to_string()),
+    };
+    map.iter()
+        .map(|(container, host)| {
+            let (port, protocol) = container.split_once('/').unwrap_or((container, "tcp"));
+            if port.parse::<u16>().is_err() || !["tcp", "udp", "sctp"].contains(&protocol) {
+                return Err(format!("Invalid container port: '{}'", container));
+            }
+            let host = match host {
+                serde_json::Value::String(host) => host.clone(),
+                serde_json::Value::Number(port) => port.to_string(),
+                _ => return Err(format!("'ports.{}' must be a host port", container)),
+            };
+            let (host_ip, host_port) = match host.rsplit_once(':') {
+                Some((ip, port)) => (Some(ip.trim_matches(['[', ']'])), port),
+                None => (None, host.as_str()),
+            };
+            if host_port.parse::<u16>().is_err()
+                || host_ip.is_some_and(|ip| ip.parse::<std::net::IpAddr>().is_err())
+            {
+                return Err(format!("Invalid host port for '{}': '{}'", container, host));
+            }
+            Ok(PortSpec {
+                container: format!("{}/{}", port, protocol),
+                host_ip: host_ip.map(str::to_string),
+                host_port: host_port.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Reads `details.image`, which must not look like a docker option.
+fn image_name(details: &serde_json::Value, command: &str) -> Result<String, String> {
+    let image = details["image"]
+        .as_str()
+        .map(str::trim)
+        .ok_or_else(|| format!("{} requires a string 'image'", command))?;
+    // A leading dash would be parsed by docker as an option.
+    if image.is_empty() || image.starts_with('-') {
+        return Err(format!("Invalid image name: '{}'", image));
+    }
+    Ok(image.to_string())
+}
+
+/// Starts a detached container from `details.image`, with optional `name`,
+/// `env` (`secret://<name>` values resolved through `SECRETS_PROVIDER`),
+/// `labels`, `mounts`, `ports` and `args` (the container's command),
+/// capped at the task's memory and CPU [`Limits`], and returns its id.
+/// Every container is labeled `managed-by=mcp-worker` and
+/// `mcp-task-id=<id>` so `list_managed` can find it later. A missing image is
+/// pulled first, as `docker run` does.
+async fn run_container(task: &Task, config: &Config) -> Result<String, String> {
+    let mut spec = RunSpec::from_task(task, config)?;
+    spec.env = secrets::resolve_env(spec.env, &task.details, config).await?;
+    log(&format!("Executing docker run for image {}", spec.image));
+    let id = if config.docker_cli {
+        run_with_cli(&spec).await?
+    } else {
+        engine::run_container(&spec).await?
+    };
+    let labels: serde_json::Map<String, serde_json::Value> = spec
+        .labels
+        .into_iter()
+        .map(|(key, value)| (key, value.into()))
+        .collect();
+    Ok(serde_json::json!({ "id": id, "image": spec.image, "labels": labels }).to_string())
+}
+
+/// A `--env-file` for the docker CLI, readable by this user only and
+/// removed once dropped, so variables (which may be resolved secrets) stay
+/// off its command line.
+pub struct EnvFile {
+    path: std::path::PathBuf,
+}
+
+impl EnvFile {
+    /// Writes `env` as `KEY=VALUE` lines; `None` when there is nothing to
+    /// pass. Docker reads values literally, so they can't hold newlines.
+    pub fn write(env: &[(String, String)]) -> Result<Option<Self>, String> {
+        use std::io::Write;
+        #[cfg(unix)]
+        use std::os::unix::fs::OpenOptionsExt;
+
+        if env.is_empty() {
+            return Ok(None);
+        }
+        let mut lines = String::new();
+        for (key, value) in env {
+            if key.is_empty() || key.contains(['=', '\n']) || value.contains('\n') {
+                return Err(format!("env '{}' can't be passed to docker", key));
+            }
+            lines.push_str(&format!("{}={}\n", key, value));
+        }
+        let path = std::env::temp_dir().join(format!(
+            "mcp-env-{}-{:016x}",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let written = options
+            .open(&path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()));
+        let env_file = EnvFile { path };
+        written.map_err(|e| format!("Failed to write docker env file: {}", e))?;
+        Ok(Some(env_file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for EnvFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+async fn run_with_cli(spec: &RunSpec) -> Result<String, String> {
+    let mut args = vec!["run".to_string(), "-d".to_string()];
+    if let Some(name) = &spec.name {
+        args.push(format!("--name={}", name));
+    }
+    // Kept until `docker run` has read it.
+    let env_file = EnvFile::write(&spec.env)?;
+    if let Some(env_file) = &env_file {
+        args.push(format!("--env-file={}", env_file.path().display()));
+    }
+    for (key, value) in &spec.labels {
+        args.push("--label".to_string());
+        args.push(format!("{}={}", key, value));
+    }
+    for mount in &spec.mounts {
+        args.push("--mount".to_string());
+        args.push(format!(
+            "type={},source={},target={}{}",
+            if mount.bind { "bind" } else { "volume" },
+            mount.source,
+            mount.target,
+            if mount.read_only { ",readonly" } else { "" }
+        ));
+    }
+    if let Some(bytes) = spec.memory_bytes {
+        args.push(format!("--memory={}", bytes));
+    }
+    if let Some(nano_cpus) = spec.nano_cpus {
+        args.push(format!("--cpus={}", nano_cpus as f64 / 1e9));
+    }
+    for port in &spec.ports {
+        let host_ip = match &port.host_ip {
+            Some(ip) if ip.contains(':') => format!("[{}]:", ip),
+            Some(ip) => format!("{}:", ip),
+            None => String::new(),
+        };
+        args.push("-p".to_string());
+        args.push(format!("{}{}:{}", host_ip, port.host_port, port.container));
+    }
+    args.push(spec.image.clone());
+    args.extend(spec.args.iter().cloned());
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = docker_output(&args).await?;
+    if !output.status.success() {
+        return Err(docker_failure(&output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[derive(Clone, Copy)]
+pub enum Lifecycle {
+    Start,
+    Stop,
+    Restart,
+}
+
+impl Lifecycle {
+    fn verb(self) -> &'static str {
+        match self {
+            Lifecycle::Start => "start",
+            Lifecycle::Stop => "stop",
+            Lifecycle::Restart => "restart",
+        }
+    }
+
+    fn past_tense(self) -> &'static str {
+        match self {
+            Lifecycle::Start => "started",
+            Lifecycle::Stop => "stopped",
+            Lifecycle::Restart => "restarted",
+        }
+    }
+}
+
+/// Starts, stops or restarts `details.container`. Stopping (and restarting)
+/// waits `details.timeout_secs` (docker's default when unset) before the
+/// daemon kills the container.
+async fn change_state(task: &Task, config: &Config, action: Lifecycle) -> Result<String, String> {
+    let command = format!("{}_container", action.verb());
+    let container = container_id(&task.details, &command)?;
+    let timeout_secs =
+        match &task.details["timeout_secs"] {
+            serde_json::Value::Null => None,
+            value => Some(value.as_u64().ok_or_else(|| {
+                format!("{} 'timeout_secs' must be a non-negative integer", command)
+            })?),
+        };
+
+    log(&format!("Executing docker {} {}", action.verb(), container));
+    if config.docker_cli {
+        let timeout_secs = timeout_secs.map(|t| t.to_string());
+        let mut args = vec![action.verb()];
+        if let (Some(t), Lifecycle::Stop | Lifecycle::Restart) = (&timeout_secs, action) {
+            args.extend(["--time", t]);
+        }
+        args.extend(["--", &container]);
+        let output = docker_output(&args).await?;
+        if !output.status.success() {
+            return Err(docker_failure(&output));
+        }
+    } else {
+        engine::change_state(&container, action, timeout_secs).await?;
+    }
+    Ok(serde_json::json!({ "container": container, "status": action.past_tense() }).to_string())
+}
+
+/// Pulls `details.image` (`:latest` when it names no tag or digest),
+/// reporting each progress line to the task's progress list, with the pull's
+/// percentage, and stream.
+async fn pull_image(
+    task: &Task,
+    config: &Config,
+    mut progress: Progress,
+    stream: Option<&mut ResultStream>,
+    cancel: &CancellationToken,
+    grace: Duration,
+) -> Result<String, String> {
+    let image = image_name(&task.details, "pull_image")?;
+    let reference = with_default_tag(&image);
+    log(&format!("Executing docker pull {}", reference));
+    progress.phase("pull");
+    if config.docker_cli {
+        let mut cmd = docker_command(None, &["pull"]);
+        cmd.args(["--", &reference]);
+        run_streaming(cmd, &mut progress, stream, cancel, grace).await?;
+    } else {
+        engine::pull_image(task, &reference, &mut progress, stream, cancel).await?;
+    }
+    progress
+        .update(Some(100.0), &format!("Pulled {}", reference))
+        .await;
+    Ok(serde_json::json!({ "image": reference }).to_string())
+}
+
+/// How far an image pull is, over the layers seen so far: a layer counts
+/// as done once pulled or already present, and as partly done while it
+/// downloads (the first 80%) and extracts (the rest) when byte counts are
+/// known.
+#[derive(Default)]
+pub struct PullTracker {
+    layers: HashMap<String, f64>,
+}
+
+impl PullTracker {
+    /// Records `layer`'s new `status`, returning the pull's percentage, or
+    /// `None` for a status that says nothing about progress.
+    pub fn layer(&mut self, layer: &str, status: &str, bytes: Option<(u64, u64)>) -> Option<f64> {
+        let fraction = bytes
+            .filter(|(_, total)| *total > 0)
+            .map_or(0.0, |(done, total)| (done as f64 / total as f64).min(1.0));
+        let done = match status {
+            "Pulling fs layer" | "Waiting" => 0.0,
+            "Verifying Checksum" | "Download complete" => 0.8,
+            "Pull complete" | "Already exists" => 1.0,
+            _ if status.starts_with("Downloading") => 0.8 * fraction,
+            _ if status.starts_with("Extracting") => 0.8 + 0.2 * fraction,
+            _ => return None,
+        };
+        self.layers.insert(layer.to_string(), done);
+        Some(self.layers.values().sum::<f64>() * 100.0 / self.layers.len() as f64)
+    }
+}
+
+impl Tracker for PullTracker {
+    /// Reads the `<layer id>: <status>` lines `docker pull` prints.
+    fn observe(&mut self, line: &str) -> (Option<&'static str>, Option<f64>) {
+        let percent = line.split_once(": ").and_then(|(layer, status)| {
+            let is_layer = layer.len() == 12 && layer.bytes().all(|b| b.is_ascii_hexdigit());
+            is_layer
+                .then(|| self.layer(layer, status.trim(), None))
+                .flatten()
+        });
+        (None, percent)
+    }
+}
+
+/// Appends `:latest` to an image reference with no tag or digest; the Engine
+/// API would otherwise pull every tag of the repository.
+pub fn with_default_tag(image: &str) -> String {
+    let name = image.rsplit('/').next().unwrap_or(image);
+    if image.contains('@') || name.contains(':') {
+        image.to_string()
+    } else {
+        format!("{}:latest", image)
+    }
+}
+
+/// Lists the containers `run_container` started, on any worker, as
+/// `docker ps -a` JSON lines.
+async fn list_managed(output_options: &OutputOptions) -> Result<String, String> {
+    let filter = format!("label={}={}", MANAGED_BY_LABEL, MANAGED_BY);
+    log(&format!("Executing docker ps -a --filter {}", filter));
+    let output =
+        docker_output(&["ps", "-a", "--filter", &filter, "--format", "{{json .}}"]).await?;
+    if output.status.success() {
+        Ok(output_options.render(&output.stdout))
+    } else {
+        Err(docker_failure(&output))
+    }
+}
+
+/// Runs `docker system df` and returns per-type totals keyed `images`,
+/// `containers`, `volumes` and `build_cache`, each with its size and its
+/// reclaimable space as reported and in bytes. `details.verbose` adds docker's
+/// per-object breakdown under `detail`.
+async fn disk_usage(task: &Task) -> Result<String, String> {
+    log("Executing docker system df");
+    let output = docker_output(&["system", "df", "--format", "{{json .}}"]).await?;
+    if !output.status.success() {
+        return Err(docker_failure(&output));
+    }
+
+    let mut totals = serde_json::Map::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row: serde_json::Value = serde_json::from_str(line)
+            .map_err(|e| format!("Unexpected docker system df output '{}': {}", line, e))?;
+        let field = |name: &str| row[name].as_str().unwrap_or_default().trim().to_string();
+        let kind = match field("Type").as_str() {
+            "Images" => "images",
+            "Containers" => "containers",
+            "Local Volumes" => "volumes",
+            "Build Cache" => "build_cache",
+            other => {
+                log(&format!("[WARN] Unknown docker system df type '{}'", other));
+                continue;
+            }
+        };
+        // Reclaimable reads like `1.2GB (50%)`.
+        let reclaimable = field("Reclaimable");
+        let (reclaimable_size, reclaimable_pct) = match reclaimable.split_once(" (") {
+            Some((size, pct)) => (
+                size.to_string(),
+                pct.trim_end_matches("%)").parse::<f64>().ok(),
+            ),
+            None => (reclaimable.clone(), None),
+        };
+        let size = field("Size");
+        totals.insert(
+            kind.to_string(),
+            serde_json::json!({
+                "total_count": field("TotalCount").parse::<u64>().ok(),
+                "active": field("Active").parse::<u64>().ok(),
+                "size": size,
+                "size_bytes": size_bytes(&size),
+                "reclaimable": reclaimable_size,
+                "reclaimable_bytes": size_bytes(&reclaimable_size),
+                "reclaimable_pct": reclaimable_pct,
+            }),
+        );
+    }
+
+    if task.details["verbose"].as_bool().unwrap_or(false) {
+        let output = docker_output(&["system", "df", "-v", "--format", "{{json .}}"]).await?;
+        if !output.status.success() {
+            return Err(docker_failure(&output));
+        }
+        let detail: serde_json::Value = serde_json::from_slice(&output.stdout)
+            .map_err(|e| format!("Unexpected docker system df -v output: {}", e))?;
+        totals.insert("detail".to_string(), detail);
+    }
+    Ok(serde_json::Value::Object(totals).to_string())
+}
+
+/// Parses a size as docker prints it (`1.2GB`, `512kB`, `0B`), using the
+/// decimal units docker uses. `None` for anything else.
+fn size_bytes(size: &str) -> Option<u64> {
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier: f64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "B" | "" => 1.0,
+        "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        "PB" => 1e15,
+        _ => return None,
+    };
+    let number: f64 = number.parse().ok()?;
+    Some((number * multiplier).round() as u64)
+}
+
+/// Polls a container until its healthcheck reports `healthy`, or, for
+/// containers without a healthcheck, until it is `running` (unless
+/// `require_healthcheck` is set). Fails once `timeout_secs` elapses, the
+/// container stops or the task is cancelled.
+async fn wait_healthy(task: &Task, cancel: &CancellationToken) -> Result<String, String> {
+    let details = &task.details;
+    let container = &container_id(details, "wait_healthy")?;
+    let timeout = Duration::from_secs(
+        details["timeout_secs"]
+            .as_u64()
+            .unwrap_or(DEFAULT_WAIT_TIMEOUT_SECS),
+    );
+    let interval = Duration::from_secs(
+        details["interval_secs"]
+            .as_u64()
+            .unwrap_or(DEFAULT_POLL_INTERVAL_SECS)
+            .max(1),
+    );
+    let require_healthcheck = details["require_healthcheck"].as_bool().unwrap_or(false);
+
+    let deadline = Instant::now() + timeout;
+    log(&format!(
+        "Waiting up to {:?} for {} to become healthy",
+        timeout, container
+    ));
+
+    loop {
+        let output = docker_output(&[
+            "inspect",
+            "--format",
+            "{{.State.Status}}|{{if .State.Health}}{{.State.Health.Status}}{{end}}",
+            container,
+        ])
+        .await?;
+        if !output.status.success() {
+            return Err(docker_failure(&output));
+        }
+
+        let inspected = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        let (state, health) = inspected.split_once('|').unwrap_or((&inspected, ""));
+        let final_state = |s: &str| {
+            serde_json::json!({
+                "container": container,
+                "state": state,
+                "health": if health.is_empty() { None } else { Some(health) },
+                "result": s,
+            })
+            .to_string()
+        };
+
+        if health == "healthy" {
+            return Ok(final_state("healthy"));
+        }
+        if health.is_empty() {
+            if require_healthcheck {
+                return Err(format!("Container {} has no healthcheck", container));
+            }
+            if state == "running" {
+                return Ok(final_state("running"));
+            }
+        }
+        if matches!(state, "exited" | "dead") {
+            return Err(format!(
+                "Container {} stopped while waiting: {}",
+                container,
+                final_state(state)
+            ));
+        }
+        // The last poll lands on the deadline rather than an interval short
+        // of it.
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(format!(
+                "Timed out after {:?} waiting for {}: {}",
+                timeout,
+                container,
+                final_state("timeout")
+            ));
+        }
+        tokio::select! {
+            _ = time::sleep(interval.min(remaining)) => {}
+            _ = cancel.cancelled() => {
+                return Err(format!(
+                    "Stopped waiting for {} ({}): {}",
+                    container,
+                    cancel::reason(task),
+                    final_state(state)
+                ));
+            }
+        }
+    }
+}
+
+/// Reads and normalizes `details.container` for `command`.
+fn container_id(details: &serde_json::Value, command: &str) -> Result<String, String> {
+    let raw = details["container"]
+        .as_str()
+        .ok_or_else(|| format!("{} requires a string 'container'", command))?;
+    running::normalize_container(raw)
+        .map(str::to_string)
+        .ok_or_else(|| format!("{} 'container' must not be empty", command))
+}
+
+/// Runs `docker` with the given arguments and captures its output.
+async fn docker_output(args: &[&str]) -> Result<std::process::Output, String> {
+    docker_output_on(None, args).await
+}
+
+/// Like [`docker_output`], but against `host` (`docker -H <host>`) when set.
+async fn docker_output_on(
+    host: Option<&str>,
+    args: &[&str],
+) -> Result<std::process::Output, String> {
+    let words = match args {
+        [first, second, ..] if MANAGEMENT_COMMANDS.contains(first) && !second.starts_with('-') => 2,
+        _ => 1,
+    }
+    .min(args.len());
+    docker_command(host, &args[..words])
+        .args(&args[words..])
+        .output()
+        .await
+        .map_err(|e| format!("Failed to execute docker command: {}", e))
+}
+
+pub fn docker_failure(output: &std::process::Output) -> String {
+    format!(
+        "Docker command failed ({}): {}",
+        exit::describe(&output.status),
+        String::from_utf8_lossy(&output.stderr)
+    )
+}
+
+/// Runs `docker build`, streaming every output line to the task's progress
+/// list, and returns the built image id and tag as JSON.
+async fn build_image(
+    task: &Task,
+    mut progress: Progress,
+    stream: Option<&mut ResultStream>,
+    cancel: &CancellationToken,
+    grace: Duration,
+) -> Result<String, String> {
+    let details = &task.details;
+    let context = details["context"]
+        .as_str()
+        .ok_or("build_image requires a string 'context'")?;
+    if !Path::new(context).is_dir() {
+        return Err(format!("Build context is not a directory: {}", context));
+    }
+
+    // The image id is read back from an --iidfile, which works with both the
+    // classic builder and BuildKit.
+    let safe_id = task
+        .id
+        .replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_");
+    let iid_file = std::env::temp_dir().join(format!("mcp-build-{}.iid", safe_id));
+
+    let mut cmd = docker_command(None, &["build"]);
+    cmd.arg("--iidfile").arg(&iid_file);
+    if let Some(dockerfile) = details["dockerfile"].as_str() {
+        cmd.arg("--file").arg(dockerfile);
+    }
+    let tag = details["tag"].as_str();
+    if let Some(tag) = tag {
+        cmd.arg("--tag").arg(tag);
+    }
+    if let Some(build_args) = details["build_args"].as_object() {
+        for (key, value) in build_args {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            cmd.arg("--build-arg").arg(format!("{}={}", key, value));
+        }
+    }
+    cmd.arg(context);
+
+    log(&format!(
+        "Executing docker build for task {} in {}",
+        task.id, context
+    ));
+    let streamed = run_streaming(cmd, &mut progress, stream, cancel, grace).await;
+    let image_id = std::fs::read_to_string(&iid_file).map(|s| s.trim().to_string());
+    std::fs::remove_file(&iid_file).ok();
+
+    streamed?;
+    let image_id = image_id
+        .map_err(|e| format!("Docker build finished but no image id was written: {}", e))?;
+
+    Ok(serde_json::json!({ "image_id": image_id, "tag": tag }).to_string())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CopyDirection {
+    ToContainer,
+    FromContainer,
+}
+
+/// Runs `docker cp` between the host and a container. The host side must
+/// resolve inside `DOCKER_COPY_DIR`, so tasks can't read or overwrite
+/// arbitrary host files; without it both copy commands are disabled.
+/// Returns the container, paths and the bytes copied on the host side.
+async fn copy_files(
+    task: &Task,
+    config: &Config,
+    direction: CopyDirection,
+) -> Result<String, String> {
+    let allowed_dir = config
+        .docker_copy_dir
+        .as_deref()
+        .ok_or("Docker copy commands are disabled on this worker (set DOCKER_COPY_DIR)")?;
+    let details = &task.details;
+    let container = &container_id(details, "copy")?;
+    let src = details["src"]
+        .as_str()
+        .ok_or("copy requires a string 'src'")?;
+    let dest = details["dest"]
+        .as_str()
+        .ok_or("copy requires a string 'dest'")?;
+
+    let (host_path, args) = match direction {
+        CopyDirection::ToContainer => {
+            let host_path = allowed_host_path(allowed_dir, src, true)?;
+            let target = format!("{}:{}", container, dest);
+            let arg = host_path.display().to_string();
+            (host_path, [arg, target])
+        }
+        CopyDirection::FromContainer => {
+            let host_path = allowed_host_path(allowed_dir, dest, false)?;
+            let source = format!("{}:{}", container, src);
+            let arg = host_path.display().to_string();
+            (host_path, [source, arg])
+        }
+    };
+
+    // Copying into an existing directory nests the source under it.
+    let counted = match (direction, Path::new(src).file_name()) {
+        (CopyDirection::FromContainer, Some(name)) if host_path.is_dir() => host_path.join(name),
+        _ => host_path,
+    };
+
+    log(&format!("Executing docker cp {} {}", args[0], args[1]));
+    let output = docker_output(&["cp", &args[0], &args[1]]).await?;
+    if !output.status.success() {
+        return Err(docker_failure(&output));
+    }
+
+    let bytes = path_bytes(&counted);
+    Ok(serde_json::json!({
+        "container": container,
+        "src": src,
+        "dest": dest,
+        "bytes": bytes,
+    })
+    .to_string())
+}
+
+/// Checks a `docker cp` host path against `DOCKER_COPY_DIR`.
+fn allowed_host_path(
+    allowed_dir: &str,
+    path: &str,
+    must_exist: bool,
+) -> Result<std::path::PathBuf, String> {
+    if path == "-" {
+        return Err("Streaming copies through stdin/stdout are not supported".to_string());
+    }
+    paths::confine(allowed_dir, "DOCKER_COPY_DIR", Path::new(path), must_exist)
+}
+
+/// Total size of the regular files at `path`, walking directories.
+fn path_bytes(path: &Path) -> u64 {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| path_bytes(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Runs `docker logs -f` and appends each line to the Redis stream
+/// `mcp::logs::<container>` until `duration_secs` elapses, the task is
+/// cancelled (`stopped_by` is then the cancellation reason), or the log ends.
+/// A `docker logs` still running is stopped with SIGTERM, then SIGKILL after
+/// `grace`; `terminated_by` says which it took.
+async fn follow_logs(
+    task: &Task,
+    conn: &mut redis::aio::MultiplexedConnection,
+    cancel: &CancellationToken,
+    grace: Duration,
+) -> Result<String, String> {
+    let container = &container_id(&task.details, "follow_logs")?;
+    let duration = Duration::from_secs(
+        task.details["duration_secs"]
+            .as_u64()
+            .unwrap_or(DEFAULT_FOLLOW_SECS),
+    );
+    let stream_key = format!("mcp::logs::{}", container);
+
+    let mut cmd = docker_command(None, &["logs"]);
+    cmd.arg("--follow");
+    if let Some(tail) = task.details["tail"].as_u64() {
+        cmd.arg("--tail").arg(tail.to_string());
+    }
+    let mut child = cmd
+        .arg(container)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to execute docker command: {}", e))?;
+
+    log(&format!(
+        "Following logs of {} into {} for up to {:?}",
+        container, stream_key, duration
+    ));
+
+    let mut stdout = child.stdout.take().map(|s| BufReader::new(s).lines());
+    let mut stderr = child.stderr.take().map(|s| BufReader::new(s).lines());
+    let deadline = time::sleep_until(Instant::now() + duration);
+    tokio::pin!(deadline);
+    let mut forwarded: u64 = 0;
+
+    let stopped_by = loop {
+        if stdout.is_none() && stderr.is_none() {
+            break "exited";
+        }
+        let (line, source) = tokio::select! {
+            line = next_line(&mut stdout), if stdout.is_some() => (line, "stdout"),
+            line = next_line(&mut stderr), if stderr.is_some() => (line, "stderr"),
+            _ = &mut deadline => break "duration",
+            _ = cancel.cancelled() => break cancel::reason(task),
+        };
+        let Some(line) = line else { continue };
+
+        let added: redis::RedisResult<String> = redis::cmd("XADD")
+            .arg(&stream_key)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(LOG_STREAM_MAXLEN)
+            .arg("*")
+            .arg("source")
+            .arg(source)
+            .arg("line")
+            .arg(&line)
+            .query_async(conn)
+            .await;
+        match added {
+            Ok(_) => forwarded += 1,
+            Err(e) => log(&format!("[ERROR] Failed to forward log line: {}", e)),
+        }
+    };
+
+    let mut terminated_by = None;
+    if stopped_by == "exited" {
+        let status = child
+            .wait()
+            .await
+            .map_err(|e| format!("Failed to wait for docker command: {}", e))?;
+        if !status.success() {
+            return Err(format!(
+                "docker logs for {} exited with {} after {} lines",
+                container,
+                exit::describe(&status),
+                forwarded
+            ));
+        }
+    } else {
+        terminated_by = Some(exit::terminate(&mut child, grace).await.as_str());
+    }
+    log(&format!(
+        "Stopped following {} ({}), {} lines forwarded",
+        container, stopped_by, forwarded
+    ));
+
+    Ok(serde_json::json!({
+        "container": container,
+        "stream": stream_key,
+        "lines_forwarded": forwarded,
+        "stopped_by": stopped_by,
+        "terminated_by": terminated_by,
+    })
+    .to_string())
+}
+
+/// Spawns `cmd`, forwarding stdout and stderr lines to `progress` (and the
+/// result stream, if any) as they arrive. On a non-zero exit the last few
+/// lines are returned in the error. When `cancel` fires the child is stopped
+/// (SIGTERM, then SIGKILL after `grace`) and the error says how.
+pub async fn run_streaming(
+    mut cmd: tokio::process::Command,
+    progress: &mut Progress,
+    mut stream: Option<&mut ResultStream>,
+    cancel: &CancellationToken,
+    grace: Duration,
+) -> Result<(), String> {
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to execute docker command: {}", e))?;
+
+    let mut stdout = child.stdout.take().map(|s| BufReader::new(s).lines());
+    let mut stderr = child.stderr.take().map(|s| BufReader::new(s).lines());
+    let mut tail = VecDeque::with_capacity(ERROR_TAIL_LINES);
+
+    while stdout.is_some() || stderr.is_some() {
+        let line = tokio::select! {
+            line = next_line(&mut stdout), if stdout.is_some() => line.map(|l| (Fd::Stdout, l)),
+            line = next_line(&mut stderr), if stderr.is_some() => line.map(|l| (Fd::Stderr, l)),
+            _ = cancel.cancelled() => {
+                let terminated_by = exit::terminate(&mut child, grace).await;
+                return Err(format!(
+                    "Docker command stopped with {}:\n{}",
+                    terminated_by.as_str(),
+                    tail.into_iter().collect::<Vec<_>>().join("\n")
+                ));
+            }
+        };
+        if let Some((fd, line)) = line {
+            progress.report(&line).await;
+            if let Some(stream) = stream.as_mut() {
+                stream.push_from(fd, &line).await;
+            }
+            if tail.len() == ERROR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+    }
+
+    let status = child
+        .wait()
+        .await
+        .map_err(|e| format!("Failed to wait for docker command: {}", e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Docker command failed ({}):\n{}",
+            exit::describe(&status),
+            tail.into_iter().collect::<Vec<_>>().join("\n")
+        ))
+    }
+}
+
+/// Reads the next line from a stream, closing it (setting it to `None`) at EOF or on error.
+async fn next_line<R>(stream: &mut Option<tokio::io::Lines<BufReader<R>>>) -> Option<String>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let lines = stream.as_mut()?;
+    match lines.next_line().await {
+        Ok(Some(line)) => Some(line),
+        _ => {
+            *stream = None;
+            None
+        }
+    }
+}
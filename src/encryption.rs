@@ -0,0 +1,169 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::Aes256Gcm;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+// --- Result Encryption ---
+/// Encrypts result values with AES-256-GCM under `RESULT_ENCRYPTION_KEY`
+/// (32 bytes, base64). Each value gets a fresh 96-bit nonce and is stored as
+/// the JSON envelope
+///
+/// ```text
+/// {"alg":"AES-256-GCM","nonce":"<base64>","ciphertext":"<base64>"}
+/// ```
+///
+/// where `ciphertext` includes the 16-byte tag, as produced by most AES-GCM
+/// libraries. Anything that transforms the plaintext, such as compression,
+/// must run before encryption.
+pub struct ResultCipher {
+    cipher: Aes256Gcm,
+}
+
+/// Never prints the key.
+impl std::fmt::Debug for ResultCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ResultCipher(AES-256-GCM)")
+    }
+}
+
+impl ResultCipher {
+    /// Builds the cipher from `RESULT_ENCRYPTION_KEY`; `Ok(None)` when unset.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match crate::config::var("RESULT_ENCRYPTION_KEY") {
+            Ok(key) if !key.trim().is_empty() => Self::from_base64_key(&key).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn from_base64_key(key_b64: &str) -> Result<Self, String> {
+        let key = BASE64
+            .decode(key_b64.trim())
+            .map_err(|e| format!("RESULT_ENCRYPTION_KEY is not valid base64: {}", e))?;
+        let cipher = Aes256Gcm::new_from_slice(&key).map_err(|_| {
+            format!(
+                "RESULT_ENCRYPTION_KEY must decode to 32 bytes, got {}",
+                key.len()
+            )
+        })?;
+        Ok(ResultCipher { cipher })
+    }
+
+    /// Encrypts `plaintext` and returns the serialized envelope.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<String, String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|e| format!("Result encryption failed: {}", e))?;
+        Ok(serde_json::json!({
+            "alg": "AES-256-GCM",
+            "nonce": BASE64.encode(nonce),
+            "ciphertext": BASE64.encode(ciphertext),
+        })
+        .to_string())
+    }
+
+    /// Decrypts an envelope the way a reader of the result would.
+    #[cfg(test)]
+    pub fn open(&self, envelope: &str) -> Result<Vec<u8>, String> {
+        let envelope: serde_json::Value =
+            serde_json::from_str(envelope).map_err(|e| e.to_string())?;
+        let decode = |field: &str| {
+            BASE64
+                .decode(envelope[field].as_str().unwrap_or_default())
+                .map_err(|e| e.to_string())
+        };
+        let nonce = decode("nonce")?;
+        if nonce.len() != 12 {
+            return Err(format!("nonce is {} bytes", nonce.len()));
+        }
+        self.cipher
+            .decrypt(
+                aes_gcm::Nonce::from_slice(&nonce),
+                decode("ciphertext")?.as_slice(),
+            )
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 32 bytes, base64.
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    /// `envelope` with `field` replaced by `edit` of its decoded bytes.
+    fn tampered(envelope: &str, field: &str, edit: impl Fn(&mut Vec<u8>)) -> String {
+        let mut envelope: serde_json::Value = serde_json::from_str(envelope).unwrap();
+        let mut bytes = BASE64.decode(envelope[field].as_str().unwrap()).unwrap();
+        edit(&mut bytes);
+        envelope[field] = BASE64.encode(bytes).into();
+        envelope.to_string()
+    }
+
+    #[test]
+    fn seals_and_opens_round_trip() {
+        let cipher = ResultCipher::from_base64_key(&format!("  {}\n", KEY)).unwrap();
+        let plaintext = br#"{"status":"SUCCESS","stdout":"secret"}"#;
+        let sealed = cipher.seal(plaintext).unwrap();
+        assert!(!sealed.contains("secret"));
+
+        let envelope: serde_json::Value = serde_json::from_str(&sealed).unwrap();
+        assert_eq!(envelope["alg"], "AES-256-GCM");
+        let nonce = BASE64.decode(envelope["nonce"].as_str().unwrap()).unwrap();
+        assert_eq!(nonce.len(), 12);
+        let ciphertext = BASE64
+            .decode(envelope["ciphertext"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(ciphertext.len(), plaintext.len() + 16);
+        assert_eq!(cipher.open(&sealed).unwrap(), plaintext);
+
+        // A fresh nonce every time.
+        let again = cipher.seal(plaintext).unwrap();
+        assert_ne!(again, sealed);
+        assert_eq!(cipher.open(&again).unwrap(), plaintext);
+        assert_eq!(cipher.open(&cipher.seal(b"").unwrap()).unwrap(), b"");
+    }
+
+    #[test]
+    fn rejects_bad_keys() {
+        let error = |key: &str| ResultCipher::from_base64_key(key).unwrap_err();
+        assert_eq!(
+            error(&BASE64.encode([7u8; 16])),
+            "RESULT_ENCRYPTION_KEY must decode to 32 bytes, got 16"
+        );
+        assert_eq!(
+            error(&BASE64.encode([7u8; 33])),
+            "RESULT_ENCRYPTION_KEY must decode to 32 bytes, got 33"
+        );
+        assert_eq!(
+            error(""),
+            "RESULT_ENCRYPTION_KEY must decode to 32 bytes, got 0"
+        );
+        assert!(error("not base64!").starts_with("RESULT_ENCRYPTION_KEY is not valid base64: "));
+        assert_eq!(
+            format!("{:?}", ResultCipher::from_base64_key(KEY).unwrap()),
+            "ResultCipher(AES-256-GCM)"
+        );
+    }
+
+    #[test]
+    fn tampered_envelopes_do_not_open() {
+        let cipher = ResultCipher::from_base64_key(KEY).unwrap();
+        let sealed = cipher.seal(b"result").unwrap();
+        let flipped = tampered(&sealed, "ciphertext", |bytes| bytes[0] ^= 1);
+        assert!(cipher.open(&flipped).is_err());
+        let bad_tag = tampered(&sealed, "ciphertext", |bytes| {
+            *bytes.last_mut().unwrap() ^= 0x80
+        });
+        assert!(cipher.open(&bad_tag).is_err());
+        let truncated = tampered(&sealed, "ciphertext", |bytes| bytes.truncate(4));
+        assert!(cipher.open(&truncated).is_err());
+        let other_nonce = tampered(&sealed, "nonce", |bytes| bytes[11] ^= 1);
+        assert!(cipher.open(&other_nonce).is_err());
+
+        let other_key = ResultCipher::from_base64_key(&BASE64.encode([9u8; 32])).unwrap();
+        assert!(other_key.open(&sealed).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::cancel;
+use crate::chunks::ResultStream;
+use crate::docker::{with_default_tag, Lifecycle, PullTracker, RunSpec};
+use crate::log;
+use crate::progress::Progress;
+use crate::Task;
+use bollard::container::{
+    Config, CreateContainerOptions, InspectContainerOptions, ListContainersOptions,
+    RestartContainerOptions, StartContainerOptions, StopContainerOptions,
+};
+use bollard::errors::Error;
+use bollard::image::CreateImageOptions;
+use bollard::models::{HostConfig, Mount, MountTypeEnum, PortBinding};
+use bollard::{Docker, API_DEFAULT_VERSION};
+use futures_util::{Stream, StreamExt};
+use std::collections::HashMap;
+use tokio_util::sync::CancellationToken;
+
+// --- Docker Engine API ---
+/// How long a single Engine API request may take.
+const REQUEST_TIMEOUT_SECS: u64 = 120;
+
+/// A client for `host` (a `unix://` socket or a `tcp://`/`http://` address),
+/// or for `DOCKER_HOST` and then the local socket when unset. Connecting
+/// makes no request, so an unreachable daemon fails on the first call.
+fn connect(host: Option<&str>) -> Result<Docker, String> {
+    let connected = match host {
+        None => Docker::connect_with_defaults(),
+        Some(h) if h.starts_with("unix://") => {
+            Docker::connect_with_unix(h, REQUEST_TIMEOUT_SECS, API_DEFAULT_VERSION)
+        }
+        Some(h) if h.starts_with("tcp://") || h.starts_with("http://") => {
+            Docker::connect_with_http(h, REQUEST_TIMEOUT_SECS, API_DEFAULT_VERSION)
+        }
+        Some(h) => {
+            return Err(format!(
+                "Docker host '{}' can't be reached through the Engine API (set DOCKER_BACKEND=cli)",
+                h
+            ))
+        }
+    };
+    connected.map_err(|e| format!("Failed to connect to the Docker daemon: {}", e))
+}
+
+fn failure(e: Error) -> String {
+    format!("Docker API request failed: {}", e)
+}
+
+/// Every container on `host`, one JSON object per line like `docker ps -a
+/// --format '{{json .}}'` (with the API's field names).
+pub async fn list_containers(host: Option<&str>) -> Result<Vec<u8>, String> {
+    let options = ListContainersOptions::<String> {
+        all: true,
+        ..Default::default()
+    };
+    let containers = connect(host)?
+        .list_containers(Some(options))
+        .await
+        .map_err(failure)?;
+    let mut lines = Vec::new();
+    for container in containers {
+        serde_json::to_writer(&mut lines, &container).map_err(|e| e.to_string())?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+/// `container` on `host` as a one-element array, the shape `docker inspect`
+/// prints.
+pub async fn inspect_container(host: Option<&str>, container: &str) -> Result<String, String> {
+    let inspected = connect(host)?
+        .inspect_container(container, None::<InspectContainerOptions>)
+        .await
+        .map_err(failure)?;
+    serde_json::to_string(&[inspected]).map_err(|e| e.to_string())
+}
+
+pub async fn change_state(
+    container: &str,
+    action: Lifecycle,
+    timeout_secs: Option<u64>,
+) -> Result<(), String> {
+    let docker = connect(None)?;
+    let changed = match action {
+        Lifecycle::Start => {
+            docker
+                .start_container(container, None::<StartContainerOptions<String>>)
+                .await
+        }
+        Lifecycle::Stop => {
+            let options = timeout_secs.map(|t| StopContainerOptions { t: t as i64 });
+            docker.stop_container(container, options).await
+        }
+        Lifecycle::Restart => {
+            let options = timeout_secs.map(|t| RestartContainerOptions { t: t as isize });
+            docker.restart_container(container, options).await
+        }
+    };
+    changed.map_err(failure)
+}
+
+/// Pulls `reference`, forwarding each status line to `progress` (and the
+/// result stream, if any) along with the pull's percentage from the layers'
+/// byte counts. Cancelling stops waiting on the pull; the daemon may still
+/// finish it.
+pub async fn pull_image(
+    task: &Task,
+    reference: &str,
+    progress: &mut Progress,
+    mut stream: Option<&mut ResultStream>,
+    cancel: &CancellationToken,
+) -> Result<(), String> {
+    let docker = connect(None)?;
+    let mut pulled = Box::pin(pull_lines(&docker, reference));
+    let mut tracker = PullTracker::default();
+    loop {
+        let pull = tokio::select! {
+            pull = pulled.next() => pull,
+            _ = cancel.cancelled() => return Err(cancel::error(task)),
+        };
+        let Some(pull) = pull else { return Ok(()) };
+        let pull = pull?;
+        let percent = pull
+            .layer
+            .as_deref()
+            .and_then(|layer| tracker.layer(layer, &pull.status, pull.bytes));
+        // Byte counts come several times a second per layer.
+        match pull.bytes {
+            Some(_) => progress.advance(percent, &pull.line).await,
+            None => progress.update(percent, &pull.line).await,
+        }
+        if let Some(stream) = stream.as_mut() {
+            stream.push(&pull.line).await;
+        }
+    }
+}
+
+/// One status update from the daemon during a pull.
+struct PullStatus {
+    /// The update as a `docker pull`-style line.
+    line: String,
+    layer: Option<String>,
+    status: String,
+    /// Bytes done and total, while a layer downloads or extracts.
+    bytes: Option<(u64, u64)>,
+}
+
+/// The daemon's pull progress.
+fn pull_lines<'a>(
+    docker: &'a Docker,
+    reference: &'a str,
+) -> impl Stream<Item = Result<PullStatus, String>> + 'a {
+    let options = CreateImageOptions {
+        from_image: reference,
+        ..Default::default()
+    };
+    docker.create_image(Some(options), None, None).map(|info| {
+        let info = info.map_err(failure)?;
+        if let Some(error) = info.error {
+            return Err(format!("Docker pull failed: {}", error));
+        }
+        let status = info.status.unwrap_or_default();
+        let line = match (&info.id, &info.progress) {
+            (Some(id), Some(bar)) => format!("{}: {} {}", id, status, bar),
+            (Some(id), None) => format!("{}: {}", id, status),
+            (None, _) => status.clone(),
+        };
+        let bytes = info.progress_detail.and_then(|detail| {
+            Some((
+                u64::try_from(detail.current?).ok()?,
+                u64::try_from(detail.total?).ok()?,
+            ))
+        });
+        Ok(PullStatus {
+            line,
+            layer: info.id,
+            status,
+            bytes,
+        })
+    })
+}
+
+/// Creates and starts the container `spec` describes and returns its id.
+/// A missing image is pulled and the create retried once.
+pub async fn run_container(spec: &RunSpec) -> Result<String, String> {
+    let docker = connect(None)?;
+    let options = spec.name.as_ref().map(|name| CreateContainerOptions {
+        name: name.clone(),
+        platform: None,
+    });
+    let created = match docker
+        .create_container(options.clone(), container_config(spec))
+        .await
+    {
+        Err(Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => {
+            let reference = with_default_tag(&spec.image);
+            log(&format!(
+                "Image {} not found locally; pulling it",
+                reference
+            ));
+            let mut pulled = Box::pin(pull_lines(&docker, &reference));
+            while let Some(pull) = pulled.next().await {
+                pull?;
+            }
+            docker
+                .create_container(options, container_config(spec))
+                .await
+        }
+        created => created,
+    }
+    .map_err(failure)?;
+    docker
+        .start_container(&created.id, None::<StartContainerOptions<String>>)
+        .await
+        .map_err(failure)?;
+    Ok(created.id)
+}
+
+fn container_config(spec: &RunSpec) -> Config<String> {
+    let mounts = spec
+        .mounts
+        .iter()
+        .map(|mount| Mount {
+            source: Some(mount.source.clone()),
+            target: Some(mount.target.clone()),
+            typ: Some(if mount.bind {
+                MountTypeEnum::BIND
+            } else {
+                MountTypeEnum::VOLUME
+            }),
+            read_only: Some(mount.read_only),
+            ..Default::default()
+        })
+        .collect();
+    let mut exposed_ports = HashMap::new();
+    let mut port_bindings: HashMap<String, Option<Vec<PortBinding>>> = HashMap::new();
+    for port in &spec.ports {
+        exposed_ports.insert(port.container.clone(), HashMap::new());
+        port_bindings
+            .entry(port.container.clone())
+            .or_default()
+            .get_or_insert_with(Vec::new)
+            .push(PortBinding {
+                host_ip: port.host_ip.clone(),
+                host_port: Some(port.host_port.clone()),
+            });
+    }
+    Config {
+        image: Some(spec.image.clone()),
+        env: Some(
+            spec.env
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect(),
+        ),
+        labels: Some(spec.labels.iter().cloned().collect()),
+        cmd: (!spec.args.is_empty()).then(|| spec.args.clone()),
+        exposed_ports: Some(exposed_ports),
+        host_config: Some(HostConfig {
+            mounts: Some(mounts),
+            port_bindings: Some(port_bindings),
+            memory: spec.memory_bytes,
+            nano_cpus: spec.nano_cpus,
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{Task, TaskType};
+use serde::Serialize;
+
+/// JSON-RPC error codes used in responses.
+const METHOD_NOT_FOUND: i64 = -32601;
+const SERVER_ERROR: i64 = -32000;
+
+// --- Task Envelopes ---
+/// The wire format of queued tasks, from `ENVELOPE`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Envelope {
+    /// The native `Task` JSON (the default).
+    Native,
+    /// JSON-RPC 2.0 requests: `method` is the task type, `params` the task
+    /// details (`params.target_host`/`params.target_selector` route it).
+    /// String results are written as JSON-RPC response objects.
+    JsonRpc,
+}
+
+impl Envelope {
+    pub fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "jsonrpc" | "json-rpc" => Envelope::JsonRpc,
+            _ => Envelope::Native,
+        }
+    }
+
+    /// Parses a queued payload into a task.
+    pub fn parse(self, json_str: &str) -> Result<Task, String> {
+        match self {
+            Envelope::Native => serde_json::from_str(json_str).map_err(|e| e.to_string()),
+            Envelope::JsonRpc => parse_jsonrpc(json_str),
+        }
+    }
+}
+
+fn parse_jsonrpc(json_str: &str) -> Result<Task, String> {
+    let request: serde_json::Value = serde_json::from_str(json_str).map_err(|e| e.to_string())?;
+    if request["jsonrpc"] != "2.0" {
+        return Err("not a JSON-RPC 2.0 request (jsonrpc must be \"2.0\")".to_string());
+    }
+    let method = request["method"]
+        .as_str()
+        .ok_or("JSON-RPC request requires a string 'method'")?;
+    let rpc_id = match &request["id"] {
+        id @ (serde_json::Value::String(_) | serde_json::Value::Number(_)) => id.clone(),
+        _ => return Err("JSON-RPC request requires a string or number 'id'".to_string()),
+    };
+    let params = &request["params"];
+    let mut task: Task = serde_json::from_value(serde_json::json!({
+        "id": rpc_id.as_str().map_or_else(|| rpc_id.to_string(), str::to_string),
+        "target_host": params["target_host"].as_str().unwrap_or_default(),
+        "target_selector": params["target_selector"],
+        "correlation_id": params["correlation_id"],
+        "deadline": params["deadline"],
+        "task_type": method,
+        "details": params,
+        "attempts": request["attempts"].as_u64().unwrap_or_default(),
+    }))
+    .map_err(|e| e.to_string())?;
+    task.rpc_id = Some(rpc_id);
+    Ok(task)
+}
+
+/// Builds the JSON-RPC response for a finished task.
+pub fn jsonrpc_response(task: &Task, task_result: &Result<String, String>) -> String {
+    let response = match task_result {
+        Ok(output) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": task.rpc_id,
+            "result": output,
+        }),
+        Err(e) => {
+            let code = match task.task_type {
+                TaskType::Unknown(_) => METHOD_NOT_FOUND,
+                _ => SERVER_ERROR,
+            };
+            serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": task.rpc_id,
+                "error": { "code": code, "message": e },
+            })
+        }
+    };
+    response.to_string()
+}
@@ -0,0 +1,183 @@
+use crate::output::OutputEncoding;
+use crate::redact;
+use crate::secrets;
+use std::cell::RefCell;
+use std::future::Future;
+use std::process::ExitStatus;
+#[cfg(any(feature = "docker", feature = "shell"))]
+use tokio::time::{self, Duration};
+
+tokio::task_local! {
+    /// What the last process run inside the current [`capture`] reported.
+    static CAPTURED: RefCell<Captured>;
+}
+
+/// The exit code and stderr of the last process a handler ran, and how its
+/// output was encoded.
+#[derive(Debug, Clone, Default)]
+pub struct Captured {
+    pub exit_code: Option<i32>,
+    pub stderr: Option<String>,
+    pub output_encoding: Option<OutputEncoding>,
+}
+
+impl Captured {
+    /// `output_encoding` as results report it: `text` unless a handler said
+    /// otherwise.
+    pub fn output_encoding(&self) -> &'static str {
+        self.output_encoding
+            .unwrap_or(OutputEncoding::Text)
+            .as_str()
+    }
+}
+
+/// Runs `work` and returns what the last process it ran reported through
+/// [`record`] and [`record_stderr`]. That is also passed on to any enclosing
+/// capture, so nested executions (a BATCH step under auditing) all see it.
+pub async fn capture<F: Future>(work: F) -> (F::Output, Captured) {
+    let (output, captured) = CAPTURED
+        .scope(RefCell::new(Captured::default()), async {
+            let output = work.await;
+            (output, CAPTURED.with(|current| current.borrow().clone()))
+        })
+        .await;
+    if captured.exit_code.is_some()
+        || captured.stderr.is_some()
+        || captured.output_encoding.is_some()
+    {
+        CAPTURED
+            .try_with(|outer| *outer.borrow_mut() = captured.clone())
+            .ok();
+    }
+    (output, captured)
+}
+
+/// Reports the exit code of a process a handler ran. Does nothing outside a
+/// [`capture`].
+pub fn record(code: Option<i32>) {
+    CAPTURED
+        .try_with(|current| current.borrow_mut().exit_code = code)
+        .ok();
+}
+
+/// Reports the stderr of a process a handler ran, alongside [`record`],
+/// with the secrets resolved for it scrubbed out.
+#[cfg_attr(not(any(feature = "docker", feature = "shell")), allow(dead_code))]
+pub fn record_stderr(stderr: &[u8]) {
+    let stderr = redact::scrub(&String::from_utf8_lossy(stderr), &secrets::resolved());
+    CAPTURED
+        .try_with(|current| current.borrow_mut().stderr = Some(stderr))
+        .ok();
+}
+
+/// Reports how a handler encoded its output. A handler combining other
+/// tasks' outputs (BATCH) reports its own after theirs.
+pub fn record_output_encoding(encoding: OutputEncoding) {
+    CAPTURED
+        .try_with(|current| current.borrow_mut().output_encoding = Some(encoding))
+        .ok();
+}
+
+// --- Child Termination ---
+/// How [`terminate`] stopped a child.
+#[cfg(any(feature = "docker", feature = "shell"))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Termination {
+    /// Exited within the grace period after SIGTERM.
+    Sigterm,
+    /// Still running after the grace period, so it was SIGKILLed.
+    Sigkill,
+}
+
+#[cfg(any(feature = "docker", feature = "shell"))]
+impl Termination {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Termination::Sigterm => "SIGTERM",
+            Termination::Sigkill => "SIGKILL",
+        }
+    }
+}
+
+/// Stops `child` by sending SIGTERM, waiting up to `grace` (`KILL_GRACE_SECS`)
+/// for it to exit, then SIGKILLing it. A zero grace kills straight away.
+/// Either way the child is reaped.
+#[cfg(any(feature = "docker", feature = "shell"))]
+pub async fn terminate(child: &mut tokio::process::Child, grace: Duration) -> Termination {
+    #[cfg(unix)]
+    if !grace.is_zero() {
+        if let Some(pid) = child.id().and_then(|pid| i32::try_from(pid).ok()) {
+            // SAFETY: signals a child we spawned and haven't reaped yet.
+            unsafe { libc::kill(pid, libc::SIGTERM) };
+            if time::timeout(grace, child.wait()).await.is_ok() {
+                return Termination::Sigterm;
+            }
+        }
+    }
+    child.kill().await.ok();
+    Termination::Sigkill
+}
+
+// --- Child Exit Status ---
+/// Describes how a child process ended, naming the signal when it was
+/// killed by one (e.g. `terminated by signal 9 (SIGKILL)` for an OOM kill),
+/// which a bare exit status hides.
+pub fn describe(status: &ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            let core = if status.core_dumped() {
+                ", core dumped"
+            } else {
+                ""
+            };
+            return match signal_name(signal) {
+                Some(name) => format!("terminated by signal {} ({}{})", signal, name, core),
+                None => format!("terminated by signal {}{}", signal, core),
+            };
+        }
+    }
+    match status.code() {
+        Some(code) => format!("exit code {}", code),
+        None => status.to_string(),
+    }
+}
+
+/// The signal number that ended the child, if any.
+pub fn signal(status: &ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+#[cfg(unix)]
+fn signal_name(signal: i32) -> Option<&'static str> {
+    Some(match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        _ => return None,
+    })
+}
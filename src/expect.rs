@@ -0,0 +1,92 @@
+use regex::Regex;
+
+// --- Result Expectations ---
+/// Assertions from `details.expect` that turn a task into a probe: a task
+/// whose command succeeded still fails when an assertion doesn't hold.
+/// `exit_code` is checked by executors that see the child's exit code
+/// (SHELL); `output_contains` and `output_matches` apply to the returned
+/// output of any task type.
+pub struct Expectation {
+    pub exit_code: Option<i32>,
+    output_contains: Option<String>,
+    output_matches: Option<Regex>,
+}
+
+impl Expectation {
+    /// `Ok(None)` when the task has no `expect` block.
+    pub fn from_details(details: &serde_json::Value) -> Result<Option<Self>, String> {
+        let expect = match details.get("expect") {
+            None | Some(serde_json::Value::Null) => return Ok(None),
+            Some(serde_json::Value::Object(expect)) => expect,
+            Some(other) => return Err(format!("'expect' must be an object, got: {}", other)),
+        };
+        let exit_code = match expect.get("exit_code") {
+            None => None,
+            Some(code) => Some(
+                code.as_i64()
+                    .and_then(|c| i32::try_from(c).ok())
+                    .ok_or_else(|| format!("expect.exit_code must be an integer, got: {}", code))?,
+            ),
+        };
+        let output_contains = match expect.get("output_contains") {
+            None => None,
+            Some(serde_json::Value::String(s)) => Some(s.clone()),
+            Some(other) => {
+                return Err(format!(
+                    "expect.output_contains must be a string, got: {}",
+                    other
+                ))
+            }
+        };
+        let output_matches = match expect.get("output_matches") {
+            None => None,
+            Some(serde_json::Value::String(pattern)) => Some(
+                Regex::new(pattern)
+                    .map_err(|e| format!("Invalid expect.output_matches regex: {}", e))?,
+            ),
+            Some(other) => {
+                return Err(format!(
+                    "expect.output_matches must be a string, got: {}",
+                    other
+                ))
+            }
+        };
+        Ok(Some(Expectation {
+            exit_code,
+            output_contains,
+            output_matches,
+        }))
+    }
+
+    #[cfg(feature = "shell")]
+    pub fn check_exit_code(&self, actual: Option<i32>) -> Result<(), String> {
+        match self.exit_code {
+            Some(expected) if actual != Some(expected) => Err(format!(
+                "Expectation failed: exit code {} (expected {})",
+                actual.map_or("none".to_string(), |c| c.to_string()),
+                expected
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_output(&self, output: &str) -> Result<(), String> {
+        if let Some(needle) = &self.output_contains {
+            if !output.contains(needle.as_str()) {
+                return Err(format!(
+                    "Expectation failed: output does not contain {:?}",
+                    needle
+                ));
+            }
+        }
+        if let Some(pattern) = &self.output_matches {
+            if !pattern.is_match(output) {
+                return Err(format!(
+                    "Expectation failed: output does not match /{}/",
+                    pattern.as_str()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
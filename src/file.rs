@@ -0,0 +1,420 @@
+use crate::cancel;
+use crate::chunks::ResultStream;
+use crate::config::Config;
+use crate::details::string_map;
+use crate::log;
+use crate::output::OutputEncoding;
+use crate::paths;
+use crate::progress::{self, Progress};
+use crate::Task;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+/// Actions a FILE task may perform.
+pub const ACTIONS: [&str; 8] = [
+    "read", "write", "append", "delete", "stat", "download", "upload", "checksum",
+];
+/// Bytes read at a time when hashing, and per chunk of a streamed upload.
+const READ_CHUNK_BYTES: usize = 48 * 1024;
+
+// --- File Tasks ---
+/// Reads, writes, appends to, deletes or stats `details.path` without a
+/// shell. Paths are relative to `FILE_BASE_DIR` and may not leave it; without
+/// it FILE tasks are disabled. With `encoding: "base64"`, `read` returns
+/// base64 output (`output_encoding` `base64`) and `write`/`append` expect
+/// base64 `content`.
+///
+/// Files also move to and from the worker: `download` fetches
+/// `details.url` into the path, `upload` returns a file's contents to the
+/// caller, and `checksum` reports (and with `details.sha256`, verifies) a
+/// file's SHA-256. All three report the bytes done to the task's progress
+/// list as they go.
+pub async fn execute(
+    task: &Task,
+    config: &Config,
+    conn: Option<redis::aio::MultiplexedConnection>,
+    stream: Option<&mut ResultStream>,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    let base_dir = config
+        .file_base_dir
+        .as_deref()
+        .ok_or("FILE tasks are disabled on this worker (set FILE_BASE_DIR)")?;
+    let details = &task.details;
+    let action = details["action"]
+        .as_str()
+        .ok_or("FILE task requires a string 'action'")?;
+    if !ACTIONS.contains(&action) {
+        return Err(format!(
+            "Unsupported FILE action '{}'. Supported: {}",
+            action,
+            ACTIONS.join(", ")
+        ));
+    }
+    let raw_path = details["path"]
+        .as_str()
+        .ok_or("FILE task requires a string 'path'")?;
+    let encoding = OutputEncoding::from_details(details)?;
+    let host_path = Path::new(base_dir).join(raw_path);
+    // A symlink is deleted itself, never its target.
+    let path = match action {
+        "delete" => paths::confine_entry(base_dir, "FILE_BASE_DIR", &host_path)?,
+        _ => paths::confine(base_dir, "FILE_BASE_DIR", &host_path, false)?,
+    };
+
+    log(&format!("Executing FILE {} on {}", action, path.display()));
+    let mut progress = Progress::new(conn, &task.id);
+    progress.phase(action);
+    match action {
+        "read" => {
+            let bytes = tokio::fs::read(&path)
+                .await
+                .map_err(|e| io_error(&path, e))?;
+            Ok(encoding.encode(&bytes))
+        }
+        "write" | "append" => {
+            let content = content(details, encoding)?;
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .write(action == "write")
+                .truncate(action == "write")
+                .append(action == "append")
+                .open(&path)
+                .await
+                .map_err(|e| io_error(&path, e))?;
+            file.write_all(&content)
+                .await
+                .map_err(|e| io_error(&path, e))?;
+            Ok(serde_json::json!({
+                "path": path,
+                "action": action,
+                "bytes_written": content.len(),
+            })
+            .to_string())
+        }
+        "delete" => {
+            tokio::fs::remove_file(&path)
+                .await
+                .map_err(|e| io_error(&path, e))?;
+            Ok(serde_json::json!({ "path": path, "deleted": true }).to_string())
+        }
+        "download" => download(task, &path, config, &mut progress, cancel).await,
+        "upload" => {
+            let stream = stream.filter(|_| task.stream_result);
+            upload(&path, config, &mut progress, stream).await
+        }
+        "checksum" => checksum(&path, details, &mut progress).await,
+        _ => stat(&path).await,
+    }
+}
+
+/// Downloads `details.url` (http or https, sent with `details.headers`) to
+/// `path` through a hidden `.<name>.mcp-download` file beside it, so a failed
+/// or cancelled download leaves the old file alone. The file may not exceed
+/// `FILE_MAX_DOWNLOAD_BYTES` and must match `details.sha256` when given;
+/// `details.mode` (octal, e.g. `"0640"`) sets its permissions.
+async fn download(
+    task: &Task,
+    path: &Path,
+    config: &Config,
+    progress: &mut Progress,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    let details = &task.details;
+    let url = details["url"]
+        .as_str()
+        .ok_or("FILE download requires a string 'url'")?;
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid url '{}': {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("Unsupported url scheme '{}'", parsed.scheme()));
+    }
+    let expected = expected_sha256(details)?;
+    let mode = file_mode(details)?;
+    let mut request = reqwest::Client::new().get(parsed);
+    for (name, value) in string_map(&details["headers"], "headers")? {
+        request = request.header(name, value);
+    }
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("FILE download needs a file name: {}", path.display()))?;
+    let partial = path.with_file_name(format!(".{}.mcp-download", file_name.to_string_lossy()));
+
+    let fetched = tokio::select! {
+        fetched = fetch(request, &partial, config.file_max_download_bytes, progress) => fetched,
+        _ = cancel.cancelled() => Err(format!("FILE download stopped: {}", cancel::reason(task))),
+    };
+    let verified = fetched.and_then(|(bytes, sha256)| match &expected {
+        Some(expected) if *expected != sha256 => Err(format!(
+            "Checksum mismatch for {}: expected sha256 {}, got {}",
+            url, expected, sha256
+        )),
+        _ => Ok((bytes, sha256)),
+    });
+    let (bytes, sha256) = match verified {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            tokio::fs::remove_file(&partial).await.ok();
+            return Err(e);
+        }
+    };
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(&partial, std::fs::Permissions::from_mode(mode))
+            .await
+            .map_err(|e| io_error(&partial, e))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    tokio::fs::rename(&partial, path)
+        .await
+        .map_err(|e| io_error(path, e))?;
+    Ok(serde_json::json!({
+        "path": path,
+        "action": "download",
+        "url": url,
+        "bytes_written": bytes,
+        "sha256": sha256,
+    })
+    .to_string())
+}
+
+/// Writes the response body to `partial`, returning its size and SHA-256.
+async fn fetch(
+    request: reqwest::RequestBuilder,
+    partial: &Path,
+    max_bytes: u64,
+    progress: &mut Progress,
+) -> Result<(u64, String), String> {
+    let too_large = || {
+        format!(
+            "Download exceeds FILE_MAX_DOWNLOAD_BYTES ({} bytes)",
+            max_bytes
+        )
+    };
+    let mut response = request
+        .send()
+        .await
+        .map_err(|e| format!("Download failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("Download failed: HTTP {}", response.status()));
+    }
+    if response.content_length().is_some_and(|len| len > max_bytes) {
+        return Err(too_large());
+    }
+    let total = response.content_length();
+    let mut file = tokio::fs::File::create(partial)
+        .await
+        .map_err(|e| io_error(partial, e))?;
+    let mut hasher = Sha256::new();
+    let mut written = 0;
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| format!("Download failed: {}", e))?
+    {
+        written += chunk.len() as u64;
+        if written > max_bytes {
+            return Err(too_large());
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk)
+            .await
+            .map_err(|e| io_error(partial, e))?;
+        report(progress, "Downloaded", written, total).await;
+    }
+    file.flush().await.map_err(|e| io_error(partial, e))?;
+    Ok((written, hex::encode(hasher.finalize())))
+}
+
+/// Returns the file base64-encoded with its size and SHA-256. A
+/// `stream_result` task gets the contents as base64 chunks (each decoded on
+/// its own) in `<result key>::chunks` instead, with no size limit; inline,
+/// the file may not exceed `FILE_MAX_UPLOAD_BYTES`.
+async fn upload(
+    path: &Path,
+    config: &Config,
+    progress: &mut Progress,
+    stream: Option<&mut ResultStream>,
+) -> Result<String, String> {
+    let base64 = &base64::engine::general_purpose::STANDARD;
+    let Some(stream) = stream else {
+        let size = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| io_error(path, e))?
+            .len();
+        if size > config.file_max_upload_bytes {
+            return Err(format!(
+                "{} is {} bytes, more than FILE_MAX_UPLOAD_BYTES ({}); set stream_result to upload it in chunks",
+                path.display(),
+                size,
+                config.file_max_upload_bytes
+            ));
+        }
+        let bytes = tokio::fs::read(path).await.map_err(|e| io_error(path, e))?;
+        return Ok(serde_json::json!({
+            "path": path,
+            "action": "upload",
+            "size": bytes.len(),
+            "sha256": hex::encode(Sha256::digest(&bytes)),
+            "encoding": "base64",
+            "content": base64.encode(&bytes),
+        })
+        .to_string());
+    };
+
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| io_error(path, e))?;
+    let total = file.metadata().await.map(|meta| meta.len()).ok();
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; READ_CHUNK_BYTES];
+    let (mut size, mut chunks) = (0, 0);
+    loop {
+        let read = file.read(&mut buf).await.map_err(|e| io_error(path, e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        stream.push(&base64.encode(&buf[..read])).await;
+        size += read;
+        chunks += 1;
+        report(progress, "Uploaded", size as u64, total).await;
+    }
+    Ok(serde_json::json!({
+        "path": path,
+        "action": "upload",
+        "size": size,
+        "sha256": hex::encode(hasher.finalize()),
+        "encoding": "base64",
+        "chunks": chunks,
+    })
+    .to_string())
+}
+
+/// The file's size and SHA-256; with `details.sha256` the task fails, with
+/// the same JSON, unless they match.
+async fn checksum(
+    path: &Path,
+    details: &serde_json::Value,
+    progress: &mut Progress,
+) -> Result<String, String> {
+    let expected = expected_sha256(details)?;
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| io_error(path, e))?;
+    let total = file.metadata().await.map(|meta| meta.len()).ok();
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; READ_CHUNK_BYTES];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buf).await.map_err(|e| io_error(path, e))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        size += read;
+        report(progress, "Hashed", size as u64, total).await;
+    }
+    let sha256 = hex::encode(hasher.finalize());
+    let matches = expected.as_ref().map(|expected| *expected == sha256);
+    let result = serde_json::json!({
+        "path": path,
+        "size": size,
+        "sha256": sha256,
+        "matches": matches,
+    })
+    .to_string();
+    match matches {
+        Some(false) => Err(result),
+        _ => Ok(result),
+    }
+}
+
+/// Records `done` of `total` bytes transferred.
+async fn report(progress: &mut Progress, verb: &str, done: u64, total: Option<u64>) {
+    let message = match total {
+        Some(total) => format!("{} {} of {} bytes", verb, done, total),
+        None => format!("{} {} bytes", verb, done),
+    };
+    progress
+        .advance(progress::percent(done, total), &message)
+        .await;
+}
+
+/// Reads the optional `details.sha256`, lower-cased.
+fn expected_sha256(details: &serde_json::Value) -> Result<Option<String>, String> {
+    match &details["sha256"] {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(hex)
+            if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Ok(Some(hex.to_ascii_lowercase()))
+        }
+        _ => Err("'sha256' must be a 64-character hex digest".to_string()),
+    }
+}
+
+/// Reads the optional octal `details.mode`.
+fn file_mode(details: &serde_json::Value) -> Result<Option<u32>, String> {
+    match &details["mode"] {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(mode) => u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .map(Some)
+            .ok_or_else(|| format!("Invalid 'mode' '{}': must be octal, e.g. \"0644\"", mode)),
+        _ => Err("'mode' must be an octal string, e.g. \"0644\"".to_string()),
+    }
+}
+
+async fn stat(path: &PathBuf) -> Result<String, String> {
+    let meta = tokio::fs::metadata(path)
+        .await
+        .map_err(|e| io_error(path, e))?;
+    let modified = meta
+        .modified()
+        .ok()
+        .map(|t| chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339());
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(format!("{:o}", meta.permissions().mode() & 0o7777))
+    };
+    #[cfg(not(unix))]
+    let mode: Option<String> = None;
+    Ok(serde_json::json!({
+        "path": path,
+        "size": meta.len(),
+        "is_file": meta.is_file(),
+        "is_dir": meta.is_dir(),
+        "modified": modified,
+        "mode": mode,
+    })
+    .to_string())
+}
+
+/// Reads `details.content`, decoding it when the task uses base64.
+fn content(details: &serde_json::Value, encoding: OutputEncoding) -> Result<Vec<u8>, String> {
+    let content = details["content"]
+        .as_str()
+        .ok_or("FILE write/append requires a string 'content'")?;
+    match encoding {
+        OutputEncoding::Text => Ok(content.as_bytes().to_vec()),
+        OutputEncoding::Base64 => base64::engine::general_purpose::STANDARD
+            .decode(content.strip_prefix("base64:").unwrap_or(content))
+            .map_err(|e| format!("FILE 'content' is not valid base64: {}", e)),
+    }
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> String {
+    match e.kind() {
+        ErrorKind::NotFound => format!("File not found: {}", path.display()),
+        ErrorKind::PermissionDenied => format!("Permission denied: {}", path.display()),
+        _ => format!("{}: {}", path.display(), e),
+    }
+}
@@ -0,0 +1,411 @@
+use crate::batch;
+use crate::chunks::ResultStream;
+use crate::config::Config;
+use crate::control;
+#[cfg(feature = "docker")]
+use crate::docker;
+use crate::file;
+use crate::http;
+use crate::routing;
+#[cfg(feature = "shell")]
+use crate::shell;
+use crate::stats;
+use crate::systemd;
+use crate::validation::{self, optional, required, Field, Kind, Problem};
+use crate::{Task, TaskType};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{LazyLock, RwLock};
+use tokio_util::sync::CancellationToken;
+
+// --- Queue Handlers ---
+/// What a handler gets besides the task itself.
+pub struct Context<'a> {
+    pub config: &'a Config,
+    pub conn: Option<redis::aio::MultiplexedConnection>,
+    pub stream: Option<&'a mut ResultStream>,
+    pub cancel: &'a CancellationToken,
+}
+
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>>;
+
+/// Runs a task popped from the queue it is registered for.
+pub type Handler = for<'a> fn(&'a Task, Context<'a>) -> HandlerFuture<'a>;
+
+/// Queue name to handler. Queues without an entry use [`builtin`].
+static HANDLERS: LazyLock<RwLock<HashMap<String, Handler>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Registers the built-in executors and the default handlers for the shell
+/// and docker queues. Called once at startup; further task types are added
+/// with [`register_executor`], further categories with [`register`].
+pub fn init() {
+    register_executor(&Batch);
+    register_executor(&Control);
+    register_executor(&File);
+    register_executor(&Http);
+    register_executor(&Status);
+    register_executor(&Systemd);
+    #[cfg(feature = "shell")]
+    {
+        register_executor(&Shell);
+        register("mcp::tasks::shell", shell_queue);
+    }
+    #[cfg(feature = "docker")]
+    {
+        register_executor(&Docker);
+        register("mcp::tasks::docker", docker_queue);
+    }
+}
+
+/// Makes `handler` run every task popped from `queue`, replacing any
+/// handler registered for it before.
+#[cfg_attr(not(any(feature = "docker", feature = "shell")), allow(dead_code))]
+pub fn register(queue: &str, handler: Handler) {
+    HANDLERS.write().unwrap().insert(queue.to_string(), handler);
+}
+
+/// The handler for tasks popped from `queue` or one of its priority twins.
+pub fn for_queue(queue: &str) -> Handler {
+    HANDLERS
+        .read()
+        .unwrap()
+        .get(routing::base_queue(queue))
+        .copied()
+        .unwrap_or(builtin)
+}
+
+// --- Task Executors ---
+/// Runs one task type. Executors are registered by name at startup (see
+/// [`init`]), so a new task type, including one behind a cargo feature,
+/// plugs in with an implementation and a [`register_executor`] call instead
+/// of another arm in the dispatch.
+pub trait TaskExecutor: Send + Sync {
+    /// The upper-case task type this executor runs, such as `SHELL`.
+    fn name(&self) -> &'static str;
+
+    /// The details fields the task type reads, checked by [`Self::validate`].
+    fn schema(&self) -> &'static [Field] {
+        &[]
+    }
+
+    /// Checks `details` before anything runs, listing every field that
+    /// doesn't fit.
+    fn validate(&self, details: &serde_json::Value) -> Result<(), Vec<Problem>> {
+        validation::check(details, self.schema())
+    }
+
+    fn execute<'a>(&'a self, task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a>;
+
+    /// Whether the executor watches [`Context::cancel`] itself, stopping its
+    /// child gracefully and keeping what it printed. Other executors are
+    /// dropped when the token fires.
+    fn watches_cancel(&self) -> bool {
+        false
+    }
+
+    /// Whether the worker advertises the task type in its heartbeat.
+    fn enabled(&self, _config: &Config) -> bool {
+        true
+    }
+}
+
+/// Task type name to executor, filled by [`init`].
+static EXECUTORS: LazyLock<RwLock<HashMap<String, &'static dyn TaskExecutor>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Makes `executor` run every task of its type, replacing any executor
+/// registered for the type before.
+pub fn register_executor(executor: &'static dyn TaskExecutor) {
+    EXECUTORS
+        .write()
+        .unwrap()
+        .insert(executor.name().to_string(), executor);
+}
+
+/// The executor for the task type named `task_type` (in any case), if one
+/// is registered.
+pub fn executor(task_type: &str) -> Option<&'static dyn TaskExecutor> {
+    EXECUTORS
+        .read()
+        .unwrap()
+        .get(&task_type.to_ascii_uppercase())
+        .copied()
+}
+
+/// The task types this worker can run, as advertised in its heartbeat.
+pub fn supported_task_types(config: &Config) -> Vec<&'static str> {
+    let mut types: Vec<&'static str> = EXECUTORS
+        .read()
+        .unwrap()
+        .values()
+        .filter(|executor| executor.enabled(config))
+        .map(|executor| executor.name())
+        .collect();
+    types.sort_unstable();
+    types
+}
+
+/// Runs a task with the executor registered for its `task_type`.
+pub fn builtin<'a>(task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a> {
+    match executor(task.task_type.as_str()) {
+        Some(executor) => executor.execute(task, ctx),
+        None => {
+            let error = match &task.task_type {
+                TaskType::SHELL => "shell support not compiled in".to_string(),
+                TaskType::DOCKER => "docker support not compiled in".to_string(),
+                other => format!("unsupported task type: {}", other.as_str()),
+            };
+            Box::pin(async move { Err(error) })
+        }
+    }
+}
+
+struct Batch;
+
+impl TaskExecutor for Batch {
+    fn name(&self) -> &'static str {
+        "BATCH"
+    }
+
+    fn schema(&self) -> &'static [Field] {
+        const SCHEMA: &[Field] = &[
+            required("steps", Kind::Array),
+            optional("templating", Kind::Bool),
+        ];
+        SCHEMA
+    }
+
+    /// Also checks each step's details against its own type's schema.
+    fn validate(&self, details: &serde_json::Value) -> Result<(), Vec<Problem>> {
+        validation::check(details, self.schema())?;
+        let mut problems = Vec::new();
+        let steps = details["steps"].as_array().into_iter().flatten();
+        for (index, step) in steps.enumerate() {
+            let prefix = format!("details.steps[{}]", index);
+            let Ok(task_type) = serde_json::from_value::<TaskType>(step["task_type"].clone())
+            else {
+                let problem = match step["task_type"] {
+                    serde_json::Value::Null => "is required",
+                    _ => "must be a string",
+                };
+                problems.push(Problem::new(format!("{}.task_type", prefix), problem));
+                continue;
+            };
+            let Some(executor) = executor(task_type.as_str()) else {
+                problems.push(Problem::new(
+                    format!("{}.task_type", prefix),
+                    format!("unsupported task type: {}", task_type.as_str()),
+                ));
+                continue;
+            };
+            if let Err(found) = executor.validate(&step["details"]) {
+                problems.extend(found.into_iter().map(|problem| problem.nested(&prefix)));
+            }
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems),
+        }
+    }
+
+    fn execute<'a>(&'a self, task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a> {
+        batch::execute(task, ctx.config, ctx.conn, ctx.cancel)
+    }
+
+    fn watches_cancel(&self) -> bool {
+        true
+    }
+}
+
+struct Control;
+
+impl TaskExecutor for Control {
+    fn name(&self) -> &'static str {
+        "CONTROL"
+    }
+
+    fn schema(&self) -> &'static [Field] {
+        const SCHEMA: &[Field] = &[required("action", Kind::String)];
+        SCHEMA
+    }
+
+    fn execute<'a>(&'a self, task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move { control::execute(task, ctx.config) })
+    }
+}
+
+#[cfg(feature = "shell")]
+struct Shell;
+
+#[cfg(feature = "shell")]
+impl TaskExecutor for Shell {
+    fn name(&self) -> &'static str {
+        "SHELL"
+    }
+
+    fn schema(&self) -> &'static [Field] {
+        const SCHEMA: &[Field] = &[
+            required("command", Kind::String),
+            optional("args", Kind::StringList),
+            optional("env", Kind::StringMap),
+            optional("secrets", Kind::StringMap),
+            optional("cwd", Kind::String),
+            optional("timeout_secs", Kind::Number),
+            optional("structured", Kind::Bool),
+        ];
+        SCHEMA
+    }
+
+    fn execute<'a>(&'a self, task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a> {
+        Box::pin(shell::execute(task, ctx.config, ctx.stream, ctx.cancel))
+    }
+
+    fn watches_cancel(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "docker")]
+struct Docker;
+
+#[cfg(feature = "docker")]
+impl TaskExecutor for Docker {
+    fn name(&self) -> &'static str {
+        "DOCKER"
+    }
+
+    fn schema(&self) -> &'static [Field] {
+        const SCHEMA: &[Field] = &[
+            required("command", Kind::String),
+            optional("image", Kind::String),
+            optional("container", Kind::String),
+            optional("name", Kind::String),
+            optional("args", Kind::StringList),
+            optional("env", Kind::StringMap),
+            optional("secrets", Kind::StringMap),
+            optional("labels", Kind::StringMap),
+            optional("timeout_secs", Kind::Number),
+            optional("tail", Kind::Unsigned),
+        ];
+        SCHEMA
+    }
+
+    fn execute<'a>(&'a self, task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a> {
+        Box::pin(docker::execute(
+            task, ctx.config, ctx.conn, ctx.stream, ctx.cancel,
+        ))
+    }
+
+    fn watches_cancel(&self) -> bool {
+        true
+    }
+}
+
+struct File;
+
+impl TaskExecutor for File {
+    fn name(&self) -> &'static str {
+        "FILE"
+    }
+
+    fn schema(&self) -> &'static [Field] {
+        const SCHEMA: &[Field] = &[
+            required("action", Kind::OneOf(&file::ACTIONS)),
+            optional("path", Kind::String),
+            optional("url", Kind::String),
+            optional("content", Kind::String),
+            optional("headers", Kind::StringMap),
+            optional("mode", Kind::String),
+        ];
+        SCHEMA
+    }
+
+    fn execute<'a>(&'a self, task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a> {
+        Box::pin(file::execute(
+            task, ctx.config, ctx.conn, ctx.stream, ctx.cancel,
+        ))
+    }
+}
+
+struct Http;
+
+impl TaskExecutor for Http {
+    fn name(&self) -> &'static str {
+        "HTTP"
+    }
+
+    fn schema(&self) -> &'static [Field] {
+        const SCHEMA: &[Field] = &[
+            required("url", Kind::String),
+            optional("method", Kind::String),
+            optional("headers", Kind::StringMap),
+            optional("timeout_secs", Kind::Number),
+        ];
+        SCHEMA
+    }
+
+    fn execute<'a>(&'a self, task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a> {
+        Box::pin(http::execute(task, ctx.config, ctx.cancel))
+    }
+}
+
+struct Status;
+
+impl TaskExecutor for Status {
+    fn name(&self) -> &'static str {
+        "STATUS"
+    }
+
+    fn execute<'a>(&'a self, _task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a> {
+        Box::pin(async move { Ok(stats::status(ctx.config)) })
+    }
+}
+
+struct Systemd;
+
+impl TaskExecutor for Systemd {
+    fn name(&self) -> &'static str {
+        "SYSTEMD"
+    }
+
+    fn schema(&self) -> &'static [Field] {
+        const SCHEMA: &[Field] = &[
+            required("action", Kind::OneOf(&systemd::ALLOWED_ACTIONS)),
+            required("unit", Kind::String),
+            optional("now", Kind::Bool),
+            optional("journal_lines", Kind::Unsigned),
+        ];
+        SCHEMA
+    }
+
+    fn execute<'a>(&'a self, task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a> {
+        Box::pin(systemd::execute(task, ctx.config))
+    }
+
+    /// Registered regardless, so a disabled worker says why it refuses.
+    fn enabled(&self, config: &Config) -> bool {
+        config.allow_systemd
+    }
+}
+
+/// The shell queue's handler. Other task types sent to the queue (STATUS,
+/// BATCH, ...) still run through [`builtin`].
+#[cfg(feature = "shell")]
+fn shell_queue<'a>(task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a> {
+    if task.task_type != TaskType::SHELL {
+        return builtin(task, ctx);
+    }
+    Shell.execute(task, ctx)
+}
+
+/// The docker queue's handler. Other task types sent to the queue still run
+/// through [`builtin`].
+#[cfg(feature = "docker")]
+fn docker_queue<'a>(task: &'a Task, ctx: Context<'a>) -> HandlerFuture<'a> {
+    if task.task_type != TaskType::DOCKER {
+        return builtin(task, ctx);
+    }
+    Docker.execute(task, ctx)
+}
@@ -0,0 +1,85 @@
+use crate::cancel;
+use crate::stats;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+// --- Health Probes ---
+/// Whether the last Redis round trip of a listener succeeded.
+static REDIS_CONNECTED: AtomicBool = AtomicBool::new(false);
+/// Whether the worker is paused (see [`crate::control::PauseState`]).
+static PAUSED: AtomicBool = AtomicBool::new(false);
+/// Listener loops currently running.
+static LISTENERS: AtomicUsize = AtomicUsize::new(0);
+/// Uptime in milliseconds at the last sign of life: a completed pop or a
+/// published heartbeat.
+static LAST_ALIVE_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Held by a running listener loop; counts it out when dropped.
+pub struct ListenerGuard;
+
+impl ListenerGuard {
+    pub fn new() -> Self {
+        LISTENERS.fetch_add(1, Ordering::Relaxed);
+        mark_alive();
+        ListenerGuard
+    }
+}
+
+impl Drop for ListenerGuard {
+    fn drop(&mut self) {
+        LISTENERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn set_redis_connected(connected: bool) {
+    REDIS_CONNECTED.store(connected, Ordering::Relaxed);
+}
+
+pub fn set_paused(paused: bool) {
+    PAUSED.store(paused, Ordering::Relaxed);
+}
+
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Records that a pop completed or a heartbeat went out.
+pub fn mark_alive() {
+    LAST_ALIVE_MS.store(stats::uptime().as_millis() as u64, Ordering::Relaxed);
+}
+
+/// `/readyz`: ready once Redis is connected and a listener loop is running,
+/// and no longer while paused or once a shutdown starts draining.
+pub fn readiness() -> Result<(), String> {
+    if cancel::draining() {
+        return Err("shutting down".to_string());
+    }
+    if paused() {
+        return Err("paused".to_string());
+    }
+    if !REDIS_CONNECTED.load(Ordering::Relaxed) {
+        return Err("Redis is not connected".to_string());
+    }
+    if LISTENERS.load(Ordering::Relaxed) == 0 {
+        return Err("no listener loop is running".to_string());
+    }
+    Ok(())
+}
+
+/// `/healthz`: unhealthy once listeners have run but neither a pop nor a
+/// heartbeat has completed for `stuck_after` (`HEALTH_STUCK_SECS`; zero
+/// never reports stuck). A worker still connecting is healthy.
+pub fn liveness(stuck_after: Duration) -> Result<(), String> {
+    if stuck_after.is_zero() || LISTENERS.load(Ordering::Relaxed) == 0 {
+        return Ok(());
+    }
+    let last = Duration::from_millis(LAST_ALIVE_MS.load(Ordering::Relaxed));
+    let quiet = stats::uptime().saturating_sub(last);
+    if quiet > stuck_after {
+        return Err(format!(
+            "listener loop stuck: no pop or heartbeat for {}s",
+            quiet.as_secs()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,220 @@
+use crate::batch;
+use crate::config::Config;
+use crate::log;
+use crate::redact;
+use crate::Task;
+use rusqlite::{params, Connection};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::time::{self, Duration};
+
+/// How often rows past `HISTORY_RETENTION_DAYS` are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+/// Runs listed by `history` when `--limit` isn't given.
+const DEFAULT_LIMIT: usize = 20;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS task_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    task_id TEXT NOT NULL,
+    task_type TEXT NOT NULL,
+    queue TEXT NOT NULL,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    worker_id TEXT NOT NULL,
+    correlation_id TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    finished_at TEXT NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    exit_code INTEGER,
+    payload TEXT NOT NULL,
+    output TEXT NOT NULL,
+    output_truncated INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS task_history_task_id ON task_history (task_id);
+CREATE INDEX IF NOT EXISTS task_history_started_at ON task_history (started_at);
+";
+
+/// Set by [`init`] when `HISTORY_DB` is configured.
+static DB: OnceLock<Arc<Mutex<Connection>>> = OnceLock::new();
+
+// --- Task History ---
+/// One finished run, as recorded by [`record`].
+pub struct Run<'a> {
+    pub task: &'a Task,
+    pub queue_name: &'a str,
+    /// The raw payload; secret detail values are redacted before it is kept.
+    pub payload: &'a str,
+    pub status: &'a str,
+    pub output: &'a str,
+    pub exit_code: Option<i32>,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub duration: Duration,
+}
+
+fn open(path: &str) -> Result<Connection, String> {
+    let conn =
+        Connection::open(path).map_err(|e| format!("Failed to open HISTORY_DB {}: {}", path, e))?;
+    conn.execute_batch(SCHEMA)
+        .map_err(|e| format!("Failed to create the history schema in {}: {}", path, e))?;
+    Ok(conn)
+}
+
+/// Opens `HISTORY_DB`, creating its table on first use, and spawns the loop
+/// that drops rows older than `HISTORY_RETENTION_DAYS`. Without
+/// `HISTORY_DB` nothing is recorded.
+pub fn init(config: &Config) -> Result<(), String> {
+    let Some(path) = &config.history_db else {
+        return Ok(());
+    };
+    let db = Arc::new(Mutex::new(open(path)?));
+    DB.set(Arc::clone(&db)).ok();
+    log(&format!("Recording task history in {}", path));
+    if config.history_retention_days > 0 {
+        let days = config.history_retention_days;
+        tokio::spawn(async move {
+            let mut interval = time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let db = Arc::clone(&db);
+                let pruned = tokio::task::spawn_blocking(move || prune(&db, days)).await;
+                match pruned {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(rows)) => log(&format!("Pruned {} task history rows", rows)),
+                    Ok(Err(e)) => log(&format!("[ERROR] Failed to prune task history: {}", e)),
+                    Err(e) => log(&format!("[ERROR] Task history pruning failed: {}", e)),
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+fn prune(db: &Mutex<Connection>, days: u64) -> rusqlite::Result<usize> {
+    let cutoff = chrono::Utc::now() - chrono::Duration::days(days as i64);
+    db.lock().unwrap().execute(
+        "DELETE FROM task_history WHERE started_at < ?1",
+        params![cutoff.to_rfc3339()],
+    )
+}
+
+/// Appends a finished run, keeping at most `HISTORY_OUTPUT_BYTES` of its
+/// output with the task's secret values scrubbed. A failed write is logged;
+/// it never fails the task.
+pub async fn record(config: &Config, run: Run<'_>) {
+    let Some(db) = DB.get() else {
+        return;
+    };
+    let secrets = redact::secret_values(&run.task.details);
+    let output = redact::scrub(run.output, &secrets);
+    let (output, truncated) = batch::truncate(&output, config.history_output_bytes);
+    let params = (
+        run.task.id.clone(),
+        run.task.task_type.reported(),
+        run.queue_name.to_string(),
+        run.status.to_string(),
+        run.task.attempts,
+        config.worker_id.clone(),
+        run.task.correlation_id().to_string(),
+        run.started_at.to_rfc3339(),
+        (run.started_at + run.duration).to_rfc3339(),
+        run.duration.as_millis() as u64,
+        run.exit_code,
+        redact::redact_payload(run.payload),
+        output.to_string(),
+        truncated,
+    );
+    let db = Arc::clone(db);
+    let inserted = tokio::task::spawn_blocking(move || {
+        db.lock().unwrap().execute(
+            "INSERT INTO task_history (task_id, task_type, queue, status, attempts, worker_id,
+                correlation_id, started_at, finished_at, duration_ms, exit_code, payload, output,
+                output_truncated)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            params,
+        )
+    })
+    .await;
+    match inserted {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => log(&format!(
+            "[ERROR] Failed to record history for task {}: {}",
+            run.task.id, e
+        )),
+        Err(e) => log(&format!(
+            "[ERROR] Recording history for task {} failed: {}",
+            run.task.id, e
+        )),
+    }
+}
+
+/// The `history` subcommand: prints the recorded runs of `--task-id <id>`,
+/// or the latest runs with `--status <status>` or none, newest first and up
+/// to `--limit` (default 20), as one JSON line each.
+pub fn run_cli(args: &[String], config: &Config) -> Result<(), String> {
+    let flag = |name: &str| -> Result<Option<&str>, String> {
+        match args.iter().position(|a| a == name) {
+            Some(pos) => args
+                .get(pos + 1)
+                .map(|value| Some(value.as_str()))
+                .ok_or_else(|| format!("{} needs a value", name)),
+            None => Ok(None),
+        }
+    };
+    let limit = match flag("--limit")? {
+        Some(limit) => limit
+            .parse::<usize>()
+            .map_err(|_| format!("--limit must be a number, not '{}'", limit))?,
+        None => DEFAULT_LIMIT,
+    };
+    for run in runs(config, flag("--task-id")?, flag("--status")?, limit)? {
+        println!("{}", run);
+    }
+    Ok(())
+}
+
+/// Recorded runs, newest first, optionally only those of `task_id` or with
+/// `status`.
+pub fn runs(
+    config: &Config,
+    task_id: Option<&str>,
+    status: Option<&str>,
+    limit: usize,
+) -> Result<Vec<serde_json::Value>, String> {
+    let path = config
+        .history_db
+        .as_deref()
+        .ok_or("task history needs HISTORY_DB to be set")?;
+    let conn = open(path)?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT task_id, task_type, queue, status, attempts, worker_id, correlation_id,
+                started_at, finished_at, duration_ms, exit_code, payload, output, output_truncated
+             FROM task_history
+             WHERE (?1 IS NULL OR task_id = ?1) AND (?2 IS NULL OR status = ?2)
+             ORDER BY id DESC LIMIT ?3",
+        )
+        .map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map(params![task_id, status, limit as i64], |row| {
+            let payload: String = row.get(11)?;
+            Ok(serde_json::json!({
+                "task_id": row.get::<_, String>(0)?,
+                "task_type": row.get::<_, String>(1)?,
+                "queue": row.get::<_, String>(2)?,
+                "status": row.get::<_, String>(3)?,
+                "attempts": row.get::<_, i64>(4)?,
+                "worker_id": row.get::<_, String>(5)?,
+                "correlation_id": row.get::<_, String>(6)?,
+                "started_at": row.get::<_, String>(7)?,
+                "finished_at": row.get::<_, String>(8)?,
+                "duration_ms": row.get::<_, i64>(9)?,
+                "exit_code": row.get::<_, Option<i64>>(10)?,
+                "payload": serde_json::from_str(&payload)
+                    .unwrap_or(serde_json::Value::String(payload)),
+                "output": row.get::<_, String>(12)?,
+                "output_truncated": row.get::<_, bool>(13)?,
+            }))
+        })
+        .map_err(|e| e.to_string())?;
+    rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+}
@@ -0,0 +1,182 @@
+use crate::cancel;
+use crate::config::Config;
+use crate::details::string_map;
+use crate::log;
+use crate::redact;
+use crate::Task;
+use std::collections::BTreeMap;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+// --- HTTP Tasks ---
+/// Sends `details.method` (default `GET`) to `details.url` with
+/// `details.headers`, and `details.body` (a string, or any other JSON value
+/// sent as `application/json`). The request is bounded by
+/// `details.timeout_secs`, else `HTTP_TASK_TIMEOUT_SECS`. Returns
+/// `{"url","method","status","headers","body","truncated","duration_ms"}`,
+/// the body cut to `HTTP_MAX_BODY_BYTES`. A status outside
+/// `details.expected_status` (any 2xx when unset) fails the task with that
+/// same JSON.
+pub async fn execute(
+    task: &Task,
+    config: &Config,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    let details = &task.details;
+    let url = details["url"]
+        .as_str()
+        .ok_or("HTTP task requires a string 'url'")?;
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid url '{}': {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("Unsupported url scheme '{}'", parsed.scheme()));
+    }
+    let method = match &details["method"] {
+        serde_json::Value::Null => reqwest::Method::GET,
+        serde_json::Value::String(method) => {
+            reqwest::Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| format!("Invalid HTTP method '{}'", method))?
+        }
+        _ => return Err("'method' must be a string".to_string()),
+    };
+    let headers = string_map(&details["headers"], "headers")?;
+    let timeout = match &details["timeout_secs"] {
+        serde_json::Value::Null => Duration::from_secs(config.http_task_timeout_secs),
+        value => value
+            .as_f64()
+            .filter(|secs| *secs > 0.0)
+            .map(Duration::from_secs_f64)
+            .ok_or("'timeout_secs' must be a positive number")?,
+    };
+    let expected = expected_status(&details["expected_status"])?;
+
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|e| format!("HTTP client setup failed: {}", e))?;
+    let has_content_type = headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("content-type"));
+    let shown_url = loggable_url(&parsed, details);
+    let mut request = client.request(method.clone(), parsed);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    request = match &details["body"] {
+        serde_json::Value::Null => request,
+        serde_json::Value::String(body) => request.body(body.clone()),
+        body if has_content_type => request.body(body.to_string()),
+        body => request
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string()),
+    };
+
+    log(&format!("Executing HTTP {} {}", method, shown_url));
+    let started = Instant::now();
+    let response = tokio::select! {
+        response = send(request, config.http_max_body_bytes) => response?,
+        _ = cancel.cancelled() => {
+            return Err(serde_json::json!({
+                "status": cancel::reason(task),
+                "url": url,
+                "method": method.as_str(),
+            })
+            .to_string())
+        }
+    };
+
+    let status = response.status;
+    let result = serde_json::json!({
+        "url": url,
+        "method": method.as_str(),
+        "status": status,
+        "headers": response.headers,
+        "body": String::from_utf8_lossy(&response.body),
+        "truncated": response.truncated,
+        "duration_ms": started.elapsed().as_millis() as u64,
+    })
+    .to_string();
+    let ok = match &expected {
+        Some(codes) => codes.contains(&status),
+        None => (200..300).contains(&status),
+    };
+    match ok {
+        true => Ok(result),
+        false => Err(result),
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: BTreeMap<String, String>,
+    body: Vec<u8>,
+    truncated: bool,
+}
+
+/// Sends `request` and reads at most `max_body` bytes of its body.
+async fn send(request: reqwest::RequestBuilder, max_body: usize) -> Result<Response, String> {
+    let mut response = request
+        .send()
+        .await
+        .map_err(|e| format!("HTTP request failed: {}", e))?;
+    let mut headers = BTreeMap::new();
+    for (name, value) in response.headers() {
+        let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+        headers
+            .entry(name.as_str().to_string())
+            .and_modify(|joined: &mut String| {
+                joined.push_str(", ");
+                joined.push_str(&value);
+            })
+            .or_insert(value);
+    }
+    let status = response.status().as_u16();
+    let mut body = Vec::new();
+    let mut truncated = false;
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| format!("Failed to read HTTP response: {}", e))?
+    {
+        let room = max_body - body.len();
+        if chunk.len() > room {
+            body.extend_from_slice(&chunk[..room]);
+            truncated = true;
+            break;
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Response {
+        status,
+        headers,
+        body,
+        truncated,
+    })
+}
+
+/// Reads the optional `expected_status` list of status codes.
+fn expected_status(value: &serde_json::Value) -> Result<Option<Vec<u16>>, String> {
+    let error = || "'expected_status' must be an array of status codes".to_string();
+    match value {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::Array(codes) => codes
+            .iter()
+            .map(|code| {
+                code.as_u64()
+                    .and_then(|c| u16::try_from(c).ok())
+                    .ok_or_else(error)
+            })
+            .collect::<Result<_, _>>()
+            .map(Some),
+        _ => Err(error()),
+    }
+}
+
+/// `url` for the log: a password in it is masked and secret detail values
+/// are scrubbed, since tokens often ride in the query string.
+fn loggable_url(url: &reqwest::Url, details: &serde_json::Value) -> String {
+    let mut url = url.clone();
+    if url.password().is_some() {
+        url.set_password(Some(redact::REDACTED)).ok();
+    }
+    redact::scrub(url.as_str(), &redact::secret_values(details))
+}
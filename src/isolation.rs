@@ -0,0 +1,116 @@
+use std::path::PathBuf;
+
+// --- Process Isolation ---
+/// Optional per-task isolation for shell commands, from `details.isolate`
+/// (fresh mount and PID namespaces) and `details.root` (chroot directory).
+///
+/// Set-up happens in the child between fork and exec. If the worker lacks
+/// the privileges for it (CAP_SYS_ADMIN for namespaces, CAP_SYS_CHROOT for
+/// chroot) the spawn fails instead of running the command unisolated.
+#[derive(Debug, Default)]
+pub struct Isolation {
+    namespaces: bool,
+    root: Option<PathBuf>,
+}
+
+impl Isolation {
+    pub fn from_details(details: &serde_json::Value) -> Result<Self, String> {
+        let namespaces = match details.get("isolate") {
+            None | Some(serde_json::Value::Null) => false,
+            Some(serde_json::Value::Bool(b)) => *b,
+            Some(other) => return Err(format!("isolate must be a boolean, got: {}", other)),
+        };
+        let root = match details.get("root") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(serde_json::Value::String(path)) => {
+                let path = PathBuf::from(path);
+                if !path.is_dir() {
+                    return Err(format!(
+                        "Isolation root is not a directory: {}",
+                        path.display()
+                    ));
+                }
+                Some(path)
+            }
+            Some(other) => return Err(format!("root must be a string, got: {}", other)),
+        };
+        Ok(Isolation { namespaces, root })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.namespaces || self.root.is_some()
+    }
+
+    /// Arranges for the child to enter its namespaces and/or chroot before
+    /// exec. The mount namespace applies to the command itself; the PID
+    /// namespace applies to the processes it starts.
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self, cmd: &mut tokio::process::Command) -> Result<(), String> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        // Allocate before fork: only async-signal-safe calls run in pre_exec.
+        let root = match &self.root {
+            Some(path) => Some(
+                CString::new(path.as_os_str().as_bytes())
+                    .map_err(|_| format!("Invalid isolation root: {}", path.display()))?,
+            ),
+            None => None,
+        };
+        let namespaces = self.namespaces;
+
+        // SAFETY: the closure only calls async-signal-safe libc functions on
+        // pre-allocated data.
+        unsafe {
+            cmd.pre_exec(move || {
+                if namespaces {
+                    if libc::unshare(libc::CLONE_NEWNS | libc::CLONE_NEWPID) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    // Keep mounts made inside the namespace from propagating out.
+                    if libc::mount(
+                        std::ptr::null(),
+                        c"/".as_ptr(),
+                        std::ptr::null(),
+                        libc::MS_REC | libc::MS_PRIVATE,
+                        std::ptr::null(),
+                    ) != 0
+                    {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(root) = &root {
+                    if libc::chroot(root.as_ptr()) != 0 || libc::chdir(c"/".as_ptr()) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self, _cmd: &mut tokio::process::Command) -> Result<(), String> {
+        if self.is_enabled() {
+            return Err("Task isolation is only supported on Linux".to_string());
+        }
+        Ok(())
+    }
+
+    /// Explains a spawn failure, pointing at isolation when it was requested.
+    pub fn spawn_error(&self, e: std::io::Error) -> String {
+        if self.is_enabled() {
+            format!(
+                "Failed to start isolated command (isolation needs CAP_SYS_ADMIN/CAP_SYS_CHROOT): {}",
+                e
+            )
+        } else {
+            format!("Failed to execute shell command: {}", e)
+        }
+    }
+}
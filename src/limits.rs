@@ -0,0 +1,347 @@
+use crate::batch;
+use crate::config::Config;
+#[cfg(feature = "shell")]
+use crate::log;
+#[cfg(feature = "shell")]
+use std::path::{Path, PathBuf};
+
+/// `cpu.max` period; a task's quota is `cpus` times this.
+#[cfg(feature = "shell")]
+const CPU_PERIOD_USECS: u64 = 100_000;
+/// Extra CPU seconds between SIGXCPU at `cpu_secs` and SIGKILL.
+#[cfg(feature = "shell")]
+const CPU_KILL_GRACE_SECS: u64 = 5;
+
+// --- Resource Limits ---
+/// What a task may use: `details.limits` (`memory_mb`, `cpus`, `cpu_secs`,
+/// `nice`, `max_output_bytes`) within the worker-wide `TASK_MEMORY_MB`,
+/// `TASK_CPUS`, `TASK_CPU_SECS`, `TASK_NICE` and `MAX_OUTPUT_BYTES`. A task
+/// can tighten a worker-wide limit but not lift it. Memory and CPU limits
+/// apply to SHELL children and `run_container`; the output limit to every
+/// task's result.
+#[derive(Debug, Default)]
+pub struct Limits {
+    pub memory_mb: Option<u64>,
+    /// CPUs' worth of time per period (`1.5` is one and a half cores).
+    pub cpus: Option<f64>,
+    /// CPU time after which a shell child gets SIGXCPU.
+    #[cfg_attr(not(feature = "shell"), allow(dead_code))]
+    pub cpu_secs: Option<u64>,
+    /// Scheduling niceness for a shell child, 0 to 19.
+    #[cfg_attr(not(feature = "shell"), allow(dead_code))]
+    pub nice: Option<i32>,
+    pub max_output_bytes: Option<usize>,
+}
+
+impl Limits {
+    pub fn from_task(details: &serde_json::Value, config: &Config) -> Result<Self, String> {
+        let limits = &details["limits"];
+        if !matches!(
+            limits,
+            serde_json::Value::Null | serde_json::Value::Object(_)
+        ) {
+            return Err("'limits' must be an object".to_string());
+        }
+        let whole = |key: &str| -> Result<Option<u64>, String> {
+            match &limits[key] {
+                serde_json::Value::Null => Ok(None),
+                value => value
+                    .as_u64()
+                    .filter(|n| *n > 0)
+                    .map(Some)
+                    .ok_or_else(|| format!("'limits.{}' must be a positive integer", key)),
+            }
+        };
+        let cpus = match &limits["cpus"] {
+            serde_json::Value::Null => None,
+            value => Some(
+                value
+                    .as_f64()
+                    .filter(|cpus| *cpus > 0.0)
+                    .ok_or("'limits.cpus' must be a positive number")?,
+            ),
+        };
+        let nice = match &limits["nice"] {
+            serde_json::Value::Null => None,
+            value => Some(
+                value
+                    .as_i64()
+                    .filter(|nice| (0..=19).contains(nice))
+                    .ok_or("'limits.nice' must be an integer from 0 to 19")? as i32,
+            ),
+        };
+        Ok(Limits {
+            memory_mb: tightest(whole("memory_mb")?, config.task_memory_mb),
+            cpus: match (cpus, config.task_cpus) {
+                (Some(cpus), max) if max > 0.0 => Some(cpus.min(max)),
+                (None, max) if max > 0.0 => Some(max),
+                (cpus, _) => cpus,
+            },
+            cpu_secs: tightest(whole("cpu_secs")?, config.task_cpu_secs),
+            // Nicer is tighter.
+            nice: match (nice, config.task_nice) {
+                (Some(nice), worker) => Some(nice.max(worker)),
+                (None, 0) => None,
+                (None, worker) => Some(worker),
+            },
+            max_output_bytes: tightest(
+                whole("max_output_bytes")?.map(|n| n as usize),
+                config.max_output_bytes,
+            ),
+        })
+    }
+
+    #[cfg(feature = "shell")]
+    fn is_empty(&self) -> bool {
+        self.memory_mb.is_none()
+            && self.cpus.is_none()
+            && self.cpu_secs.is_none()
+            && self.nice.is_none()
+    }
+
+    /// `docker run --memory` in bytes.
+    #[cfg_attr(not(feature = "docker"), allow(dead_code))]
+    pub fn memory_bytes(&self) -> Option<i64> {
+        self.memory_mb.map(|mb| (mb * 1024 * 1024) as i64)
+    }
+
+    /// `docker run --cpus` in billionths of a CPU.
+    #[cfg_attr(not(feature = "docker"), allow(dead_code))]
+    pub fn nano_cpus(&self) -> Option<i64> {
+        self.cpus.map(|cpus| (cpus * 1e9) as i64)
+    }
+
+    /// Caps a shell child between fork and exec: it joins `cgroup` (which
+    /// holds its memory and CPU limits) when there is one, otherwise memory
+    /// is capped with `RLIMIT_AS` and `cpus` can't be enforced. `cpu_secs`
+    /// becomes `RLIMIT_CPU` and `nice` its priority. Apply this before
+    /// [`crate::isolation::Isolation::apply`], whose chroot would hide the
+    /// cgroup filesystem.
+    #[cfg(all(feature = "shell", target_os = "linux"))]
+    pub fn apply(
+        &self,
+        cmd: &mut tokio::process::Command,
+        cgroup: Option<&Cgroup>,
+    ) -> Result<(), String> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        if self.is_empty() {
+            return Ok(());
+        }
+        if self.cpus.is_some() && cgroup.is_none() {
+            return Err("'limits.cpus' needs TASK_CGROUP_DIR".to_string());
+        }
+        // Allocate before fork: only async-signal-safe calls run in pre_exec.
+        let procs = match cgroup {
+            Some(cgroup) => Some(
+                CString::new(cgroup.path.join("cgroup.procs").as_os_str().as_bytes())
+                    .map_err(|_| format!("Invalid cgroup path: {}", cgroup.path.display()))?,
+            ),
+            None => None,
+        };
+        let address_space = match cgroup {
+            Some(_) => None,
+            None => self.memory_bytes().map(|bytes| bytes as libc::rlim_t),
+        };
+        let cpu_secs = self.cpu_secs.map(|secs| secs as libc::rlim_t);
+        let nice = self.nice;
+
+        // SAFETY: the closure only calls async-signal-safe libc functions on
+        // pre-allocated data.
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(procs) = &procs {
+                    let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    let written = libc::write(fd, c"0".as_ptr().cast(), 1);
+                    libc::close(fd);
+                    if written != 1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(bytes) = address_space {
+                    let limit = libc::rlimit {
+                        rlim_cur: bytes,
+                        rlim_max: bytes,
+                    };
+                    if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(secs) = cpu_secs {
+                    let limit = libc::rlimit {
+                        rlim_cur: secs,
+                        rlim_max: secs + CPU_KILL_GRACE_SECS as libc::rlim_t,
+                    };
+                    if libc::setrlimit(libc::RLIMIT_CPU, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(all(feature = "shell", not(target_os = "linux")))]
+    pub fn apply(
+        &self,
+        _cmd: &mut tokio::process::Command,
+        _cgroup: Option<&Cgroup>,
+    ) -> Result<(), String> {
+        if !self.is_empty() {
+            return Err("Resource limits are only supported on Linux".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The lower of a task's limit and the worker's, where a worker limit of 0
+/// means none.
+fn tightest<T: Ord + Copy + Default + PartialEq>(task: Option<T>, worker: T) -> Option<T> {
+    match (task, worker == T::default()) {
+        (Some(task), false) => Some(task.min(worker)),
+        (task, true) => task,
+        (None, false) => Some(worker),
+    }
+}
+
+/// A cgroup v2 group a shell child runs in, created under `TASK_CGROUP_DIR`
+/// (which must be delegated to the worker, with the `memory` and `cpu`
+/// controllers enabled for its children). Dropping it kills anything left
+/// inside and removes the group.
+#[cfg(feature = "shell")]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+#[cfg(feature = "shell")]
+impl Cgroup {
+    /// A group for `task_id` with the memory and CPU limits written, or
+    /// `None` without `TASK_CGROUP_DIR` or limits to put in it.
+    pub fn create(task_id: &str, limits: &Limits, config: &Config) -> Result<Option<Self>, String> {
+        let Some(dir) = &config.task_cgroup_dir else {
+            return Ok(None);
+        };
+        if limits.memory_mb.is_none() && limits.cpus.is_none() {
+            return Ok(None);
+        }
+        let name: String = task_id
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        let path = Path::new(dir).join(format!("mcp-{}-{:08x}", name, rand::random::<u32>()));
+        std::fs::create_dir(&path)
+            .map_err(|e| format!("Failed to create cgroup {}: {}", path.display(), e))?;
+        let cgroup = Cgroup { path };
+        if let Some(bytes) = limits.memory_bytes() {
+            cgroup.write("memory.max", &bytes.to_string())?;
+            // Absent without swap accounting; the memory limit still holds.
+            cgroup.write("memory.swap.max", "0").ok();
+        }
+        if let Some(cpus) = limits.cpus {
+            let quota = ((cpus * CPU_PERIOD_USECS as f64) as u64).max(1000);
+            cgroup.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD_USECS))?;
+        }
+        Ok(Some(cgroup))
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<(), String> {
+        let path = self.path.join(file);
+        std::fs::write(&path, value)
+            .map_err(|e| format!("Failed to set {} to {}: {}", path.display(), value, e))
+    }
+}
+
+#[cfg(feature = "shell")]
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Background children outliving the command would keep it busy.
+        self.write("cgroup.kill", "1").ok();
+        if let Err(e) = std::fs::remove_dir(&self.path) {
+            log(&format!(
+                "[WARN] Failed to remove cgroup {}: {}",
+                self.path.display(),
+                e
+            ));
+        }
+    }
+}
+
+// --- Output Limits ---
+/// Output kept up to a limit, counting what was dropped past it.
+#[cfg(feature = "shell")]
+#[derive(Default)]
+pub struct Capture {
+    max: Option<usize>,
+    bytes: Vec<u8>,
+    dropped: u64,
+}
+
+#[cfg(feature = "shell")]
+impl Capture {
+    /// A capture keeping at most `max` bytes, marker included; `None` keeps
+    /// everything.
+    pub fn new(max: Option<usize>) -> Self {
+        Capture {
+            max,
+            ..Default::default()
+        }
+    }
+
+    /// Keeps `chunk` while the capture stays within its limit; past it the
+    /// rest is only counted. Returns whether any of the chunk was kept.
+    pub fn push(&mut self, chunk: &[u8]) -> bool {
+        let room = self
+            .max
+            .map_or(chunk.len(), |max| max.saturating_sub(self.bytes.len()));
+        let kept = chunk.len().min(room);
+        self.bytes.extend_from_slice(&chunk[..kept]);
+        self.dropped += (chunk.len() - kept) as u64;
+        kept > 0
+    }
+
+    /// The captured bytes, cut further when some were dropped so that they
+    /// and the truncation marker ending them still fit the limit.
+    pub fn finish(mut self) -> Vec<u8> {
+        let Some(max) = self.max.filter(|_| self.dropped > 0) else {
+            return self.bytes;
+        };
+        let total = self.bytes.len() as u64 + self.dropped;
+        let room = max.saturating_sub(truncation_marker(total).len());
+        self.bytes.truncate(room);
+        let dropped = total - self.bytes.len() as u64;
+        self.bytes
+            .extend_from_slice(truncation_marker(dropped).as_bytes());
+        self.bytes
+    }
+}
+
+/// Marks where output was cut. Never longer for fewer `dropped` bytes, so
+/// one built for the whole output's length leaves room for the real one.
+fn truncation_marker(dropped: u64) -> String {
+    format!("\n[output truncated: {} more bytes]\n", dropped)
+}
+
+/// Cuts a task's result so that it fits in `max` bytes along with a marker
+/// saying how much was cut.
+pub fn cap_output(output: String, max: Option<usize>) -> String {
+    let Some(max) = max.filter(|max| output.len() > *max) else {
+        return output;
+    };
+    let room = max.saturating_sub(truncation_marker(output.len() as u64).len());
+    let (kept, _) = batch::truncate(&output, room);
+    let dropped = (output.len() - kept.len()) as u64;
+    format!("{}{}", kept, truncation_marker(dropped))
+}
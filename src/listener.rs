@@ -0,0 +1,775 @@
+use crate::backoff;
+use crate::callback;
+use crate::cancel;
+use crate::concurrency;
+use crate::config::Config;
+use crate::control::{self, PauseState};
+use crate::deadletter;
+use crate::dedup;
+use crate::exit;
+use crate::handlers;
+use crate::health;
+use crate::history;
+use crate::log;
+use crate::logging;
+use crate::metrics;
+use crate::policy::{self, POLICY_DENIED};
+use crate::queue::TaskQueue;
+use crate::redact;
+use crate::result;
+use crate::retry;
+use crate::routing;
+use crate::running;
+use crate::scheduler;
+use crate::signing;
+use crate::stats::{self, InflightGuard, STATS};
+use crate::validation::{self, Problem, VALIDATION_ERROR};
+use crate::{execute_task, Task};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration, Instant};
+
+/// How long a single pop blocks, so control changes are noticed while idle.
+pub const POP_TIMEOUT_SECS: f64 = 5.0;
+/// Pause after a failed pop before trying again, doubled on each further
+/// failure up to `REDIS_RECONNECT_MAX_SECS`.
+const ERROR_BACKOFF_START: Duration = Duration::from_millis(500);
+
+/// Pops and processes tasks from `queue_keys` forever. By default earlier
+/// queues in the list are served first whenever several have work (strict
+/// priority), which can starve later queues under sustained load. With
+/// `QUEUE_FAIRNESS=rotate` the order shifts by one on every pop so each queue
+/// takes a turn at the front, at the cost of no longer honoring priority.
+/// With `MAX_TOTAL_CONCURRENCY` set a listener only pops once a permit is
+/// free, so unclaimed work stays in Redis for other workers. Each task runs
+/// as its own Tokio task, up to `MAX_CONCURRENT_TASKS` at once per listener,
+/// with a [`TaskQueue::job`] handle to write its result through; the
+/// listener doesn't pop again until one of those slots is free. A popped
+/// task stays in the worker's processing list for its queue
+/// (`mcp::processing::<worker_id>::<queue>`) until its result is written
+/// (or it is re-queued or dead-lettered), so one lost to a crash is
+/// re-queued by the next start. Returns once a `restart_worker` request is
+/// seen or a shutdown starts, after finishing the tasks in hand (which a
+/// shutdown cancels when its drain period runs out).
+pub async fn command_listener<Q: TaskQueue + Send + 'static>(
+    queue: &mut Q,
+    config: &Config,
+    pause: &PauseState,
+    queue_keys: &[String],
+) {
+    let mut queue_keys: Vec<&str> = queue_keys.iter().map(String::as_str).collect();
+    log(&format!(
+        "Listening for commands on queues: {:?} in db {} ({})",
+        queue_keys,
+        config.redis_db,
+        if config.queue_rotate {
+            "rotating"
+        } else {
+            "strict priority"
+        }
+    ));
+    log(&format!(
+        "Worker {} (host: {}, labels: {:?}, Redis pool size: {})",
+        config.worker_id, config.worker_host, config.worker_labels, config.redis_pool_size
+    ));
+
+    let _health = health::ListenerGuard::new();
+    let shared_config = Arc::new(config.clone());
+    let pool = Arc::new(Semaphore::new(config.max_concurrent_tasks));
+    let mut jobs = JoinSet::new();
+    let mut last_control = None;
+    let mut error_backoff = ERROR_BACKOFF_START;
+    while !control::restart_requested() && !cancel::draining() {
+        while let Some(joined) = jobs.try_join_next() {
+            log_job_failure(joined);
+        }
+        // 0. Honor pause requests before pulling more work
+        if let Some(mut conn) = queue.redis_connection() {
+            control::poll_control_key(&mut conn, &config.worker_id, pause, &mut last_control).await;
+        }
+        if pause.is_paused() {
+            time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+
+        // 1. Safe Pop from the queue, once there is capacity to run the task
+        let Ok(job_permit) = Arc::clone(&pool).acquire_owned().await else {
+            break;
+        };
+        let slot = concurrency::reserve().await;
+        // Waiting for capacity may have outlasted the start of a shutdown.
+        if cancel::draining() {
+            break;
+        }
+        let pop_started = Instant::now();
+        let popped = queue.pop(&queue_keys, POP_TIMEOUT_SECS).await;
+        if config.queue_rotate {
+            queue_keys.rotate_left(1);
+        }
+        health::set_redis_connected(popped.is_ok());
+        if popped.is_ok() {
+            health::mark_alive();
+            error_backoff = ERROR_BACKOFF_START;
+        }
+        match popped {
+            Ok(None) => {}
+            Ok(Some((queue_name, payload))) => {
+                metrics::observe_pop(pop_started.elapsed());
+                let mut job = queue.job();
+                let config = Arc::clone(&shared_config);
+                jobs.spawn(async move {
+                    let _job_permit = job_permit;
+                    process_payload(&mut job, &config, slot, &queue_name, &payload).await;
+                    // Only now can a crash no longer lose the task.
+                    if let Err(e) = job.ack(&queue_name, &payload).await {
+                        log(&format!(
+                            "[ERROR] Failed to remove a finished task from the processing list: {}",
+                            e
+                        ));
+                    }
+                });
+            }
+            Err(e) => {
+                log(&format!("[ERROR] Redis Error in Loop: {}", e));
+                // The queue has already tried to reconnect; back off before
+                // the next attempt.
+                let wait = backoff::jitter(error_backoff, config.backoff_jitter_pct);
+                log(&format!("Retrying Redis in {:?}", wait));
+                time::sleep(wait).await;
+                let max = Duration::from_secs(config.redis_reconnect_max_secs);
+                error_backoff = (error_backoff * 2).min(max);
+            }
+        }
+    }
+    while let Some(joined) = jobs.join_next().await {
+        log_job_failure(joined);
+    }
+}
+
+fn log_job_failure(joined: Result<(), tokio::task::JoinError>) {
+    if let Err(e) = joined {
+        log(&format!("[ERROR] Task job failed: {}", e));
+    }
+}
+
+/// Validates, routes, executes, and records a single popped payload, running
+/// it under the global permit reserved before the pop.
+async fn process_payload<Q: TaskQueue>(
+    queue: &mut Q,
+    config: &Config,
+    slot: concurrency::Slot,
+    queue_name: &str,
+    payload: &[u8],
+) {
+    stats::incr(&STATS.received);
+
+    // Oversized payloads are neither logged nor parsed.
+    if payload.len() > config.max_task_bytes {
+        let reason = format!(
+            "Payload of {} bytes exceeds MAX_TASK_BYTES ({})",
+            payload.len(),
+            config.max_task_bytes
+        );
+        log(&format!("[ERROR] {}", reason));
+        let lossy = String::from_utf8_lossy(payload);
+        deadletter::dead_letter(queue, queue_name, &lossy, &reason).await;
+        return;
+    }
+    // The dead-letter entry keeps a lossy copy, with invalid bytes as U+FFFD.
+    let json_str = match std::str::from_utf8(payload) {
+        Ok(json_str) => json_str,
+        Err(e) => {
+            let reason = format!(
+                "non-UTF-8 payload: invalid byte at offset {}",
+                e.valid_up_to()
+            );
+            log(&format!("[ERROR] {}", reason));
+            let lossy = String::from_utf8_lossy(payload);
+            deadletter::dead_letter(queue, queue_name, &lossy, &reason).await;
+            return;
+        }
+    };
+    log(&format!(
+        ">>> RECEIVED: {}",
+        redact::redact_payload(json_str)
+    ));
+
+    // 2. Safe Parse the JSON into a Task
+    let mut task = match config.envelope.parse(json_str) {
+        Ok(task) => task,
+        Err(e) => {
+            log(&format!("[ERROR] JSON Parse Error: {}", e));
+            let reason = format!("JSON Parse Error: {}", e);
+            deadletter::dead_letter(queue, queue_name, json_str, &reason).await;
+            return;
+        }
+    };
+    task.assign_correlation_id();
+    task.apply_defaults();
+    metrics::task_received(&task.task_type);
+    let span = logging::span(&task);
+    let handled = handle_task(queue, config, slot, queue_name, json_str, task);
+    logging::in_span(span, handled).await;
+}
+
+/// Routes, executes, and records a parsed task.
+async fn handle_task<Q: TaskQueue>(
+    queue: &mut Q,
+    config: &Config,
+    slot: concurrency::Slot,
+    queue_name: &str,
+    json_str: &str,
+    mut task: Task,
+) {
+    // A host-scoped queue is itself the address.
+    let shared_queue = routing::shared_queue(queue_name, &config.worker_host);
+    // Per-queue settings are looked up by the shared queue's name.
+    let configured_queue = shared_queue.as_deref().unwrap_or(queue_name);
+    if shared_queue.is_none() && !routing::accepts(&task, config) {
+        if config.reject_mismatched {
+            let reason = format!(
+                "Task {} is targeted at {}, not this worker",
+                task.id, task.target_host
+            );
+            log(&format!("[WARN] {}", reason));
+            deadletter::dead_letter(queue, queue_name, json_str, &reason).await;
+            return;
+        }
+        if let (Some(mut conn), max_age @ 1..) =
+            (queue.redis_connection(), config.mismatch_max_age_secs)
+        {
+            match routing::bounced_for(&mut conn, &task.id, max_age).await {
+                Ok(age) if age >= max_age => {
+                    let reason = format!(
+                        "Task {} is targeted at {}, but no worker took it in {}s of re-queueing",
+                        task.id, task.target_host, age
+                    );
+                    log(&format!("[WARN] {}", reason));
+                    deadletter::dead_letter(queue, queue_name, json_str, &reason).await;
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => log(&format!(
+                    "[ERROR] Failed to track re-queueing of task {}: {}",
+                    task.id, e
+                )),
+            }
+        }
+        let target = routing::requeue_target(queue_name, task.priority, config);
+        log(&format!(
+            "Task {} not targeted at this worker, re-queueing on {}",
+            task.id, target
+        ));
+        if let Err(e) = queue.push(&target, json_str).await {
+            log(&format!(
+                "[ERROR] Failed to re-queue task {}: {}",
+                task.id, e
+            ));
+        }
+        // Give matching workers a chance to pick it up.
+        time::sleep(Duration::from_millis(250)).await;
+        return;
+    }
+
+    // Checked once routed here, so a task passed on to another worker
+    // doesn't use up its nonce.
+    if let Err(e) = signing::verify(json_str, config, queue.redis_connection()).await {
+        let reason = format!("Task {} {}: {}", task.id, signing::REJECTED, e);
+        log(&format!("[WARN] {}", reason));
+        deadletter::dead_letter(queue, queue_name, json_str, &reason).await;
+        return;
+    }
+
+    // Malformed details fail before the task is scheduled or run, with
+    // every offending field listed.
+    let invalid = handlers::executor(task.task_type.as_str())
+        .and_then(|executor| executor.validate(&task.details).err());
+    if let Some(problems) = invalid {
+        reject_invalid(queue, config, queue_name, &task, &problems).await;
+        return;
+    }
+
+    if scheduler::is_deferred(&task) {
+        let scheduled = match queue.redis_connection() {
+            Some(mut conn) => scheduler::schedule(&mut conn, queue_name, json_str, &task).await,
+            None => Err("scheduled tasks need a Redis connection".to_string()),
+        };
+        match scheduled {
+            Ok(due) => log(&format!(
+                "Task {} scheduled, next due {}",
+                task.id,
+                due.to_rfc3339()
+            )),
+            Err(e) => {
+                log(&format!("[ERROR] Task {} not scheduled: {}", task.id, e));
+                let outcome = result::Outcome {
+                    result: Err(e),
+                    stopped: None,
+                    captured: exit::Captured::default(),
+                    started_at: chrono::Utc::now(),
+                    duration: Duration::ZERO,
+                };
+                result::store(queue, config, queue_name, &task, outcome).await;
+            }
+        }
+        return;
+    }
+
+    if let Some(mut conn) = queue.redis_connection() {
+        match dedup::check(&mut conn, config, queue_name, &task).await {
+            Ok(None) => {}
+            Ok(Some(duplicate)) => {
+                answer_duplicate(config, conn, &task, duplicate);
+                return;
+            }
+            // Running twice beats not running at all.
+            Err(e) => log(&format!(
+                "[WARN] Duplicate check for task {} failed, running it: {}",
+                task.id, e
+            )),
+        }
+    }
+
+    log(&format!("Processing Task ID: {}", task.id));
+    if config.write_ack {
+        if let Some(mut conn) = queue.redis_connection() {
+            result::write_ack(&mut conn, config, queue_name, &task.id).await;
+        }
+    }
+
+    // 3. Execute the task based on its type, unless its queue or the policy
+    // refuses it
+    let denied = match config.task_types_for(configured_queue) {
+        Some(types)
+            if !types
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(task.task_type.as_str())) =>
+        {
+            Some(format!(
+                "Queue {} only runs {} tasks",
+                configured_queue,
+                types.join(", ")
+            ))
+        }
+        _ => policy::check(&task).err(),
+    };
+    if let Some(reason) = &denied {
+        log(&format!("[WARN] Task {} denied: {}", task.id, reason));
+    }
+    if let (None, Some(secs)) = (&task.deadline, config.timeout_for(configured_queue)) {
+        let deadline = chrono::Utc::now() + chrono::Duration::seconds(secs as i64);
+        task.deadline = Some(deadline.to_rfc3339());
+    }
+    let (cancel, _cancel_guard) = cancel::for_task(&task, queue.redis_connection());
+    let _running = running::register(&task.id, &task.details, cancel.clone());
+    let _permits = slot
+        .acquire(configured_queue, task.task_type.as_str())
+        .await;
+    let started = Instant::now();
+    let started_at = chrono::Utc::now();
+    let (task_result, captured) = if let Some(reason) = denied.clone() {
+        (Err(reason), exit::Captured::default())
+    } else {
+        let _inflight = InflightGuard::new();
+        let execution = execute_task(
+            handlers::for_queue(configured_queue),
+            &task,
+            config,
+            queue.redis_connection(),
+            &cancel,
+        );
+        exit::capture(warn_if_slow(&task, config, started, execution)).await
+    };
+    let duration = started.elapsed();
+    match &task_result {
+        Ok(_) => stats::incr(&STATS.succeeded),
+        Err(_) => stats::incr(&STATS.failed),
+    }
+    metrics::task_finished(&task.task_type, task_result.is_ok(), duration);
+    let stopped = match denied {
+        Some(_) => Some(POLICY_DENIED),
+        None => cancel::stopped_status(&cancel, &task),
+    };
+    if stopped == Some("CANCELLED") {
+        log(&format!("Task {} was cancelled", task.id));
+    }
+
+    // Set once a retried task has used up its attempts, so it is
+    // dead-lettered alongside its error result.
+    let mut exhausted = None;
+    if let (Err(e), Some(policy)) = (&task_result, retry::Policy::for_task(&task, config)) {
+        let error = redact::scrub(e, &redact::secret_values(&task.details));
+        let errors = retry::history(&task, config, &error);
+        // Retrying can't help once the deadline has passed or the policy
+        // refused the task, and would undo a cancellation.
+        let retryable =
+            !task.past_deadline() && !matches!(stopped, Some("CANCELLED" | POLICY_DENIED));
+        if retryable && task.attempts < policy.max_retries {
+            let delay = policy.delay(task.attempts, config);
+            log(&format!(
+                "Task {} failed ({}), retrying in {:?} (attempt {} of {})",
+                task.id,
+                error,
+                delay,
+                task.attempts + 1,
+                policy.max_retries
+            ));
+            let target = routing::requeue_target(queue_name, task.priority, config);
+            requeue(queue, config, &target, json_str, &task, &errors, delay).await;
+            return;
+        } else if retryable {
+            exhausted = Some((error, errors));
+        }
+    }
+
+    if let Some((error, errors)) = exhausted {
+        let payload = retry::retry_payload(json_str, &task, &errors, config);
+        let reason = format!(
+            "Task {} failed after {} attempts: {}",
+            task.id,
+            task.attempts + 1,
+            error
+        );
+        deadletter::dead_letter_failed(queue, queue_name, &payload, &reason, errors).await;
+    }
+
+    let (status, output) = match &task_result {
+        Ok(output) => ("SUCCESS", output),
+        Err(e) => (stopped.unwrap_or("ERROR"), e),
+    };
+    let run = history::Run {
+        task: &task,
+        queue_name,
+        payload: json_str,
+        status,
+        output,
+        exit_code: captured.exit_code,
+        started_at,
+        duration,
+    };
+    history::record(config, run).await;
+
+    // Built now but sent after the result is written, so a callback
+    // receiver can already read the result key.
+    let callback = task.callback_url.as_ref().map(|url| {
+        let body = serde_json::json!({
+            "id": task.id,
+            "status": status,
+            "output": output,
+            "output_encoding": captured.output_encoding(),
+            "duration_ms": duration.as_millis() as u64,
+            "worker_id": config.worker_id,
+            "correlation_id": task.correlation_id(),
+        });
+        (url, body)
+    });
+
+    // 4. Write the result back
+    if !task.store_result {
+        match &task_result {
+            Ok(_) => log(&format!("Task {} succeeded (result not stored).", task.id)),
+            Err(e) => log(&format!(
+                "Task {} failed (result not stored): {}",
+                task.id,
+                redact::scrub(e, &redact::secret_values(&task.details))
+            )),
+        }
+    } else {
+        let outcome = result::Outcome {
+            result: task_result,
+            stopped,
+            captured,
+            started_at,
+            duration,
+        };
+        result::store(queue, config, queue_name, &task, outcome).await;
+    }
+
+    if let Some((url, body)) = callback {
+        callback::spawn(config, queue.redis_connection(), url, body);
+    }
+}
+
+/// Writes a `VALIDATION_ERROR` result for a task whose details don't fit
+/// its type's schema, and sends it to the task's `callback_url`.
+async fn reject_invalid<Q: TaskQueue>(
+    queue: &mut Q,
+    config: &Config,
+    queue_name: &str,
+    task: &Task,
+    problems: &[Problem],
+) {
+    let report = validation::report(problems);
+    log(&format!(
+        "[WARN] Task {} has invalid details: {}",
+        task.id, report
+    ));
+    stats::incr(&STATS.failed);
+    if task.store_result {
+        let outcome = result::Outcome {
+            result: Err(report.clone()),
+            stopped: Some(VALIDATION_ERROR),
+            captured: exit::Captured::default(),
+            started_at: chrono::Utc::now(),
+            duration: Duration::ZERO,
+        };
+        result::store(queue, config, queue_name, task, outcome).await;
+    }
+    if let Some(url) = &task.callback_url {
+        let body = serde_json::json!({
+            "id": task.id,
+            "status": VALIDATION_ERROR,
+            "output": report,
+            "duration_ms": 0,
+            "worker_id": config.worker_id,
+            "correlation_id": task.correlation_id(),
+        });
+        callback::spawn(config, queue.redis_connection(), url, body);
+    }
+}
+
+/// Answers a task whose id was already delivered instead of running it
+/// again: its result stays as the earlier run left it, and a `callback_url`
+/// is sent that run's status and output, marked `duplicate`.
+fn answer_duplicate(
+    config: &Config,
+    conn: redis::aio::MultiplexedConnection,
+    task: &Task,
+    duplicate: dedup::Duplicate,
+) {
+    let Some((status, output)) = duplicate.cached else {
+        log(&format!(
+            "[WARN] Task {} was already delivered and has no readable result in {} yet; not running it again",
+            task.id, duplicate.result_key
+        ));
+        return;
+    };
+    log(&format!(
+        "Task {} was already delivered; answering with its result in {}",
+        task.id, duplicate.result_key
+    ));
+    if let Some(url) = &task.callback_url {
+        let body = serde_json::json!({
+            "id": task.id,
+            "status": status,
+            "output": output,
+            "duplicate": true,
+            "worker_id": config.worker_id,
+            "correlation_id": task.correlation_id(),
+        });
+        callback::spawn(config, Some(conn), url, body);
+    }
+}
+
+/// Awaits a task's execution, logging a warning and counting it in
+/// `slow_tasks` once it has run for `SLOW_TASK_SECS`. The task keeps running.
+async fn warn_if_slow(
+    task: &Task,
+    config: &Config,
+    started: Instant,
+    execution: impl std::future::Future<Output = Result<String, String>>,
+) -> Result<String, String> {
+    if config.slow_task_secs == 0 {
+        return execution.await;
+    }
+    tokio::pin!(execution);
+    tokio::select! {
+        result = &mut execution => result,
+        _ = time::sleep(Duration::from_secs(config.slow_task_secs)) => {
+            stats::incr(&STATS.slow_tasks);
+            log(&format!(
+                "[WARN] Slow task {} ({}) still running after {:?}",
+                task.id,
+                task.task_type.as_str(),
+                started.elapsed()
+            ));
+            execution.await
+        }
+    }
+}
+
+/// Puts a failed task back on its queue with `attempts` incremented and its
+/// error history updated, so any worker can retry it. The backoff `delay` is
+/// waited out in the scheduler's sorted set rather than in the job, which
+/// ends at once and frees its slot; without Redis the retry is pushed
+/// straight away.
+async fn requeue<Q: TaskQueue>(
+    queue: &mut Q,
+    config: &Config,
+    queue_name: &str,
+    json_str: &str,
+    task: &Task,
+    errors: &[retry::Failure],
+    delay: Duration,
+) {
+    let payload = retry::retry_payload(json_str, task, errors, config);
+    if let (false, Some(mut conn)) = (delay.is_zero(), queue.redis_connection()) {
+        match scheduler::schedule_retry(&mut conn, queue_name, &payload, task, delay).await {
+            Ok(()) => return,
+            Err(e) => log(&format!(
+                "[ERROR] Failed to schedule retry of task {}, requeueing now: {}",
+                task.id, e
+            )),
+        }
+    }
+    log(&format!(
+        "Requeueing task {} on {} (attempt {})",
+        task.id,
+        queue_name,
+        task.attempts + 1
+    ));
+    if let Err(e) = queue.push(queue_name, &payload).await {
+        log(&format!(
+            "[ERROR] Failed to requeue task {}: {}",
+            task.id, e
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption;
+    use crate::queue::memory::MemoryQueue;
+    use crate::result::ResultStorage;
+
+    const QUEUE: &str = "mcp::tasks::test";
+
+    /// The default config, with the built-in executors registered as they
+    /// are at startup.
+    fn setup() -> Config {
+        handlers::init();
+        Config::for_tests()
+    }
+
+    /// Pops the next payload from `QUEUE` and runs it the way a listener job
+    /// does, acking it afterwards.
+    async fn run_next(queue: &mut MemoryQueue, config: &Config) {
+        let (queue_name, payload) = queue.pop(&[QUEUE], 0.0).await.unwrap().unwrap();
+        let slot = concurrency::reserve().await;
+        process_payload(queue, config, slot, &queue_name, &payload).await;
+        queue.ack(&queue_name, &payload).await.unwrap();
+    }
+
+    fn result(queue: &MemoryQueue, config: &Config, id: &str) -> serde_json::Value {
+        let key = config.result_key_for(QUEUE, id);
+        let stored = queue.state().results.get(&key).cloned().unwrap();
+        serde_json::from_slice(&stored).unwrap()
+    }
+
+    fn dead_letters(queue: &MemoryQueue) -> Vec<deadletter::DeadLetter> {
+        queue
+            .state()
+            .queues
+            .get(deadletter::DEADLETTER_KEY)
+            .into_iter()
+            .flatten()
+            .map(|entry| serde_json::from_str(entry).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn runs_a_task_and_stores_its_result() {
+        let config = setup();
+        let mut queue = MemoryQueue::new();
+        let task = r#"{"id":"t1","task_type":"STATUS","target_host":"*","details":{}}"#;
+        queue.push(QUEUE, task).await.unwrap();
+
+        run_next(&mut queue, &config).await;
+
+        let result = result(&queue, &config, "t1");
+        assert_eq!(result["status"], "SUCCESS");
+        assert_eq!(result["task_id"], "t1");
+        assert_eq!(result["output_encoding"], "text");
+        assert!(queue.state().processing.is_empty());
+        assert!(queue.state().queues[QUEUE].is_empty());
+    }
+
+    #[tokio::test]
+    async fn stores_only_sealed_results_with_an_encryption_key() {
+        let key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+        for storage in [ResultStorage::String, ResultStorage::Hash] {
+            let mut config = setup();
+            config.result_cipher = Some(Arc::new(
+                encryption::ResultCipher::from_base64_key(key).unwrap(),
+            ));
+            config.result_storage = storage;
+            let mut queue = MemoryQueue::new();
+            let task = r#"{"id":"t1","task_type":"STATUS","target_host":"*","details":{}}"#;
+            queue.push(QUEUE, task).await.unwrap();
+
+            run_next(&mut queue, &config).await;
+
+            let key = config.result_key_for(QUEUE, "t1");
+            let stored = queue.state().results[&key].clone();
+            // The whole value, or a hash's `output`, is the envelope alone.
+            let envelope: serde_json::Value = match storage {
+                ResultStorage::String => serde_json::from_slice(&stored).unwrap(),
+                ResultStorage::Hash => {
+                    let fields: serde_json::Value = serde_json::from_slice(&stored).unwrap();
+                    assert_eq!(fields["status"], "SUCCESS");
+                    serde_json::from_str(fields["output"].as_str().unwrap()).unwrap()
+                }
+            };
+            let mut names: Vec<&String> = envelope.as_object().unwrap().keys().collect();
+            names.sort();
+            assert_eq!(names, ["alg", "ciphertext", "nonce"]);
+            assert_eq!(envelope["alg"], "AES-256-GCM");
+        }
+    }
+
+    #[tokio::test]
+    async fn dead_letters_a_payload_that_isnt_a_task() {
+        let config = setup();
+        let mut queue = MemoryQueue::new();
+        queue.push(QUEUE, "not json").await.unwrap();
+
+        run_next(&mut queue, &config).await;
+
+        let dead = dead_letters(&queue);
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].queue, QUEUE);
+        assert_eq!(dead[0].payload, "not json");
+        assert!(dead[0].reason.starts_with("JSON Parse Error"));
+        assert!(queue.state().processing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requeues_a_task_for_another_host() {
+        let mut config = setup();
+        config.reject_mismatched = false;
+        let mut queue = MemoryQueue::new();
+        let task = r#"{"id":"t2","task_type":"STATUS","target_host":"elsewhere","details":{}}"#;
+        queue.push(QUEUE, task).await.unwrap();
+
+        run_next(&mut queue, &config).await;
+
+        assert_eq!(queue.state().queues[QUEUE], [task]);
+        assert!(queue.state().results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_a_failing_task_then_dead_letters_it() {
+        let mut config = setup();
+        config.retry_backoff_secs = 0.0;
+        let mut queue = MemoryQueue::new();
+        let task =
+            r#"{"id":"t3","task_type":"NOPE","target_host":"*","details":{"max_retries":1}}"#;
+        queue.push(QUEUE, task).await.unwrap();
+
+        run_next(&mut queue, &config).await;
+        let retry: serde_json::Value =
+            serde_json::from_str(&queue.state().queues[QUEUE][0]).unwrap();
+        assert_eq!(retry["attempts"], 1);
+        assert_eq!(retry["errors"].as_array().map(Vec::len), Some(1));
+        assert!(queue.state().results.is_empty());
+
+        run_next(&mut queue, &config).await;
+        assert_eq!(result(&queue, &config, "t3")["status"], "ERROR");
+        let dead = dead_letters(&queue);
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].errors.len(), 2);
+        assert!(queue.state().queues[QUEUE].is_empty());
+    }
+}
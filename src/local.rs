@@ -0,0 +1,46 @@
+use crate::cancel;
+use crate::config::Config;
+use crate::exit;
+use crate::handlers;
+use crate::logging;
+use crate::policy::{self, POLICY_DENIED};
+use crate::{execute_task, Task};
+
+// --- Local Task Files ---
+/// Runs every task in a JSON array file through the normal executors and
+/// prints one JSON line per task to stdout. Nothing touches Redis, so
+/// features that need it (progress, log streams) are skipped.
+pub async fn run_file(path: &str, config: &Config) -> Result<(), String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let mut tasks: Vec<Task> = serde_json::from_str(&contents)
+        .map_err(|e| format!("{} is not a JSON array of tasks: {}", path, e))?;
+
+    for task in &mut tasks {
+        task.assign_correlation_id();
+        task.apply_defaults();
+        let (cancel, _cancel_guard) = cancel::for_task(task, None);
+        let execution = execute_task(handlers::builtin, task, config, None, &cancel);
+        let (status, output, captured) = match policy::check(task) {
+            Err(reason) => (POLICY_DENIED, reason, exit::Captured::default()),
+            Ok(()) => {
+                let span = logging::span(task);
+                match exit::capture(logging::in_span(span, execution)).await {
+                    (Ok(output), captured) => ("SUCCESS", output, captured),
+                    (Err(e), captured) => ("ERROR", e, captured),
+                }
+            }
+        };
+        let line = serde_json::json!({
+            "id": task.id,
+            "status": status,
+            "output": output,
+            "output_encoding": captured.output_encoding(),
+            "correlation_id": task.correlation_id(),
+        });
+        // Keep results in order with the task's queued log lines.
+        logging::flush().await;
+        println!("{}", line);
+    }
+    Ok(())
+}
@@ -0,0 +1,560 @@
+use crate::config::Config;
+use crate::Task;
+use chrono::{DateTime, Local, NaiveDate, SecondsFormat, Utc};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, Once, OnceLock};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Event, Instrument, Metadata};
+
+static WORKER_ID: OnceLock<String> = OnceLock::new();
+/// Set by [`configure`]; [`Output::default`] until then.
+static OUTPUT: OnceLock<Output> = OnceLock::new();
+/// The open log file, reopened after each rotation.
+static FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+/// Set once the log file fails to open, so later lines skip the attempt.
+static FILE_DISABLED: AtomicBool = AtomicBool::new(false);
+/// Feeds the background writer once [`init`] has run; before that lines are
+/// written synchronously.
+static SENDER: OnceLock<mpsc::Sender<Message>> = OnceLock::new();
+/// Set by [`use_stderr`] when stdout carries a protocol instead.
+static STDERR: AtomicBool = AtomicBool::new(false);
+/// Lines dropped because the writer's buffer was full.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+/// Most lines written per batch.
+const MAX_BATCH_LINES: usize = 256;
+
+enum Message {
+    Line(String),
+    /// Answered once every line queued before it has been written.
+    Flush(oneshot::Sender<()>),
+}
+
+/// How severe a line is, read from its `[ERROR]`/`FATAL:`/`[WARN]`/`[DEBUG]`
+/// prefix. `LOG_LEVEL` drops lines below it.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn of_tracing(level: &tracing::Level) -> Self {
+        match *level {
+            tracing::Level::ERROR => Level::Error,
+            tracing::Level::WARN => Level::Warn,
+            tracing::Level::INFO => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+
+    pub fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "error" => Level::Error,
+            "warn" | "warning" => Level::Warn,
+            "debug" | "trace" => Level::Debug,
+            _ => Level::Info,
+        }
+    }
+
+    /// The level of `msg`, and `msg` without a bracketed level prefix.
+    fn of(msg: &str) -> (Level, &str) {
+        for (prefix, level) in [
+            ("[ERROR] ", Level::Error),
+            ("[WARN] ", Level::Warn),
+            ("[DEBUG] ", Level::Debug),
+        ] {
+            if let Some(rest) = msg.strip_prefix(prefix) {
+                return (level, rest);
+            }
+        }
+        match msg.starts_with("FATAL:") {
+            true => (Level::Error, msg),
+            false => (Level::Info, msg),
+        }
+    }
+}
+
+/// `LOG_FORMAT`: plain lines, or one JSON object per line for log shippers.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "json" => LogFormat::Json,
+            _ => LogFormat::Text,
+        }
+    }
+}
+
+/// Where and how lines are written.
+struct Output {
+    /// Empty for stdout only.
+    path: String,
+    level: Level,
+    format: LogFormat,
+    /// Rotates the file before it would grow past this; 0 never does.
+    rotate_bytes: u64,
+    /// Rotates the file on the first line of each (local) day.
+    rotate_daily: bool,
+    /// Rotated files kept as `<path>.1` (newest) to `<path>.<keep>`.
+    keep: usize,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            // Tests log nowhere: not into the working directory (nor to
+            // stdout, see `write_lines`).
+            path: if cfg!(test) { "" } else { "mcp-worker.log" }.to_string(),
+            level: Level::Info,
+            format: LogFormat::Text,
+            rotate_bytes: 0,
+            rotate_daily: false,
+            keep: 5,
+        }
+    }
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+    day: NaiveDate,
+}
+
+/// The `task` span for `task`, with its id, type, target host and
+/// correlation id as fields.
+pub fn span(task: &Task) -> tracing::Span {
+    install();
+    tracing::info_span!(
+        "task",
+        task_id = %task.id,
+        task_type = %task.task_type.reported(),
+        target_host = %task.target_host,
+        correlation_id = %task.correlation_id(),
+    )
+}
+
+/// Runs `work` inside `span`, so every log line it writes is tagged with
+/// the task: its correlation id in text lines, and every span field in JSON
+/// lines. Tasks spawned from inside `work` are not tagged.
+pub async fn in_span<F: Future>(span: tracing::Span, work: F) -> F::Output {
+    work.instrument(span).await
+}
+
+/// Tags every following log line with the worker id. Only the first call has
+/// an effect.
+pub fn set_worker_id(worker_id: &str) {
+    WORKER_ID.set(worker_id.to_string()).ok();
+}
+
+/// Applies the `LOG_*` settings: file, level, format and rotation. Only the
+/// first call has an effect.
+pub fn configure(config: &Config) {
+    OUTPUT
+        .set(Output {
+            path: config.log_file.clone(),
+            level: config.log_level,
+            format: config.log_format,
+            rotate_bytes: config.log_rotate_bytes,
+            rotate_daily: config.log_rotate_daily,
+            keep: config.log_rotate_keep,
+        })
+        .ok();
+}
+
+fn output() -> &'static Output {
+    static DEFAULT: OnceLock<Output> = OnceLock::new();
+    OUTPUT
+        .get()
+        .unwrap_or_else(|| DEFAULT.get_or_init(Output::default))
+}
+
+/// Moves log output to a background task buffering up to `capacity` lines
+/// (`LOG_BUFFER_LINES`), so log I/O never blocks task processing. Only the
+/// first call has an effect.
+pub fn init(capacity: usize) {
+    let (sender, receiver) = mpsc::channel(capacity.max(1));
+    if SENDER.set(sender).is_ok() {
+        tokio::spawn(write_batches(receiver));
+    }
+}
+
+/// How many lines have been dropped because the buffer was full.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Waits until every line logged so far has been written. Call before the
+/// process exits or re-execs, which would lose whatever is still queued.
+pub async fn flush() {
+    if let Some(sender) = SENDER.get() {
+        let (done, written) = oneshot::channel();
+        if sender.send(Message::Flush(done)).await.is_ok() {
+            written.await.ok();
+        }
+    }
+}
+
+// --- Logging ---
+/// Logs `msg` as a `tracing` event at the level its prefix names (see
+/// [`Level`]); the prefix stays part of the message.
+pub fn log(msg: &str) {
+    install();
+    match Level::of(msg).0 {
+        Level::Error => tracing::error!("{}", msg),
+        Level::Warn => tracing::warn!("{}", msg),
+        Level::Info => tracing::info!("{}", msg),
+        Level::Debug => tracing::debug!("{}", msg),
+    }
+}
+
+/// Makes [`Subscriber`] the global `tracing` subscriber. Only the first
+/// call has an effect.
+fn install() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        tracing::subscriber::set_global_default(Subscriber::default()).ok();
+    });
+}
+
+/// Fields of the spans that are open, by span id.
+#[derive(Default)]
+struct Subscriber {
+    spans: Mutex<HashMap<u64, OpenSpan>>,
+    next_id: AtomicU64,
+}
+
+struct OpenSpan {
+    fields: Fields,
+    /// Handles to the span still alive; it is forgotten at zero.
+    refs: usize,
+}
+
+thread_local! {
+    /// The spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A span's or event's fields, `message` among them, in recorded order.
+#[derive(Default, Clone)]
+struct Fields(Vec<(&'static str, String)>);
+
+impl Fields {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.retain(|(name, _)| *name != field.name());
+        self.0.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.retain(|(name, _)| *name != field.name());
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+/// A minimal subscriber for this crate's events, writing each through the
+/// same buffered writer, file and rotation as before. Events from
+/// dependencies are dropped.
+impl tracing::Subscriber for Subscriber {
+    fn register_callsite(&self, _: &'static Metadata<'static>) -> Interest {
+        // `LOG_LEVEL` is only known once the config is loaded.
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+            && (metadata.is_span() || Level::of_tracing(metadata.level()) <= output().level)
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.spans
+            .lock()
+            .unwrap()
+            .insert(id, OpenSpan { fields, refs: 1 });
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(open) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(&mut open.fields);
+        }
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let span = ENTERED
+            .with(|entered| entered.borrow().last().copied())
+            .and_then(|id| Some(self.spans.lock().unwrap().get(&id)?.fields.clone()));
+        let level = Level::of_tracing(event.metadata().level());
+        let message = fields.get("message").unwrap_or_default();
+        let line = match output().format {
+            LogFormat::Text => text_line(level, message, span.as_ref()),
+            LogFormat::Json => json_line(level, Level::of(message).1, span.as_ref()),
+        };
+        emit(line);
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(at) = entered.iter().rposition(|id| *id == span.into_u64()) {
+                entered.remove(at);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(open) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            open.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let Some(open) = spans.get_mut(&span.into_u64()) else {
+            return false;
+        };
+        open.refs -= 1;
+        if open.refs > 0 {
+            return false;
+        }
+        spans.remove(&span.into_u64());
+        true
+    }
+}
+
+/// Writes a formatted line to stdout and the log file. After [`init`] the
+/// line is only queued for the writer, and dropped (and counted) if the
+/// buffer is full rather than making the caller wait.
+fn emit(line: String) {
+    let Some(sender) = SENDER.get() else {
+        write_lines(&[line]);
+        return;
+    };
+    match sender.try_send(Message::Line(line)) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(TrySendError::Closed(Message::Line(line))) => write_lines(&[line]),
+        Err(TrySendError::Closed(Message::Flush(_))) => {}
+    }
+}
+
+fn timestamp() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// An RFC3339 UTC timestamp (millisecond precision), once known the worker
+/// id, inside a task the task's correlation id, then `msg`. A message
+/// without its level's prefix gets one.
+fn text_line(level: Level, msg: &str, span: Option<&Fields>) -> String {
+    let prefixed = match level {
+        _ if Level::of(msg).0 != Level::Info => msg.to_string(),
+        Level::Error => format!("[ERROR] {}", msg),
+        Level::Warn => format!("[WARN] {}", msg),
+        Level::Info => msg.to_string(),
+        Level::Debug => format!("[DEBUG] {}", msg),
+    };
+    let msg = match span.and_then(|span| span.get("correlation_id")) {
+        Some(correlation_id) => format!("[correlation_id={}] {}", correlation_id, prefixed),
+        None => prefixed,
+    };
+    match WORKER_ID.get() {
+        Some(worker_id) => format!("{} [{}] {}", timestamp(), worker_id, msg),
+        None => format!("{} {}", timestamp(), msg),
+    }
+}
+
+/// The same as a JSON object, with every span field as a field.
+fn json_line(level: Level, msg: &str, span: Option<&Fields>) -> String {
+    let mut line = serde_json::json!({
+        "timestamp": timestamp(),
+        "level": level,
+        "message": msg,
+    });
+    if let Some(worker_id) = WORKER_ID.get() {
+        line["worker_id"] = worker_id.as_str().into();
+    }
+    for (name, value) in span.into_iter().flat_map(|span| &span.0) {
+        line[*name] = value.as_str().into();
+    }
+    line.to_string()
+}
+
+/// Drains the buffer, writing up to [`MAX_BATCH_LINES`] lines at a time off
+/// the async threads.
+async fn write_batches(mut receiver: mpsc::Receiver<Message>) {
+    let mut reported_dropped = 0;
+    while let Some(message) = receiver.recv().await {
+        let mut lines = Vec::new();
+        let mut waiters = Vec::new();
+        let mut next = Some(message);
+        while let Some(message) = next.take() {
+            match message {
+                Message::Line(line) => lines.push(line),
+                Message::Flush(done) => waiters.push(done),
+            }
+            if lines.len() < MAX_BATCH_LINES {
+                next = receiver.try_recv().ok();
+            }
+        }
+
+        let dropped = DROPPED.load(Ordering::Relaxed);
+        if dropped > reported_dropped {
+            let msg = format!(
+                "Log buffer full; dropped {} line(s) ({} total)",
+                dropped - reported_dropped,
+                dropped
+            );
+            lines.push(match output().format {
+                LogFormat::Text => format!("{} [WARN] {}", timestamp(), msg),
+                LogFormat::Json => json_line(Level::Warn, &msg, None),
+            });
+            reported_dropped = dropped;
+        }
+        tokio::task::spawn_blocking(move || write_lines(&lines))
+            .await
+            .ok();
+        for done in waiters {
+            done.send(()).ok();
+        }
+    }
+}
+
+/// Writes console lines to stderr rather than stdout from now on, for the
+/// MCP server, whose stdout belongs to the client.
+pub fn use_stderr() {
+    STDERR.store(true, Ordering::Relaxed);
+}
+
+/// Writes lines to stdout (stderr after [`use_stderr`]) and, unless
+/// disabled, the log file, rotating it first when the lines would take it
+/// past `LOG_ROTATE_BYTES` or a new day started with `LOG_ROTATE_DAILY`. If
+/// the file can't be opened (read-only filesystem, permissions), file
+/// logging is disabled for the rest of the run with a single stderr warning.
+fn write_lines(lines: &[String]) {
+    if cfg!(test) {
+        return;
+    }
+    let mut console: Box<dyn Write> = match STDERR.load(Ordering::Relaxed) {
+        true => Box::new(std::io::stderr().lock()),
+        false => Box::new(std::io::stdout().lock()),
+    };
+    for line in lines {
+        writeln!(console, "{}", line).ok();
+    }
+    console.flush().ok();
+    drop(console);
+    let output = output();
+    if output.path.is_empty() || FILE_DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let bytes: u64 = lines.iter().map(|line| line.len() as u64 + 1).sum();
+    let today = Local::now().date_naive();
+    let mut file = FILE.lock().unwrap();
+    if file.is_none() {
+        *file = open_or_disable(&output.path);
+    }
+    let rotate = file.as_ref().is_some_and(|open| {
+        (output.rotate_bytes > 0 && open.size > 0 && open.size + bytes > output.rotate_bytes)
+            || (output.rotate_daily && open.day != today)
+    });
+    if rotate {
+        *file = None;
+        rotate_files(output);
+        *file = open_or_disable(&output.path);
+    }
+    let Some(open) = file.as_mut() else { return };
+    for line in lines {
+        writeln!(open.file, "{}", line).ok();
+    }
+    open.file.flush().ok();
+    open.size += bytes;
+    open.day = today;
+}
+
+/// Opens `path` for appending, disabling file logging if it can't be.
+fn open_or_disable(path: &str) -> Option<LogFile> {
+    match open(path) {
+        Ok(opened) => Some(opened),
+        Err(e) => {
+            if !FILE_DISABLED.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "WARNING: cannot open {} ({}); file logging disabled",
+                    path, e
+                );
+            }
+            None
+        }
+    }
+}
+
+/// Opens `path` for appending. Its last modification dates it, so a file
+/// left from yesterday still rotates daily.
+fn open(path: &str) -> std::io::Result<LogFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    let day = metadata
+        .modified()
+        .map(|modified| DateTime::<Local>::from(modified).date_naive())
+        .unwrap_or_else(|_| Local::now().date_naive());
+    Ok(LogFile {
+        file,
+        size: metadata.len(),
+        day,
+    })
+}
+
+/// Shifts `<path>.N` to `<path>.N+1`, dropping the oldest, and moves the
+/// current file to `<path>.1`.
+fn rotate_files(output: &Output) {
+    let path = &output.path;
+    if output.keep == 0 {
+        std::fs::remove_file(path).ok();
+        return;
+    }
+    std::fs::remove_file(format!("{}.{}", path, output.keep)).ok();
+    for n in (1..output.keep).rev() {
+        std::fs::rename(format!("{}.{}", path, n), format!("{}.{}", path, n + 1)).ok();
+    }
+    std::fs::rename(path, format!("{}.1", path)).ok();
+}
@@ -1,9 +1,79 @@
-use redis::AsyncCommands;
+mod audit;
+mod backoff;
+mod batch;
+mod callback;
+mod cancel;
+mod chunks;
+#[cfg(feature = "docker")]
+mod compose;
+mod concurrency;
+mod config;
+mod config_file;
+mod connection;
+mod control;
+mod deadletter;
+mod dedup;
+mod details;
+#[cfg(feature = "docker")]
+mod docker;
+mod encryption;
+#[cfg(feature = "docker")]
+mod engine;
+mod envelope;
+mod exit;
+mod expect;
+mod file;
+mod handlers;
+mod health;
+mod history;
+mod http;
+#[cfg(feature = "shell")]
+mod isolation;
+mod limits;
+mod listener;
+mod local;
+mod logging;
+mod mcp;
+mod metrics;
+mod output;
+mod packing;
+mod paths;
+mod policy;
+mod progress;
+mod queue;
+mod recovery;
+mod redact;
+mod result;
+mod retry;
+mod routing;
+#[cfg(feature = "docker")]
+mod runners;
+mod running;
+mod scheduler;
+mod secrets;
+mod sentinel;
+mod serializer;
+mod server;
+#[cfg(feature = "shell")]
+mod shell;
+mod signing;
+mod ssh;
+mod stats;
+mod systemd;
+mod touch;
+mod validation;
+mod worker;
+
+use config::Config;
+use connection::Connections;
+use control::PauseState;
+use logging::log;
+use queue::RedisQueue;
 use serde::{Deserialize, Serialize};
 use std::env;
-use std::fs::OpenOptions;
-use std::io::Write;
-use tokio::time::{self, Duration};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
 
 // --- Structs and Enums ---
 #[derive(Serialize, Deserialize, Debug)]
@@ -12,135 +82,532 @@ struct Task {
     target_host: String,
     task_type: TaskType,
     details: serde_json::Value,
+    /// Label selector such as `region=us-east,role=gpu`; overrides `target_host`.
+    #[serde(default)]
+    target_selector: Option<String>,
+    /// Fire-and-forget tasks set this to `false` to skip writing a result.
+    #[serde(default = "default_true")]
+    store_result: bool,
+    /// Push failed tasks back onto their queue (up to `MAX_REQUEUE` times,
+    /// or `details.max_retries`) instead of writing an error result.
+    #[serde(default)]
+    requeue_on_error: bool,
+    /// Streams output chunks to `<result key>::chunks` while the task runs.
+    #[serde(default)]
+    stream_result: bool,
+    /// Appends output lines to the Redis Stream `mcp::stream::<id>` while
+    /// the task runs (always on with `OUTPUT_STREAMS`).
+    #[serde(default)]
+    stream_output: bool,
+    /// Receives the result as a JSON POST once the task finishes.
+    #[serde(default)]
+    callback_url: Option<String>,
+    /// Propagated by the producer for cross-service tracing, and generated
+    /// when absent. Carried into the result and the task's log lines.
+    #[serde(default)]
+    correlation_id: Option<String>,
+    /// RFC3339 time after which the result is useless. A task popped past it
+    /// isn't run; otherwise the time left bounds its execution.
+    #[serde(default)]
+    deadline: Option<String>,
+    /// How many times the task has been requeued after failing.
+    #[serde(default)]
+    attempts: u64,
+    /// The errors of earlier attempts, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<retry::Failure>,
+    /// How many times the task has been replayed from the dead-letter queue.
+    #[serde(default)]
+    replay_count: u64,
+    /// Runs the task even if its id was already delivered, replacing the
+    /// earlier result, instead of answering with that result.
+    #[serde(default)]
+    force: bool,
+    /// `high`, `normal` (the default) or `low`; with `PRIORITY_QUEUES` a
+    /// re-queued task goes onto the queue for its priority.
+    #[serde(default)]
+    priority: routing::Priority,
+    /// When a promoted `run_at`/`schedule` run was due; such a run executes
+    /// instead of being scheduled again.
+    #[serde(default)]
+    scheduled_for: Option<String>,
+    /// The request id of a task received in a JSON-RPC envelope.
+    #[serde(skip)]
+    rpc_id: Option<serde_json::Value>,
+}
+
+impl Task {
+    /// Fills `details` fields the task leaves unset from its type's
+    /// `[defaults.<TYPE>]` table in the config file. Only top-level fields
+    /// are filled, and one the task sets, even to `null`, is kept; details
+    /// that aren't an object are left alone.
+    fn apply_defaults(&mut self) {
+        let (Some(defaults), Some(details)) = (
+            config_file::task_defaults(self.task_type.as_str()),
+            self.details.as_object_mut(),
+        ) else {
+            return;
+        };
+        for (key, value) in defaults {
+            details.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+
+    /// Generates a correlation id unless the producer supplied one.
+    fn assign_correlation_id(&mut self) {
+        if self.correlation_id.as_deref().is_none_or(str::is_empty) {
+            self.correlation_id = Some(format!("{:032x}", rand::random::<u128>()));
+        }
+    }
+
+    fn correlation_id(&self) -> &str {
+        self.correlation_id.as_deref().unwrap_or_default()
+    }
+
+    /// Time left before `deadline` (zero once it has passed), or `None`
+    /// without one.
+    fn time_left(&self) -> Result<Option<std::time::Duration>, String> {
+        let Some(deadline) = &self.deadline else {
+            return Ok(None);
+        };
+        let deadline = chrono::DateTime::parse_from_rfc3339(deadline)
+            .map_err(|e| format!("invalid deadline '{}': {}", deadline, e))?;
+        let left = deadline.with_timezone(&chrono::Utc) - chrono::Utc::now();
+        Ok(Some(left.to_std().unwrap_or_default()))
+    }
+
+    fn past_deadline(&self) -> bool {
+        matches!(self.time_left(), Ok(Some(left)) if left.is_zero())
+    }
+
+    fn deadline_exceeded(&self) -> String {
+        serde_json::json!({"status": "deadline_exceeded", "deadline": self.deadline}).to_string()
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-#[serde(rename_all = "UPPERCASE")]
+fn default_true() -> bool {
+    true
+}
+
+/// Task types are written in lower case once set, from `TASK_TYPE_CASE`.
+static LOWERCASE_TASK_TYPES: AtomicBool = AtomicBool::new(false);
+
+/// Serialized as the upper-case variant name (lower case with
+/// `TASK_TYPE_CASE=lower`) and parsed case-insensitively, so `docker`,
+/// `Docker` and `DOCKER` all work. Names this worker doesn't
+/// know parse into `Unknown` so the producer gets an "unsupported task type"
+/// result instead of a dead-lettered payload.
+#[derive(Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 enum TaskType {
+    BATCH,
+    /// Worker self-management, such as `restart_worker`.
+    CONTROL,
     DOCKER,
+    FILE,
+    /// A REST call or webhook, sent with `reqwest`.
+    HTTP,
     SHELL,
+    /// Reports this worker's own counters; `METRICS` is accepted as an alias.
+    STATUS,
+    SYSTEMD,
+    Unknown(String),
 }
 
-// --- Logging ---
-fn log(msg: &str) {
-    println!("{}", msg);
-    if let Ok(mut file) = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open("mcp-worker.log")
-    {
-        writeln!(file, "{}", msg).ok();
-        file.flush().ok();
+impl TaskType {
+    fn as_str(&self) -> &str {
+        match self {
+            TaskType::BATCH => "BATCH",
+            TaskType::CONTROL => "CONTROL",
+            TaskType::DOCKER => "DOCKER",
+            TaskType::FILE => "FILE",
+            TaskType::HTTP => "HTTP",
+            TaskType::SHELL => "SHELL",
+            TaskType::STATUS => "STATUS",
+            TaskType::SYSTEMD => "SYSTEMD",
+            TaskType::Unknown(name) => name,
+        }
+    }
+
+    /// The name as the worker reports it in results, logs and history, in
+    /// the case `TASK_TYPE_CASE` picks. Lookups keep using [`Self::as_str`].
+    fn reported(&self) -> String {
+        match LOWERCASE_TASK_TYPES.load(Ordering::Relaxed) {
+            true => self.as_str().to_ascii_lowercase(),
+            false => self.as_str().to_string(),
+        }
+    }
+}
+
+impl Serialize for TaskType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.reported())
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(match name.to_ascii_uppercase().as_str() {
+            "BATCH" => TaskType::BATCH,
+            "CONTROL" => TaskType::CONTROL,
+            "DOCKER" => TaskType::DOCKER,
+            "FILE" => TaskType::FILE,
+            "HTTP" => TaskType::HTTP,
+            "SHELL" => TaskType::SHELL,
+            "STATUS" | "METRICS" => TaskType::STATUS,
+            "SYSTEMD" => TaskType::SYSTEMD,
+            _ => TaskType::Unknown(name),
+        })
     }
 }
 
 // --- Main Application Logic ---
 #[tokio::main]
 async fn main() {
+    run().await;
+    // Lines still queued for the log writer would be lost on exit.
+    logging::flush().await;
+}
+
+async fn run() {
     dotenv::dotenv().ok();
+    stats::mark_started();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let serve_mcp = args.first().map(String::as_str) == Some("serve-mcp");
+    if serve_mcp {
+        logging::use_stderr();
+    }
+    let loaded = config_file::path(&args)
+        .and_then(|path| path.map_or(Ok(()), |path| config_file::load(&path)));
+    if let Err(e) = loaded {
+        log(&format!("FATAL: {}", e));
+        return;
+    }
+    let mut config = Config::from_env();
+    LOWERCASE_TASK_TYPES.store(config.lowercase_task_types, Ordering::Relaxed);
+    logging::configure(&config);
     log("--- MCP-WORKER START ---");
+    logging::set_worker_id(&config.worker_id);
+    logging::init(config.log_buffer_lines);
+    cancel::watch_shutdown_signals(std::time::Duration::from_secs(config.shutdown_drain_secs));
+    match encryption::ResultCipher::from_env() {
+        Ok(cipher) => config.result_cipher = cipher.map(Arc::new),
+        Err(e) => {
+            log(&format!("FATAL: {}", e));
+            return;
+        }
+    }
+    match secrets::Provider::from_env() {
+        Ok(provider) => config.secrets = provider.map(Arc::new),
+        Err(e) => {
+            log(&format!("FATAL: {}", e));
+            return;
+        }
+    }
 
-    let redis_host = env::var("REDIS_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
-    let redis_url = format!("redis://{}/", redis_host);
+    if args.first().map(String::as_str) == Some("history") {
+        if let Err(e) = history::run_cli(&args[1..], &config) {
+            log(&format!("FATAL: {}", e));
+        }
+        return;
+    }
+    if let Err(e) = policy::init() {
+        log(&format!("FATAL: {}", e));
+        return;
+    }
+    if let Err(e) = ssh::init() {
+        log(&format!("FATAL: {}", e));
+        return;
+    }
+    if let Err(e) = history::init(&config) {
+        log(&format!("FATAL: {}", e));
+        return;
+    }
+    concurrency::init(&config);
+    handlers::init();
+    #[cfg(feature = "docker")]
+    {
+        docker::init(&config);
+        runners::init(&config).await;
+    }
 
-    let client = match redis::Client::open(redis_url) {
-        Ok(c) => c,
-        Err(e) => {
-            log(&format!("FATAL: Redis client creation failed: {}", e));
+    if let Some(pos) = args.iter().position(|a| a == "--from-file") {
+        let Some(path) = args.get(pos + 1) else {
+            log("FATAL: --from-file needs a path");
             return;
+        };
+        if let Err(e) = local::run_file(path, &config).await {
+            log(&format!("FATAL: {}", e));
         }
-    };
+        #[cfg(feature = "docker")]
+        runners::remove_idle().await;
+        return;
+    }
+
+    if serve_mcp {
+        if let Err(e) = mcp::serve(&config).await {
+            log(&format!("FATAL: {}", e));
+        }
+        #[cfg(feature = "docker")]
+        runners::remove_idle().await;
+        return;
+    }
 
-    let mut conn = match client.get_multiplexed_async_connection().await {
-        Ok(c) => c,
+    if let Some(addr) = &config.http_listen {
+        let stuck_after = std::time::Duration::from_secs(config.health_stuck_secs);
+        if let Err(e) = server::spawn(addr, stuck_after).await {
+            log(&format!("FATAL: {}", e));
+            return;
+        }
+    }
+
+    let conns = match Connections::open_with_retry(&config).await {
+        Ok(c) => {
+            health::set_redis_connected(true);
+            c
+        }
         Err(e) => {
-            log(&format!("FATAL: Failed to get multiplexed Redis connection: {}", e));
+            log(&format!("FATAL: {}", e));
             return;
         }
     };
 
+    if let Some(pos) = args.iter().position(|a| a == "--replay-deadletter") {
+        let limit = args.get(pos + 1).and_then(|n| n.parse::<usize>().ok());
+        let mut writer = conns.writer().await;
+        deadletter::replay(&mut writer, limit, &config).await;
+    }
+
+    let info = worker::WorkerInfo::new(&config);
+    // Without refreshes the key has to outlive the worker's first TTL.
+    let info_ttl = match config.heartbeat_interval_secs {
+        0 => 0,
+        _ => config.heartbeat_ttl_secs,
+    };
+    worker::publish_info(&mut conns.shared(), &info, info_ttl).await;
+    let (mut info_conn, worker_id) = (conns.shared(), config.worker_id.clone());
+    worker::publish_config(&mut conns.shared(), &config).await;
+    recovery::report_abandoned(&mut conns.shared(), &config).await;
+
+    let pause = PauseState::default();
+    control::watch_signal(pause.clone());
+    running::watch_container_cancellations(conns.shared());
+    stats::spawn_summary(config.stats_interval_secs);
+    touch::spawn_touch_loop(conns.shared(), &config);
+    worker::spawn_heartbeat(
+        conns.shared(),
+        info,
+        config.heartbeat_interval_secs,
+        info_ttl,
+    );
+
     log("Successfully connected to Redis. Entering command listener loop...");
-    command_listener(&mut conn).await;
+    if config.redis_dbs.len() > 1 {
+        run_per_db_listeners(conns, config, pause).await;
+    } else {
+        run_listeners(conns, config, pause).await;
+    }
+
+    #[cfg(feature = "docker")]
+    runners::remove_idle().await;
+    // Listeners only return on shutdown, or once a restart was requested and
+    // they drained.
+    if cancel::draining() {
+        worker::remove_info(&mut info_conn, &worker_id).await;
+        log("Listeners stopped; exiting.");
+    } else if control::restart_requested() {
+        log("Listeners drained; re-executing the worker.");
+        logging::flush().await;
+        log(&format!("FATAL: {}", control::reexec()));
+    }
+}
+
+/// Serves the configured queues on one Redis database, after re-queueing
+/// what a previous run left in its processing lists there.
+async fn run_listeners(conns: Connections, config: Config, pause: PauseState) {
+    recovery::requeue_abandoned(&mut conns.shared(), &config).await;
+    scheduler::spawn(conns.shared(), &config);
+    if config.listener_per_queue {
+        run_per_queue_listeners(conns, config, pause).await;
+    } else {
+        let mut queue = RedisQueue::new(conns, &config);
+        listener::command_listener(&mut queue, &config, &pause, &config.queues).await;
+    }
 }
 
-async fn command_listener(conn: &mut redis::aio::MultiplexedConnection) {
-    let queue_keys = ["mcp::tasks::shell", "mcp::tasks::docker"];
-    log(&format!("Listening for commands on queues: {:?}", queue_keys));
-
-    loop {
-        // 1. Safe Pop from the queue
-        let pop_result: redis::RedisResult<(String, String)> = conn.blpop(&queue_keys, 0).await;
-
-        match pop_result {
-            Ok((_queue_name, json_str)) => {
-                log(&format!(">>> RECEIVED: {}", json_str));
-
-                // 2. Safe Parse the JSON into a Task
-                match serde_json::from_str::<Task>(&json_str) {
-                    Ok(task) => {
-                        log(&format!("Processing Task ID: {}", task.id));
-                        
-                        // 3. Execute the task based on its type
-                        let task_result = execute_task(&task).await;
-                        
-                        // 4. Write the result back to Redis
-                        let res_key = format!("mcp::result::{}", task.id);
-                        let res_val = match task_result {
-                            Ok(output) => format!("SUCCESS: {}", output),
-                            Err(e) => format!("ERROR: {}", e),
-                        };
-
-                        let _: redis::RedisResult<()> = conn.set_ex(&res_key, res_val, 3600).await;
-                        log(&format!("Result for task {} written to Redis.", task.id));
-                    }
-                    Err(e) => {
-                        log(&format!("[ERROR] JSON Parse Error: {}", e));
-                    }
+/// Runs a full set of listeners against each database in `REDIS_DBS`, each
+/// with its own connections (and so its own reconnects). The first reuses
+/// the startup connections, which also carry worker info and control keys.
+async fn run_per_db_listeners(conns: Connections, config: Config, pause: PauseState) {
+    let mut conns = Some(conns);
+    let mut listeners = Vec::new();
+
+    for db in config.redis_dbs.clone() {
+        let db_config = Config {
+            redis_db: db,
+            ..config.clone()
+        };
+        let db_conns = match conns.take() {
+            Some(c) => c,
+            None => match Connections::open_with_retry(&db_config).await {
+                Ok(c) => c,
+                Err(e) => {
+                    log(&format!("FATAL: listener for Redis db {}: {}", db, e));
+                    return;
                 }
-            }
-            Err(e) => {
-                log(&format!("[ERROR] Redis Error in Loop: {:?}", e));
-                // If a Redis error occurs, wait a bit before retrying.
-                time::sleep(Duration::from_secs(5)).await;
-            }
+            },
+        };
+        let pause = pause.clone();
+        listeners.push(tokio::spawn(run_listeners(db_conns, db_config, pause)));
+    }
+
+    for handle in listeners {
+        if let Err(e) = handle.await {
+            log(&format!("[ERROR] Listener task failed: {}", e));
         }
     }
 }
 
-async fn execute_task(task: &Task) -> Result<String, String> {
-    log(&format!("Executing task type: {:?}", task.task_type));
-    match task.task_type {
-        TaskType::SHELL => {
-            // Mock execution for now
-            log("TaskType was SHELL. (Not implemented, mock success)");
-            Ok("Shell command executed successfully.".to_string())
-        }
-        TaskType::DOCKER => {
-            let command = task.details["command"].as_str().unwrap_or("");
-            match command {
-                "list_containers" => {
-                    log("Executing docker ps -a --format '{{json .}}'");
-                    let output = tokio::process::Command::new("docker")
-                        .arg("ps")
-                        .arg("-a")
-                        .arg("--format")
-                        .arg("{{json .}}")
-                        .output()
-                        .await
-                        .map_err(|e| format!("Failed to execute docker command: {}", e))?;
-
-                    if output.status.success() {
-                        Ok(String::from_utf8_lossy(&output.stdout).to_string())
-                    } else {
-                        Err(format!(
-                            "Docker command failed: {}",
-                            String::from_utf8_lossy(&output.stderr)
-                        ))
-                    }
+/// Runs one listener loop per configured queue so a hot queue can't starve
+/// the others; a queue's priority twins share its loop. Each loop blocks on
+/// its own connection; the first reuses the startup connections.
+async fn run_per_queue_listeners(conns: Connections, config: Config, pause: PauseState) {
+    let config = Arc::new(config);
+    let mut conns = Some(conns);
+    let mut listeners = Vec::new();
+
+    let mut groups: Vec<(&str, Vec<String>)> = Vec::new();
+    for queue in &config.queues {
+        let base = routing::base_queue(queue);
+        match groups.iter_mut().find(|(group, _)| *group == base) {
+            Some((_, queues)) => queues.push(queue.clone()),
+            None => groups.push((base, vec![queue.clone()])),
+        }
+    }
+    for (queue_name, queue_keys) in groups {
+        let queue_conns = match conns.take() {
+            Some(c) => c,
+            None => match Connections::open(&config).await {
+                Ok(c) => c,
+                Err(e) => {
+                    log(&format!("FATAL: listener for {}: {}", queue_name, e));
+                    return;
                 }
-                _ => Err(format!("Unsupported Docker command: {}", command)),
-            }
+            },
+        };
+        let config = Arc::clone(&config);
+        let pause = pause.clone();
+        listeners.push(tokio::spawn(async move {
+            let mut queue = RedisQueue::new(queue_conns, &config);
+            listener::command_listener(&mut queue, &config, &pause, &queue_keys).await;
+        }));
+    }
+
+    for handle in listeners {
+        if let Err(e) = handle.await {
+            log(&format!("[ERROR] Listener task failed: {}", e));
         }
     }
-}
\ No newline at end of file
+}
+
+/// Runs a task through `handler` (see [`handlers`]) under `cancel` (see
+/// [`cancel::for_task`]). Handlers that spawn children watch the token
+/// themselves and stop the child with SIGTERM then SIGKILL, keeping what it
+/// printed; any other handler is dropped when the token fires.
+async fn execute_task(
+    handler: handlers::Handler,
+    task: &Task,
+    config: &Config,
+    conn: Option<redis::aio::MultiplexedConnection>,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    task.time_left()?;
+    if task.past_deadline() {
+        log(&format!(
+            "Task {} is past its deadline, not running it",
+            task.id
+        ));
+        return Err(task.deadline_exceeded());
+    }
+    if cancel.is_cancelled() {
+        return Err(cancel::error(task));
+    }
+
+    log(&format!("Executing task type: {:?}", task.task_type));
+    let expectation = expect::Expectation::from_details(&task.details)?;
+    if expectation.as_ref().is_some_and(|e| e.exit_code.is_some())
+        && task.task_type != TaskType::SHELL
+    {
+        return Err("expect.exit_code is only supported for SHELL tasks".to_string());
+    }
+    let limits = limits::Limits::from_task(&task.details, config)?;
+    let executor = handlers::executor(task.task_type.as_str());
+    if let Some(executor) = executor {
+        executor
+            .validate(&task.details)
+            .map_err(|problems| validation::report(&problems))?;
+    }
+
+    // A failed precondition skips the task without running it.
+    #[cfg(feature = "shell")]
+    if let Some(skipped) = shell::precondition(task, config, cancel).await? {
+        return Ok(skipped);
+    }
+    #[cfg(not(feature = "shell"))]
+    if !task.details["precondition"].is_null() {
+        return Err("precondition needs shell support, which is not compiled in".to_string());
+    }
+
+    let audit = match config.audit {
+        true => Some(audit::Entry::begin(task, config, conn.clone()).await?),
+        false => None,
+    };
+    #[cfg_attr(not(any(feature = "docker", feature = "shell")), allow(unused_mut))]
+    let mut stream = chunks::ResultStream::for_task(task, config, conn.clone());
+    let dispatch = handler(
+        task,
+        handlers::Context {
+            config,
+            conn,
+            stream: stream.as_mut(),
+            cancel,
+        },
+    );
+    let bounded = async {
+        match executor.is_some_and(|executor| executor.watches_cancel()) {
+            true => dispatch.await,
+            false => cancel::run_until_cancelled(cancel, task, dispatch).await,
+        }
+    };
+    let ((output, resolved), captured) = exit::capture(secrets::track(bounded)).await;
+    // Secrets looked up for the task's environment never reach its result.
+    let output = match output {
+        Ok(output) => Ok(limits::cap_output(
+            redact::scrub(&output, &resolved),
+            limits.max_output_bytes,
+        )),
+        Err(e) => Err(limits::cap_output(
+            redact::scrub(&e, &resolved),
+            limits.max_output_bytes,
+        )),
+    };
+    if let Some(mut stream) = stream {
+        if let (0, Ok(output)) = (stream.chunks(), &output) {
+            stream.push(output).await;
+        }
+        let status = match &output {
+            Ok(_) => "SUCCESS",
+            Err(_) => cancel::stopped_status(cancel, task).unwrap_or("ERROR"),
+        };
+        stream.finish(status).await;
+    }
+    if let Some(audit) = audit {
+        audit.end(&output, captured.exit_code).await;
+    }
+    let output = output?;
+    if let Some(expectation) = expectation {
+        expectation.check_output(&output)?;
+    }
+    Ok(output)
+}
@@ -0,0 +1,368 @@
+use crate::cancel;
+use crate::config::Config;
+use crate::exit;
+use crate::handlers;
+use crate::history;
+use crate::log;
+use crate::logging;
+use crate::policy::{self, POLICY_DENIED};
+use crate::{execute_task, Task};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Protocol revisions this server speaks, newest first.
+const PROTOCOL_VERSIONS: [&str; 3] = ["2025-06-18", "2025-03-26", "2024-11-05"];
+/// The queue name tool runs are recorded under in the task history.
+const MCP_QUEUE: &str = "mcp::stdio";
+/// Results of this session kept for `get_task_result`; older ones are only
+/// found in `HISTORY_DB`.
+const MAX_SESSION_RESULTS: usize = 256;
+
+/// JSON-RPC error codes used in responses.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+type RpcError = (i64, String);
+
+// --- MCP Server ---
+/// The tools offered to clients; those whose executor isn't compiled in are
+/// left out.
+fn tools() -> Vec<Value> {
+    let mut tools = Vec::new();
+    if cfg!(feature = "shell") {
+        tools.push(json!({
+            "name": "run_shell",
+            "description": "Run a command on the worker host, without a shell. Returns the task id, status and output.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "command": { "type": "string", "description": "The program to run." },
+                    "args": { "type": "array", "items": { "type": "string" } },
+                    "cwd": { "type": "string" },
+                    "env": { "type": "object", "additionalProperties": { "type": "string" } },
+                    "timeout_secs": { "type": "integer", "minimum": 1 },
+                },
+                "required": ["command"],
+            },
+        }));
+    }
+    if cfg!(feature = "docker") {
+        tools.push(json!({
+            "name": "docker_ps",
+            "description": "List every container on the worker host, stopped ones included, one JSON object per line.",
+            "inputSchema": { "type": "object", "properties": {} },
+        }));
+        tools.push(json!({
+            "name": "docker_restart",
+            "description": "Restart a container by name or id.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "container": { "type": "string" },
+                    "timeout_secs": { "type": "integer", "minimum": 0 },
+                },
+                "required": ["container"],
+            },
+        }));
+    }
+    tools.push(json!({
+        "name": "get_task_result",
+        "description": "Get the result of an earlier tool call by its task id.",
+        "inputSchema": {
+            "type": "object",
+            "properties": { "task_id": { "type": "string" } },
+            "required": ["task_id"],
+        },
+    }));
+    tools
+}
+
+/// The task a tool call runs, with only the arguments its schema lists
+/// copied into `details`.
+fn tool_task(name: &str, arguments: &Value) -> Result<Option<Task>, String> {
+    let pick = |keys: &[&str]| -> serde_json::Map<String, Value> {
+        keys.iter()
+            .filter(|key| !arguments[**key].is_null())
+            .map(|key| (key.to_string(), arguments[*key].clone()))
+            .collect()
+    };
+    let (task_type, details) = match name {
+        "run_shell" if cfg!(feature = "shell") => (
+            "SHELL",
+            pick(&["command", "args", "cwd", "env", "timeout_secs"]),
+        ),
+        "docker_ps" if cfg!(feature = "docker") => (
+            "DOCKER",
+            json_object(json!({ "command": "list_containers" })),
+        ),
+        "docker_restart" if cfg!(feature = "docker") => {
+            let mut details = pick(&["container", "timeout_secs"]);
+            details.insert("command".to_string(), "restart_container".into());
+            ("DOCKER", details)
+        }
+        _ => return Ok(None),
+    };
+    let mut task: Task = serde_json::from_value(json!({
+        "id": format!("mcp-{:016x}", rand::random::<u64>()),
+        "target_host": "",
+        "task_type": task_type,
+        "details": details,
+    }))
+    .map_err(|e| e.to_string())?;
+    task.assign_correlation_id();
+    task.apply_defaults();
+    Ok(Some(task))
+}
+
+fn json_object(value: Value) -> serde_json::Map<String, Value> {
+    match value {
+        Value::Object(fields) => fields,
+        _ => unreachable!(),
+    }
+}
+
+/// A `tools/call` result with the text as its only content.
+fn tool_result(text: String, is_error: bool) -> Value {
+    json!({
+        "content": [{ "type": "text", "text": text }],
+        "isError": is_error,
+    })
+}
+
+/// State shared by the requests of one stdio session.
+struct Session {
+    config: Config,
+    /// Lines for the stdout writer.
+    out: mpsc::UnboundedSender<String>,
+    /// Tokens of running tool calls, by request id, for
+    /// `notifications/cancelled`.
+    running: Mutex<HashMap<String, CancellationToken>>,
+    /// Recent results by task id, and their ids oldest first.
+    results: Mutex<(HashMap<String, Value>, VecDeque<String>)>,
+}
+
+/// Serves the Model Context Protocol over stdio: newline-delimited JSON-RPC
+/// requests on stdin, responses on stdout (logs go to stderr). Tool calls
+/// run concurrently, as tasks through the same policy check and executors
+/// as queued ones, but without Redis; with `HISTORY_DB` set they are
+/// recorded there too. Returns once stdin closes, after cancelling any tool
+/// call still running.
+pub async fn serve(config: &Config) -> Result<(), String> {
+    let (out, mut lines) = mpsc::unbounded_channel::<String>();
+    let writer = tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        while let Some(line) = lines.recv().await {
+            let written = stdout.write_all(format!("{}\n", line).as_bytes()).await;
+            if written.is_err() || stdout.flush().await.is_err() {
+                break;
+            }
+        }
+    });
+    let session = Arc::new(Session {
+        config: config.clone(),
+        out,
+        running: Mutex::new(HashMap::new()),
+        results: Mutex::new((HashMap::new(), VecDeque::new())),
+    });
+
+    log("Serving MCP on stdio");
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let read = loop {
+        let line = match stdin.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(format!("Failed to read stdin: {}", e)),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Value>(&line) {
+            Ok(message) => {
+                let session = Arc::clone(&session);
+                tokio::spawn(async move { session.handle(message).await });
+            }
+            Err(e) => session.reply(Value::Null, Err((PARSE_ERROR, e.to_string()))),
+        }
+    };
+
+    log("MCP client closed stdin; stopping");
+    for token in session.running.lock().unwrap().values() {
+        token.cancel();
+    }
+    // The writer ends once the last running call has replied.
+    drop(session);
+    writer.await.ok();
+    read
+}
+
+impl Session {
+    fn reply(&self, id: Value, result: Result<Value, RpcError>) {
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        self.out.send(response.to_string()).ok();
+    }
+
+    async fn handle(&self, message: Value) {
+        let method = message["method"].as_str();
+        let Some(id) = message.get("id").cloned() else {
+            // A notification: nothing is sent back.
+            if method == Some("notifications/cancelled") {
+                let request_id = message["params"]["requestId"].to_string();
+                if let Some(token) = self.running.lock().unwrap().get(&request_id) {
+                    token.cancel();
+                }
+            }
+            return;
+        };
+        let Some(method) = method else {
+            // A response to a request this server never sends.
+            if message.get("result").is_none() && message.get("error").is_none() {
+                self.reply(id, Err((INVALID_REQUEST, "missing 'method'".to_string())));
+            }
+            return;
+        };
+        let params = &message["params"];
+        let result = match method {
+            "initialize" => Ok(initialize(params)),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": tools() })),
+            "tools/call" => self.call_tool(&id, params).await,
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method '{}'", method))),
+        };
+        self.reply(id, result);
+    }
+
+    async fn call_tool(&self, id: &Value, params: &Value) -> Result<Value, RpcError> {
+        let name = params["name"].as_str().ok_or((
+            INVALID_PARAMS,
+            "tools/call requires a string 'name'".to_string(),
+        ))?;
+        let arguments = &params["arguments"];
+        if name == "get_task_result" {
+            let Some(task_id) = arguments["task_id"].as_str() else {
+                return Ok(tool_result(
+                    "get_task_result requires a string 'task_id'".to_string(),
+                    true,
+                ));
+            };
+            return Ok(match self.task_result(task_id) {
+                Ok(result) => tool_result(result.to_string(), false),
+                Err(e) => tool_result(e, true),
+            });
+        }
+        let task = match tool_task(name, arguments) {
+            Ok(Some(task)) => task,
+            Ok(None) => return Err((INVALID_PARAMS, format!("Unknown tool '{}'", name))),
+            Err(e) => return Ok(tool_result(e, true)),
+        };
+
+        log(&format!("MCP tool {} running as task {}", name, task.id));
+        let (cancel, _cancel_guard) = cancel::for_task(&task, None);
+        let request_id = id.to_string();
+        self.running
+            .lock()
+            .unwrap()
+            .insert(request_id.clone(), cancel.clone());
+        let started = Instant::now();
+        let started_at = chrono::Utc::now();
+        let denied = policy::check(&task).err();
+        let (task_result, captured) = match denied.clone() {
+            Some(reason) => (Err(reason), exit::Captured::default()),
+            None => {
+                let execution = execute_task(handlers::builtin, &task, &self.config, None, &cancel);
+                exit::capture(logging::in_span(logging::span(&task), execution)).await
+            }
+        };
+        self.running.lock().unwrap().remove(&request_id);
+        let duration = started.elapsed();
+        let (status, output) = match &task_result {
+            Ok(output) => ("SUCCESS", output),
+            Err(e) if denied.is_some() => (POLICY_DENIED, e),
+            Err(e) => (cancel::stopped_status(&cancel, &task).unwrap_or("ERROR"), e),
+        };
+
+        let payload = serde_json::to_string(&task).unwrap_or_default();
+        let run = history::Run {
+            task: &task,
+            queue_name: MCP_QUEUE,
+            payload: &payload,
+            status,
+            output,
+            exit_code: captured.exit_code,
+            started_at,
+            duration,
+        };
+        history::record(&self.config, run).await;
+        let result = json!({
+            "task_id": task.id,
+            "status": status,
+            "output": output,
+            "output_encoding": captured.output_encoding(),
+            "duration_ms": duration.as_millis() as u64,
+            "correlation_id": task.correlation_id(),
+        });
+        self.remember(&task.id, result.clone());
+        Ok(tool_result(result.to_string(), status != "SUCCESS"))
+    }
+
+    fn remember(&self, task_id: &str, result: Value) {
+        let mut results = self.results.lock().unwrap();
+        let (by_id, order) = &mut *results;
+        by_id.insert(task_id.to_string(), result);
+        order.push_back(task_id.to_string());
+        if order.len() > MAX_SESSION_RESULTS {
+            if let Some(oldest) = order.pop_front() {
+                by_id.remove(&oldest);
+            }
+        }
+    }
+
+    /// A result of this session, else the latest run recorded in
+    /// `HISTORY_DB`.
+    fn task_result(&self, task_id: &str) -> Result<Value, String> {
+        if let Some(result) = self.results.lock().unwrap().0.get(task_id) {
+            return Ok(result.clone());
+        }
+        if self.config.history_db.is_none() {
+            return Err(format!(
+                "No result for task '{}' in this session (earlier ones need HISTORY_DB)",
+                task_id
+            ));
+        }
+        history::runs(&self.config, Some(task_id), None, 1)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("No result for task '{}'", task_id))
+    }
+}
+
+/// The `initialize` result: the client's protocol revision when this server
+/// speaks it, else the newest one it does.
+fn initialize(params: &Value) -> Value {
+    let requested = params["protocolVersion"].as_str();
+    let version = PROTOCOL_VERSIONS
+        .iter()
+        .find(|version| Some(**version) == requested)
+        .unwrap_or(&PROTOCOL_VERSIONS[0]);
+    json!({
+        "protocolVersion": version,
+        "capabilities": { "tools": {} },
+        "serverInfo": {
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+    })
+}
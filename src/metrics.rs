@@ -0,0 +1,210 @@
+use crate::stats::STATS;
+use crate::TaskType;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+// --- Prometheus Metrics ---
+/// Upper bounds, in seconds, of the task duration histogram's buckets.
+const DURATION_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0,
+];
+/// Upper bounds, in seconds, of the queue pop latency histogram's buckets.
+const POP_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket (not cumulative); the last is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += secs;
+    }
+
+    /// Writes the `_bucket`, `_sum` and `_count` series, with `labels`
+    /// (`key="value"` pairs, possibly empty) on each.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, le, cumulative
+            )
+            .ok();
+        }
+        let braces = |labels: &str| match labels {
+            "" => String::new(),
+            _ => format!("{{{}}}", labels),
+        };
+        writeln!(out, "{}_sum{} {}", name, braces(labels), self.sum).ok();
+        writeln!(out, "{}_count{} {}", name, braces(labels), cumulative).ok();
+    }
+}
+
+struct TypeMetrics {
+    received: u64,
+    succeeded: u64,
+    failed: u64,
+    duration: Histogram,
+}
+
+/// Reads one counter of a [`TypeMetrics`].
+type Field = fn(&TypeMetrics) -> u64;
+
+impl Default for TypeMetrics {
+    fn default() -> Self {
+        TypeMetrics {
+            received: 0,
+            succeeded: 0,
+            failed: 0,
+            duration: Histogram::new(DURATION_BUCKETS),
+        }
+    }
+}
+
+/// Per task type, keyed by its name (`UNKNOWN` for every unrecognized type,
+/// so producers can't grow the label set).
+static BY_TYPE: LazyLock<Mutex<BTreeMap<String, TypeMetrics>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+static POP_LATENCY: LazyLock<Mutex<Histogram>> =
+    LazyLock::new(|| Mutex::new(Histogram::new(POP_BUCKETS)));
+static REDIS_RECONNECTS: AtomicU64 = AtomicU64::new(0);
+
+fn label(task_type: &TaskType) -> &str {
+    match task_type {
+        TaskType::Unknown(_) => "UNKNOWN",
+        known => known.as_str(),
+    }
+}
+
+fn with_type(task_type: &TaskType, update: impl FnOnce(&mut TypeMetrics)) {
+    let mut by_type = BY_TYPE.lock().unwrap();
+    update(by_type.entry(label(task_type).to_string()).or_default());
+}
+
+/// Counts a parsed task of `task_type`.
+pub fn task_received(task_type: &TaskType) {
+    with_type(task_type, |metrics| metrics.received += 1);
+}
+
+/// Counts a finished task and records how long it ran.
+pub fn task_finished(task_type: &TaskType, succeeded: bool, duration: Duration) {
+    with_type(task_type, |metrics| {
+        match succeeded {
+            true => metrics.succeeded += 1,
+            false => metrics.failed += 1,
+        }
+        metrics.duration.observe(duration);
+    });
+}
+
+/// Records how long a pop that returned a task took, from the request to
+/// the task arriving.
+pub fn observe_pop(latency: Duration) {
+    POP_LATENCY.lock().unwrap().observe(latency);
+}
+
+pub fn redis_reconnected() {
+    REDIS_RECONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Every metric in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    let by_type = BY_TYPE.lock().unwrap();
+    let counters: [(&str, &str, Field); 3] = [
+        (
+            "mcp_tasks_received_total",
+            "Tasks parsed from a queue.",
+            |m| m.received,
+        ),
+        (
+            "mcp_tasks_succeeded_total",
+            "Tasks that finished successfully.",
+            |m| m.succeeded,
+        ),
+        (
+            "mcp_tasks_failed_total",
+            "Tasks that finished with an error.",
+            |m| m.failed,
+        ),
+    ];
+    for (name, help, value) in counters {
+        writeln!(out, "# HELP {} {}", name, help).ok();
+        writeln!(out, "# TYPE {} counter", name).ok();
+        for (task_type, metrics) in by_type.iter() {
+            writeln!(
+                out,
+                "{}{{task_type=\"{}\"}} {}",
+                name,
+                task_type,
+                value(metrics)
+            )
+            .ok();
+        }
+    }
+
+    let name = "mcp_task_duration_seconds";
+    writeln!(out, "# HELP {} How long tasks ran.", name).ok();
+    writeln!(out, "# TYPE {} histogram", name).ok();
+    for (task_type, metrics) in by_type.iter() {
+        let labels = format!("task_type=\"{}\"", task_type);
+        metrics.duration.render(&mut out, name, &labels);
+    }
+    drop(by_type);
+
+    let name = "mcp_queue_pop_duration_seconds";
+    writeln!(
+        out,
+        "# HELP {} How long pops that returned a task took.",
+        name
+    )
+    .ok();
+    writeln!(out, "# TYPE {} histogram", name).ok();
+    POP_LATENCY.lock().unwrap().render(&mut out, name, "");
+
+    writeln!(
+        out,
+        "# HELP mcp_redis_reconnects_total Successful Redis reconnects.\n\
+         # TYPE mcp_redis_reconnects_total counter\n\
+         mcp_redis_reconnects_total {}",
+        REDIS_RECONNECTS.load(Ordering::Relaxed)
+    )
+    .ok();
+    writeln!(
+        out,
+        "# HELP mcp_tasks_running Tasks currently executing.\n\
+         # TYPE mcp_tasks_running gauge\n\
+         mcp_tasks_running {}",
+        STATS.inflight.load(Ordering::Relaxed)
+    )
+    .ok();
+    out
+}
@@ -0,0 +1,90 @@
+use crate::exit;
+use base64::Engine;
+
+// --- Output Encoding ---
+/// How a command's raw stdout is turned into the result string.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputEncoding {
+    /// Lossy UTF-8 text (the default).
+    Text,
+    /// Standard base64 of the raw bytes, for binary output such as `docker save`.
+    Base64,
+}
+
+impl OutputEncoding {
+    /// Reads the optional `encoding` field from the task details.
+    pub fn from_details(details: &serde_json::Value) -> Result<Self, String> {
+        match details.get("encoding") {
+            None | Some(serde_json::Value::Null) => Ok(OutputEncoding::Text),
+            Some(serde_json::Value::String(s)) => match s.to_lowercase().as_str() {
+                "text" | "utf8" | "utf-8" => Ok(OutputEncoding::Text),
+                "base64" => Ok(OutputEncoding::Base64),
+                other => Err(format!("Unsupported output encoding: {}", other)),
+            },
+            Some(other) => Err(format!("Output encoding must be a string, got: {}", other)),
+        }
+    }
+
+    /// The name results report in `output_encoding`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutputEncoding::Text => "text",
+            OutputEncoding::Base64 => "base64",
+        }
+    }
+
+    /// Encodes raw output, recording the encoding (see
+    /// [`exit::record_output_encoding`]) so the result says how to read it.
+    pub fn encode(self, bytes: &[u8]) -> String {
+        exit::record_output_encoding(self);
+        match self {
+            OutputEncoding::Text => String::from_utf8_lossy(bytes).to_string(),
+            OutputEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(bytes),
+        }
+    }
+}
+
+// --- Output Options ---
+/// Per-task post-processing applied to a command's stdout before it becomes
+/// the result: optional line filtering followed by encoding.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(any(feature = "docker", feature = "shell")), allow(dead_code))]
+pub struct OutputOptions {
+    pub encoding: OutputEncoding,
+    /// Keeps only lines matching this pattern, from `details.grep`.
+    pub grep: Option<regex::bytes::Regex>,
+}
+
+#[cfg_attr(not(any(feature = "docker", feature = "shell")), allow(dead_code))]
+impl OutputOptions {
+    /// Reads output options from the task details, rejecting an invalid
+    /// `grep` pattern up front so the command is never run with it.
+    pub fn from_details(details: &serde_json::Value) -> Result<Self, String> {
+        let grep = match details.get("grep") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(serde_json::Value::String(pattern)) => Some(
+                regex::bytes::Regex::new(pattern)
+                    .map_err(|e| format!("Invalid grep pattern '{}': {}", pattern, e))?,
+            ),
+            Some(other) => return Err(format!("grep must be a string, got: {}", other)),
+        };
+
+        Ok(OutputOptions {
+            encoding: OutputEncoding::from_details(details)?,
+            grep,
+        })
+    }
+
+    pub fn render(&self, stdout: &[u8]) -> String {
+        match &self.grep {
+            Some(re) => {
+                let filtered: Vec<&[u8]> = stdout
+                    .split(|b| *b == b'\n')
+                    .filter(|line| re.is_match(line))
+                    .collect();
+                self.encoding.encode(&filtered.join(&b'\n'))
+            }
+            None => self.encoding.encode(stdout),
+        }
+    }
+}
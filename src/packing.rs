@@ -0,0 +1,287 @@
+use crate::config::Config;
+use crate::envelope::Envelope;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+
+// --- Large Results ---
+/// Set on a manifest, so readers can tell it from a result that is
+/// stored whole.
+pub const MANIFEST_MARKER: &str = "mcp_result_manifest";
+
+/// How [`compress`] left a result.
+pub struct Compressed {
+    /// `gzip`, or `identity` when the value was left as it was.
+    pub encoding: &'static str,
+    pub original_size: usize,
+}
+
+/// Gzips `value` once it is larger than `RESULT_COMPRESS_BYTES`, unless
+/// that wouldn't make it smaller.
+pub fn compress(value: Vec<u8>, config: &Config) -> (Vec<u8>, Compressed) {
+    let original_size = value.len();
+    if config.result_compress_bytes > 0 && original_size > config.result_compress_bytes {
+        if let Ok(gzipped) = gzip(&value) {
+            if gzipped.len() < original_size {
+                let encoding = "gzip";
+                return (
+                    gzipped,
+                    Compressed {
+                        encoding,
+                        original_size,
+                    },
+                );
+            }
+        }
+    }
+    let encoding = "identity";
+    (
+        value,
+        Compressed {
+            encoding,
+            original_size,
+        },
+    )
+}
+
+/// Like [`compress`] for a hash result's `output` field, which has to stay
+/// text: gzipped output is base64-encoded.
+pub fn compress_text(output: String, config: &Config) -> (String, &'static str) {
+    let (value, compressed) = compress(output.into_bytes(), config);
+    match compressed.encoding {
+        "gzip" => (BASE64.encode(value), "gzip"),
+        // Never compressed, so still the UTF-8 it started as.
+        _ => (String::from_utf8(value).unwrap_or_default(), "identity"),
+    }
+}
+
+pub fn gzip(value: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(value)?;
+    encoder.finish()
+}
+
+pub fn gunzip(value: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    GzDecoder::new(value).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// The key chunk `n` of the result under `key` is stored at.
+pub fn chunk_key(key: &str, n: usize) -> String {
+    format!("{}::{}", key, n)
+}
+
+/// Splits a stored value (compressed, then sealed when encryption is on)
+/// across `<result key>::<n>` keys when it was compressed or is larger than
+/// `RESULT_CHUNK_BYTES`. Returns the manifest to store under the result key
+/// in its place, along with the chunks, or `None` to store the value whole.
+pub fn chunk(
+    key: &str,
+    compressed: &Compressed,
+    value: &[u8],
+    status: &str,
+    task_id: &str,
+    config: &Config,
+) -> Option<(Vec<u8>, Vec<Vec<u8>>)> {
+    let oversized = config.result_chunk_bytes > 0 && value.len() > config.result_chunk_bytes;
+    if compressed.encoding == "identity" && !oversized {
+        return None;
+    }
+    let chunk_size = match config.result_chunk_bytes {
+        0 => value.len().max(1),
+        size => size,
+    };
+    let chunks: Vec<Vec<u8>> = value.chunks(chunk_size).map(<[u8]>::to_vec).collect();
+    let format = match config.envelope {
+        Envelope::JsonRpc => serde_json::json!("jsonrpc"),
+        _ => serde_json::to_value(config.result_format).unwrap_or_default(),
+    };
+    let manifest = serde_json::json!({
+        MANIFEST_MARKER: 1,
+        "task_id": task_id,
+        "status": status,
+        "worker_id": config.worker_id,
+        "format": format,
+        "encoding": compressed.encoding,
+        "encrypted": config.result_cipher.is_some(),
+        "original_size": compressed.original_size,
+        "size": value.len(),
+        "sha256": hex::encode(Sha256::digest(value)),
+        "chunk_size": chunk_size,
+        "chunks": chunks.len(),
+        "chunk_prefix": format!("{}::", key),
+    });
+    Some((manifest.to_string().into_bytes(), chunks))
+}
+
+/// Reads a manifest out of a stored string result, if it is one.
+pub fn manifest(value: &[u8]) -> Option<serde_json::Value> {
+    let manifest: serde_json::Value = serde_json::from_slice(value).ok()?;
+    manifest.get(MANIFEST_MARKER)?;
+    Some(manifest)
+}
+
+/// Puts an unencrypted result back together from its manifest and chunks,
+/// checking the digest and undoing the compression.
+pub fn reassemble(manifest: &serde_json::Value, chunks: Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+    if manifest["encrypted"].as_bool() == Some(true) {
+        return Err("Result is encrypted".to_string());
+    }
+    let value = chunks.concat();
+    if manifest["sha256"].as_str() != Some(hex::encode(Sha256::digest(&value)).as_str()) {
+        return Err("Result chunks are incomplete or don't match the manifest".to_string());
+    }
+    match manifest["encoding"].as_str() {
+        Some("gzip") => gunzip(&value).map_err(|e| format!("Failed to decompress result: {}", e)),
+        _ => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn config(compress_bytes: usize, chunk_bytes: usize) -> Config {
+        Config {
+            result_compress_bytes: compress_bytes,
+            result_chunk_bytes: chunk_bytes,
+            ..Config::for_tests()
+        }
+    }
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn compresses_only_past_the_threshold_and_when_smaller() {
+        let text = b"a".repeat(100);
+        let (value, compressed) = compress(text.clone(), &config(100, 0));
+        assert_eq!(
+            (value.as_slice(), compressed.encoding),
+            (text.as_slice(), "identity")
+        );
+        assert_eq!(compressed.original_size, 100);
+
+        let (value, compressed) = compress(text.clone(), &config(99, 0));
+        assert_eq!(compressed.encoding, "gzip");
+        assert_eq!(compressed.original_size, 100);
+        assert!(value.len() < 100);
+        assert_eq!(gunzip(&value).unwrap(), text);
+
+        let (value, compressed) = compress(text.clone(), &config(0, 0));
+        assert_eq!((value, compressed.encoding), (text, "identity"));
+
+        // Random bytes only grow when gzipped.
+        let noise = random(4096);
+        let (value, compressed) = compress(noise.clone(), &config(1, 0));
+        assert_eq!((value, compressed.encoding), (noise, "identity"));
+    }
+
+    #[test]
+    fn compressed_text_is_base64() {
+        let output = "line\n".repeat(50);
+        let (text, encoding) = compress_text(output.clone(), &config(10, 0));
+        assert_eq!(encoding, "gzip");
+        assert_eq!(
+            gunzip(&BASE64.decode(text).unwrap()).unwrap(),
+            output.as_bytes()
+        );
+        let (text, encoding) = compress_text(output.clone(), &config(0, 0));
+        assert_eq!((text, encoding), (output, "identity"));
+    }
+
+    #[test]
+    fn chunks_oversized_or_compressed_values() {
+        let identity = |size| Compressed {
+            encoding: "identity",
+            original_size: size,
+        };
+        let chunked = |value: &[u8], compressed: &Compressed, config: &Config| {
+            chunk("r:t1", compressed, value, "SUCCESS", "t1", config)
+                .map(|(_, chunks)| chunks.iter().map(Vec::len).collect::<Vec<_>>())
+        };
+        let limited = config(0, 10);
+        assert_eq!(chunked(&[1; 10], &identity(10), &limited), None);
+        assert_eq!(
+            chunked(&[1; 11], &identity(11), &limited),
+            Some(vec![10, 1])
+        );
+        assert_eq!(
+            chunked(&[1; 20], &identity(20), &limited),
+            Some(vec![10, 10])
+        );
+        assert_eq!(
+            chunked(&[1; 25], &identity(25), &limited),
+            Some(vec![10, 10, 5])
+        );
+        let gzipped = Compressed {
+            encoding: "gzip",
+            original_size: 500,
+        };
+        assert_eq!(chunked(&[1; 4], &gzipped, &limited), Some(vec![4]));
+        // Without a chunk size a compressed value is one chunk.
+        let unlimited = config(0, 0);
+        assert_eq!(chunked(&[1; 10_000], &identity(10_000), &unlimited), None);
+        assert_eq!(chunked(&[1; 40], &gzipped, &unlimited), Some(vec![40]));
+        assert_eq!(chunked(&[], &gzipped, &unlimited), Some(vec![]));
+    }
+
+    #[test]
+    fn manifest_describes_the_chunks() {
+        let config = config(10, 16);
+        let original = b"result ".repeat(40);
+        let (value, compressed) = compress(original.clone(), &config);
+        let (manifest, chunks) = chunk(
+            "mcp::result::t1",
+            &compressed,
+            &value,
+            "SUCCESS",
+            "t1",
+            &config,
+        )
+        .unwrap();
+        let manifest = super::manifest(&manifest).unwrap();
+        assert_eq!(
+            manifest,
+            serde_json::json!({
+                "mcp_result_manifest": 1,
+                "task_id": "t1",
+                "status": "SUCCESS",
+                "worker_id": "test-worker",
+                "format": "json",
+                "encoding": "gzip",
+                "encrypted": false,
+                "original_size": original.len(),
+                "size": value.len(),
+                "sha256": hex::encode(Sha256::digest(&value)),
+                "chunk_size": 16,
+                "chunks": value.len().div_ceil(16),
+                "chunk_prefix": "mcp::result::t1::",
+            })
+        );
+        assert_eq!(chunk_key("mcp::result::t1", 2), "mcp::result::t1::2");
+        assert_eq!(reassemble(&manifest, chunks.clone()).unwrap(), original);
+
+        let mut tampered = chunks.clone();
+        tampered[0][0] ^= 1;
+        assert!(reassemble(&manifest, tampered).is_err());
+        assert!(reassemble(&manifest, chunks[1..].to_vec()).is_err());
+        let mut encrypted = manifest.clone();
+        encrypted["encrypted"] = true.into();
+        assert_eq!(
+            reassemble(&encrypted, chunks).unwrap_err(),
+            "Result is encrypted"
+        );
+        assert!(super::manifest(br#"{"task_id":"t1","status":"SUCCESS"}"#).is_none());
+        assert!(super::manifest(b"not json").is_none());
+    }
+}
@@ -0,0 +1,175 @@
+use std::path::{Path, PathBuf};
+
+// --- Host Path Confinement ---
+/// Resolves `path` (following symlinks) and checks it lies within
+/// `base_dir`, named `setting` in errors. A path that doesn't exist yet is
+/// checked through its parent directory, unless `must_exist` is set; a
+/// dangling symlink in its place is rejected since writing through it could
+/// escape `base_dir`.
+pub fn confine(
+    base_dir: &str,
+    setting: &str,
+    path: &Path,
+    must_exist: bool,
+) -> Result<PathBuf, String> {
+    let base = Path::new(base_dir)
+        .canonicalize()
+        .map_err(|e| format!("{} {} is not usable: {}", setting, base_dir, e))?;
+    let resolved = match path.canonicalize() {
+        Ok(resolved) => resolved,
+        Err(e) if must_exist => return Err(format!("{}: {}", path.display(), e)),
+        Err(_) => {
+            if path.symlink_metadata().is_ok() {
+                return Err(format!("{} is a dangling symlink", path.display()));
+            }
+            let name = path
+                .file_name()
+                .ok_or_else(|| format!("Invalid host path: {}", path.display()))?;
+            let parent = match path.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p,
+                _ => Path::new("."),
+            };
+            parent
+                .canonicalize()
+                .map_err(|e| format!("{}: {}", parent.display(), e))?
+                .join(name)
+        }
+    };
+    if resolved.starts_with(&base) {
+        Ok(resolved)
+    } else {
+        Err(format!(
+            "Host path {} is outside {} ({})",
+            resolved.display(),
+            setting,
+            base.display()
+        ))
+    }
+}
+
+/// Like [`confine`] with `must_exist`, but doesn't follow `path` itself:
+/// only its parent directory is resolved, so a symlink names the link
+/// rather than its target. For removing entries.
+pub fn confine_entry(base_dir: &str, setting: &str, path: &Path) -> Result<PathBuf, String> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("Invalid host path: {}", path.display()))?;
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let entry = confine(base_dir, setting, parent, true)?.join(name);
+    entry
+        .symlink_metadata()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A fresh `<tmp>/mcp-paths-<name>-<pid>` holding `base/inner.txt` and
+    /// `outside.txt`, and the canonical `base`.
+    fn layout(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("mcp-paths-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(dir.join("base")).unwrap();
+        std::fs::write(dir.join("base/inner.txt"), "in").unwrap();
+        std::fs::write(dir.join("outside.txt"), "out").unwrap();
+        let base = dir.join("base").canonicalize().unwrap();
+        (dir, base)
+    }
+
+    #[test]
+    fn keeps_paths_within_the_base() {
+        let (dir, base) = layout("within");
+        let base_dir = base.to_str().unwrap();
+        let confined = |path: &str, must_exist| {
+            confine(base_dir, "FILE_BASE_DIR", &base.join(path), must_exist)
+        };
+        assert_eq!(confined("inner.txt", true).unwrap(), base.join("inner.txt"));
+        assert!(confined("sub/../inner.txt", false).is_err());
+        std::fs::create_dir(base.join("sub")).unwrap();
+        assert_eq!(
+            confined("sub/../inner.txt", false),
+            confined("inner.txt", false)
+        );
+        assert!(confined("sub/../../outside.txt", false).is_err());
+        assert_eq!(confined("new.txt", false).unwrap(), base.join("new.txt"));
+        assert!(confined("new.txt", true).is_err());
+        assert!(confined("missing/new.txt", false).is_err());
+
+        let escaped = confined("../outside.txt", true).unwrap_err();
+        assert!(escaped.starts_with("Host path "), "{}", escaped);
+        assert!(escaped.contains("is outside FILE_BASE_DIR"), "{}", escaped);
+        assert!(confined("../new.txt", false).is_err());
+        let outside = dir.join("outside.txt");
+        assert!(confine(base_dir, "FILE_BASE_DIR", &outside, true).is_err());
+        assert!(confine(base_dir, "FILE_BASE_DIR", Path::new("/etc/passwd"), true).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn follows_symlinks_before_checking() {
+        let (dir, base) = layout("symlinks");
+        let base_dir = base.to_str().unwrap();
+        symlink(dir.join("outside.txt"), base.join("out-link")).unwrap();
+        symlink(&dir, base.join("out-dir")).unwrap();
+        symlink(base.join("inner.txt"), base.join("in-link")).unwrap();
+        symlink(dir.join("gone.txt"), base.join("dangling")).unwrap();
+        let confined = |path: &str, must_exist| {
+            confine(base_dir, "FILE_BASE_DIR", &base.join(path), must_exist)
+        };
+        assert!(confined("out-link", false).is_err());
+        assert!(confined("out-dir/outside.txt", false).is_err());
+        assert!(confined("out-dir/new.txt", false).is_err());
+        assert_eq!(confined("in-link", true).unwrap(), base.join("inner.txt"));
+        assert_eq!(
+            confined("dangling", false).unwrap_err(),
+            format!("{} is a dangling symlink", base.join("dangling").display())
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn rejects_a_base_that_does_not_exist_yet() {
+        let (dir, base) = layout("no-base");
+        let missing = dir.join("not-yet");
+        let error = confine(
+            missing.to_str().unwrap(),
+            "FILE_BASE_DIR",
+            &missing.join("a.txt"),
+            false,
+        )
+        .unwrap_err();
+        assert!(
+            error.starts_with(&format!(
+                "FILE_BASE_DIR {} is not usable: ",
+                missing.display()
+            )),
+            "{}",
+            error
+        );
+        assert!(!missing.exists());
+        assert!(base.exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn confines_an_entry_without_following_it() {
+        let (dir, base) = layout("entry");
+        let base_dir = base.to_str().unwrap();
+        symlink(dir.join("outside.txt"), base.join("out-link")).unwrap();
+        symlink(&dir, base.join("out-dir")).unwrap();
+        let entry = |path: &str| confine_entry(base_dir, "FILE_BASE_DIR", &base.join(path));
+        assert_eq!(entry("out-link").unwrap(), base.join("out-link"));
+        assert_eq!(entry("inner.txt").unwrap(), base.join("inner.txt"));
+        assert!(entry("missing.txt").is_err());
+        assert!(entry("out-dir/outside.txt").is_err());
+        assert!(entry("../outside.txt").is_err());
+        assert!(entry("..").is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,328 @@
+use crate::config_file;
+use crate::details::string_list;
+use crate::log;
+use crate::{Task, TaskType};
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::sync::OnceLock;
+
+/// Result status of a task the policy refused to run.
+pub const POLICY_DENIED: &str = "POLICY_DENIED";
+
+// --- Execution Policy ---
+/// What SHELL and DOCKER tasks may run, from the config file's `[policy]`
+/// table. An empty list leaves that aspect unrestricted.
+struct Policy {
+    /// Exact `details.command` values a SHELL task may run.
+    shell_binaries: Vec<String>,
+    /// Patterns the whole SHELL command line (quoted like a shell would)
+    /// may match instead.
+    shell_patterns: Vec<Regex>,
+    /// DOCKER `details.command` values allowed.
+    docker_commands: Vec<String>,
+    /// `details.image` globs (`*` matches anything) DOCKER tasks may use.
+    docker_images: Vec<Regex>,
+}
+
+/// Set by [`init`]; without a `[policy]` table every task is allowed.
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+/// Loads the policy from the config file:
+///
+/// ```toml
+/// [policy.shell]
+/// binaries = ["df", "/usr/bin/uptime"]
+/// patterns = ['^git -C /srv/\S+ (status|log)( .*)?$']
+///
+/// [policy.docker]
+/// commands = ["list_containers", "restart_container", "run_container"]
+/// images = ["nginx:*", "registry.internal/*"]
+/// ```
+///
+/// A SHELL task must name one of `binaries` exactly or have its command line
+/// match one of `patterns` (anchor them: they match anywhere otherwise), and
+/// so must the `details.precondition` of a task of any type. With `images`
+/// set, a DOCKER task may not bring its own `compose_yaml`, whose images
+/// can't be checked before it runs.
+/// Only the first call has an effect; an invalid pattern stops startup.
+pub fn init() -> Result<(), String> {
+    let Some(table) = config_file::policy() else {
+        return Ok(());
+    };
+    let policy = Policy::from_table(table)?;
+    log(&format!(
+        "Execution policy: {} shell binaries, {} shell patterns, {} docker commands, {} docker images",
+        policy.shell_binaries.len(),
+        policy.shell_patterns.len(),
+        policy.docker_commands.len(),
+        policy.docker_images.len()
+    ));
+    POLICY.set(policy).ok();
+    Ok(())
+}
+
+impl Policy {
+    fn from_table(table: &Map<String, Value>) -> Result<Self, String> {
+        let shell = section(table, "shell")?;
+        let docker = section(table, "docker")?;
+        Ok(Policy {
+            shell_binaries: list(shell, "shell", "binaries")?,
+            shell_patterns: list(shell, "shell", "patterns")?
+                .iter()
+                .map(|pattern| {
+                    Regex::new(pattern)
+                        .map_err(|e| format!("Invalid policy.shell pattern '{}': {}", pattern, e))
+                })
+                .collect::<Result<_, _>>()?,
+            docker_commands: list(docker, "docker", "commands")?,
+            docker_images: list(docker, "docker", "images")?
+                .iter()
+                .map(|glob| image_glob(glob))
+                .collect(),
+        })
+    }
+}
+
+fn section<'a>(table: &'a Map<String, Value>, name: &str) -> Result<&'a Value, String> {
+    match &table.get(name) {
+        None => Ok(&Value::Null),
+        Some(value @ Value::Object(_)) => Ok(value),
+        Some(_) => Err(format!("policy.{} must be a table", name)),
+    }
+}
+
+fn list(section: &Value, name: &str, key: &str) -> Result<Vec<String>, String> {
+    string_list(&section[key], key).map_err(|e| format!("policy.{}: {}", name, e))
+}
+
+/// Anchors a `*` glob as a regex.
+fn image_glob(glob: &str) -> Regex {
+    let pattern = glob
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join(".*");
+    Regex::new(&format!("^{}$", pattern)).expect("escaped glob is a valid regex")
+}
+
+/// Checks `task` against the policy before anything runs, including every
+/// step of a BATCH. Returns why an out-of-policy task is refused.
+pub fn check(task: &Task) -> Result<(), String> {
+    match POLICY.get() {
+        Some(policy) => policy.check_task(task),
+        None => Ok(()),
+    }
+}
+
+impl Policy {
+    fn check_task(&self, task: &Task) -> Result<(), String> {
+        self.check_details(task.task_type.as_str(), &task.details)?;
+        if task.task_type == TaskType::BATCH {
+            let steps = task.details["steps"].as_array().into_iter().flatten();
+            for (index, step) in steps.enumerate() {
+                let task_type = step["task_type"].as_str().unwrap_or_default();
+                self.check_details(&task_type.to_ascii_uppercase(), &step["details"])
+                    .map_err(|e| format!("BATCH step {}: {}", index, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_details(&self, task_type: &str, details: &Value) -> Result<(), String> {
+        match task_type {
+            "SHELL" => self.check_shell(details)?,
+            "DOCKER" => self.check_docker(details)?,
+            _ => {}
+        }
+        // A precondition runs locally whatever the task type.
+        match &details["precondition"] {
+            Value::Null => Ok(()),
+            precondition => self
+                .check_shell(precondition)
+                .map_err(|e| format!("precondition: {}", e)),
+        }
+    }
+
+    fn check_shell(&self, details: &Value) -> Result<(), String> {
+        if self.shell_binaries.is_empty() && self.shell_patterns.is_empty() {
+            return Ok(());
+        }
+        let command = details["command"].as_str().unwrap_or_default();
+        if self.shell_binaries.iter().any(|binary| binary == command) {
+            return Ok(());
+        }
+        let mut argv = vec![command.to_string()];
+        argv.extend(string_list(&details["args"], "args")?);
+        let line = shlex::try_join(argv.iter().map(String::as_str))
+            .map_err(|e| format!("SHELL command can't be checked against the policy: {}", e))?;
+        if self.shell_patterns.iter().any(|re| re.is_match(&line)) {
+            return Ok(());
+        }
+        Err(format!("SHELL command '{}' is not allowed by policy", line))
+    }
+
+    fn check_docker(&self, details: &Value) -> Result<(), String> {
+        let command = details["command"].as_str().unwrap_or_default();
+        if !self.docker_commands.is_empty() && !self.docker_commands.iter().any(|c| c == command) {
+            return Err(format!(
+                "Docker command '{}' is not allowed by policy",
+                command
+            ));
+        }
+        if !self.docker_images.is_empty() && !details["compose_yaml"].is_null() {
+            return Err(
+                "Inline 'compose_yaml' is not allowed while policy.docker limits images; use a 'project_dir'"
+                    .to_string(),
+            );
+        }
+        if let Some(image) = details["image"].as_str() {
+            if !self.docker_images.is_empty()
+                && !self
+                    .docker_images
+                    .iter()
+                    .any(|re| re.is_match(image.trim()))
+            {
+                return Err(format!("Docker image '{}' is not allowed by policy", image));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parsed(table: Value) -> Policy {
+        Policy::from_table(table.as_object().unwrap()).unwrap()
+    }
+
+    fn task(task_type: &str, details: Value) -> Task {
+        serde_json::from_value(json!({
+            "id": "t1",
+            "target_host": "*",
+            "task_type": task_type,
+            "details": details,
+        }))
+        .unwrap()
+    }
+
+    fn shell_policy() -> Policy {
+        parsed(json!({"shell": {
+            "binaries": ["df"],
+            "patterns": ["^git -C /srv/\\S+ status$"],
+        }}))
+    }
+
+    #[test]
+    fn allows_listed_binaries_and_matching_command_lines() {
+        let policy = shell_policy();
+        assert_eq!(
+            policy.check_task(&task("SHELL", json!({"command": "df", "args": ["-h"]}))),
+            Ok(())
+        );
+        let git = json!({"command": "git", "args": ["-C", "/srv/app", "status"]});
+        assert_eq!(policy.check_task(&task("SHELL", git)), Ok(()));
+        // Quoting keeps an argument from passing for several.
+        let quoted = json!({"command": "git", "args": ["-C", "/srv/app status; rm", "status"]});
+        assert_eq!(
+            policy.check_task(&task("SHELL", quoted)),
+            Err(
+                "SHELL command 'git -C '/srv/app status; rm' status' is not allowed by policy"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            policy.check_task(&task("SHELL", json!({"command": "rm"}))),
+            Err("SHELL command 'rm' is not allowed by policy".to_string())
+        );
+    }
+
+    #[test]
+    fn checks_preconditions_of_every_task_type() {
+        let policy = shell_policy();
+        let precondition = |command: &str| json!({"precondition": {"command": command}});
+        for task_type in ["SHELL", "DOCKER", "HTTP", "STATUS", "BATCH"] {
+            let mut details = precondition("rm");
+            if task_type == "SHELL" {
+                details["command"] = "df".into();
+            }
+            assert_eq!(
+                policy.check_task(&task(task_type, details)),
+                Err("precondition: SHELL command 'rm' is not allowed by policy".to_string()),
+                "{}",
+                task_type
+            );
+        }
+        assert_eq!(
+            policy.check_task(&task("STATUS", precondition("df"))),
+            Ok(())
+        );
+        let batch = json!({"steps": [{"task_type": "status", "details": precondition("rm")}]});
+        assert_eq!(
+            policy.check_task(&task("BATCH", batch)),
+            Err(
+                "BATCH step 0: precondition: SHELL command 'rm' is not allowed by policy"
+                    .to_string()
+            )
+        );
+        // Without a shell policy any precondition may run.
+        let open = parsed(json!({"docker": {"commands": ["list_containers"]}}));
+        assert_eq!(open.check_task(&task("STATUS", precondition("rm"))), Ok(()));
+    }
+
+    #[test]
+    fn limits_docker_commands_and_images() {
+        let policy = parsed(json!({"docker": {
+            "commands": ["run_container", "compose_up"],
+            "images": ["nginx:*", "registry.internal/*"],
+        }}));
+        let run = |image: &str| json!({"command": "run_container", "image": image});
+        assert_eq!(
+            policy.check_task(&task("DOCKER", run("nginx:1.25"))),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check_task(&task("DOCKER", run("registry.internal/app:2"))),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check_task(&task("DOCKER", run("evil/nginx:1"))),
+            Err("Docker image 'evil/nginx:1' is not allowed by policy".to_string())
+        );
+        assert_eq!(
+            policy.check_task(&task("DOCKER", json!({"command": "remove_container"}))),
+            Err("Docker command 'remove_container' is not allowed by policy".to_string())
+        );
+    }
+
+    #[test]
+    fn refuses_inline_compose_files_while_images_are_limited() {
+        let compose = json!({
+            "command": "compose_up",
+            "compose_yaml": "services:\n  web:\n    image: evil/miner\n",
+        });
+        let limited = parsed(json!({"docker": {"images": ["nginx:*"]}}));
+        assert_eq!(
+            limited.check_task(&task("DOCKER", compose.clone())),
+            Err("Inline 'compose_yaml' is not allowed while policy.docker limits images; use a 'project_dir'".to_string())
+        );
+        let project = json!({"command": "compose_up", "project_dir": "web"});
+        assert_eq!(limited.check_task(&task("DOCKER", project)), Ok(()));
+        let commands_only = parsed(json!({"docker": {"commands": ["compose_up"]}}));
+        assert_eq!(commands_only.check_task(&task("DOCKER", compose)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_malformed_tables() {
+        let table = |value: Value| Policy::from_table(value.as_object().unwrap()).err();
+        assert_eq!(
+            table(json!({"shell": ["df"]})),
+            Some("policy.shell must be a table".to_string())
+        );
+        assert!(table(json!({"shell": {"patterns": ["("]}}))
+            .unwrap()
+            .starts_with("Invalid policy.shell pattern '('"));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::log;
+use crate::result::RESULT_TTL_SECS;
+use tokio::time::{Duration, Instant};
+
+/// Least time between two [`Progress::advance`] entries.
+const ADVANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+// --- Progress Reporting ---
+/// Appends progress entries for a running task to `mcp::progress::<id>`, so
+/// producers can follow slow tasks before the result is written. Each entry
+/// is `{"at","phase","percent","message"}`; `percent` (0 to 100) is null
+/// until the current phase has one, and the newest entry is last. The list
+/// expires along with the result. Without a Redis connection progress is
+/// silently dropped.
+pub struct Progress {
+    conn: Option<redis::aio::MultiplexedConnection>,
+    key: String,
+    phase: String,
+    percent: Option<f64>,
+    tracker: Option<Box<dyn Tracker>>,
+    last_advance: Option<Instant>,
+}
+
+/// Reads progress out of the lines a tool prints, for [`Progress::report`].
+pub trait Tracker: Send {
+    /// The phase `line` starts, if any, and the percentage it puts the
+    /// phase at, if it says.
+    fn observe(&mut self, line: &str) -> (Option<&'static str>, Option<f64>);
+}
+
+impl Progress {
+    pub fn new(conn: Option<redis::aio::MultiplexedConnection>, task_id: &str) -> Self {
+        Progress {
+            conn,
+            key: format!("mcp::progress::{}", task_id),
+            phase: String::new(),
+            percent: None,
+            tracker: None,
+            last_advance: None,
+        }
+    }
+
+    /// Reads phases and percentages out of reported lines with `tracker`.
+    #[cfg_attr(not(feature = "docker"), allow(dead_code))]
+    pub fn tracking(mut self, tracker: impl Tracker + 'static) -> Self {
+        self.tracker = Some(Box::new(tracker));
+        self
+    }
+
+    /// Starts a new phase; its percentage is unknown until updated.
+    pub fn phase(&mut self, phase: &str) {
+        if self.phase != phase {
+            self.phase = phase.to_string();
+            self.percent = None;
+        }
+    }
+
+    /// Records a line of output, with whatever the tracker reads from it.
+    #[cfg_attr(not(feature = "docker"), allow(dead_code))]
+    pub async fn report(&mut self, line: &str) {
+        let (phase, percent) = match self.tracker.as_mut() {
+            Some(tracker) => tracker.observe(line),
+            None => (None, None),
+        };
+        if let Some(phase) = phase {
+            self.phase(phase);
+        }
+        self.update(percent, line).await;
+    }
+
+    /// Records `message`, at `percent` or the phase's last percentage.
+    pub async fn update(&mut self, percent: Option<f64>, message: &str) {
+        if let Some(percent) = percent {
+            self.percent = Some((percent.clamp(0.0, 100.0) * 10.0).round() / 10.0);
+        }
+        let Some(conn) = self.conn.as_mut() else {
+            return;
+        };
+        let entry = serde_json::json!({
+            "at": chrono::Utc::now().to_rfc3339(),
+            "phase": self.phase,
+            "percent": self.percent,
+            "message": message,
+        });
+        let pushed: redis::RedisResult<()> = redis::pipe()
+            .rpush(&self.key, entry.to_string())
+            .ignore()
+            .expire(&self.key, RESULT_TTL_SECS as i64)
+            .ignore()
+            .query_async(conn)
+            .await;
+        if let Err(e) = pushed {
+            log(&format!(
+                "[ERROR] Failed to write progress to {}: {}",
+                self.key, e
+            ));
+        }
+    }
+
+    /// Like [`Progress::update`] for frequent updates, such as per chunk of
+    /// a transfer: at most one is recorded per `ADVANCE_INTERVAL`, besides
+    /// the one reaching 100%.
+    pub async fn advance(&mut self, percent: Option<f64>, message: &str) {
+        let done = percent.is_some_and(|percent| percent >= 100.0);
+        if !done
+            && self
+                .last_advance
+                .is_some_and(|last| last.elapsed() < ADVANCE_INTERVAL)
+        {
+            return;
+        }
+        self.last_advance = Some(Instant::now());
+        self.update(percent, message).await;
+    }
+}
+
+/// `done` out of `total` as a percentage; unknown without a total.
+pub fn percent(done: u64, total: Option<u64>) -> Option<f64> {
+    match total {
+        Some(0) => Some(100.0),
+        Some(total) => Some(done as f64 * 100.0 / total as f64),
+        None => None,
+    }
+}
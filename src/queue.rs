@@ -0,0 +1,459 @@
+use crate::config::Config;
+use crate::connection::Connections;
+use crate::log;
+use crate::metrics;
+use crate::recovery;
+use crate::result;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Direction};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration, Instant};
+
+// --- Queue Backends ---
+/// A popped payload as raw bytes, with the name of the queue it came from.
+pub type Popped = Option<(String, Vec<u8>)>;
+
+/// The queue operations the command listener needs, so the core loop can run
+/// against Redis or an in-memory backend.
+pub trait TaskQueue {
+    /// Pops the next payload from the first non-empty queue, waiting up to
+    /// `timeout_secs`, and keeps it in the worker's in-progress list for that
+    /// queue until [`ack`](TaskQueue::ack)ed. Returns the queue name alongside the raw
+    /// payload bytes, which the listener decodes itself so non-UTF-8 input
+    /// can be dead-lettered with a clear reason.
+    fn pop(
+        &mut self,
+        queues: &[&str],
+        timeout_secs: f64,
+    ) -> impl Future<Output = Result<Popped, String>> + Send;
+
+    /// Removes a payload popped from `queue` from the in-progress list once it
+    /// has been dealt with (its result written, or it was re-queued or
+    /// dead-lettered).
+    fn ack(
+        &mut self,
+        queue: &str,
+        payload: &[u8],
+    ) -> impl Future<Output = Result<(), String>> + Send;
+
+    /// Appends a payload to the tail of a queue.
+    fn push(
+        &mut self,
+        queue: &str,
+        payload: &str,
+    ) -> impl Future<Output = Result<(), String>> + Send;
+
+    /// Stores a serialized task result under `key` for `ttl_secs`. Returns `false` when
+    /// an existing result was kept because `overwrite` is off.
+    fn store_result(
+        &mut self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: u64,
+        overwrite: bool,
+    ) -> impl Future<Output = Result<bool, String>> + Send;
+
+    /// Like `store_result`, but writes the result as a set of hash fields.
+    /// Also returns the `worker_id` of a result already stored under `key`.
+    fn store_result_hash(
+        &mut self,
+        key: &str,
+        fields: &[(&str, String)],
+        ttl_secs: u64,
+        overwrite: bool,
+    ) -> impl Future<Output = Result<(bool, Option<String>), String>> + Send;
+
+    /// Like `store_result`, for a result split into chunks: writes
+    /// `manifest` under `key` and each chunk under `<key>::<n>`, all at once.
+    fn store_result_chunks(
+        &mut self,
+        key: &str,
+        manifest: Vec<u8>,
+        chunks: Vec<Vec<u8>>,
+        ttl_secs: u64,
+        overwrite: bool,
+    ) -> impl Future<Output = Result<bool, String>> + Send;
+
+    /// Direct Redis access for features beyond plain queueing (progress,
+    /// log streams, control keys). `None` for backends without Redis.
+    fn redis_connection(&self) -> Option<MultiplexedConnection>;
+
+    /// A handle onto the same queues and results for one task running
+    /// alongside the listener, which keeps popping through `self`.
+    fn job(&self) -> Self;
+}
+
+/// The production backend: Redis lists for queues, string or hash keys for
+/// results. Pops are `BLMOVE`s into `mcp::processing::<worker_id>::<queue>`,
+/// so a task in hand survives a crash there, along with the queue it came
+/// from (needs Redis 6.2+). A background PING
+/// every `REDIS_PING_INTERVAL_SECS` catches half-open connections while idle
+/// and makes the next pop reconnect.
+pub struct RedisQueue {
+    conns: Connections,
+    config: Config,
+    ping_failed: Arc<Notify>,
+    pinger: Option<JoinHandle<()>>,
+}
+
+/// How long a health-check PING may take before the connection is presumed dead.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// With several queues, how long each blocking wait on the first one lasts
+/// before the others are checked again.
+const MULTI_QUEUE_WAIT: Duration = Duration::from_secs(1);
+
+impl RedisQueue {
+    pub fn new(conns: Connections, config: &Config) -> Self {
+        let mut queue = RedisQueue {
+            conns,
+            config: config.clone(),
+            ping_failed: Arc::new(Notify::new()),
+            pinger: None,
+        };
+        queue.start_pinger();
+        queue
+    }
+
+    /// (Re)starts the PING task against the current connections.
+    fn start_pinger(&mut self) {
+        if let Some(old) = self.pinger.take() {
+            old.abort();
+        }
+        if self.config.redis_ping_interval_secs == 0 {
+            return;
+        }
+        let interval = Duration::from_secs(self.config.redis_ping_interval_secs);
+        let mut conn = self.conns.shared();
+        let ping_failed = self.ping_failed.clone();
+        self.pinger = Some(tokio::spawn(async move {
+            loop {
+                time::sleep(interval).await;
+                let cmd = redis::cmd("PING");
+                let ping = cmd.query_async::<_, String>(&mut conn);
+                let error = match time::timeout(PING_TIMEOUT, ping).await {
+                    Ok(Ok(_)) => continue,
+                    Ok(Err(e)) => e.to_string(),
+                    Err(_) => format!("no reply within {:?}", PING_TIMEOUT),
+                };
+                log(&format!("[ERROR] Redis PING failed: {}", error));
+                ping_failed.notify_one();
+                return;
+            }
+        }));
+    }
+
+    /// Replaces the connections, following Sentinel to the current master
+    /// when configured.
+    async fn reconnect(&mut self, cause: &str) {
+        match Connections::open(&self.config).await {
+            Ok(new_conns) => {
+                log(&format!("Reconnected to Redis after {}.", cause));
+                metrics::redis_reconnected();
+                self.conns = new_conns;
+            }
+            Err(e) => log(&format!(
+                "[ERROR] Redis reconnect after {} failed: {}",
+                cause, e
+            )),
+        }
+        self.start_pinger();
+    }
+}
+
+impl TaskQueue for RedisQueue {
+    async fn pop(&mut self, queues: &[&str], timeout_secs: f64) -> Result<Popped, String> {
+        let ping_failed = self.ping_failed.clone();
+        let moved = move_next(
+            self.conns.listener(),
+            queues,
+            &self.config.worker_id,
+            timeout_secs,
+        );
+        let popped: Option<redis::RedisResult<Popped>> = tokio::select! {
+            popped = moved => Some(popped),
+            _ = ping_failed.notified() => None,
+        };
+        match popped {
+            None => {
+                self.reconnect("a failed PING").await;
+                Err("Redis PING failed; connection presumed dead".to_string())
+            }
+            Some(Err(e)) => {
+                // A dead or timed-out connection won't recover on its own, and
+                // behind Sentinel any error may be a failover to follow.
+                if !self.config.redis_sentinels.is_empty()
+                    || e.is_timeout()
+                    || e.is_connection_dropped()
+                    || e.is_io_error()
+                {
+                    self.reconnect(&format!("'{}'", e)).await;
+                }
+                Err(format!("{:?}", e))
+            }
+            Some(Ok(popped)) => Ok(popped),
+        }
+    }
+
+    async fn ack(&mut self, queue: &str, payload: &[u8]) -> Result<(), String> {
+        self.conns
+            .writer()
+            .await
+            .lrem(
+                recovery::processing_key(&self.config.worker_id, queue),
+                1,
+                payload,
+            )
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn push(&mut self, queue: &str, payload: &str) -> Result<(), String> {
+        self.conns
+            .writer()
+            .await
+            .rpush(queue, payload)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn store_result(
+        &mut self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: u64,
+        overwrite: bool,
+    ) -> Result<bool, String> {
+        let mut writer = self.conns.writer().await;
+        result::set_result(&mut writer, key, value, ttl_secs, overwrite)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn store_result_hash(
+        &mut self,
+        key: &str,
+        fields: &[(&str, String)],
+        ttl_secs: u64,
+        overwrite: bool,
+    ) -> Result<(bool, Option<String>), String> {
+        let mut writer = self.conns.writer().await;
+        result::set_result_hash(&mut writer, key, fields, ttl_secs, overwrite)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn store_result_chunks(
+        &mut self,
+        key: &str,
+        manifest: Vec<u8>,
+        chunks: Vec<Vec<u8>>,
+        ttl_secs: u64,
+        overwrite: bool,
+    ) -> Result<bool, String> {
+        let mut writer = self.conns.writer().await;
+        result::set_result_chunks(&mut writer, key, manifest, chunks, ttl_secs, overwrite)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    fn redis_connection(&self) -> Option<MultiplexedConnection> {
+        Some(self.conns.shared())
+    }
+
+    /// Shares the connections but runs no pinger; only the listener's handle
+    /// pops, so only it needs to notice a dead connection.
+    fn job(&self) -> Self {
+        RedisQueue {
+            conns: self.conns.clone(),
+            config: self.config.clone(),
+            ping_failed: Arc::new(Notify::new()),
+            pinger: None,
+        }
+    }
+}
+
+/// Moves the head of the first non-empty queue to the tail of the worker's
+/// processing list for that queue. `BLMOVE` only watches one list, so with
+/// several queues every pass checks them all in order and then blocks
+/// briefly on the first.
+async fn move_next(
+    conn: &mut MultiplexedConnection,
+    queues: &[&str],
+    worker_id: &str,
+    timeout_secs: f64,
+) -> redis::RedisResult<Popped> {
+    let deadline = Instant::now() + Duration::from_secs_f64(timeout_secs);
+    loop {
+        for name in queues {
+            let processing = recovery::processing_key(worker_id, name);
+            let moved: Option<Vec<u8>> = conn
+                .lmove(*name, &processing, Direction::Left, Direction::Right)
+                .await?;
+            if let Some(payload) = moved {
+                return Ok(Some((name.to_string(), payload)));
+            }
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        let (Some(first), false) = (queues.first(), left.is_zero()) else {
+            return Ok(None);
+        };
+        let wait = if queues.len() == 1 {
+            left
+        } else {
+            left.min(MULTI_QUEUE_WAIT)
+        };
+        let moved: Option<Vec<u8>> = conn
+            .blmove(
+                *first,
+                recovery::processing_key(worker_id, first),
+                Direction::Left,
+                Direction::Right,
+                wait.as_secs_f64(),
+            )
+            .await?;
+        if let Some(payload) = moved {
+            return Ok(Some((first.to_string(), payload)));
+        }
+    }
+}
+
+/// An in-memory [`TaskQueue`] for driving the listener in tests.
+#[cfg(test)]
+pub mod memory {
+    use super::{Popped, TaskQueue};
+    use crate::packing;
+    use redis::aio::MultiplexedConnection;
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    /// A `VecDeque`-backed queue for exercising the listener without Redis.
+    /// Clones (and jobs) share the same state.
+    #[derive(Default, Clone)]
+    pub struct MemoryQueue {
+        state: Arc<Mutex<MemoryState>>,
+    }
+
+    #[derive(Default)]
+    pub struct MemoryState {
+        pub queues: HashMap<String, VecDeque<String>>,
+        /// Popped payloads not yet acked, with the queue each came from.
+        pub processing: Vec<(String, Vec<u8>)>,
+        pub results: HashMap<String, Vec<u8>>,
+    }
+
+    impl MemoryQueue {
+        pub fn new() -> Self {
+            MemoryQueue::default()
+        }
+
+        pub fn state(&self) -> MutexGuard<'_, MemoryState> {
+            self.state.lock().unwrap()
+        }
+    }
+
+    impl TaskQueue for MemoryQueue {
+        /// Never waits: returns `None` as soon as every queue is empty.
+        async fn pop(&mut self, queues: &[&str], _timeout_secs: f64) -> Result<Popped, String> {
+            let mut state = self.state();
+            for name in queues {
+                if let Some(payload) = state.queues.get_mut(*name).and_then(VecDeque::pop_front) {
+                    state
+                        .processing
+                        .push((name.to_string(), payload.clone().into_bytes()));
+                    return Ok(Some((name.to_string(), payload.into_bytes())));
+                }
+            }
+            Ok(None)
+        }
+
+        async fn ack(&mut self, queue: &str, payload: &[u8]) -> Result<(), String> {
+            let mut state = self.state();
+            if let Some(pos) = state
+                .processing
+                .iter()
+                .position(|(name, p)| name == queue && p == payload)
+            {
+                state.processing.remove(pos);
+            }
+            Ok(())
+        }
+
+        async fn push(&mut self, queue: &str, payload: &str) -> Result<(), String> {
+            self.state()
+                .queues
+                .entry(queue.to_string())
+                .or_default()
+                .push_back(payload.to_string());
+            Ok(())
+        }
+
+        /// Results never expire in memory; `ttl_secs` is ignored.
+        async fn store_result(
+            &mut self,
+            key: &str,
+            value: Vec<u8>,
+            _ttl_secs: u64,
+            overwrite: bool,
+        ) -> Result<bool, String> {
+            let mut state = self.state();
+            if !overwrite && state.results.contains_key(key) {
+                return Ok(false);
+            }
+            state.results.insert(key.to_string(), value);
+            Ok(true)
+        }
+
+        /// Hash results are kept as a JSON object of their fields.
+        async fn store_result_hash(
+            &mut self,
+            key: &str,
+            fields: &[(&str, String)],
+            ttl_secs: u64,
+            overwrite: bool,
+        ) -> Result<(bool, Option<String>), String> {
+            let previous = self
+                .state()
+                .results
+                .get(key)
+                .and_then(|stored| serde_json::from_slice::<serde_json::Value>(stored).ok())
+                .and_then(|stored| stored["worker_id"].as_str().map(str::to_string));
+            let object: serde_json::Map<String, serde_json::Value> = fields
+                .iter()
+                .map(|(field, value)| (field.to_string(), value.clone().into()))
+                .collect();
+            let value = serde_json::Value::Object(object).to_string().into_bytes();
+            let written = self.store_result(key, value, ttl_secs, overwrite).await?;
+            Ok((written, previous))
+        }
+
+        async fn store_result_chunks(
+            &mut self,
+            key: &str,
+            manifest: Vec<u8>,
+            chunks: Vec<Vec<u8>>,
+            _ttl_secs: u64,
+            overwrite: bool,
+        ) -> Result<bool, String> {
+            let mut state = self.state();
+            if !overwrite && state.results.contains_key(key) {
+                return Ok(false);
+            }
+            for (n, chunk) in chunks.into_iter().enumerate() {
+                state.results.insert(packing::chunk_key(key, n), chunk);
+            }
+            state.results.insert(key.to_string(), manifest);
+            Ok(true)
+        }
+
+        fn redis_connection(&self) -> Option<MultiplexedConnection> {
+            None
+        }
+
+        fn job(&self) -> Self {
+            self.clone()
+        }
+    }
+}
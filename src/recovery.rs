@@ -0,0 +1,284 @@
+use crate::config::Config;
+use crate::log;
+use crate::signing;
+use redis::AsyncCommands;
+use serde::Serialize;
+
+// --- Crash Recovery Report ---
+/// Summary of what a previous run of this worker left in its processing
+/// lists (`mcp::processing::<worker_id>::<queue>`, see [`processing_key`]),
+/// built once at startup so crash recoveries are visible.
+#[derive(Serialize, Debug)]
+pub struct RecoveryReport {
+    pub worker_id: String,
+    /// The processing lists that held anything, one per source queue.
+    pub processing_keys: Vec<String>,
+    pub checked_at: String,
+    pub count: usize,
+    pub tasks: Vec<AbandonedTask>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AbandonedTask {
+    /// `None` when the entry isn't a parseable task.
+    pub id: Option<String>,
+    /// The queue it was popped from, and goes back to.
+    pub queue: String,
+    /// Age from the payload's `enqueued_at`, when present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_secs: Option<i64>,
+}
+
+/// The list a task popped from `queue` sits in until this worker is done
+/// with it: `mcp::processing::<worker_id>::<queue>`, e.g.
+/// `mcp::processing::web-1::mcp::tasks::shell`. There is one list per queue
+/// rather than a single `mcp::processing::<worker_id>`, because a payload
+/// doesn't say which queue it came from (custom, host-scoped, priority and
+/// prefixed queues all look alike), and recovery has to put it back there.
+/// To see everything a worker holds, `SCAN` for
+/// `mcp::processing::<worker_id>::*`. An earlier version's single list is
+/// not recovered; drain it by hand before upgrading a worker that crashed.
+pub fn processing_key(worker_id: &str, queue: &str) -> String {
+    format!("mcp::processing::{}::{}", worker_id, queue)
+}
+
+/// This worker's non-empty processing lists, with the queue each is for.
+async fn processing_lists(
+    conn: &mut redis::aio::MultiplexedConnection,
+    worker_id: &str,
+) -> redis::RedisResult<Vec<(String, String)>> {
+    let prefix = processing_key(worker_id, "");
+    let mut keys: Vec<String> = Vec::new();
+    let mut iter: redis::AsyncIter<String> = conn.scan_match(format!("{}*", prefix)).await?;
+    while let Some(key) = iter.next_item().await {
+        keys.push(key);
+    }
+    keys.sort_unstable();
+    keys.dedup();
+    Ok(keys
+        .into_iter()
+        .filter_map(|key| {
+            let queue = key.strip_prefix(&prefix)?.to_string();
+            Some((key, queue))
+        })
+        .collect())
+}
+
+pub fn recovery_key(worker_id: &str) -> String {
+    format!("mcp::recovery::{}", worker_id)
+}
+
+/// Logs what is sitting in this worker's processing lists and, with
+/// `RECOVERY_REPORT` set, writes the report to `mcp::recovery::<worker_id>`.
+/// The lists themselves are left to [`requeue_abandoned`].
+pub async fn report_abandoned(conn: &mut redis::aio::MultiplexedConnection, config: &Config) {
+    let lists = match processing_lists(conn, &config.worker_id).await {
+        Ok(lists) => lists,
+        Err(e) => {
+            log(&format!("[ERROR] Failed to find processing lists: {}", e));
+            return;
+        }
+    };
+    let now = chrono::Utc::now();
+    let mut tasks = Vec::new();
+    let mut keys = Vec::new();
+    for (key, queue) in lists {
+        let entries: Vec<String> = match conn.lrange(&key, 0, -1).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                log(&format!("[ERROR] Failed to read {}: {}", key, e));
+                continue;
+            }
+        };
+        tasks.extend(entries.iter().map(|e| abandoned(e, &queue, now)));
+        keys.push(key);
+    }
+    if tasks.is_empty() {
+        log(&format!(
+            "Recovery: no abandoned tasks in {}*",
+            processing_key(&config.worker_id, "")
+        ));
+    } else {
+        let summary: Vec<String> = tasks
+            .iter()
+            .map(|t| match t.age_secs {
+                Some(age) => format!(
+                    "{} from {} ({}s old)",
+                    t.id.as_deref().unwrap_or("<unparseable>"),
+                    t.queue,
+                    age
+                ),
+                None => format!(
+                    "{} from {}",
+                    t.id.as_deref().unwrap_or("<unparseable>"),
+                    t.queue
+                ),
+            })
+            .collect();
+        log(&format!(
+            "[WARN] Recovery: {} abandoned task(s): {}",
+            tasks.len(),
+            summary.join(", ")
+        ));
+    }
+
+    if !config.recovery_report {
+        return;
+    }
+    let report = RecoveryReport {
+        worker_id: config.worker_id.clone(),
+        processing_keys: keys,
+        checked_at: now.to_rfc3339(),
+        count: tasks.len(),
+        tasks,
+    };
+    let report_json = match serde_json::to_string(&report) {
+        Ok(s) => s,
+        Err(e) => {
+            log(&format!(
+                "[ERROR] Failed to serialize recovery report: {}",
+                e
+            ));
+            return;
+        }
+    };
+    if let Err(e) = conn
+        .set::<_, _, ()>(recovery_key(&config.worker_id), report_json)
+        .await
+    {
+        log(&format!("[ERROR] Failed to write recovery report: {}", e));
+    }
+}
+
+/// Moves every entry of this worker's processing lists (tasks a crashed run
+/// never finished) back to the front of the queue it was popped from, oldest
+/// first, so they run again with that queue's settings. With
+/// `MCP_TASK_SECRET` a task is re-signed, as its nonce was already used.
+/// Must run before the listeners start adding to the lists.
+pub async fn requeue_abandoned(conn: &mut redis::aio::MultiplexedConnection, config: &Config) {
+    let lists = match processing_lists(conn, &config.worker_id).await {
+        Ok(lists) => lists,
+        Err(e) => {
+            log(&format!("[ERROR] Failed to find processing lists: {}", e));
+            return;
+        }
+    };
+    for (key, queue) in lists {
+        loop {
+            // The newest entry goes first, so the oldest ends up at the head.
+            let entry: Option<Vec<u8>> = match conn.lindex(&key, -1).await {
+                Ok(entry) => entry,
+                Err(e) => {
+                    log(&format!("[ERROR] Failed to read {}: {}", key, e));
+                    return;
+                }
+            };
+            let Some(entry) = entry else { break };
+            let task = std::str::from_utf8(&entry)
+                .ok()
+                .and_then(|json| config.envelope.parse(json).ok());
+            let moved: redis::RedisResult<()> = redis::pipe()
+                .atomic()
+                .lrem(&key, -1, &entry)
+                .ignore()
+                .lpush(&queue, resigned(&entry, config))
+                .ignore()
+                .query_async(conn)
+                .await;
+            if let Err(e) = moved {
+                log(&format!("[ERROR] Failed to re-queue from {}: {}", key, e));
+                return;
+            }
+            log(&format!(
+                "Recovery: re-queued task {} on {}",
+                task.as_ref().map_or("<unparseable>", |t| t.id.as_str()),
+                queue
+            ));
+        }
+    }
+}
+
+/// `entry` re-signed when tasks are signed and it is a JSON object with a
+/// matching signature; otherwise unchanged, so the listener refuses it if
+/// it had to be signed.
+fn resigned(entry: &[u8], config: &Config) -> Vec<u8> {
+    if config.task_secret.is_none() {
+        return entry.to_vec();
+    }
+    match serde_json::from_slice::<serde_json::Value>(entry) {
+        Ok(serde_json::Value::Object(mut fields)) => {
+            if let Err(e) = signing::check_signature(&fields, config) {
+                log(&format!(
+                    "[WARN] Recovery: not re-signing task {}: {}",
+                    fields
+                        .get("id")
+                        .and_then(|id| id.as_str())
+                        .unwrap_or("<no id>"),
+                    e
+                ));
+                return entry.to_vec();
+            }
+            signing::resign(&mut fields, config);
+            serde_json::Value::Object(fields).to_string().into_bytes()
+        }
+        _ => entry.to_vec(),
+    }
+}
+
+/// Extracts the id and age of a processing-list entry. `enqueued_at` may be
+/// an RFC3339 string or Unix seconds.
+fn abandoned(entry: &str, queue: &str, now: chrono::DateTime<chrono::Utc>) -> AbandonedTask {
+    let value: serde_json::Value = serde_json::from_str(entry).unwrap_or_default();
+    let enqueued_at = match &value["enqueued_at"] {
+        serde_json::Value::String(s) => chrono::DateTime::parse_from_rfc3339(s)
+            .ok()
+            .map(|t| t.timestamp()),
+        serde_json::Value::Number(n) => n.as_i64(),
+        _ => None,
+    };
+    AbandonedTask {
+        id: value["id"].as_str().map(str::to_string),
+        queue: queue.to_string(),
+        age_secs: enqueued_at.map(|t| now.timestamp() - t),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn config() -> Config {
+        let mut config = Config::for_tests();
+        config.task_secret = Some("k3y".to_string());
+        config
+    }
+
+    fn fields(entry: &[u8]) -> serde_json::Map<String, Value> {
+        serde_json::from_slice(entry).unwrap()
+    }
+
+    #[test]
+    fn re_signs_a_signed_task() {
+        let config = config();
+        let mut original = fields(br#"{"id":"t1","task_type":"STATUS"}"#);
+        signing::resign(&mut original, &config);
+        let entry = Value::Object(original.clone()).to_string();
+
+        let requeued = fields(&resigned(entry.as_bytes(), &config));
+        assert_ne!(requeued["nonce"], original["nonce"]);
+        assert_eq!(signing::check_signature(&requeued, &config), Ok(()));
+    }
+
+    #[test]
+    fn leaves_a_forged_task_unsigned() {
+        let config = config();
+        let forged = json!({"id": "t1", "task_type": "SHELL", "details": {"command": "id"}});
+        let entry = forged.to_string();
+        assert_eq!(resigned(entry.as_bytes(), &config), entry.as_bytes());
+
+        let tampered = json!({"id": "t1", "task_type": "SHELL", "signature": "00", "nonce": "n"});
+        let entry = tampered.to_string();
+        assert_eq!(resigned(entry.as_bytes(), &config), entry.as_bytes());
+    }
+}
@@ -0,0 +1,173 @@
+use serde_json::Value;
+
+/// What a secret value is replaced with wherever it would be shown.
+pub const REDACTED: &str = "***";
+
+// --- Secret Redaction ---
+/// Names of detail fields whose values must never be logged: those listed in
+/// `details.secret_fields` plus any field whose name starts with `_`.
+fn secret_fields(details: &Value) -> Vec<String> {
+    details["secret_fields"]
+        .as_array()
+        .map(|fields| {
+            fields
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn is_secret(key: &str, secret_fields: &[String]) -> bool {
+    key.starts_with('_') || secret_fields.iter().any(|f| f == key)
+}
+
+/// Replaces secret values anywhere under `value` (including nested maps such
+/// as `env`) with `***`.
+fn redact_value(value: &mut Value, secret_fields: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map.iter_mut() {
+                if is_secret(key, secret_fields) {
+                    *v = Value::String(REDACTED.to_string());
+                } else {
+                    redact_value(v, secret_fields);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                redact_value(item, secret_fields);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns a loggable copy of a raw task payload with secret detail values
+/// redacted. Payloads that aren't JSON objects are returned unchanged.
+pub fn redact_payload(json_str: &str) -> String {
+    let Ok(mut value) = serde_json::from_str::<Value>(json_str) else {
+        return json_str.to_string();
+    };
+    let fields = secret_fields(&value["details"]);
+    if let Some(details) = value.get_mut("details") {
+        redact_value(details, &fields);
+    }
+    value.to_string()
+}
+
+/// Collects the plain-text secret values in `details`, for scrubbing free-form
+/// text such as command errors before it is logged. Every string under a
+/// secret field counts, since [`redact_payload`] hides all of it.
+pub fn secret_values(details: &Value) -> Vec<String> {
+    fn collect(value: &Value, fields: &[String], secret: bool, out: &mut Vec<String>) {
+        match value {
+            Value::String(s) if secret && !s.is_empty() => out.push(s.clone()),
+            Value::Object(map) => {
+                for (key, v) in map {
+                    collect(v, fields, secret || is_secret(key, fields), out);
+                }
+            }
+            Value::Array(items) => items.iter().for_each(|v| collect(v, fields, secret, out)),
+            _ => {}
+        }
+    }
+
+    let mut out = Vec::new();
+    collect(details, &secret_fields(details), false, &mut out);
+    out
+}
+
+/// Replaces every occurrence of a secret value in `text` with `***`. Longer
+/// secrets go first, so one that contains another isn't left half shown.
+pub fn scrub(text: &str, secrets: &[String]) -> String {
+    let mut secrets: Vec<&String> = secrets.iter().collect();
+    secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    secrets.into_iter().fold(text.to_string(), |acc, secret| {
+        acc.replace(secret.as_str(), REDACTED)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn redacted(payload: Value) -> Value {
+        serde_json::from_str(&redact_payload(&payload.to_string())).unwrap()
+    }
+
+    #[test]
+    fn redacts_secret_fields_anywhere_in_details() {
+        let payload = json!({
+            "id": "t1",
+            "_top": "outside details",
+            "details": {
+                "command": "deploy",
+                "_token": "s3cret",
+                "password": "hunter2",
+                "secret_fields": ["password"],
+                "env": {"_KEY": "k", "PATH": "/bin"},
+                "steps": [{"details": {"password": "nested", "_n": 7}}],
+                "_creds": {"user": "admin"},
+            },
+        });
+        assert_eq!(
+            redacted(payload),
+            json!({
+                "id": "t1",
+                "_top": "outside details",
+                "details": {
+                    "command": "deploy",
+                    "_token": "***",
+                    "password": "***",
+                    "secret_fields": ["password"],
+                    "env": {"_KEY": "***", "PATH": "/bin"},
+                    "steps": [{"details": {"password": "***", "_n": "***"}}],
+                    "_creds": "***",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn leaves_payloads_without_secrets_alone() {
+        assert_eq!(redact_payload("not json {"), "not json {");
+        assert_eq!(redact_payload("[1,2]"), "[1,2]");
+        let payload = json!({"id": "t1", "details": {"command": "df"}});
+        assert_eq!(redacted(payload.clone()), payload);
+        // A malformed secret_fields list names nothing.
+        let payload = json!({"details": {"secret_fields": "password", "password": "p"}});
+        assert_eq!(redacted(payload.clone()), payload);
+    }
+
+    #[test]
+    fn collects_every_string_under_a_secret_field() {
+        let details = json!({
+            "command": "deploy",
+            "_token": "s3cret",
+            "_empty": "",
+            "_port": 5432,
+            "password": "hunter2",
+            "secret_fields": ["password", 7],
+            "env": {"_KEY": "k"},
+            "_creds": {"user": "admin", "keys": ["a1", "b2"]},
+        });
+        let mut values = secret_values(&details);
+        values.sort();
+        assert_eq!(values, ["a1", "admin", "b2", "hunter2", "k", "s3cret"]);
+        assert!(secret_values(&json!("details")).is_empty());
+    }
+
+    #[test]
+    fn scrubs_every_occurrence() {
+        let secrets = ["abc".to_string(), "abcdef".to_string()];
+        assert_eq!(
+            scrub("abcdef then abc, abcabc", &secrets),
+            "*** then ***, ******"
+        );
+        assert_eq!(scrub("nothing here", &[]), "nothing here");
+    }
+}
@@ -0,0 +1,403 @@
+use crate::config::Config;
+use crate::envelope::{self, Envelope};
+use crate::exit::Captured;
+use crate::log;
+use crate::packing;
+use crate::queue::TaskQueue;
+use crate::serializer::TaskResult;
+use crate::Task;
+use serde::Serialize;
+use std::time::Duration;
+
+/// TTL for result-adjacent keys (progress, cancellation counts) and the
+/// default result TTL when `RESULT_TTL_SECS` is unset.
+pub const RESULT_TTL_SECS: u64 = 3600;
+
+/// How results are laid out in Redis, from `RESULT_STORAGE`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultStorage {
+    /// A string in the `RESULT_FORMAT` encoding (the default).
+    String,
+    /// A hash with `status`, `output`, `output_encoding` (`identity`,
+    /// `base64`, `gzip` or `base64, gzip`), `duration_ms`, `worker_id`,
+    /// `redis_db`, `correlation_id` and `key_prefix` fields, plus `exit_code`
+    /// when the task ran a process that exited and `region` and `zone` when
+    /// `WORKER_REGION` and `WORKER_ZONE` are set.
+    Hash,
+}
+
+impl ResultStorage {
+    pub fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "hash" => ResultStorage::Hash,
+            _ => ResultStorage::String,
+        }
+    }
+}
+
+/// How a task run ended, for [`store`].
+pub struct Outcome {
+    pub result: Result<String, String>,
+    /// The status for a failed task in place of `ERROR` (see
+    /// [`crate::cancel::stopped_status`]).
+    pub stopped: Option<&'static str>,
+    pub captured: Captured,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub duration: Duration,
+}
+
+/// Writes a task result to the queue backend, using the TTL and key prefix
+/// configured for the queue the task came from. Unless `RESULT_OVERWRITE` is
+/// set, the first worker to finish a task keeps its result when the same id
+/// is delivered twice (a `force` task replaces it); with it, overwriting a
+/// hash result written by another worker logs a warning, since that usually
+/// means tenants sharing a key space through a misconfigured prefix or
+/// database. String results are encoded by the `RESULT_FORMAT` serializer,
+/// except that JSON-RPC tasks get a JSON-RPC response object. With
+/// `RESULT_ENCRYPTION_KEY` set the string value, or the hash's `output`
+/// field, is stored encrypted; a result that can't be encrypted is never
+/// written in plaintext. Large results are gzipped before
+/// encryption (a hash's `output` is then base64 with `gzip` last in
+/// `output_encoding`, which otherwise names the output's own encoding, such
+/// as `base64`), and a compressed or oversized string result is stored in
+/// chunks behind a manifest (see [`packing::chunk`]).
+pub async fn store<Q: TaskQueue>(
+    queue: &mut Q,
+    config: &Config,
+    queue_name: &str,
+    task: &Task,
+    outcome: Outcome,
+) {
+    let Outcome {
+        result: task_result,
+        stopped,
+        captured,
+        started_at,
+        duration,
+    } = outcome;
+    let task_id = &task.id;
+    let key_prefix = config.result_key_prefix_for(queue_name);
+    let key = config.result_key_for(queue_name, task_id);
+    let ttl_secs = config.result_ttl_for(queue_name);
+    // A forced rerun replaces the result of the run it repeats.
+    let overwrite = config.result_overwrite || task.force;
+    let rpc_response = (config.envelope == Envelope::JsonRpc)
+        .then(|| envelope::jsonrpc_response(task, &task_result));
+    let (status, output) = match task_result {
+        Ok(output) => ("SUCCESS", output),
+        Err(e) => (stopped.unwrap_or("ERROR"), e),
+    };
+    let seal = |plaintext: Vec<u8>| match &config.result_cipher {
+        Some(cipher) => cipher.seal(&plaintext).map(String::into_bytes),
+        None => Ok(plaintext),
+    };
+    let seal_text = |plaintext: String| match &config.result_cipher {
+        Some(cipher) => cipher.seal(plaintext.as_bytes()),
+        None => Ok(plaintext),
+    };
+    let written = match config.result_storage {
+        ResultStorage::String => {
+            let value = rpc_response.map(String::into_bytes).unwrap_or_else(|| {
+                let succeeded = status == "SUCCESS";
+                config.result_format.serializer().serialize(&TaskResult {
+                    task_id,
+                    status,
+                    exit_code: captured.exit_code,
+                    stdout: succeeded.then_some(output.as_str()),
+                    output_encoding: captured.output_encoding(),
+                    stderr: captured.stderr.as_deref(),
+                    error: (!succeeded).then_some(output.as_str()),
+                    started_at: started_at.to_rfc3339(),
+                    finished_at: (started_at + duration).to_rfc3339(),
+                    duration_ms: duration.as_millis() as u64,
+                    worker_id: &config.worker_id,
+                    region: config.worker_region.as_deref(),
+                    zone: config.worker_zone.as_deref(),
+                    correlation_id: task.correlation_id(),
+                })
+            });
+            let (value, compressed) = packing::compress(value, config);
+            match seal(value) {
+                Ok(value) => {
+                    match packing::chunk(&key, &compressed, &value, status, task_id, config) {
+                        Some((manifest, chunks)) => {
+                            queue
+                                .store_result_chunks(&key, manifest, chunks, ttl_secs, overwrite)
+                                .await
+                        }
+                        None => queue.store_result(&key, value, ttl_secs, overwrite).await,
+                    }
+                }
+                Err(e) => Err(e),
+            }
+        }
+        ResultStorage::Hash => {
+            let (output, compression) = packing::compress_text(output, config);
+            // Encodings in the order they were applied, like HTTP's
+            // Content-Encoding.
+            let output_encoding = match (status == "SUCCESS")
+                .then(|| captured.output_encoding())
+                .filter(|encoding| *encoding != "text")
+            {
+                Some(encoding) if compression != "identity" => {
+                    format!("{}, {}", encoding, compression)
+                }
+                Some(encoding) => encoding.to_string(),
+                None => compression.to_string(),
+            };
+            match seal_text(output) {
+                Ok(output) => {
+                    let mut fields = vec![
+                        ("status", status.to_string()),
+                        ("output", output),
+                        ("output_encoding", output_encoding),
+                        ("duration_ms", duration.as_millis().to_string()),
+                        ("worker_id", config.worker_id.clone()),
+                        ("redis_db", config.redis_db.to_string()),
+                        ("correlation_id", task.correlation_id().to_string()),
+                        ("key_prefix", key_prefix.to_string()),
+                    ];
+                    if let Some(exit_code) = captured.exit_code {
+                        fields.push(("exit_code", exit_code.to_string()));
+                    }
+                    if let Some(region) = &config.worker_region {
+                        fields.push(("region", region.clone()));
+                    }
+                    if let Some(zone) = &config.worker_zone {
+                        fields.push(("zone", zone.clone()));
+                    }
+                    queue
+                    .store_result_hash(&key, &fields, ttl_secs, overwrite)
+                    .await
+                    .map(|(written, previous)| {
+                        // Only another worker's result is suspicious; a
+                        // redelivered task rewriting our own isn't.
+                        if let Some(previous) =
+                            previous.filter(|p| written && *p != config.worker_id)
+                        {
+                            log(&format!(
+                                "[WARN] Overwrote result {} written by worker {}; check RESULT_KEY_PREFIX and REDIS_DB for tenants sharing task ids",
+                                key, previous
+                            ));
+                        }
+                        written
+                    })
+                }
+                Err(e) => Err(e),
+            }
+        }
+    };
+    match written {
+        Ok(true) => log(&format!(
+            "Result for task {} stored under {} in db {} (ttl {}s).",
+            task_id, key, config.redis_db, ttl_secs
+        )),
+        Ok(false) => log(&format!(
+            "Result for task {} already exists, skipping write.",
+            task_id
+        )),
+        Err(e) => log(&format!(
+            "[ERROR] Failed to write result for task {}: {}",
+            task_id, e
+        )),
+    }
+}
+
+/// Writes a result key with a TTL, using `SET ... NX` unless `overwrite` is
+/// set. Returns `false` when NX kept an existing result.
+pub async fn set_result(
+    conn: &mut redis::aio::MultiplexedConnection,
+    key: &str,
+    value: Vec<u8>,
+    ttl_secs: u64,
+    overwrite: bool,
+) -> redis::RedisResult<bool> {
+    let mut cmd = redis::cmd("SET");
+    cmd.arg(key).arg(value).arg("EX").arg(ttl_secs);
+    if !overwrite {
+        cmd.arg("NX");
+    }
+
+    // SET replies nil when NX prevented the write.
+    let written: Option<String> = cmd.query_async(conn).await?;
+    Ok(written.is_some())
+}
+
+pub fn ack_key(task_id: &str) -> String {
+    format!("mcp::ack::{}", task_id)
+}
+
+/// Records under `mcp::ack::<id>` that this worker accepted a task, so
+/// producers can tell "never picked up" from "picked up but slow". The ack
+/// expires with the result.
+pub async fn write_ack(
+    conn: &mut redis::aio::MultiplexedConnection,
+    config: &Config,
+    queue_name: &str,
+    task_id: &str,
+) {
+    let ack = serde_json::json!({
+        "worker_id": config.worker_id,
+        "acked_at": chrono::Utc::now().to_rfc3339(),
+    });
+    let written: redis::RedisResult<()> = redis::cmd("SET")
+        .arg(ack_key(task_id))
+        .arg(ack.to_string())
+        .arg("EX")
+        .arg(config.result_ttl_for(queue_name))
+        .query_async(conn)
+        .await;
+    if let Err(e) = written {
+        log(&format!(
+            "[ERROR] Failed to write ack for task {}: {}",
+            task_id, e
+        ));
+    }
+}
+
+/// Writes a chunked result's manifest and chunks with their TTL in one
+/// step, keeping an existing result without `overwrite` like `SET NX`.
+const SET_RESULT_CHUNKS: &str = r"
+if ARGV[2] == '0' and redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+for i = 2, #KEYS do
+    redis.call('SET', KEYS[i], ARGV[i + 2], 'EX', ARGV[1])
+end
+redis.call('SET', KEYS[1], ARGV[3], 'EX', ARGV[1])
+return 1
+";
+
+/// Writes `manifest` under `key` and its chunks under `<key>::<n>`, with a
+/// TTL. Returns `false` when an existing result was kept.
+pub async fn set_result_chunks(
+    conn: &mut redis::aio::MultiplexedConnection,
+    key: &str,
+    manifest: Vec<u8>,
+    chunks: Vec<Vec<u8>>,
+    ttl_secs: u64,
+    overwrite: bool,
+) -> redis::RedisResult<bool> {
+    let script = redis::Script::new(SET_RESULT_CHUNKS);
+    let mut invocation = script.key(key);
+    for n in 0..chunks.len() {
+        invocation.key(packing::chunk_key(key, n));
+    }
+    invocation
+        .arg(ttl_secs)
+        .arg(if overwrite { "1" } else { "0" })
+        .arg(manifest);
+    for chunk in chunks {
+        invocation.arg(chunk);
+    }
+    let written: i64 = invocation.invoke_async(conn).await?;
+    Ok(written == 1)
+}
+
+/// Replaces a result hash and sets its TTL in one step. Without `overwrite`
+/// an existing key is kept, mirroring `SET NX`.
+const SET_RESULT_HASH: &str = r"
+local previous = false
+if redis.call('TYPE', KEYS[1]).ok == 'hash' then
+    previous = redis.call('HGET', KEYS[1], 'worker_id')
+end
+if ARGV[2] == '0' and redis.call('EXISTS', KEYS[1]) == 1 then
+    return {0, previous}
+end
+redis.call('DEL', KEYS[1])
+for i = 3, #ARGV, 2 do
+    redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 1])
+end
+redis.call('EXPIRE', KEYS[1], ARGV[1])
+return {1, previous}
+";
+
+/// Writes a result hash with a TTL. Returns `false` when an existing result
+/// was kept, along with the `worker_id` of any result that was already there.
+pub async fn set_result_hash(
+    conn: &mut redis::aio::MultiplexedConnection,
+    key: &str,
+    fields: &[(&str, String)],
+    ttl_secs: u64,
+    overwrite: bool,
+) -> redis::RedisResult<(bool, Option<String>)> {
+    let script = redis::Script::new(SET_RESULT_HASH);
+    let mut invocation = script.key(key);
+    invocation
+        .arg(ttl_secs)
+        .arg(if overwrite { "1" } else { "0" });
+    for (field, value) in fields {
+        invocation.arg(*field).arg(value);
+    }
+    let (written, previous): (i64, Option<String>) = invocation.invoke_async(conn).await?;
+    Ok((written == 1, previous))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::ResultCipher;
+    use crate::queue::memory::MemoryQueue;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use std::sync::Arc;
+
+    const QUEUE: &str = "mcp::tasks::test";
+
+    fn outcome(output: &str) -> Outcome {
+        Outcome {
+            result: Ok(output.to_string()),
+            stopped: None,
+            captured: Captured::default(),
+            started_at: chrono::Utc::now(),
+            duration: Duration::from_millis(5),
+        }
+    }
+
+    /// Compressing after sealing would gain nothing, so large results are
+    /// gzipped first and the gzip is what gets sealed, then chunked.
+    #[tokio::test]
+    async fn gzips_then_seals_then_chunks() {
+        let cipher =
+            ResultCipher::from_base64_key("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+        let cipher = Arc::new(cipher);
+        let task: Task = serde_json::from_value(serde_json::json!({
+            "id": "t1", "task_type": "STATUS", "target_host": "*", "details": {},
+        }))
+        .unwrap();
+        let output = "same line\n".repeat(500);
+
+        let mut config = Config::for_tests();
+        config.result_cipher = Some(cipher.clone());
+        config.result_compress_bytes = 1024;
+        config.result_chunk_bytes = 64;
+        let mut queue = MemoryQueue::new();
+        store(&mut queue, &config, QUEUE, &task, outcome(&output)).await;
+        let key = config.result_key_for(QUEUE, "t1");
+        let results = queue.state().results.clone();
+        let manifest = packing::manifest(&results[&key]).unwrap();
+        assert_eq!(manifest["encoding"], "gzip");
+        assert_eq!(manifest["encrypted"], true);
+        let chunks = manifest["chunks"].as_u64().unwrap() as usize;
+        assert!(chunks > 1);
+        let sealed: Vec<u8> = (0..chunks)
+            .flat_map(|n| results[&packing::chunk_key(&key, n)].clone())
+            .collect();
+        let gzipped = cipher.open(std::str::from_utf8(&sealed).unwrap()).unwrap();
+        assert_eq!(gzipped[..2], [0x1f, 0x8b]);
+        let result: serde_json::Value =
+            serde_json::from_slice(&packing::gunzip(&gzipped).unwrap()).unwrap();
+        assert_eq!(result["stdout"], output);
+
+        config.result_storage = ResultStorage::Hash;
+        let mut queue = MemoryQueue::new();
+        store(&mut queue, &config, QUEUE, &task, outcome(&output)).await;
+        let fields: serde_json::Value =
+            serde_json::from_slice(&queue.state().results[&key]).unwrap();
+        assert_eq!(fields["output_encoding"], "gzip");
+        let base64 = cipher.open(fields["output"].as_str().unwrap()).unwrap();
+        let gzipped = BASE64.decode(base64).unwrap();
+        assert_eq!(packing::gunzip(&gzipped).unwrap(), output.as_bytes());
+    }
+}
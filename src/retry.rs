@@ -0,0 +1,118 @@
+use crate::backoff;
+use crate::config::Config;
+use crate::signing;
+use crate::Task;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// --- Retry Policy ---
+/// One failed attempt, kept in the task's `errors` so the history travels
+/// with every retry and ends up in the dead-letter entry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Failure {
+    pub attempt: u64,
+    pub worker_id: String,
+    pub error: String,
+    pub failed_at: String,
+}
+
+/// How a failing task is retried: up to `max_retries` more attempts, each
+/// delayed by a backoff that doubles from `base` up to `max`.
+pub struct Policy {
+    pub max_retries: u64,
+    base: Duration,
+    max: Duration,
+}
+
+impl Policy {
+    /// The policy from `details.max_retries`, `details.retry_backoff_secs`
+    /// and `details.retry_backoff_max_secs`, falling back to `MAX_REQUEUE`
+    /// for a `requeue_on_error` task and to `RETRY_BACKOFF_SECS` and
+    /// `RETRY_BACKOFF_MAX_SECS`. `None` when the task isn't retried.
+    pub fn for_task(task: &Task, config: &Config) -> Option<Policy> {
+        let max_retries = match task.details["max_retries"].as_u64() {
+            Some(n) => n,
+            None if task.requeue_on_error => config.max_requeue,
+            None => return None,
+        };
+        if max_retries == 0 {
+            return None;
+        }
+        let secs = |field: &str, default: f64| {
+            let secs = task.details[field].as_f64().unwrap_or(default);
+            Duration::try_from_secs_f64(secs).unwrap_or(Duration::ZERO)
+        };
+        Some(Policy {
+            max_retries,
+            base: secs("retry_backoff_secs", config.retry_backoff_secs),
+            max: secs("retry_backoff_max_secs", config.retry_backoff_max_secs),
+        })
+    }
+
+    /// The delay before retrying a task that has been requeued `attempts`
+    /// times, jittered by `BACKOFF_JITTER_PCT`.
+    pub fn delay(&self, attempts: u64, config: &Config) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.min(31) as u32);
+        let delay = self.base.saturating_mul(factor).min(self.max);
+        backoff::jitter(delay, config.backoff_jitter_pct)
+    }
+}
+
+/// The task's history with this attempt's `error` appended.
+pub fn history(task: &Task, config: &Config, error: &str) -> Vec<Failure> {
+    let mut errors = task.errors.clone();
+    errors.push(Failure {
+        attempt: task.attempts + 1,
+        worker_id: config.worker_id.clone(),
+        error: error.to_string(),
+        failed_at: chrono::Utc::now().to_rfc3339(),
+    });
+    errors
+}
+
+/// `json_str` with `attempts` and `errors` updated for the next attempt,
+/// re-signed when tasks are signed. Retries keep the correlation id
+/// generated for the first attempt.
+pub fn retry_payload(json_str: &str, task: &Task, errors: &[Failure], config: &Config) -> String {
+    match serde_json::from_str::<serde_json::Value>(json_str) {
+        Ok(serde_json::Value::Object(mut fields)) => {
+            fields.insert("attempts".to_string(), (task.attempts + 1).into());
+            fields.insert("correlation_id".to_string(), task.correlation_id().into());
+            fields.insert(
+                "errors".to_string(),
+                serde_json::to_value(errors).unwrap_or_default(),
+            );
+            signing::resign(&mut fields, config);
+            serde_json::Value::Object(fields).to_string()
+        }
+        _ => json_str.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_then_jitters() {
+        let policy = Policy {
+            max_retries: 5,
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+        };
+        let mut config = Config::for_tests();
+        config.backoff_jitter_pct = 0;
+        let delays: Vec<u64> = [0, 1, 2, 3, 4, 31, 32, u64::MAX]
+            .iter()
+            .map(|attempts| policy.delay(*attempts, &config).as_secs())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10, 10, 10]);
+
+        config.backoff_jitter_pct = 20;
+        for _ in 0..500 {
+            let delay = policy.delay(10, &config);
+            assert!(delay >= Duration::from_secs(8), "{:?}", delay);
+            assert!(delay <= Duration::from_secs(12), "{:?}", delay);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use crate::config::{parse_labels, Config};
+use crate::ssh;
+use crate::Task;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Prefix of the shared task queues (`mcp::tasks::shell`, ...).
+const TASK_QUEUE_PREFIX: &str = "mcp::tasks::";
+
+/// A task's `priority`. With `PRIORITY_QUEUES` each queue is served as
+/// `<queue>::high`, then `<queue>` itself, then `<queue>::low`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    /// Highest first, the order their queues are popped in.
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn suffix(self) -> Option<&'static str> {
+        match self {
+            Priority::High => Some("::high"),
+            Priority::Normal => None,
+            Priority::Low => Some("::low"),
+        }
+    }
+}
+
+/// The twin of `queue` that tasks of `priority` go on; normal priority
+/// tasks use `queue` itself.
+pub fn priority_queue(queue: &str, priority: Priority) -> String {
+    format!("{}{}", queue, priority.suffix().unwrap_or_default())
+}
+
+/// `queue` without its `::high`/`::low` priority suffix.
+pub fn base_queue(queue: &str) -> &str {
+    Priority::ALL
+        .iter()
+        .filter_map(|priority| queue.strip_suffix(priority.suffix()?))
+        .next()
+        .unwrap_or(queue)
+}
+
+/// Where the worker pushes a task of `priority` back after popping it from
+/// `queue`: with `PRIORITY_QUEUES`, the twin for that priority, otherwise
+/// `queue` again.
+pub fn requeue_target(queue: &str, priority: Priority, config: &Config) -> String {
+    match config.priority_queues {
+        true => priority_queue(base_queue(queue), priority),
+        false => queue.to_string(),
+    }
+}
+
+/// The host-scoped twin of a shared task queue, `mcp::tasks::<host>::<kind>`
+/// for `mcp::tasks::<kind>`, that only `host` serves. `None` for queues
+/// outside `mcp::tasks::`.
+pub fn host_queue(queue: &str, host: &str) -> Option<String> {
+    let kind = queue.strip_prefix(TASK_QUEUE_PREFIX)?;
+    Some(format!("{}{}::{}", TASK_QUEUE_PREFIX, host, kind))
+}
+
+/// The shared queue `queue` stands in for when it is `host`'s host-scoped
+/// queue.
+pub fn shared_queue(queue: &str, host: &str) -> Option<String> {
+    let kind = queue
+        .strip_prefix(TASK_QUEUE_PREFIX)?
+        .strip_prefix(host)?
+        .strip_prefix("::")?;
+    Some(format!("{}{}", TASK_QUEUE_PREFIX, kind))
+}
+
+/// Decides whether this worker should run `task`. A `target_selector` takes
+/// precedence; without one, `target_host` must name this worker exactly, with
+/// `*` (or an empty host) matching any worker, or be an SSH host this worker
+/// runs SHELL tasks on (see [`ssh::target`]). Selectors pick workers by
+/// their own labels only: a task with one runs on the worker itself, never
+/// over SSH, whatever its `target_host`.
+pub fn accepts(task: &Task, config: &Config) -> bool {
+    match &task.target_selector {
+        Some(selector) => selector_matches(selector, &config.worker_labels),
+        None => {
+            task.target_host.is_empty()
+                || task.target_host == "*"
+                || task.target_host == config.worker_host
+                || ssh::target(task, config).is_some()
+        }
+    }
+}
+
+/// Every `key=value` term in the selector must match a worker label; a bare
+/// `key` only requires the label to be present.
+fn selector_matches(selector: &str, labels: &BTreeMap<String, String>) -> bool {
+    parse_labels(selector)
+        .iter()
+        .all(|(key, value)| match labels.get(key) {
+            Some(label) => value.is_empty() || label == value,
+            None => false,
+        })
+}
+
+/// Seconds since a worker first re-queued task `task_id` for not being its
+/// target, tracked fleet-wide under `mcp::bounced::<id>` (kept a minute past
+/// `max_age_secs`). The first re-queue starts the clock and reports 0.
+pub async fn bounced_for(
+    conn: &mut redis::aio::MultiplexedConnection,
+    task_id: &str,
+    max_age_secs: u64,
+) -> redis::RedisResult<u64> {
+    let key = format!("mcp::bounced::{}", task_id);
+    let now = chrono::Utc::now().timestamp();
+    let (first,): (i64,) = redis::pipe()
+        .cmd("SET")
+        .arg(&key)
+        .arg(now)
+        .arg("NX")
+        .arg("EX")
+        .arg(max_age_secs + 60)
+        .ignore()
+        .get(&key)
+        .query_async(conn)
+        .await?;
+    Ok(now.saturating_sub(first).max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn task(target_host: &str, target_selector: Option<&str>) -> Task {
+        serde_json::from_value(json!({
+            "id": "t1",
+            "target_host": target_host,
+            "target_selector": target_selector,
+            "task_type": "SHELL",
+            "details": {"command": "true"},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn selector_matches_labels() {
+        let labels = parse_labels("region=us-east, role=gpu,spot");
+        let cases = [
+            ("", true),
+            ("region=us-east", true),
+            ("region=us-east,role=gpu", true),
+            (" role = gpu , region=us-east ", true),
+            ("role", true),
+            ("spot", true),
+            ("spot=", true),
+            ("spot=yes", false),
+            ("region=us-west", false),
+            ("region=us-east,role=cpu", false),
+            ("zone", false),
+            ("REGION=us-east", false),
+            ("region=US-EAST", false),
+        ];
+        for (selector, expected) in cases {
+            assert_eq!(
+                selector_matches(selector, &labels),
+                expected,
+                "{:?}",
+                selector
+            );
+        }
+        assert!(selector_matches("", &BTreeMap::new()));
+        assert!(!selector_matches("role", &BTreeMap::new()));
+    }
+
+    #[test]
+    fn accepts_by_host_or_selector() {
+        let mut config = Config::for_tests();
+        config.worker_labels = parse_labels("region=us-east,role=gpu");
+        let cases = [
+            (task("test-host", None), true),
+            (task("*", None), true),
+            (task("", None), true),
+            (task("other-host", None), false),
+            (task("TEST-HOST", None), false),
+            // With a selector, `target_host` is ignored either way.
+            (task("other-host", Some("role=gpu")), true),
+            (task("test-host", Some("role=cpu")), false),
+            (task("*", Some("region=us-east,role=gpu")), true),
+            (task("*", Some("region=us-west")), false),
+        ];
+        for (task, expected) in cases {
+            assert_eq!(
+                accepts(&task, &config),
+                expected,
+                "{} / {:?}",
+                task.target_host,
+                task.target_selector
+            );
+        }
+    }
+
+    #[test]
+    fn selector_tasks_never_run_over_ssh() {
+        let config = Config::for_tests();
+        assert!(ssh::target(&task("web-1", Some("role=gpu")), &config).is_none());
+        assert!(ssh::target(&task("test-host", None), &config).is_none());
+    }
+}
@@ -0,0 +1,299 @@
+use crate::cancel;
+use crate::config::Config;
+use crate::details::{string_list, string_map};
+use crate::docker::{self, MANAGED_BY, MANAGED_BY_LABEL};
+use crate::exit;
+use crate::log;
+use crate::output::OutputOptions;
+use crate::redact;
+use crate::secrets;
+use crate::Task;
+use std::process::Stdio;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::{self, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+// --- Warm Runner Pool ---
+/// Label marking a pooled runner, valued with the owning worker's id.
+const RUNNER_LABEL: &str = "mcp-runner";
+/// How often idle runners are checked against `RUNNER_IDLE_TTL_SECS`.
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Settings {
+    image: String,
+    worker_id: String,
+    idle_ttl: Duration,
+}
+
+struct Runner {
+    id: String,
+    idle_since: Instant,
+}
+
+/// Set by [`init`] when `RUNNER_IMAGE` is configured.
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+/// One permit per runner the pool may have, idle or leased.
+static SLOTS: OnceLock<Semaphore> = OnceLock::new();
+/// Started runners waiting for an `exec`.
+static IDLE: LazyLock<Mutex<Vec<Runner>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+
+/// A runner checked out for one `exec`.
+struct Lease {
+    id: String,
+    _permit: SemaphorePermit<'static>,
+}
+
+/// Enables the pool when `RUNNER_IMAGE` is set, removes runners a previous
+/// run of this worker left behind, and spawns the idle reaper.
+pub async fn init(config: &Config) {
+    let Some(image) = &config.runner_image else {
+        return;
+    };
+    let settings = SETTINGS.get_or_init(|| Settings {
+        image: image.clone(),
+        worker_id: config.worker_id.clone(),
+        idle_ttl: Duration::from_secs(config.runner_idle_ttl_secs),
+    });
+    SLOTS.get_or_init(|| Semaphore::new(config.runner_pool_size));
+    log(&format!(
+        "Runner pool enabled: up to {} {} container(s), idle TTL {:?}",
+        config.runner_pool_size, settings.image, settings.idle_ttl
+    ));
+
+    // Before any exec can start a runner this would match.
+    remove_stale(settings).await;
+    tokio::spawn(async move {
+        let mut interval = time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let expired: Vec<Runner> = {
+                let mut idle = IDLE.lock().unwrap();
+                let (expired, kept) = std::mem::take(&mut *idle)
+                    .into_iter()
+                    .partition(|r| r.idle_since.elapsed() >= settings.idle_ttl);
+                *idle = kept;
+                expired
+            };
+            for runner in expired {
+                log(&format!("Reaping idle runner {}", runner.id));
+                remove(&runner.id).await;
+            }
+        }
+    });
+}
+
+/// Removes every idle runner, e.g. on the way out.
+pub async fn remove_idle() {
+    let idle = std::mem::take(&mut *IDLE.lock().unwrap());
+    for runner in idle {
+        remove(&runner.id).await;
+    }
+}
+
+/// Runs `details.args` in a warm runner with `docker exec` (`details.env`
+/// adds variables, `secret://<name>` values resolved through
+/// `SECRETS_PROVIDER`, passed in an env file) and returns its stdout. A
+/// runner is started on demand while the pool has room; otherwise the task
+/// waits for one to free up.
+/// A cancelled exec discards its runner, since killing the `docker exec`
+/// client doesn't stop the process inside the container.
+pub async fn exec(
+    task: &Task,
+    config: &Config,
+    output_options: &OutputOptions,
+    cancel: &CancellationToken,
+    grace: Duration,
+) -> Result<String, String> {
+    let (Some(settings), Some(slots)) = (SETTINGS.get(), SLOTS.get()) else {
+        return Err("exec needs the runner pool (set RUNNER_IMAGE)".to_string());
+    };
+    let argv = string_list(&task.details["args"], "args")?;
+    if argv.is_empty() {
+        return Err("exec requires a non-empty 'args' list".to_string());
+    }
+    let env = secrets::resolve_env(
+        string_map(&task.details["env"], "env")?,
+        &task.details,
+        config,
+    )
+    .await?;
+    // Kept until `docker exec` has read it.
+    let env_file = docker::EnvFile::write(&env)?;
+
+    let lease = tokio::select! {
+        lease = acquire(settings, slots) => lease?,
+        _ = cancel.cancelled() => return Err(cancel::error(task)),
+    };
+    let mut cmd = docker::docker_command(None, &["exec"]);
+    if let Some(env_file) = &env_file {
+        cmd.arg("--env-file").arg(env_file.path());
+    }
+    cmd.arg(&lease.id).args(&argv);
+    log(&format!(
+        "Executing {} in runner {}",
+        redact::scrub(
+            &format!("{:?}", argv),
+            &redact::secret_values(&task.details)
+        ),
+        lease.id
+    ));
+
+    let spawned = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            release(lease, true).await;
+            return Err(format!("Failed to execute docker command: {}", e));
+        }
+    };
+    let stdout = tokio::spawn(read_to_end(child.stdout.take()));
+    let stderr = tokio::spawn(read_to_end(child.stderr.take()));
+    let status = tokio::select! {
+        status = child.wait() => status,
+        _ = cancel.cancelled() => {
+            let terminated_by = exit::terminate(&mut child, grace).await;
+            release(lease, false).await;
+            return Err(serde_json::json!({
+                "status": cancel::reason(task),
+                "deadline": task.deadline,
+                "terminated_by": terminated_by.as_str(),
+            })
+            .to_string());
+        }
+    };
+    let stdout = stdout.await.unwrap_or_default();
+    let stderr = stderr.await.unwrap_or_default();
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => {
+            release(lease, false).await;
+            return Err(format!("Failed to wait for docker exec: {}", e));
+        }
+    };
+    exit::record(status.code());
+    exit::record_stderr(&stderr);
+    let runner = lease.id.clone();
+    release(lease, true).await;
+    if status.success() {
+        Ok(output_options.render(&stdout))
+    } else {
+        Err(format!(
+            "Command failed in runner {} ({}): {}",
+            runner,
+            exit::describe(&status),
+            String::from_utf8_lossy(&stderr)
+        ))
+    }
+}
+
+/// Takes a healthy idle runner, or starts one, once a pool slot is free.
+async fn acquire(settings: &Settings, slots: &'static Semaphore) -> Result<Lease, String> {
+    let permit = slots
+        .acquire()
+        .await
+        .map_err(|e| format!("runner pool closed: {}", e))?;
+    loop {
+        let next = IDLE.lock().unwrap().pop();
+        let Some(runner) = next else { break };
+        if is_running(&runner.id).await {
+            return Ok(Lease {
+                id: runner.id,
+                _permit: permit,
+            });
+        }
+        log(&format!(
+            "Runner {} is no longer running, replacing it",
+            runner.id
+        ));
+        remove(&runner.id).await;
+    }
+    let id = start(settings).await?;
+    Ok(Lease {
+        id,
+        _permit: permit,
+    })
+}
+
+/// Returns a runner to the pool, or removes it when it can't be reused.
+async fn release(lease: Lease, reusable: bool) {
+    if reusable {
+        IDLE.lock().unwrap().push(Runner {
+            id: lease.id,
+            idle_since: Instant::now(),
+        });
+    } else {
+        remove(&lease.id).await;
+    }
+}
+
+/// Starts a runner that idles on `sleep infinity` until it's exec'd into.
+async fn start(settings: &Settings) -> Result<String, String> {
+    let output = docker::docker_command(None, &["run"])
+        .arg("-d")
+        .arg("--label")
+        .arg(format!("{}={}", MANAGED_BY_LABEL, MANAGED_BY))
+        .arg("--label")
+        .arg(format!("{}={}", RUNNER_LABEL, settings.worker_id))
+        .arg(&settings.image)
+        .args(["sleep", "infinity"])
+        .output()
+        .await
+        .map_err(|e| format!("Failed to execute docker command: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to start runner from {} ({}): {}",
+            settings.image,
+            exit::describe(&output.status),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    log(&format!("Started runner {} from {}", id, settings.image));
+    Ok(id)
+}
+
+async fn is_running(id: &str) -> bool {
+    docker::docker_command(None, &["inspect"])
+        .args(["--format", "{{.State.Running}}", "--", id])
+        .output()
+        .await
+        .is_ok_and(|o| o.status.success() && o.stdout.trim_ascii() == b"true")
+}
+
+async fn remove(id: &str) {
+    let removed = docker::docker_command(None, &["rm"])
+        .args(["-f", "--", id])
+        .output()
+        .await;
+    if let Err(e) = removed {
+        log(&format!("[ERROR] Failed to remove runner {}: {}", id, e));
+    }
+}
+
+/// Removes runners labelled for this worker that no pool knows about.
+async fn remove_stale(settings: &Settings) {
+    let filter = format!("label={}={}", RUNNER_LABEL, settings.worker_id);
+    let listed = docker::docker_command(None, &["ps"])
+        .args(["-a", "-q", "--filter", &filter])
+        .output()
+        .await;
+    let Ok(listed) = listed else { return };
+    for id in String::from_utf8_lossy(&listed.stdout).split_whitespace() {
+        log(&format!("Removing stale runner {}", id));
+        remove(id).await;
+    }
+}
+
+async fn read_to_end<R: AsyncRead + Unpin>(pipe: Option<R>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_end(&mut buf).await.ok();
+    }
+    buf
+}
@@ -0,0 +1,147 @@
+use crate::log;
+use crate::result::RESULT_TTL_SECS;
+use redis::AsyncCommands;
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, Mutex};
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+
+/// How long a container cancel request stays set after a worker first acts
+/// on it, when the producer didn't give it an expiry.
+const CANCEL_REQUEST_TTL_SECS: i64 = 30;
+
+// --- Running Task Registry ---
+/// A task currently executing on this worker.
+struct RunningTask {
+    /// The container named in `details.container`, if any.
+    container: Option<String>,
+    token: CancellationToken,
+}
+
+static RUNNING: LazyLock<Mutex<HashMap<String, RunningTask>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Removes the task from the registry when dropped.
+pub struct RunningGuard {
+    task_id: String,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.task_id);
+    }
+}
+
+/// Records a task as running until the returned guard is dropped. Cancelling
+/// its container cancels `token`.
+pub fn register(
+    task_id: &str,
+    details: &serde_json::Value,
+    token: CancellationToken,
+) -> RunningGuard {
+    let container = details["container"]
+        .as_str()
+        .and_then(normalize_container)
+        .map(str::to_string);
+    RUNNING
+        .lock()
+        .unwrap()
+        .insert(task_id.to_string(), RunningTask { container, token });
+    RunningGuard {
+        task_id: task_id.to_string(),
+    }
+}
+
+/// Trims a container id or name and strips the single leading `/` docker
+/// uses in reported names (`/web` -> `web`). `None` if nothing is left.
+pub fn normalize_container(raw: &str) -> Option<&str> {
+    let trimmed = raw.trim();
+    let name = trimmed.strip_prefix('/').unwrap_or(trimmed).trim();
+    (!name.is_empty()).then_some(name)
+}
+
+/// Marks every running task that references `container` as cancelled and
+/// returns how many were newly cancelled.
+fn cancel_container(container: &str) -> usize {
+    let mut running = RUNNING.lock().unwrap();
+    let mut count = 0;
+    for task in running.values_mut() {
+        if !task.token.is_cancelled() && task.container.as_deref() == Some(container) {
+            task.token.cancel();
+            count += 1;
+        }
+    }
+    count
+}
+
+fn running_containers() -> Vec<String> {
+    let running = RUNNING.lock().unwrap();
+    let mut containers: Vec<String> = running
+        .values()
+        .filter(|t| !t.token.is_cancelled())
+        .filter_map(|t| t.container.clone())
+        .collect();
+    containers.sort();
+    containers.dedup();
+    containers
+}
+
+/// Spawns a loop that cancels running tasks bound to a container once
+/// `mcp::cancel::container::<name>` is set. Each worker adds the number of
+/// tasks it cancelled to `mcp::cancel::container::<name>::result`. A worker
+/// acts on a request once: tasks it starts for the container afterwards
+/// (such as a `restart_container` to recover it) run normally. The first
+/// worker to act gives the key an expiry of [`CANCEL_REQUEST_TTL_SECS`]
+/// unless the producer set one, long enough for every worker to see it, and
+/// a new request can be made once it is gone.
+pub fn watch_container_cancellations(mut conn: redis::aio::MultiplexedConnection) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(1));
+        // Containers whose current request this worker already acted on.
+        let mut handled: HashSet<String> = HashSet::new();
+        loop {
+            interval.tick().await;
+            let mut containers = running_containers();
+            containers.extend(handled.iter().cloned());
+            containers.sort();
+            containers.dedup();
+            for container in containers {
+                let key = format!("mcp::cancel::container::{}", container);
+                let requested: bool = conn.exists(&key).await.unwrap_or(false);
+                if !requested {
+                    handled.remove(&container);
+                    continue;
+                }
+                if !handled.insert(container.clone()) {
+                    continue;
+                }
+                let ttl: i64 = conn.ttl(&key).await.unwrap_or(0);
+                if ttl == -1 {
+                    conn.expire::<_, ()>(&key, CANCEL_REQUEST_TTL_SECS)
+                        .await
+                        .ok();
+                }
+
+                let count = cancel_container(&container);
+                log(&format!(
+                    "Cancelled {} running task(s) bound to container {}",
+                    count, container
+                ));
+                let result_key = format!("{}::result", key);
+                let recorded: redis::RedisResult<()> = redis::pipe()
+                    .incr(&result_key, count)
+                    .ignore()
+                    .expire(&result_key, RESULT_TTL_SECS as i64)
+                    .ignore()
+                    .query_async(&mut conn)
+                    .await;
+                if let Err(e) = recorded {
+                    log(&format!(
+                        "[ERROR] Failed to record container cancellation: {}",
+                        e
+                    ));
+                }
+            }
+        }
+    });
+}
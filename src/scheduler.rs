@@ -0,0 +1,339 @@
+use crate::config::Config;
+use crate::log;
+use crate::routing;
+use crate::signing;
+use crate::Task;
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use std::str::FromStr;
+use tokio::time::{self, Duration};
+
+/// Sorted set of scheduled task ids, scored by when each is next due.
+pub const SCHEDULED_KEY: &str = "mcp::scheduled";
+/// Hash of scheduled task id to `{"queue", "payload"}`, plus `"retry": true`
+/// for a retry waiting out its backoff.
+pub const SCHEDULED_TASKS_KEY: &str = "mcp::scheduled::tasks";
+/// How often due tasks are looked for.
+const PROMOTE_INTERVAL: Duration = Duration::from_secs(1);
+/// Most tasks promoted per look.
+const PROMOTE_BATCH: isize = 100;
+
+// --- Scheduled Tasks ---
+/// When a deferred task runs.
+enum When {
+    /// Once, from `details.run_at`.
+    At(DateTime<Utc>),
+    /// Repeatedly, from the cron expression in `details.schedule`.
+    Cron(Box<cron::Schedule>),
+}
+
+impl When {
+    /// Reads `details.run_at` (RFC3339 or Unix seconds) or
+    /// `details.schedule` (five-field cron, or six/seven fields starting
+    /// with seconds; evaluated in UTC).
+    fn from_details(details: &serde_json::Value) -> Result<Self, String> {
+        match (&details["run_at"], &details["schedule"]) {
+            (serde_json::Value::Null, serde_json::Value::Null) => {
+                Err("task has neither 'run_at' nor 'schedule'".to_string())
+            }
+            (run_at, serde_json::Value::Null) => {
+                let at = match run_at {
+                    serde_json::Value::String(at) => DateTime::parse_from_rfc3339(at)
+                        .map(|at| at.with_timezone(&Utc))
+                        .map_err(|e| format!("invalid 'run_at' '{}': {}", at, e))?,
+                    serde_json::Value::Number(secs) => secs
+                        .as_i64()
+                        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+                        .ok_or_else(|| format!("invalid 'run_at' {}", secs))?,
+                    _ => {
+                        return Err("'run_at' must be an RFC3339 string or Unix seconds".to_string())
+                    }
+                };
+                Ok(When::At(at))
+            }
+            (serde_json::Value::Null, serde_json::Value::String(expr)) => {
+                let fields = expr.split_whitespace().count();
+                let full = match fields {
+                    5 => format!("0 {}", expr),
+                    _ => expr.clone(),
+                };
+                cron::Schedule::from_str(&full)
+                    .map(|schedule| When::Cron(Box::new(schedule)))
+                    .map_err(|e| format!("invalid 'schedule' '{}': {}", expr, e))
+            }
+            (serde_json::Value::Null, _) => Err("'schedule' must be a cron string".to_string()),
+            _ => Err("a task takes either 'run_at' or 'schedule', not both".to_string()),
+        }
+    }
+
+    /// The next time after `now` (or at once, for a `run_at` in the past).
+    fn next(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            When::At(at) => Some(*at),
+            When::Cron(schedule) => schedule.after(&now).next(),
+        }
+    }
+}
+
+/// Whether `task` asks to run later rather than now: it sets
+/// `details.run_at` or `details.schedule` and isn't itself a promoted run.
+pub fn is_deferred(task: &Task) -> bool {
+    task.scheduled_for.is_none()
+        && (!task.details["run_at"].is_null() || !task.details["schedule"].is_null())
+}
+
+/// Stores `json_str` under its task id, to be pushed back onto `queue_name`
+/// when due. Scheduling an id again replaces its schedule; removing the id
+/// from `mcp::scheduled` and `mcp::scheduled::tasks` cancels it. Returns
+/// when it is first due.
+pub async fn schedule(
+    conn: &mut redis::aio::MultiplexedConnection,
+    queue_name: &str,
+    json_str: &str,
+    task: &Task,
+) -> Result<DateTime<Utc>, String> {
+    let due = When::from_details(&task.details)?
+        .next(Utc::now())
+        .ok_or("'schedule' never fires")?;
+    let entry = serde_json::json!({ "queue": queue_name, "payload": json_str });
+    redis::pipe()
+        .atomic()
+        .hset(SCHEDULED_TASKS_KEY, &task.id, entry.to_string())
+        .ignore()
+        .zadd(SCHEDULED_KEY, &task.id, due.timestamp())
+        .ignore()
+        .query_async::<_, ()>(conn)
+        .await
+        .map_err(|e| format!("Failed to schedule task: {}", e))?;
+    Ok(due)
+}
+
+/// Stores the retry `payload` of a failed task, to be pushed onto
+/// `queue_name` once `delay` has passed. It is kept as `retry::<id>`, so it
+/// doesn't replace a schedule of the task itself; removing that from
+/// `mcp::scheduled` cancels the retry.
+pub async fn schedule_retry(
+    conn: &mut redis::aio::MultiplexedConnection,
+    queue_name: &str,
+    payload: &str,
+    task: &Task,
+    delay: Duration,
+) -> Result<(), String> {
+    let key = format!("retry::{}", task.id);
+    let due = Utc::now().timestamp() + delay.as_secs_f64().ceil() as i64;
+    let entry = serde_json::json!({ "queue": queue_name, "payload": payload, "retry": true });
+    redis::pipe()
+        .atomic()
+        .hset(SCHEDULED_TASKS_KEY, &key, entry.to_string())
+        .ignore()
+        .zadd(SCHEDULED_KEY, &key, due)
+        .ignore()
+        .query_async::<_, ()>(conn)
+        .await
+        .map_err(|e| format!("Failed to schedule retry: {}", e))
+}
+
+/// Spawns the loop that pushes due tasks onto their queues. Every worker
+/// runs one; each due task is claimed, and its run queued, in one script
+/// that checks it is still due, so only one worker promotes it. A `run_at` task is queued under its own id and then
+/// forgotten, as is a retry. A cron task's run is queued as `<id>::<due unix seconds>`, so
+/// each run has its own result, and the task is rescheduled for its next
+/// time; runs missed while no worker was up are skipped, not caught up.
+pub fn spawn(mut conn: redis::aio::MultiplexedConnection, config: &Config) {
+    let config = config.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(PROMOTE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = promote_due(&mut conn, &config).await {
+                log(&format!("[ERROR] Failed to promote scheduled tasks: {}", e));
+            }
+        }
+    });
+}
+
+/// Claims scheduled task `ARGV[1]` if it is still due at `ARGV[2]` with
+/// entry `ARGV[3]` (`''` for none), so a task rescheduled or cancelled in
+/// the meantime is left alone. A promoted run (`ARGV[4]`) is pushed onto
+/// `KEYS[3]` in the same step, and the task is rescheduled for `ARGV[5]` or
+/// forgotten when that is `''`; without a run, it is just forgotten.
+const CLAIM_SCHEDULED: &str = r"
+if tonumber(redis.call('ZSCORE', KEYS[1], ARGV[1])) ~= tonumber(ARGV[2]) then
+    return 0
+end
+if (redis.call('HGET', KEYS[2], ARGV[1]) or '') ~= ARGV[3] then
+    return 0
+end
+if ARGV[5] == '' then
+    redis.call('ZREM', KEYS[1], ARGV[1])
+    redis.call('HDEL', KEYS[2], ARGV[1])
+else
+    redis.call('ZADD', KEYS[1], ARGV[5], ARGV[1])
+end
+if ARGV[4] ~= '' then
+    redis.call('RPUSH', KEYS[3], ARGV[4])
+end
+return 1
+";
+
+async fn promote_due(
+    conn: &mut redis::aio::MultiplexedConnection,
+    config: &Config,
+) -> redis::RedisResult<()> {
+    let now = Utc::now();
+    let due: Vec<(String, i64)> = conn
+        .zrangebyscore_limit_withscores(SCHEDULED_KEY, "-inf", now.timestamp(), 0, PROMOTE_BATCH)
+        .await?;
+    let script = redis::Script::new(CLAIM_SCHEDULED);
+    for (id, due_at) in due {
+        let entry: Option<String> = conn.hget(SCHEDULED_TASKS_KEY, &id).await?;
+        let entry = entry.unwrap_or_default();
+        let promoted = promote(&id, due_at, &entry, now, config);
+        let (queue, payload, next) = match &promoted {
+            Ok((queue, payload, next)) => (queue.as_str(), payload.as_str(), *next),
+            Err(_) => (SCHEDULED_KEY, "", None),
+        };
+        let claimed: i64 = script
+            .key(SCHEDULED_KEY)
+            .key(SCHEDULED_TASKS_KEY)
+            .key(queue)
+            .arg(&id)
+            .arg(due_at)
+            .arg(&entry)
+            .arg(payload)
+            .arg(
+                next.map(|next| next.timestamp().to_string())
+                    .unwrap_or_default(),
+            )
+            .invoke_async(conn)
+            .await?;
+        match promoted {
+            _ if claimed == 0 => {}
+            Ok((queue, _, _)) => log(&format!(
+                "Scheduled task {} is due, queued on {}",
+                id, queue
+            )),
+            Err(e) if !entry.is_empty() => {
+                log(&format!("[ERROR] Dropping scheduled task {}: {}", id, e))
+            }
+            Err(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// The queue (the one for its priority, with `PRIORITY_QUEUES`), payload
+/// and next due time (for cron tasks) of the run of scheduled task `id` due
+/// at `due_at`.
+fn promote(
+    id: &str,
+    due_at: i64,
+    entry: &str,
+    now: DateTime<Utc>,
+    config: &Config,
+) -> Result<(String, String, Option<DateTime<Utc>>), String> {
+    let entry: serde_json::Value = serde_json::from_str(entry).map_err(|e| e.to_string())?;
+    let queue = entry["queue"].as_str().ok_or("entry has no queue")?;
+    let payload = entry["payload"].as_str().ok_or("entry has no payload")?;
+    let serde_json::Value::Object(mut fields) =
+        serde_json::from_str(payload).map_err(|e| e.to_string())?
+    else {
+        return Err("payload is not a JSON object".to_string());
+    };
+    // Checked before the payload changes, as the hash may have been written
+    // directly rather than by `schedule`.
+    signing::check_signature(&fields, config)?;
+    // A retry goes back to the queue it was routed to, signed afresh as it
+    // may have waited longer than MCP_TASK_MAX_AGE_SECS.
+    if entry["retry"].as_bool() == Some(true) {
+        signing::resign(&mut fields, config);
+        return Ok((
+            queue.to_string(),
+            serde_json::Value::Object(fields).to_string(),
+            None,
+        ));
+    }
+    let priority = serde_json::from_value(fields.get("priority").cloned().unwrap_or_default())
+        .unwrap_or_default();
+    let queue = routing::requeue_target(queue, priority, config);
+    let details = fields.get("details").cloned().unwrap_or_default();
+    let when = When::from_details(&details)?;
+    let (run_id, next) = match &when {
+        When::At(_) => (id.to_string(), None),
+        When::Cron(_) => (format!("{}::{}", id, due_at), when.next(now)),
+    };
+    let scheduled_for = DateTime::from_timestamp(due_at, 0).unwrap_or(now);
+    fields.insert("id".to_string(), run_id.into());
+    fields.insert(
+        "scheduled_for".to_string(),
+        scheduled_for.to_rfc3339().into(),
+    );
+    signing::resign(&mut fields, config);
+    Ok((queue, serde_json::Value::Object(fields).to_string(), next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn config() -> Config {
+        let mut config = Config::for_tests();
+        config.task_secret = Some("k3y".to_string());
+        config
+    }
+
+    /// A scheduled-task entry holding `payload`, signed when `sign` is set.
+    fn entry(payload: Value, retry: bool, sign: bool, config: &Config) -> String {
+        let Value::Object(mut fields) = payload else {
+            unreachable!()
+        };
+        if sign {
+            signing::resign(&mut fields, config);
+        }
+        let payload = Value::Object(fields).to_string();
+        json!({"queue": "mcp::tasks::shell", "payload": payload, "retry": retry}).to_string()
+    }
+
+    fn task() -> Value {
+        json!({"id": "t1", "task_type": "SHELL", "details": {"command": "id", "schedule": "* * * * *"}})
+    }
+
+    #[test]
+    fn promotes_signed_runs_and_retries_signed_afresh() {
+        let config = config();
+        let now = Utc::now();
+        for retry in [false, true] {
+            let entry = entry(task(), retry, true, &config);
+            let (queue, payload, _) = promote("t1", now.timestamp(), &entry, now, &config).unwrap();
+            assert_eq!(queue, "mcp::tasks::shell");
+            let fields = serde_json::from_str(&payload).unwrap();
+            assert_eq!(signing::check_signature(&fields, &config), Ok(()));
+        }
+    }
+
+    #[test]
+    fn drops_forged_entries() {
+        let config = config();
+        let now = Utc::now();
+        for retry in [false, true] {
+            let forged = entry(task(), retry, false, &config);
+            assert_eq!(
+                promote("t1", now.timestamp(), &forged, now, &config).map(|_| ()),
+                Err("task is not signed".to_string())
+            );
+        }
+        // Signed, then changed in the hash.
+        let mut tampered: Value =
+            serde_json::from_str(&entry(task(), false, true, &config)).unwrap();
+        let payload = tampered["payload"]
+            .as_str()
+            .unwrap()
+            .replace(r#""command":"id""#, r#""command":"rm""#);
+        tampered["payload"] = payload.into();
+        assert_eq!(
+            promote("t1", now.timestamp(), &tampered.to_string(), now, &config).map(|_| ()),
+            Err("signature does not match".to_string())
+        );
+    }
+}
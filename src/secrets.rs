@@ -0,0 +1,259 @@
+use crate::config::Config;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Prefix of a `details.env` value the worker looks up instead of passing on.
+pub const SCHEME: &str = "secret://";
+
+/// How long a Vault lookup may take.
+const VAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// --- Secret References ---
+/// Where `secret://<name>` references are looked up, chosen by
+/// `SECRETS_PROVIDER`.
+pub enum Provider {
+    /// `env-file`: `<name>=<value>` lines in `SECRETS_FILE`, read on every
+    /// lookup so rotated values are picked up without a restart.
+    EnvFile(PathBuf),
+    /// `vault`: the `<field>` (default `value`) of the KV v2 secret at
+    /// `<path>` under `SECRETS_VAULT_MOUNT` (default `secret`), for a
+    /// `secret://<path>#<field>` reference. Read from `SECRETS_VAULT_ADDR`
+    /// with `SECRETS_VAULT_TOKEN`.
+    Vault {
+        addr: String,
+        token: String,
+        mount: String,
+    },
+    /// `encrypted`: `details.secrets.<name>` holds the value sealed with
+    /// AES-256-GCM under `SECRETS_KEY` (32 bytes, base64), as the envelope
+    /// `{"alg":"AES-256-GCM","nonce":"<base64>","ciphertext":"<base64>"}`
+    /// serialized to a string. Only this worker's key can open it, so the
+    /// task can carry it through Redis.
+    Encrypted(Box<Aes256Gcm>),
+}
+
+/// Never prints the Vault token or the key.
+impl std::fmt::Debug for Provider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretsProvider({})", self.name())
+    }
+}
+
+tokio::task_local! {
+    /// Values resolved inside the current [`track`].
+    static RESOLVED: RefCell<Vec<String>>;
+}
+
+impl Provider {
+    /// Builds the provider from `SECRETS_PROVIDER` and its settings;
+    /// `Ok(None)` when unset. Loaded by `main` so a bad setting stops
+    /// startup.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let setting = |key: &str| {
+            crate::config::var(key)
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let required = |key: &str, provider: &str| {
+            setting(key).ok_or_else(|| format!("SECRETS_PROVIDER={} needs {}", provider, key))
+        };
+        let provider = match setting("SECRETS_PROVIDER").as_deref() {
+            None => return Ok(None),
+            Some("env-file") => {
+                Provider::EnvFile(PathBuf::from(required("SECRETS_FILE", "env-file")?))
+            }
+            Some("vault") => Provider::Vault {
+                addr: required("SECRETS_VAULT_ADDR", "vault")?
+                    .trim_end_matches('/')
+                    .to_string(),
+                token: required("SECRETS_VAULT_TOKEN", "vault")?,
+                mount: setting("SECRETS_VAULT_MOUNT").unwrap_or_else(|| "secret".to_string()),
+            },
+            Some("encrypted") => {
+                let key = BASE64
+                    .decode(required("SECRETS_KEY", "encrypted")?)
+                    .map_err(|e| format!("SECRETS_KEY is not valid base64: {}", e))?;
+                Provider::Encrypted(Box::new(Aes256Gcm::new_from_slice(&key).map_err(|_| {
+                    format!("SECRETS_KEY must decode to 32 bytes, got {}", key.len())
+                })?))
+            }
+            Some(other) => {
+                return Err(format!(
+                    "SECRETS_PROVIDER must be env-file, vault or encrypted, got {}",
+                    other
+                ))
+            }
+        };
+        Ok(Some(provider))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::EnvFile(_) => "env-file",
+            Provider::Vault { .. } => "vault",
+            Provider::Encrypted(_) => "encrypted",
+        }
+    }
+
+    /// The value of secret `name`. Errors name the secret, never a value.
+    async fn lookup(&self, name: &str, details: &Value) -> Result<String, String> {
+        match self {
+            Provider::EnvFile(path) => {
+                let contents = tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|e| format!("Failed to read SECRETS_FILE: {}", e))?;
+                env_file_value(&contents, name)
+                    .ok_or_else(|| format!("Secret '{}' is not in SECRETS_FILE", name))
+            }
+            Provider::Vault { addr, token, mount } => vault_value(addr, token, mount, name).await,
+            Provider::Encrypted(cipher) => {
+                let sealed = details["secrets"][name]
+                    .as_str()
+                    .ok_or_else(|| format!("Secret '{}' is not in details.secrets", name))?;
+                open(cipher, sealed).map_err(|e| format!("Secret '{}' {}", name, e))
+            }
+        }
+    }
+}
+
+/// The value of `name` in `KEY=VALUE` lines (optionally `export`ed),
+/// skipping blank lines and `#` comments. A value wrapped in matching quotes loses them.
+fn env_file_value(contents: &str, name: &str) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let line = line.trim();
+        if line.starts_with('#') {
+            return None;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line.split_once('=')?;
+        if key.trim() != name {
+            return None;
+        }
+        let value = value.trim();
+        let unquoted = ['"', '\'']
+            .iter()
+            .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q));
+        Some(unquoted.unwrap_or(value).to_string())
+    })
+}
+
+async fn vault_value(addr: &str, token: &str, mount: &str, name: &str) -> Result<String, String> {
+    let (path, field) = name.split_once('#').unwrap_or((name, "value"));
+    let client = reqwest::Client::builder()
+        .timeout(VAULT_TIMEOUT)
+        .build()
+        .map_err(|e| format!("HTTP client setup failed: {}", e))?;
+    let response = client
+        .get(format!("{}/v1/{}/data/{}", addr, mount, path))
+        .header("X-Vault-Token", token)
+        .send()
+        .await
+        .map_err(|e| format!("Vault lookup of secret '{}' failed: {}", name, e))?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "Vault lookup of secret '{}' failed: HTTP {}",
+            name,
+            response.status()
+        ));
+    }
+    let body: Value = serde_json::from_str(
+        &response
+            .text()
+            .await
+            .map_err(|e| format!("Vault lookup of secret '{}' failed: {}", name, e))?,
+    )
+    .map_err(|e| format!("Vault returned invalid JSON for secret '{}': {}", name, e))?;
+    match &body["data"]["data"][field] {
+        Value::String(value) => Ok(value.clone()),
+        Value::Null => Err(format!("Secret '{}' has no field '{}'", path, field)),
+        other => Ok(other.to_string()),
+    }
+}
+
+/// Opens a sealed envelope, as written by [`crate::encryption::ResultCipher`].
+fn open(cipher: &Aes256Gcm, sealed: &str) -> Result<String, String> {
+    let envelope: Value =
+        serde_json::from_str(sealed).map_err(|_| "is not a sealed envelope".to_string())?;
+    let field = |key: &str| {
+        envelope[key]
+            .as_str()
+            .and_then(|text| BASE64.decode(text).ok())
+            .ok_or_else(|| format!("has no valid {}", key))
+    };
+    let nonce = field("nonce")?;
+    if nonce.len() != 12 {
+        return Err("has a nonce that isn't 12 bytes".to_string());
+    }
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce), field("ciphertext")?.as_slice())
+        .map_err(|_| "can't be decrypted with SECRETS_KEY".to_string())?;
+    String::from_utf8(plaintext).map_err(|_| "is not UTF-8".to_string())
+}
+
+/// Replaces `secret://<name>` values in `env` (read from `details.env`)
+/// with the secrets they name, just before a process gets them. Each
+/// resolved value is remembered by the enclosing [`track`] so it is
+/// scrubbed from the task's output; the task's details keep the reference.
+#[cfg_attr(not(any(feature = "docker", feature = "shell")), allow(dead_code))]
+pub async fn resolve_env(
+    env: Vec<(String, String)>,
+    details: &Value,
+    config: &Config,
+) -> Result<Vec<(String, String)>, String> {
+    let mut resolved = Vec::with_capacity(env.len());
+    for (key, value) in env {
+        let Some(name) = value.strip_prefix(SCHEME) else {
+            resolved.push((key, value));
+            continue;
+        };
+        let provider = config.secrets.as_ref().ok_or_else(|| {
+            format!(
+                "details.env.{} references a secret, but no SECRETS_PROVIDER is configured",
+                key
+            )
+        })?;
+        let secret = provider.lookup(name, details).await?;
+        if !secret.is_empty() {
+            RESOLVED
+                .try_with(|current| current.borrow_mut().push(secret.clone()))
+                .ok();
+        }
+        resolved.push((key, secret));
+    }
+    Ok(resolved)
+}
+
+/// Runs `work` and returns the secret values [`resolve_env`] looked up
+/// inside it. They are also passed on to any enclosing track, so a BATCH
+/// scrubs what its steps resolved.
+pub async fn track<F: Future>(work: F) -> (F::Output, Vec<String>) {
+    let (output, resolved) = RESOLVED
+        .scope(RefCell::new(Vec::new()), async {
+            let output = work.await;
+            (output, RESOLVED.with(|current| current.take()))
+        })
+        .await;
+    if !resolved.is_empty() {
+        RESOLVED
+            .try_with(|outer| outer.borrow_mut().extend(resolved.iter().cloned()))
+            .ok();
+    }
+    (output, resolved)
+}
+
+/// The secret values resolved so far inside the current [`track`], for
+/// scrubbing output captured or streamed before the task finishes.
+#[cfg_attr(not(any(feature = "docker", feature = "shell")), allow(dead_code))]
+pub fn resolved() -> Vec<String> {
+    RESOLVED
+        .try_with(|current| current.borrow().clone())
+        .unwrap_or_default()
+}
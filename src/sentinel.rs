@@ -0,0 +1,48 @@
+use crate::log;
+
+// --- Redis Sentinel ---
+/// Asks each sentinel in turn for the current address of `master_name`,
+/// returning the first answer as `host:port`.
+pub async fn resolve_master(sentinels: &[String], master_name: &str) -> Result<String, String> {
+    let mut last_error = String::from("no sentinels configured");
+
+    for sentinel in sentinels {
+        match query_sentinel(sentinel, master_name).await {
+            Ok(addr) => {
+                log(&format!(
+                    "Sentinel {} reports master '{}' at {}",
+                    sentinel, master_name, addr
+                ));
+                return Ok(addr);
+            }
+            Err(e) => {
+                log(&format!("[ERROR] Sentinel {} unusable: {}", sentinel, e));
+                last_error = e;
+            }
+        }
+    }
+
+    Err(format!(
+        "Could not resolve master '{}' through Sentinel: {}",
+        master_name, last_error
+    ))
+}
+
+async fn query_sentinel(sentinel: &str, master_name: &str) -> Result<String, String> {
+    let client =
+        redis::Client::open(format!("redis://{}/", sentinel)).map_err(|e| e.to_string())?;
+    let mut conn = client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let addr: Option<(String, u16)> = redis::cmd("SENTINEL")
+        .arg("get-master-addr-by-name")
+        .arg(master_name)
+        .query_async(&mut conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    addr.map(|(host, port)| format!("{}:{}", host, port))
+        .ok_or_else(|| format!("unknown master '{}'", master_name))
+}
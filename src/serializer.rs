@@ -0,0 +1,360 @@
+use serde::Serialize;
+
+// --- Result Serialization ---
+/// A finished task as written under its result key.
+#[derive(Serialize, Debug)]
+pub struct TaskResult<'a> {
+    pub task_id: &'a str,
+    /// `SUCCESS`, `ERROR`, `INTERRUPTED` for a task cut short by shutdown, or
+    /// `CANCELLED` for one stopped through `mcp::cancel::<id>`.
+    pub status: &'a str,
+    /// Exit code of the last process the task ran, if any.
+    pub exit_code: Option<i32>,
+    /// The task's output; only set on success.
+    pub stdout: Option<&'a str>,
+    /// How `stdout` is encoded: `text`, or `base64` for raw bytes asked for
+    /// with `details.encoding`.
+    pub output_encoding: &'a str,
+    /// Standard error of the last process the task ran, if any.
+    pub stderr: Option<&'a str>,
+    /// Why the task failed; only set when it did.
+    pub error: Option<&'a str>,
+    /// RFC 3339 timestamps.
+    pub started_at: String,
+    pub finished_at: String,
+    pub duration_ms: u64,
+    pub worker_id: &'a str,
+    /// Where the worker runs, from `WORKER_REGION` and `WORKER_ZONE`.
+    pub region: Option<&'a str>,
+    pub zone: Option<&'a str>,
+    pub correlation_id: &'a str,
+}
+
+impl TaskResult<'_> {
+    /// The output on success, the error otherwise.
+    fn output(&self) -> &str {
+        self.stdout.or(self.error).unwrap_or_default()
+    }
+}
+
+/// Turns a result into the bytes stored under its result key.
+pub trait ResultSerializer: Send + Sync {
+    fn serialize(&self, result: &TaskResult) -> Vec<u8>;
+}
+
+/// The original `SUCCESS: <output>`/`ERROR: <error>` string. With no field
+/// to carry `output_encoding`, base64 output keeps its `base64:` prefix.
+pub struct Legacy;
+
+impl ResultSerializer for Legacy {
+    fn serialize(&self, result: &TaskResult) -> Vec<u8> {
+        let tag = match result.stdout {
+            Some(_) if result.output_encoding == "base64" => "base64:",
+            _ => "",
+        };
+        format!("{}: {}{}", result.status, tag, result.output()).into_bytes()
+    }
+}
+
+/// The [`TaskResult`] as a JSON object.
+pub struct Json;
+
+impl ResultSerializer for Json {
+    fn serialize(&self, result: &TaskResult) -> Vec<u8> {
+        serde_json::to_vec(result).unwrap_or_default()
+    }
+}
+
+/// The [`TaskResult`] as a MessagePack map with the same fields as [`Json`].
+pub struct MessagePack;
+
+impl ResultSerializer for MessagePack {
+    fn serialize(&self, result: &TaskResult) -> Vec<u8> {
+        let mut out = Vec::new();
+        if let Ok(value) = serde_json::to_value(result) {
+            encode_msgpack(&value, &mut out);
+        }
+        out
+    }
+}
+
+/// The `RESULT_FORMAT` string results are written in. Hash results
+/// (`RESULT_STORAGE=hash`) and JSON-RPC responses keep their own layout.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultFormat {
+    /// `SUCCESS: ...`/`ERROR: ...`, for consumers not yet reading JSON.
+    Legacy,
+    /// The default.
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl ResultFormat {
+    pub fn from_env_value(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "legacy" => ResultFormat::Legacy,
+            "msgpack" | "messagepack" => ResultFormat::MessagePack,
+            _ => ResultFormat::Json,
+        }
+    }
+
+    pub fn serializer(self) -> &'static dyn ResultSerializer {
+        match self {
+            ResultFormat::Legacy => &Legacy,
+            ResultFormat::Json => &Json,
+            ResultFormat::MessagePack => &MessagePack,
+        }
+    }
+}
+
+/// Appends `value` to `out` in MessagePack, using the smallest encoding for
+/// each integer, string, array and map length.
+fn encode_msgpack(value: &serde_json::Value, out: &mut Vec<u8>) {
+    use serde_json::Value;
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                encode_uint(u, out);
+            } else if let Some(i) = n.as_i64() {
+                encode_negative(i, out);
+            } else {
+                out.push(0xcb);
+                out.extend_from_slice(&n.as_f64().unwrap_or(0.0).to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            encode_len(s.len(), [0xa0, 0xd9, 0xda, 0xdb], 32, out);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(items) => {
+            encode_len(items.len(), [0x90, 0, 0xdc, 0xdd], 16, out);
+            for item in items {
+                encode_msgpack(item, out);
+            }
+        }
+        Value::Object(map) => {
+            encode_len(map.len(), [0x80, 0, 0xde, 0xdf], 16, out);
+            for (key, item) in map {
+                encode_msgpack(&Value::String(key.clone()), out);
+                encode_msgpack(item, out);
+            }
+        }
+    }
+}
+
+fn encode_uint(u: u64, out: &mut Vec<u8>) {
+    match u {
+        0..=0x7f => out.push(u as u8),
+        0x80..=0xff => out.extend_from_slice(&[0xcc, u as u8]),
+        0x100..=0xffff => {
+            out.push(0xcd);
+            out.extend_from_slice(&(u as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xce);
+            out.extend_from_slice(&(u as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(0xcf);
+            out.extend_from_slice(&u.to_be_bytes());
+        }
+    }
+}
+
+fn encode_negative(i: i64, out: &mut Vec<u8>) {
+    if i >= -32 {
+        out.push(i as u8);
+    } else if i >= i8::MIN as i64 {
+        out.extend_from_slice(&[0xd0, i as u8]);
+    } else if i >= i16::MIN as i64 {
+        out.push(0xd1);
+        out.extend_from_slice(&(i as i16).to_be_bytes());
+    } else if i >= i32::MIN as i64 {
+        out.push(0xd2);
+        out.extend_from_slice(&(i as i32).to_be_bytes());
+    } else {
+        out.push(0xd3);
+        out.extend_from_slice(&i.to_be_bytes());
+    }
+}
+
+/// Writes a length header: the fix form (`markers[0] | len`) below `fix_max`,
+/// then the 8-bit (if the type has one), 16-bit and 32-bit forms.
+fn encode_len(len: usize, markers: [u8; 4], fix_max: usize, out: &mut Vec<u8>) {
+    if len < fix_max {
+        out.push(markers[0] | len as u8);
+    } else if len <= 0xff && markers[1] != 0 {
+        out.extend_from_slice(&[markers[1], len as u8]);
+    } else if len <= 0xffff {
+        out.push(markers[2]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(markers[3]);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn msgpack(value: Value) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_msgpack(&value, &mut out);
+        out
+    }
+
+    /// `header` followed by `len` copies of `byte`.
+    fn with_body(header: &[u8], byte: u8, len: usize) -> Vec<u8> {
+        let mut out = header.to_vec();
+        out.resize(header.len() + len, byte);
+        out
+    }
+
+    #[test]
+    fn encodes_nil_and_booleans() {
+        assert_eq!(msgpack(Value::Null), [0xc0]);
+        assert_eq!(msgpack(json!(false)), [0xc2]);
+        assert_eq!(msgpack(json!(true)), [0xc3]);
+    }
+
+    #[test]
+    fn encodes_strings_in_the_smallest_form() {
+        assert_eq!(msgpack(json!("")), [0xa0]);
+        assert_eq!(msgpack(json!("a")), [0xa1, b'a']);
+        assert_eq!(msgpack(json!("x".repeat(31))), with_body(&[0xbf], b'x', 31));
+        assert_eq!(
+            msgpack(json!("x".repeat(32))),
+            with_body(&[0xd9, 32], b'x', 32)
+        );
+        assert_eq!(
+            msgpack(json!("x".repeat(255))),
+            with_body(&[0xd9, 0xff], b'x', 255)
+        );
+        assert_eq!(
+            msgpack(json!("x".repeat(256))),
+            with_body(&[0xda, 0x01, 0x00], b'x', 256)
+        );
+        assert_eq!(
+            msgpack(json!("x".repeat(65536))),
+            with_body(&[0xdb, 0x00, 0x01, 0x00, 0x00], b'x', 65536)
+        );
+        // Lengths count bytes, not characters.
+        assert_eq!(msgpack(json!("é")), [0xa2, 0xc3, 0xa9]);
+    }
+
+    #[test]
+    fn encodes_positive_integers_in_the_smallest_form() {
+        assert_eq!(msgpack(json!(0)), [0x00]);
+        assert_eq!(msgpack(json!(127)), [0x7f]);
+        assert_eq!(msgpack(json!(128)), [0xcc, 0x80]);
+        assert_eq!(msgpack(json!(255)), [0xcc, 0xff]);
+        assert_eq!(msgpack(json!(256)), [0xcd, 0x01, 0x00]);
+        assert_eq!(msgpack(json!(65535)), [0xcd, 0xff, 0xff]);
+        assert_eq!(msgpack(json!(65536)), [0xce, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(
+            msgpack(json!(4294967295u64)),
+            [0xce, 0xff, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(
+            msgpack(json!(4294967296u64)),
+            [0xcf, 0, 0, 0, 0x01, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            msgpack(json!(u64::MAX)),
+            [0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn encodes_negative_integers_in_the_smallest_form() {
+        assert_eq!(msgpack(json!(-1)), [0xff]);
+        assert_eq!(msgpack(json!(-32)), [0xe0]);
+        assert_eq!(msgpack(json!(-33)), [0xd0, 0xdf]);
+        assert_eq!(msgpack(json!(-128)), [0xd0, 0x80]);
+        assert_eq!(msgpack(json!(-129)), [0xd1, 0xff, 0x7f]);
+        assert_eq!(msgpack(json!(-32768)), [0xd1, 0x80, 0x00]);
+        assert_eq!(msgpack(json!(-32769)), [0xd2, 0xff, 0xff, 0x7f, 0xff]);
+        assert_eq!(msgpack(json!(i32::MIN)), [0xd2, 0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            msgpack(json!(i32::MIN as i64 - 1)),
+            [0xd3, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(msgpack(json!(i64::MIN)), [0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encodes_floats_as_float64() {
+        assert_eq!(msgpack(json!(1.5)), [0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            msgpack(json!(-0.1)),
+            [0xcb, 0xbf, 0xb9, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a]
+        );
+    }
+
+    #[test]
+    fn encodes_arrays_and_maps_in_the_smallest_form() {
+        assert_eq!(msgpack(json!([])), [0x90]);
+        assert_eq!(msgpack(json!([1, "a"])), [0x92, 0x01, 0xa1, b'a']);
+        assert_eq!(msgpack(json!(vec![0; 15])), with_body(&[0x9f], 0, 15));
+        // No 8-bit form for arrays and maps.
+        assert_eq!(
+            msgpack(json!(vec![0; 16])),
+            with_body(&[0xdc, 0x00, 0x10], 0, 16)
+        );
+        assert_eq!(
+            msgpack(json!(vec![0; 65536])),
+            with_body(&[0xdd, 0x00, 0x01, 0x00, 0x00], 0, 65536)
+        );
+
+        assert_eq!(msgpack(json!({})), [0x80]);
+        assert_eq!(
+            msgpack(json!({"a": 1, "b": null})),
+            [0x82, 0xa1, b'a', 0x01, 0xa1, b'b', 0xc0]
+        );
+        let map16: serde_json::Map<String, Value> =
+            (0..16).map(|i| (format!("{:x}", i), Value::Null)).collect();
+        let encoded = msgpack(Value::Object(map16));
+        assert_eq!(encoded[..3], [0xde, 0x00, 0x10]);
+        assert_eq!(encoded[3..6], [0xa1, b'0', 0xc0]);
+        assert_eq!(encoded.len(), 3 + 16 * 3);
+    }
+
+    #[test]
+    fn message_pack_results_have_the_json_fields() {
+        let result = TaskResult {
+            task_id: "t1",
+            status: "SUCCESS",
+            exit_code: Some(0),
+            stdout: Some("ok"),
+            output_encoding: "text",
+            stderr: None,
+            error: None,
+            started_at: "2026-01-01T00:00:00+00:00".to_string(),
+            finished_at: "2026-01-01T00:00:01+00:00".to_string(),
+            duration_ms: 1000,
+            worker_id: "w1",
+            region: None,
+            zone: None,
+            correlation_id: "c1",
+        };
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(MessagePack.serialize(&result), msgpack(json.clone()));
+        let fields = json.as_object().unwrap().len();
+        assert_eq!(MessagePack.serialize(&result)[0], 0x80 | fields as u8);
+        assert_eq!(Legacy.serialize(&result), b"SUCCESS: ok");
+
+        let base64 = TaskResult {
+            stdout: Some("aGk="),
+            output_encoding: "base64",
+            ..result
+        };
+        assert_eq!(Legacy.serialize(&base64), b"SUCCESS: base64:aGk=");
+    }
+}
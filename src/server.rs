@@ -0,0 +1,114 @@
+use crate::health;
+use crate::log;
+use crate::metrics;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration};
+
+// --- Embedded HTTP Server ---
+/// Longest request head read before the connection is dropped.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+/// How long a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Binds `addr` (`HTTP_LISTEN`) and serves `GET /metrics`, plus the
+/// `/healthz` and `/readyz` probes (see [`health`]), from a background task.
+/// A failing probe answers 503 with the reason; `/readyz` answers with
+/// `{"ready":...,"paused":...,"reason":...}` so a paused worker can be told
+/// apart. Every response closes its connection.
+pub async fn spawn(addr: &str, stuck_after: Duration) -> Result<(), String> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+    log(&format!(
+        "Serving /metrics, /healthz and /readyz on {}",
+        addr
+    ));
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve(stream, stuck_after));
+                }
+                Err(e) => {
+                    log(&format!("[ERROR] HTTP accept failed: {}", e));
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn serve(mut stream: TcpStream, stuck_after: Duration) {
+    let Ok(Some(request_line)) = time::timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .unwrap_or(Ok(None))
+    else {
+        return;
+    };
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", metrics::render()),
+        ("GET", "/healthz") => probe(health::liveness(stuck_after)),
+        ("GET", "/readyz") => readiness(health::readiness()),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await.ok();
+    stream.shutdown().await.ok();
+}
+
+fn probe(state: Result<(), String>) -> (&'static str, &'static str, String) {
+    match state {
+        Ok(()) => ("200 OK", "text/plain", "ok\n".to_string()),
+        Err(reason) => (
+            "503 Service Unavailable",
+            "text/plain",
+            format!("{}\n", reason),
+        ),
+    }
+}
+
+fn readiness(state: Result<(), String>) -> (&'static str, &'static str, String) {
+    let status = if state.is_ok() {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    let body = serde_json::json!({
+        "ready": state.is_ok(),
+        "paused": health::paused(),
+        "reason": state.err(),
+    });
+    (status, "application/json", format!("{}\n", body))
+}
+
+/// Reads up to the blank line ending the request head and returns its
+/// first line; `None` if the client hung up or sent too much.
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<Option<String>> {
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST_BYTES {
+            return Ok(None);
+        }
+        head.extend_from_slice(&chunk[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    Ok(head.lines().next().map(str::to_string))
+}
@@ -0,0 +1,520 @@
+use crate::cancel;
+use crate::chunks::{Fd, ResultStream};
+use crate::config::Config;
+use crate::details::{string_list, string_map};
+use crate::exit::{self, Termination};
+use crate::expect::Expectation;
+use crate::isolation::Isolation;
+use crate::limits::{Capture, Cgroup, Limits};
+use crate::log;
+use crate::output::{OutputEncoding, OutputOptions};
+use crate::redact;
+use crate::secrets;
+use crate::ssh;
+use crate::Task;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+
+/// How long a precondition check may run before the task fails.
+const PRECONDITION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs `details.command` with `details.args` directly (no shell
+/// interpretation), optionally isolated and behind `SHELL_WRAPPER` (on an
+/// SSH host too, where the wrapper runs remotely), and returns its stdout. `details.env` adds variables to the child's
+/// environment, a `secret://<name>` value being looked up through
+/// `SECRETS_PROVIDER` first (on an SSH host, variables are passed over
+/// stdin), and `details.cwd` sets its working directory. With a result
+/// stream, stdout lines are also pushed to it as they're printed. A command
+/// still running after `details.timeout_secs`, or when `cancel` fires, is
+/// stopped (SIGTERM, then SIGKILL after `KILL_GRACE_SECS`) and fails with
+/// `{"status":"timeout",...}` (or the cancellation reason), `terminated_by`
+/// and its partial output. With `details.structured` every outcome is instead
+/// `{"status","exit_code","signal","stdout","stderr"}`, `status` being
+/// `SUCCESS`, `ERROR`, `TIMEOUT` or the upper-cased cancellation reason.
+/// The child runs within the task's [`Limits`], and stdout and stderr past
+/// `max_output_bytes` are dropped (and no longer streamed) with a marker
+/// saying how much.
+pub async fn execute(
+    task: &Task,
+    config: &Config,
+    stream: Option<&mut ResultStream>,
+    cancel: &CancellationToken,
+) -> Result<String, String> {
+    let details = &task.details;
+    let output_options = OutputOptions::from_details(details)?;
+    let isolation = Isolation::from_details(details)?;
+    let expectation = Expectation::from_details(details)?;
+    let limits = Limits::from_task(details, config)?;
+
+    let remote = ssh::target(task, config);
+    let env = string_map(&details["env"], "env")?;
+    let cwd = match &details["cwd"] {
+        serde_json::Value::Null => None,
+        // A remote directory is checked by the remote shell's `cd`.
+        serde_json::Value::String(cwd) if remote.is_some() || Path::new(cwd).is_dir() => {
+            Some(cwd.as_str())
+        }
+        serde_json::Value::String(cwd) => {
+            return Err(format!("'cwd' is not a directory: {}", cwd));
+        }
+        _ => return Err("'cwd' must be a string".to_string()),
+    };
+    let structured = details["structured"].as_bool().unwrap_or(false);
+    let timeout = match &details["timeout_secs"] {
+        serde_json::Value::Null => None,
+        value => Some(
+            value
+                .as_f64()
+                .filter(|secs| *secs > 0.0)
+                .map(Duration::from_secs_f64)
+                .ok_or("'timeout_secs' must be a positive number")?,
+        ),
+    };
+
+    let env = secrets::resolve_env(env, details, config).await?;
+    let hidden = redact::secret_values(details);
+    // A remote command gets its environment through stdin.
+    let (argv, input) = match remote {
+        Some(host) => {
+            if isolation.is_enabled() {
+                return Err("isolation is not supported on SSH hosts".to_string());
+            }
+            let argv = argv(details, config)?;
+            log(&format!(
+                "Executing shell command on {}: {}",
+                host.name(),
+                redact::scrub(&format!("{:?}", argv), &hidden)
+            ));
+            let (ssh, script) = host.command(&argv, &env, cwd)?;
+            (ssh, Some(script))
+        }
+        None => {
+            let argv = argv(details, config)?;
+            log(&format!(
+                "Executing shell command: {}{}",
+                redact::scrub(&format!("{:?}", argv), &hidden),
+                if isolation.is_enabled() {
+                    " (isolated)"
+                } else {
+                    ""
+                }
+            ));
+            (argv, None)
+        }
+    };
+    let mut cmd = command(&argv, config);
+    // Held until the child is done; dropping it clears out the group.
+    let cgroup = match remote {
+        Some(_) => None,
+        None => Cgroup::create(&task.id, &limits, config)?,
+    };
+    // Limits and isolation can't reach past the local `ssh` client.
+    if remote.is_none() {
+        cmd.envs(env);
+        if let Some(cwd) = cwd {
+            cmd.current_dir(cwd);
+        }
+        confine(&mut cmd, &limits, cgroup.as_ref(), &isolation)?;
+    }
+    let grace = Duration::from_secs(config.kill_grace_secs);
+    let output = match run(
+        cmd,
+        input,
+        stream,
+        cancel,
+        timeout,
+        grace,
+        limits.max_output_bytes,
+    )
+    .await
+    .map_err(|e| isolation.spawn_error(e))?
+    {
+        Run::Exited(output) => {
+            exit::record(output.status.code());
+            exit::record_stderr(&output.stderr);
+            output
+        }
+        Run::Stopped {
+            stdout,
+            stderr,
+            terminated_by,
+            timed_out,
+        } if structured => {
+            let status = if timed_out {
+                "TIMEOUT".to_string()
+            } else {
+                cancel::reason(task).to_uppercase()
+            };
+            return Err(serde_json::json!({
+                "status": status,
+                "exit_code": null,
+                "signal": null,
+                "stdout": String::from_utf8_lossy(&stdout),
+                "stderr": String::from_utf8_lossy(&stderr),
+                "timeout_secs": details["timeout_secs"],
+                "terminated_by": terminated_by.as_str(),
+            })
+            .to_string());
+        }
+        Run::Stopped {
+            stdout,
+            stderr,
+            terminated_by,
+            timed_out,
+        } => {
+            return Err(serde_json::json!({
+                "status": if timed_out { "timeout" } else { cancel::reason(task) },
+                "timeout_secs": details["timeout_secs"],
+                "deadline": task.deadline,
+                "terminated_by": terminated_by.as_str(),
+                "partial_output": String::from_utf8_lossy(&stdout),
+                "partial_stderr": String::from_utf8_lossy(&stderr),
+            })
+            .to_string())
+        }
+    };
+
+    if structured {
+        let checked = match expectation.filter(|e| e.exit_code.is_some()) {
+            Some(expectation) => expectation.check_exit_code(output.status.code()),
+            None if output.status.success() => Ok(()),
+            None => Err(format!(
+                "command failed ({})",
+                exit::describe(&output.status)
+            )),
+        };
+        let result = serde_json::json!({
+            "status": if checked.is_ok() { "SUCCESS" } else { "ERROR" },
+            "exit_code": output.status.code(),
+            "signal": exit::signal(&output.status),
+            "stdout": output_options.render(&output.stdout),
+            "output_encoding": output_options.encoding.as_str(),
+            "stderr": String::from_utf8_lossy(&output.stderr),
+            "error": checked.as_ref().err(),
+        })
+        .to_string();
+        // The report itself is text; `stdout` inside it says its encoding.
+        exit::record_output_encoding(OutputEncoding::Text);
+        return match checked {
+            Ok(()) => Ok(result),
+            Err(_) => Err(result),
+        };
+    }
+
+    // An expected exit code replaces the usual zero-means-success rule.
+    if let Some(expectation) = expectation.filter(|e| e.exit_code.is_some()) {
+        expectation.check_exit_code(output.status.code())?;
+        return Ok(output_options.render(&output.stdout));
+    }
+    if output.status.success() {
+        Ok(output_options.render(&output.stdout))
+    } else {
+        Err(format!(
+            "Shell command failed ({}): {}",
+            exit::describe(&output.status),
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
+
+/// Runs `details.precondition` (`command`, `args` and an expected
+/// `exit_code`, default 0), on the task's SSH host if it has one. Whatever
+/// the task type, it runs as a SHELL command would: behind `SHELL_WRAPPER`,
+/// within the task's [`Limits`] and isolated as the task asks. Returns
+/// `None` when it passes, so the task should run, or the
+/// `{"status":"skipped",...}` result to return instead.
+pub async fn precondition(
+    task: &Task,
+    config: &Config,
+    cancel: &CancellationToken,
+) -> Result<Option<String>, String> {
+    let spec = &task.details["precondition"];
+    if spec.is_null() {
+        return Ok(None);
+    }
+    let isolation = Isolation::from_details(&task.details)?;
+    let limits = Limits::from_task(&task.details, config)?;
+    let remote = ssh::target(task, config);
+    if remote.is_some() && isolation.is_enabled() {
+        return Err("isolation is not supported on SSH hosts".to_string());
+    }
+    let (argv, input) = match remote {
+        Some(host) => argv(spec, config)
+            .and_then(|argv| host.command(&argv, &[], None))
+            .map(|(ssh, script)| (ssh, Some(script))),
+        None => argv(spec, config).map(|argv| (argv, None)),
+    }
+    .map_err(|e| format!("precondition: {}", e))?;
+    let expected = match &spec["exit_code"] {
+        serde_json::Value::Null => 0,
+        value => value
+            .as_i64()
+            .ok_or("precondition 'exit_code' must be an integer")?,
+    };
+
+    log(&format!(
+        "Checking precondition: {}",
+        redact::scrub(
+            &format!("{:?}", argv),
+            &redact::secret_values(&task.details)
+        )
+    ));
+    let mut cmd = command(&argv, config);
+    let cgroup = match remote {
+        Some(_) => None,
+        None => Cgroup::create(&format!("{}-precondition", task.id), &limits, config)?,
+    };
+    if remote.is_none() {
+        confine(&mut cmd, &limits, cgroup.as_ref(), &isolation)?;
+    }
+    let grace = Duration::from_secs(config.kill_grace_secs);
+    let output = match run(
+        cmd,
+        input,
+        None,
+        cancel,
+        Some(PRECONDITION_TIMEOUT),
+        grace,
+        limits.max_output_bytes,
+    )
+    .await
+    .map_err(|e| format!("precondition: {}", isolation.spawn_error(e)))?
+    {
+        Run::Exited(output) => output,
+        Run::Stopped {
+            timed_out: true, ..
+        } => {
+            return Err(format!(
+                "precondition timed out after {:?}",
+                PRECONDITION_TIMEOUT
+            ))
+        }
+        Run::Stopped { .. } => return Err("precondition stopped: task cancelled".to_string()),
+    };
+    let exit_code = output.status.code();
+    if exit_code.map(i64::from) == Some(expected) {
+        return Ok(None);
+    }
+    log(&format!(
+        "Precondition failed ({}), skipping task",
+        exit::describe(&output.status)
+    ));
+    Ok(Some(
+        serde_json::json!({
+            "status": "skipped",
+            "precondition": {
+                "argv": argv,
+                "exit_code": exit_code,
+                "expected_exit_code": expected,
+                "stdout": String::from_utf8_lossy(&output.stdout),
+                "stderr": String::from_utf8_lossy(&output.stderr),
+            },
+        })
+        .to_string(),
+    ))
+}
+
+/// A command for `argv`. With ENV_ALLOWLIST only the listed worker variables
+/// reach the child, keeping Redis credentials and other secrets out of its
+/// environment.
+fn command(argv: &[String], config: &Config) -> tokio::process::Command {
+    let mut cmd = tokio::process::Command::new(&argv[0]);
+    cmd.args(&argv[1..]);
+    if let Some(allowlist) = &config.env_allowlist {
+        cmd.env_clear();
+        for name in allowlist {
+            if let Some(value) = std::env::var_os(name) {
+                cmd.env(name, value);
+            }
+        }
+    }
+    cmd
+}
+
+/// Puts a local child within `limits` (in `cgroup`, when they need one) and
+/// `isolation`.
+fn confine(
+    cmd: &mut tokio::process::Command,
+    limits: &Limits,
+    cgroup: Option<&Cgroup>,
+    isolation: &Isolation,
+) -> Result<(), String> {
+    limits.apply(cmd, cgroup)?;
+    isolation.apply(cmd)
+}
+
+/// How a shell child finished.
+enum Run {
+    Exited(std::process::Output),
+    /// Stopped at its timeout (`timed_out`) or because the task was
+    /// cancelled, with whatever it had printed so far.
+    Stopped {
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+        terminated_by: Termination,
+        timed_out: bool,
+    },
+}
+
+/// Like `Command::output`, but buffers output as it streams so a child
+/// stopped at `timeout` or by `cancel` (given `grace` to exit on SIGTERM)
+/// still yields what it printed, and pushes each stdout
+/// line to `stream` when there is one. Stderr is read in the background so a
+/// chatty child can't block on a full pipe, its lines going to the live
+/// output stream only. Each of stdout and stderr keeps at most `max_output`
+/// bytes; the pipes are still drained past it.
+async fn run(
+    mut cmd: tokio::process::Command,
+    input: Option<String>,
+    mut stream: Option<&mut ResultStream>,
+    cancel: &CancellationToken,
+    timeout: Option<Duration>,
+    grace: Duration,
+    max_output: Option<usize>,
+) -> std::io::Result<Run> {
+    let mut child = cmd
+        .stdin(match input {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let (Some(input), Some(mut pipe)) = (input, child.stdin.take()) {
+        // Closed once written, so the child sees end of input.
+        tokio::spawn(async move { pipe.write_all(input.as_bytes()).await.ok() });
+    }
+    let stderr = Arc::new(Mutex::new(Capture::new(max_output)));
+    let mut live = stream.as_ref().and_then(|stream| stream.live());
+    // Streamed lines are scrubbed as they go; the reader below can't see
+    // the task's resolved secrets by itself.
+    let secrets = secrets::resolved();
+    // Fired once a stopped child is gone, so the reader keeps whatever it
+    // read of an unfinished last line instead of waiting for more.
+    let stop_stderr = CancellationToken::new();
+    let stderr_reader = child.stderr.take().map(|pipe| {
+        let stderr = Arc::clone(&stderr);
+        let secrets = secrets.clone();
+        let stop = stop_stderr.clone();
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(pipe);
+            let mut line = Vec::new();
+            loop {
+                let read = tokio::select! {
+                    biased;
+                    read = reader.read_until(b'\n', &mut line) => read,
+                    _ = stop.cancelled() => Ok(0),
+                };
+                if !matches!(read, Ok(1..)) {
+                    if !line.is_empty() {
+                        stderr.lock().unwrap().push(&line);
+                    }
+                    break;
+                }
+                let kept = stderr.lock().unwrap().push(&line);
+                if let Some(live) = live.as_mut().filter(|_| kept) {
+                    let line = redact::scrub(&String::from_utf8_lossy(&line), &secrets);
+                    live.append(Fd::Stderr, &line).await;
+                }
+                line.clear();
+                // A grandchild still holding the pipe mustn't keep us here.
+                if stop.is_cancelled() {
+                    break;
+                }
+            }
+        })
+    });
+
+    let mut stdout = Capture::new(max_output);
+    let stdout_pipe = child.stdout.take();
+    // Outside the read loop so a child stopped mid-line keeps that line.
+    let mut line = Vec::new();
+    let finished = async {
+        if let Some(pipe) = stdout_pipe {
+            let mut reader = BufReader::new(pipe);
+            while reader.read_until(b'\n', &mut line).await? > 0 {
+                let kept = stdout.push(&line);
+                if let Some(stream) = stream.as_mut().filter(|_| kept) {
+                    stream
+                        .push(&redact::scrub(&String::from_utf8_lossy(&line), &secrets))
+                        .await;
+                }
+                line.clear();
+            }
+        }
+        child.wait().await
+    };
+    let timer = async {
+        match timeout {
+            Some(limit) => time::sleep(limit).await,
+            None => std::future::pending().await,
+        }
+    };
+    // No status when the child has to be stopped; `timed_out` says why.
+    let (status, timed_out) = tokio::select! {
+        status = finished => (Some(status), false),
+        _ = timer => (None, true),
+        _ = cancel.cancelled() => (None, false),
+    };
+
+    match status {
+        Some(status) => {
+            let status = status?;
+            if let Some(reader) = stderr_reader {
+                reader.await.ok();
+            }
+            let stderr = std::mem::take(&mut *stderr.lock().unwrap()).finish();
+            Ok(Run::Exited(std::process::Output {
+                status,
+                stdout: stdout.finish(),
+                stderr,
+            }))
+        }
+        None => {
+            let terminated_by = exit::terminate(&mut child, grace).await;
+            stdout.push(&line);
+            stop_stderr.cancel();
+            if let Some(reader) = stderr_reader {
+                reader.await.ok();
+            }
+            let stderr = std::mem::take(&mut *stderr.lock().unwrap()).finish();
+            Ok(Run::Stopped {
+                stdout: stdout.finish(),
+                stderr,
+                terminated_by,
+                timed_out,
+            })
+        }
+    }
+}
+
+/// The exact argv a SHELL task runs, wrapper included.
+pub fn argv(details: &serde_json::Value, config: &Config) -> Result<Vec<String>, String> {
+    let argv = program(details)?;
+    wrap(&config.shell_wrapper, &argv[0], &argv[1..])
+}
+
+/// `details.command` followed by `details.args`.
+fn program(details: &serde_json::Value) -> Result<Vec<String>, String> {
+    let program = details["command"]
+        .as_str()
+        .ok_or("SHELL task requires a string 'command'")?;
+    let mut argv = vec![program.to_string()];
+    argv.extend(string_list(&details["args"], "args")?);
+    Ok(argv)
+}
+
+/// Builds the final argv, prepending the wrapper (split like a shell would,
+/// but never run through one) when `SHELL_WRAPPER` is set.
+fn wrap(wrapper: &str, program: &str, args: &[String]) -> Result<Vec<String>, String> {
+    let mut argv = shlex::split(wrapper)
+        .ok_or_else(|| format!("SHELL_WRAPPER has unbalanced quoting: {}", wrapper))?;
+    argv.push(program.to_string());
+    argv.extend(args.iter().cloned());
+    Ok(argv)
+}
@@ -0,0 +1,271 @@
+use crate::config::Config;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// How long a nonce is remembered. A payload older than
+/// `MCP_TASK_MAX_AGE_SECS` is refused anyway, so this only has to outlast
+/// that window.
+const NONCE_TTL_SECS: u64 = 24 * 3600;
+/// Fields left out of the signed form: the signature itself, and the
+/// bookkeeping the worker adds when it retries or replays a task (which it
+/// re-signs anyway). `attempts` is signed, so a captured payload can't be
+/// passed off as a retry.
+const UNSIGNED_FIELDS: [&str; 4] = ["signature", "errors", "correlation_id", "replay_count"];
+/// In the dead-letter reason of a payload [`verify`] refused, so
+/// `--replay-deadletter` leaves it where it is.
+pub const REJECTED: &str = "failed signature verification";
+
+// --- Signed Tasks ---
+/// Verifies a queued payload against `MCP_TASK_SECRET`; without one every
+/// payload passes. The payload must carry `signature`, the hex HMAC-SHA256
+/// (optionally `sha256=`-prefixed, as on result callbacks) of the payload
+/// with the fields in [`UNSIGNED_FIELDS`] removed, serialized compactly with
+/// object keys sorted. It must also carry `signed_at` (Unix seconds), no
+/// more than `MCP_TASK_MAX_AGE_SECS` off, and a `nonce` that is only ever
+/// accepted once. Retries, recovered tasks and dead-letter replays pass
+/// because the worker [`resign`]s them with a fresh `signed_at` and
+/// `nonce`. Without a Redis connection only the signature and age are
+/// checked. Returns why the payload is rejected.
+pub async fn verify(
+    json_str: &str,
+    config: &Config,
+    conn: Option<redis::aio::MultiplexedConnection>,
+) -> Result<(), String> {
+    let Some(secret) = &config.task_secret else {
+        return Ok(());
+    };
+    let serde_json::Value::Object(fields) =
+        serde_json::from_str(json_str).map_err(|e| e.to_string())?
+    else {
+        return Err("signed task must be a JSON object".to_string());
+    };
+    let signature = signature_of(&fields)?;
+    let signed_at = fields
+        .get("signed_at")
+        .and_then(serde_json::Value::as_i64)
+        .ok_or("signed task requires integer 'signed_at' seconds")?;
+    let nonce = fields
+        .get("nonce")
+        .and_then(serde_json::Value::as_str)
+        .filter(|nonce| !nonce.is_empty())
+        .ok_or("signed task requires a string 'nonce'")?
+        .to_string();
+
+    mac(&fields, secret)
+        .verify_slice(&signature)
+        .map_err(|_| "signature does not match".to_string())?;
+
+    let age = chrono::Utc::now().timestamp() - signed_at;
+    if age.unsigned_abs() > config.task_max_age_secs {
+        return Err(format!(
+            "signed_at is {}s off, more than MCP_TASK_MAX_AGE_SECS ({})",
+            age, config.task_max_age_secs
+        ));
+    }
+    if let Some(mut conn) = conn {
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(format!("mcp::nonce::{}", nonce))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(NONCE_TTL_SECS)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| format!("nonce check failed: {}", e))?;
+        if claimed.is_none() {
+            return Err(format!("nonce '{}' was already used", nonce));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the signature of a payload the worker stored earlier (a dead
+/// letter, or a processing-list or scheduled entry) the way [`verify`]
+/// does, but without the age and nonce checks, since it is about to be
+/// re-signed. Anything Redis holds may have been written there directly, so
+/// this must pass before [`resign`]; otherwise write access to Redis would
+/// be enough to get a task signed. Without `MCP_TASK_SECRET` every payload
+/// passes.
+pub fn check_signature(
+    fields: &serde_json::Map<String, serde_json::Value>,
+    config: &Config,
+) -> Result<(), String> {
+    let Some(secret) = &config.task_secret else {
+        return Ok(());
+    };
+    mac(fields, secret)
+        .verify_slice(&signature_of(fields)?)
+        .map_err(|_| "signature does not match".to_string())
+}
+
+/// Signs a payload the worker queues itself, such as a promoted scheduled
+/// run or a retry, with a fresh `signed_at` and `nonce` so it passes
+/// [`verify`] as the original did. A payload read back from Redis must pass
+/// [`check_signature`] first. Without `MCP_TASK_SECRET` the payload is left
+/// alone.
+pub fn resign(fields: &mut serde_json::Map<String, serde_json::Value>, config: &Config) {
+    let Some(secret) = &config.task_secret else {
+        return;
+    };
+    fields.insert(
+        "signed_at".to_string(),
+        chrono::Utc::now().timestamp().into(),
+    );
+    fields.insert(
+        "nonce".to_string(),
+        format!("{:032x}", rand::random::<u128>()).into(),
+    );
+    let signature = hex::encode(mac(fields, secret).finalize().into_bytes());
+    fields.insert("signature".to_string(), signature.into());
+}
+
+/// The decoded `signature` of `fields`.
+fn signature_of(fields: &serde_json::Map<String, serde_json::Value>) -> Result<Vec<u8>, String> {
+    let signature = match fields.get("signature") {
+        Some(serde_json::Value::String(signature)) => signature,
+        None | Some(serde_json::Value::Null) => return Err("task is not signed".to_string()),
+        Some(_) => return Err("'signature' must be a string".to_string()),
+    };
+    hex::decode(signature.strip_prefix("sha256=").unwrap_or(signature))
+        .map_err(|_| "'signature' is not hex".to_string())
+}
+
+/// The HMAC of `fields` without [`UNSIGNED_FIELDS`].
+fn mac(fields: &serde_json::Map<String, serde_json::Value>, secret: &str) -> Hmac<Sha256> {
+    let mut signed = fields.clone();
+    for field in UNSIGNED_FIELDS {
+        signed.remove(field);
+    }
+    // serde_json keeps object keys sorted, so this is the canonical form.
+    let canonical = serde_json::Value::Object(signed).to_string();
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(canonical.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn config(secret: Option<&str>) -> Config {
+        let mut config = Config::for_tests();
+        config.task_secret = secret.map(str::to_string);
+        config.task_max_age_secs = 300;
+        config
+    }
+
+    fn object(value: Value) -> serde_json::Map<String, Value> {
+        match value {
+            Value::Object(fields) => fields,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn mac_covers_the_sorted_compact_form() {
+        // The keys are out of order and the worker's bookkeeping is present;
+        // neither changes the signature.
+        let fields = object(json!({
+            "task_type": "STATUS",
+            "id": "t1",
+            "details": {"b": [2, "x"], "a": 1},
+            "attempts": 1,
+            "signature": "ignored",
+            "errors": [{"error": "ignored"}],
+            "correlation_id": "ignored",
+            "replay_count": 3,
+        }));
+        // HMAC-SHA256 under "k3y" of
+        // {"attempts":1,"details":{"a":1,"b":[2,"x"]},"id":"t1","task_type":"STATUS"}
+        assert_eq!(
+            hex::encode(mac(&fields, "k3y").finalize().into_bytes()),
+            "ae1f7385413313af856fedfcbd5a11f427fbaf3e7616db2ef34436a5e4931010"
+        );
+    }
+
+    #[tokio::test]
+    async fn resigned_payloads_verify() {
+        let config = config(Some("k3y"));
+        let mut fields = object(json!({"id": "t1", "task_type": "STATUS", "attempts": 2}));
+        resign(&mut fields, &config);
+        let payload = Value::Object(fields.clone()).to_string();
+        assert_eq!(verify(&payload, &config, None).await, Ok(()));
+
+        // The signature may carry the callback-style prefix.
+        let mut prefixed = fields.clone();
+        let signature = format!("sha256={}", prefixed["signature"].as_str().unwrap());
+        prefixed.insert("signature".to_string(), signature.into());
+        let payload = Value::Object(prefixed).to_string();
+        assert_eq!(verify(&payload, &config, None).await, Ok(()));
+
+        // Retry bookkeeping isn't signed...
+        let mut retried = fields.clone();
+        retried.insert("errors".to_string(), json!([{"error": "boom"}]));
+        let payload = Value::Object(retried).to_string();
+        assert_eq!(verify(&payload, &config, None).await, Ok(()));
+
+        // ...but attempts is.
+        let mut replayed = fields;
+        replayed.insert("attempts".to_string(), 3.into());
+        let payload = Value::Object(replayed).to_string();
+        assert_eq!(
+            verify(&payload, &config, None).await,
+            Err("signature does not match".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_unsigned_stale_and_malformed_payloads() {
+        let config = config(Some("k3y"));
+        let check = |payload: Value| {
+            let config = &config;
+            async move { verify(&payload.to_string(), config, None).await }
+        };
+        assert_eq!(
+            check(json!({"id": "t1"})).await,
+            Err("task is not signed".to_string())
+        );
+        assert_eq!(
+            check(json!(["t1"])).await,
+            Err("signed task must be a JSON object".to_string())
+        );
+        assert_eq!(
+            check(json!({"signature": "zz"})).await,
+            Err("'signature' is not hex".to_string())
+        );
+        assert_eq!(
+            check(json!({"signature": "00", "nonce": "n"})).await,
+            Err("signed task requires integer 'signed_at' seconds".to_string())
+        );
+        assert_eq!(
+            check(json!({"signature": "00", "signed_at": 0})).await,
+            Err("signed task requires a string 'nonce'".to_string())
+        );
+
+        let mut stale = object(json!({"id": "t1"}));
+        resign(&mut stale, &config);
+        stale.insert(
+            "signed_at".to_string(),
+            (chrono::Utc::now().timestamp() - 600).into(),
+        );
+        let signature = hex::encode(mac(&stale, "k3y").finalize().into_bytes());
+        stale.insert("signature".to_string(), signature.into());
+        let rejected = check(Value::Object(stale)).await.unwrap_err();
+        assert!(
+            rejected.ends_with("more than MCP_TASK_MAX_AGE_SECS (300)"),
+            "{}",
+            rejected
+        );
+    }
+
+    #[tokio::test]
+    async fn without_a_secret_everything_passes() {
+        let config = config(None);
+        let mut fields = object(json!({"id": "t1"}));
+        resign(&mut fields, &config);
+        assert_eq!(fields, object(json!({"id": "t1"})));
+        assert_eq!(verify("not json", &config, None).await, Ok(()));
+    }
+}
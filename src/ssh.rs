@@ -0,0 +1,317 @@
+use crate::config::Config;
+use crate::config_file;
+use crate::log;
+use crate::{Task, TaskType};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Seconds `ssh` may take to connect when `[ssh] connect_timeout_secs` is unset.
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+
+// --- Remote Execution ---
+/// How to reach the hosts this worker runs SHELL tasks on over SSH.
+struct Remote {
+    bin: String,
+    connect_timeout_secs: u64,
+    known_hosts: Option<String>,
+    /// `StrictHostKeyChecking`: `yes` unless set to `accept-new`.
+    strict_host_key_checking: String,
+    hosts: HashMap<String, Host>,
+}
+
+/// One `[ssh.hosts.<name>]` entry.
+pub struct Host {
+    #[cfg_attr(not(feature = "shell"), allow(dead_code))]
+    name: String,
+    /// Where to connect; the host's name when unset.
+    address: String,
+    user: Option<String>,
+    port: Option<u16>,
+    identity_file: Option<String>,
+}
+
+/// Set by [`init`]; without an `[ssh]` table every task runs locally.
+static REMOTE: OnceLock<Remote> = OnceLock::new();
+
+/// Loads the SSH hosts from the config file:
+///
+/// ```toml
+/// [ssh]
+/// known_hosts = "/etc/mcp-worker/known_hosts"
+/// connect_timeout_secs = 10
+///
+/// [ssh.hosts."web-1"]
+/// address = "10.0.0.21"
+/// user = "deploy"
+/// identity_file = "/etc/mcp-worker/keys/web-1"
+/// ```
+///
+/// A SHELL task whose `target_host` names one of `hosts` (and isn't this
+/// worker) runs there through the `ssh` client (`bin`, default `ssh`),
+/// behind `SHELL_WRAPPER` as it would locally, so the wrapper must exist on
+/// the remote host.
+/// Authentication is by key only: `ssh` never prompts, and host keys must
+/// already be in `known_hosts` unless `strict_host_key_checking` is
+/// `accept-new`. Only the first call has an effect.
+pub fn init() -> Result<(), String> {
+    let Some(table) = config_file::ssh() else {
+        return Ok(());
+    };
+    let hosts = match table.get("hosts") {
+        None => Map::new(),
+        Some(Value::Object(hosts)) => hosts.clone(),
+        Some(_) => return Err("ssh.hosts must be a table".to_string()),
+    };
+    let hosts = hosts
+        .iter()
+        .map(|(name, entry)| Ok((name.clone(), Host::parse(name, entry)?)))
+        .collect::<Result<HashMap<_, _>, String>>()?;
+    let strict_host_key_checking = match text(table, "ssh", "strict_host_key_checking")? {
+        None => "yes".to_string(),
+        Some(mode) if mode == "yes" || mode == "accept-new" => mode,
+        Some(mode) => {
+            return Err(format!(
+                "ssh.strict_host_key_checking must be yes or accept-new, got {}",
+                mode
+            ))
+        }
+    };
+    let connect_timeout_secs = match table.get("connect_timeout_secs") {
+        None => DEFAULT_CONNECT_TIMEOUT_SECS,
+        Some(value) => value
+            .as_u64()
+            .ok_or("ssh.connect_timeout_secs must be a non-negative integer")?,
+    };
+    let remote = Remote {
+        bin: text(table, "ssh", "bin")?.unwrap_or_else(|| "ssh".to_string()),
+        connect_timeout_secs,
+        known_hosts: text(table, "ssh", "known_hosts")?,
+        strict_host_key_checking,
+        hosts,
+    };
+    REMOTE.set(remote).ok();
+    log(&format!("SSH hosts: {}", host_names().join(", ")));
+    Ok(())
+}
+
+fn text(table: &Map<String, Value>, name: &str, key: &str) -> Result<Option<String>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        Some(_) => Err(format!("{}.{} must be a string", name, key)),
+    }
+}
+
+impl Host {
+    fn parse(name: &str, entry: &Value) -> Result<Self, String> {
+        let Value::Object(entry) = entry else {
+            return Err(format!("ssh.hosts.{} must be a table", name));
+        };
+        let section = format!("ssh.hosts.{}", name);
+        let port = match entry.get("port") {
+            None => None,
+            Some(port) => Some(
+                port.as_u64()
+                    .and_then(|port| u16::try_from(port).ok())
+                    .ok_or_else(|| format!("{}.port must be a port number", section))?,
+            ),
+        };
+        Ok(Host {
+            name: name.to_string(),
+            address: text(entry, &section, "address")?.unwrap_or_else(|| name.to_string()),
+            user: text(entry, &section, "user")?,
+            port,
+            identity_file: text(entry, &section, "identity_file")?,
+        })
+    }
+
+    #[cfg_attr(not(feature = "shell"), allow(dead_code))]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The `ssh` argv running `argv` on this host, and the script to feed it
+    /// on stdin. `env` is exported and `cwd` entered by that script rather
+    /// than on the command line, so the values (which may be resolved
+    /// secrets) never show up in `ps` on either host. The remote login shell
+    /// must be POSIX.
+    #[cfg_attr(not(feature = "shell"), allow(dead_code))]
+    pub fn command(
+        &self,
+        argv: &[String],
+        env: &[(String, String)],
+        cwd: Option<&str>,
+    ) -> Result<(Vec<String>, String), String> {
+        let remote = REMOTE.get().ok_or("SSH is not configured")?;
+        let script = script(argv, env, cwd)?;
+
+        let mut ssh = vec![
+            remote.bin.clone(),
+            "-o".to_string(),
+            "BatchMode=yes".to_string(),
+            "-o".to_string(),
+            format!("ConnectTimeout={}", remote.connect_timeout_secs),
+            "-o".to_string(),
+            format!("StrictHostKeyChecking={}", remote.strict_host_key_checking),
+        ];
+        if let Some(known_hosts) = &remote.known_hosts {
+            ssh.extend([
+                "-o".to_string(),
+                format!("UserKnownHostsFile={}", known_hosts),
+            ]);
+        }
+        if let Some(identity_file) = &self.identity_file {
+            ssh.extend([
+                "-o".to_string(),
+                "IdentitiesOnly=yes".to_string(),
+                "-i".to_string(),
+                identity_file.clone(),
+            ]);
+        }
+        if let Some(port) = self.port {
+            ssh.extend(["-p".to_string(), port.to_string()]);
+        }
+        if let Some(user) = &self.user {
+            ssh.extend(["-l".to_string(), user.clone()]);
+        }
+        ssh.extend(["--".to_string(), self.address.clone(), "sh -s".to_string()]);
+        Ok((ssh, script))
+    }
+}
+
+/// The script `sh -s` runs on the remote host: `env` exported, `cwd`
+/// entered, then `argv` exec'd, every value single-quoted so the remote
+/// shell reads it back verbatim.
+#[cfg_attr(not(feature = "shell"), allow(dead_code))]
+fn script(argv: &[String], env: &[(String, String)], cwd: Option<&str>) -> Result<String, String> {
+    let quote = |word: &str| {
+        shlex::try_quote(word)
+            .map(|quoted| quoted.into_owned())
+            .map_err(|e| format!("Command can't be quoted for SSH: {}", e))
+    };
+    let mut script = String::new();
+    for (key, value) in env {
+        let name_ok = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !name_ok {
+            return Err(format!("'{}' is not a valid variable name for SSH", key));
+        }
+        script.push_str(&format!("export {}={}\n", key, quote(value)?));
+    }
+    if let Some(cwd) = cwd {
+        script.push_str(&format!("cd {} || exit 1\n", quote(cwd)?));
+    }
+    let words = argv
+        .iter()
+        .map(|word| quote(word))
+        .collect::<Result<Vec<_>, _>>()?;
+    script.push_str(&format!("exec {}\n", words.join(" ")));
+    Ok(script)
+}
+
+/// The SSH host a task runs on: set for a SHELL task without a
+/// `target_selector` whose `target_host` names a configured host rather
+/// than this worker.
+pub fn target(task: &Task, config: &Config) -> Option<&'static Host> {
+    if !cfg!(feature = "shell")
+        || task.task_type != TaskType::SHELL
+        || task.target_selector.is_some()
+        || task.target_host == config.worker_host
+    {
+        return None;
+    }
+    REMOTE.get()?.hosts.get(&task.target_host)
+}
+
+/// The SSH hosts this worker runs SHELL tasks on, as advertised in its
+/// heartbeat.
+pub fn host_names() -> Vec<&'static str> {
+    let mut names: Vec<&str> = REMOTE
+        .get()
+        .map(|remote| remote.hosts.keys().map(String::as_str).collect())
+        .unwrap_or_default();
+    names.sort_unstable();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    /// What `sh -s` prints running `script`, as the remote shell would.
+    fn run(script: &str) -> String {
+        let mut sh = Command::new("sh")
+            .arg("-s")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        sh.stdin
+            .take()
+            .unwrap()
+            .write_all(script.as_bytes())
+            .unwrap();
+        String::from_utf8(sh.wait_with_output().unwrap().stdout).unwrap()
+    }
+
+    const AWKWARD: [&str; 9] = [
+        "plain",
+        "two words",
+        "$(touch /tmp/mcp-ssh-pwned) `id` $HOME",
+        "a;b|c&d>e<f",
+        "it's \"quoted\"",
+        "'",
+        "line one\nline two\n",
+        "*?[a] ~ #not-a-comment",
+        "",
+    ];
+
+    #[test]
+    fn args_reach_the_remote_command_verbatim() {
+        let mut argv = vec!["printf".to_string(), "<%s>\\n".to_string()];
+        argv.extend(AWKWARD.iter().map(|arg| arg.to_string()));
+        let script = script(&argv, &[], None).unwrap();
+        let expected: String = AWKWARD.iter().map(|arg| format!("<{}>\n", arg)).collect();
+        assert_eq!(run(&script), expected);
+        assert!(!std::path::Path::new("/tmp/mcp-ssh-pwned").exists());
+    }
+
+    #[test]
+    fn env_and_cwd_reach_the_remote_shell_verbatim() {
+        let env: Vec<(String, String)> = AWKWARD
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (format!("V{}", i), value.to_string()))
+            .collect();
+        let mut argv = vec!["sh".to_string(), "-c".to_string()];
+        argv.push(
+            (0..AWKWARD.len())
+                .map(|i| format!("printf '<%s>\\n' \"$V{}\"; ", i))
+                .collect::<String>()
+                + "pwd",
+        );
+        let script = script(&argv, &env, Some("/")).unwrap();
+        let mut expected: String = AWKWARD.iter().map(|arg| format!("<{}>\n", arg)).collect();
+        expected.push_str("/\n");
+        assert_eq!(run(&script), expected);
+    }
+
+    #[test]
+    fn rejects_what_cant_be_passed_safely() {
+        let argv = ["true".to_string()];
+        let env = |key: &str| [(key.to_string(), "x".to_string())];
+        assert_eq!(
+            script(&argv, &env("A=B; id"), None).unwrap_err(),
+            "'A=B; id' is not a valid variable name for SSH"
+        );
+        assert!(script(&argv, &env("1A"), None).is_err());
+        assert!(script(&argv, &env("_OK1"), None).is_ok());
+        let nul = ["echo".to_string(), "a\0b".to_string()];
+        assert!(script(&nul, &[], None)
+            .unwrap_err()
+            .starts_with("Command can't be quoted for SSH: "));
+    }
+}
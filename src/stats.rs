@@ -0,0 +1,158 @@
+use crate::config::Config;
+use crate::log;
+use crate::logging;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+use tokio::time::{self, Duration, Instant};
+
+/// When the process started, for uptime reporting.
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Pins the uptime clock; call early in startup.
+pub fn mark_started() {
+    LazyLock::force(&STARTED);
+}
+
+/// How long the process has been running.
+pub fn uptime() -> Duration {
+    STARTED.elapsed()
+}
+
+// --- Task Counters ---
+/// Process-wide task counters. All counters are cumulative since startup;
+/// the periodic summary additionally shows the change since the previous
+/// summary, so nothing is ever reset.
+pub struct Stats {
+    pub received: AtomicU64,
+    pub succeeded: AtomicU64,
+    pub failed: AtomicU64,
+    pub dead_lettered: AtomicU64,
+    /// Tasks that ran longer than `SLOW_TASK_SECS` (slow_tasks_total).
+    pub slow_tasks: AtomicU64,
+    pub inflight: AtomicU64,
+}
+
+pub static STATS: Stats = Stats {
+    received: AtomicU64::new(0),
+    succeeded: AtomicU64::new(0),
+    failed: AtomicU64::new(0),
+    dead_lettered: AtomicU64::new(0),
+    slow_tasks: AtomicU64::new(0),
+    inflight: AtomicU64::new(0),
+};
+
+/// Counts a task as in flight until dropped.
+pub struct InflightGuard;
+
+impl InflightGuard {
+    pub fn new() -> Self {
+        STATS.inflight.fetch_add(1, Ordering::Relaxed);
+        InflightGuard
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        STATS.inflight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn incr(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A point-in-time copy of the cumulative counters.
+#[derive(Clone, Copy, Default)]
+struct Snapshot {
+    received: u64,
+    succeeded: u64,
+    failed: u64,
+    dead_lettered: u64,
+    slow_tasks: u64,
+}
+
+impl Snapshot {
+    fn take() -> Self {
+        Snapshot {
+            received: STATS.received.load(Ordering::Relaxed),
+            succeeded: STATS.succeeded.load(Ordering::Relaxed),
+            failed: STATS.failed.load(Ordering::Relaxed),
+            dead_lettered: STATS.dead_lettered.load(Ordering::Relaxed),
+            slow_tasks: STATS.slow_tasks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Spawns a loop logging a one-line summary every `interval_secs`, e.g.
+/// `STATS received=120 ok=118 fail=2 dlq=0 slow=1 inflight=3 | delta received=+4 ok=+4 fail=+0 dlq=+0 slow=+0`.
+/// An interval of 0 disables the summary.
+pub fn spawn_summary(interval_secs: u64) {
+    if interval_secs == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(interval_secs));
+        interval.tick().await;
+        let mut previous = Snapshot::default();
+        loop {
+            interval.tick().await;
+            let now = Snapshot::take();
+            log(&format!(
+                "STATS received={} ok={} fail={} dlq={} slow={} inflight={} | delta received=+{} ok=+{} fail=+{} dlq=+{} slow=+{}",
+                now.received,
+                now.succeeded,
+                now.failed,
+                now.dead_lettered,
+                now.slow_tasks,
+                STATS.inflight.load(Ordering::Relaxed),
+                now.received - previous.received,
+                now.succeeded - previous.succeeded,
+                now.failed - previous.failed,
+                now.dead_lettered - previous.dead_lettered,
+                now.slow_tasks - previous.slow_tasks,
+            ));
+            previous = now;
+        }
+    });
+}
+
+/// The STATUS task: cumulative counters, in-flight count, uptime and a
+/// summary of the non-secret configuration, as JSON.
+pub fn status(config: &Config) -> String {
+    let now = Snapshot::take();
+    serde_json::json!({
+        "worker_id": config.worker_id,
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": uptime().as_secs(),
+        "counters": {
+            "received": now.received,
+            "succeeded": now.succeeded,
+            "failed": now.failed,
+            "dead_lettered": now.dead_lettered,
+            "slow_tasks": now.slow_tasks,
+        },
+        "inflight": STATS.inflight.load(Ordering::Relaxed),
+        "log_lines_dropped": logging::dropped(),
+        "config": {
+            "host": config.worker_host,
+            "region": config.worker_region,
+            "zone": config.worker_zone,
+            "labels": config.worker_labels,
+            "queues": config.queues,
+            "redis_db": config.redis_db,
+            "listener_per_queue": config.listener_per_queue,
+            "queue_rotate": config.queue_rotate,
+            "max_total_concurrency": config.max_total_concurrency,
+            "task_type_concurrency": config.task_type_concurrency,
+            "queue_task_types": config.queue_task_types,
+            "queue_concurrency": config.queue_concurrency,
+            "queue_timeout_secs": config.queue_timeout_secs,
+            "allow_systemd": config.allow_systemd,
+            "allow_self_restart": config.allow_self_restart,
+            "result_ttl_secs": config.result_ttl_secs,
+            "result_encrypted": config.result_cipher.is_some(),
+            "secrets_provider": config.secrets.as_ref().map(|provider| provider.name()),
+        },
+    })
+    .to_string()
+}
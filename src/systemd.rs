@@ -0,0 +1,235 @@
+use crate::config::Config;
+use crate::exit;
+use crate::log;
+use crate::Task;
+
+/// The only `systemctl` verbs a SYSTEMD task may use.
+pub const ALLOWED_ACTIONS: [&str; 7] = [
+    "start",
+    "stop",
+    "restart",
+    "status",
+    "is-active",
+    "enable",
+    "disable",
+];
+/// Unit types a SYSTEMD task may name; a unit without one is a service.
+const UNIT_SUFFIXES: [&str; 11] = [
+    ".service",
+    ".socket",
+    ".target",
+    ".timer",
+    ".path",
+    ".mount",
+    ".automount",
+    ".swap",
+    ".slice",
+    ".scope",
+    ".device",
+];
+/// Unit properties reported under `state`.
+const STATE_PROPERTIES: [&str; 7] = [
+    "ActiveState",
+    "SubState",
+    "LoadState",
+    "UnitFileState",
+    "MainPID",
+    "ActiveEnterTimestamp",
+    "Description",
+];
+/// Journal lines a `status` (or failed action) reports by default.
+const DEFAULT_JOURNAL_LINES: u64 = 20;
+/// Most journal lines `details.journal_lines` may ask for.
+const MAX_JOURNAL_LINES: u64 = 200;
+
+// --- Systemd Units ---
+/// Runs `systemctl <action> -- <unit>` for `details.action` (start, stop,
+/// restart, status, is-active, enable or disable; `details.now` adds `--now`
+/// to enable/disable) on `details.unit`, a unit name such as `nginx` or
+/// `backup@daily.timer`. The result carries systemctl's output plus the
+/// unit's `state` (`ActiveState`, `SubState` and the other
+/// [`STATE_PROPERTIES`]) after the action; `status`, and a failed action,
+/// also get the unit's last `details.journal_lines` (default 20, 0 for none)
+/// journal lines.
+#[cfg(unix)]
+pub async fn execute(task: &Task, config: &Config) -> Result<String, String> {
+    if !config.allow_systemd {
+        return Err("SYSTEMD tasks are disabled on this worker (set ALLOW_SYSTEMD)".to_string());
+    }
+
+    let action = task.details["action"]
+        .as_str()
+        .ok_or("SYSTEMD task requires a string 'action'")?;
+    if !ALLOWED_ACTIONS.contains(&action) {
+        return Err(format!(
+            "Unsupported systemctl action '{}'. Allowed: {}",
+            action,
+            ALLOWED_ACTIONS.join(", ")
+        ));
+    }
+
+    let unit = task.details["unit"]
+        .as_str()
+        .map(str::trim)
+        .ok_or("SYSTEMD task requires a string 'unit'")?;
+    validate_unit(unit)?;
+    let now = match &task.details["now"] {
+        serde_json::Value::Null => false,
+        serde_json::Value::Bool(now) if matches!(action, "enable" | "disable") => *now,
+        serde_json::Value::Bool(_) => {
+            return Err("'now' only applies to enable and disable".to_string())
+        }
+        _ => return Err("'now' must be a bool".to_string()),
+    };
+    let journal_lines = match &task.details["journal_lines"] {
+        serde_json::Value::Null => DEFAULT_JOURNAL_LINES,
+        value => value
+            .as_u64()
+            .filter(|lines| *lines <= MAX_JOURNAL_LINES)
+            .ok_or_else(|| {
+                format!(
+                    "'journal_lines' must be an integer from 0 to {}",
+                    MAX_JOURNAL_LINES
+                )
+            })?,
+    };
+
+    log(&format!("Executing systemctl {} {}", action, unit));
+    let mut cmd = tokio::process::Command::new("systemctl");
+    cmd.arg(action);
+    if now {
+        cmd.arg("--now");
+    }
+    let output = cmd
+        .arg("--")
+        .arg(unit)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to execute systemctl: {}", e))?;
+
+    let exit_code = output.status.code();
+    exit::record(exit_code);
+    // For status queries a non-zero exit just reports the unit's state.
+    let succeeded = output.status.success() || matches!(action, "status" | "is-active");
+    let journal = match action == "status" || !succeeded {
+        true => journal(unit, journal_lines).await,
+        false => Vec::new(),
+    };
+    let result = serde_json::json!({
+        "unit": unit,
+        "action": action,
+        "exit_code": exit_code,
+        "signal": exit::signal(&output.status),
+        "status": exit::describe(&output.status),
+        "stdout": String::from_utf8_lossy(&output.stdout),
+        "stderr": String::from_utf8_lossy(&output.stderr),
+        "state": state(unit).await,
+        "journal": journal,
+    });
+
+    if succeeded {
+        Ok(result.to_string())
+    } else {
+        Err(format!("systemctl {} {} failed: {}", action, unit, result))
+    }
+}
+
+/// Unit names are letters, digits and `:-_.@\`, at most 255 bytes,
+/// optionally ending in one of the [`UNIT_SUFFIXES`]; a leading dash would
+/// be parsed by systemctl as an option.
+#[cfg(unix)]
+fn validate_unit(unit: &str) -> Result<(), String> {
+    let name = UNIT_SUFFIXES
+        .iter()
+        .find_map(|suffix| unit.strip_suffix(suffix))
+        .unwrap_or(unit);
+    let valid = !name.is_empty()
+        && unit.len() <= 255
+        && !unit.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ":-_.@\\".contains(c))
+        // A template needs an instance, e.g. `getty@tty1`.
+        && !name.ends_with('@');
+    if !valid {
+        return Err(format!("Invalid unit name: '{}'", unit));
+    }
+    Ok(())
+}
+
+/// The unit's [`STATE_PROPERTIES`] from `systemctl show`, or null when they
+/// can't be read.
+#[cfg(unix)]
+async fn state(unit: &str) -> serde_json::Value {
+    let output = tokio::process::Command::new("systemctl")
+        .arg("show")
+        .arg(format!("--property={}", STATE_PROPERTIES.join(",")))
+        .arg("--")
+        .arg(unit)
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => {
+            let properties: serde_json::Map<String, serde_json::Value> =
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .filter_map(|line| line.split_once('='))
+                    .map(|(key, value)| (key.to_string(), value.into()))
+                    .collect();
+            serde_json::Value::Object(properties)
+        }
+        Ok(output) => {
+            log(&format!(
+                "[WARN] systemctl show {} failed: {}",
+                unit,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+            serde_json::Value::Null
+        }
+        Err(e) => {
+            log(&format!("[WARN] Failed to execute systemctl show: {}", e));
+            serde_json::Value::Null
+        }
+    }
+}
+
+/// The unit's last `lines` journal lines, oldest first; none when the
+/// journal can't be read.
+#[cfg(unix)]
+async fn journal(unit: &str, lines: u64) -> Vec<String> {
+    if lines == 0 {
+        return Vec::new();
+    }
+    let output = tokio::process::Command::new("journalctl")
+        .args(["--no-pager", "--output=short-iso", "--lines"])
+        .arg(lines.to_string())
+        .arg("--unit")
+        .arg(unit)
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            // journalctl's own notes, e.g. `-- No entries --`.
+            .filter(|line| !line.starts_with("-- "))
+            .map(str::to_string)
+            .collect(),
+        Ok(output) => {
+            log(&format!(
+                "[WARN] journalctl for {} failed: {}",
+                unit,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+            Vec::new()
+        }
+        Err(e) => {
+            log(&format!("[WARN] Failed to execute journalctl: {}", e));
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn execute(_task: &Task, _config: &Config) -> Result<String, String> {
+    Err("SYSTEMD tasks are only supported on Unix".to_string())
+}
@@ -0,0 +1,80 @@
+use crate::config::Config;
+use crate::log;
+use redis::AsyncCommands;
+use tokio::time::{self, Duration};
+
+const TOUCH_PREFIX: &str = "mcp::touch::";
+/// How often pending touch requests are collected.
+const TOUCH_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// --- Result TTL Refresh ---
+/// With `RESULT_TOUCH` set, spawns a loop that lets slow consumers keep a
+/// result alive: setting `mcp::touch::<id>` (optionally to a number of
+/// seconds, default `RESULT_TTL_SECS`) re-EXPIREs `<prefix><id>` and its
+/// `::chunks` list. Each request is claimed with GETDEL, so only one worker
+/// in the fleet applies it. Only the default result key prefix is covered.
+pub fn spawn_touch_loop(mut conn: redis::aio::MultiplexedConnection, config: &Config) {
+    if !config.result_touch {
+        return;
+    }
+    let prefix = config.result_key_prefix.clone();
+    let default_ttl = config.result_ttl_secs;
+    tokio::spawn(async move {
+        let mut interval = time::interval(TOUCH_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let keys: Vec<String> = match conn
+                .scan_match::<_, String>(format!("{}*", TOUCH_PREFIX))
+                .await
+            {
+                Ok(mut iter) => {
+                    let mut keys = Vec::new();
+                    while let Some(key) = iter.next_item().await {
+                        keys.push(key);
+                    }
+                    keys
+                }
+                Err(e) => {
+                    log(&format!("[ERROR] Failed to list touch requests: {}", e));
+                    continue;
+                }
+            };
+            for touch_key in keys {
+                let claimed: Option<String> = redis::cmd("GETDEL")
+                    .arg(&touch_key)
+                    .query_async(&mut conn)
+                    .await
+                    .unwrap_or(None);
+                let Some(requested) = claimed else {
+                    continue;
+                };
+                let task_id = &touch_key[TOUCH_PREFIX.len()..];
+                let ttl_secs = requested.trim().parse().unwrap_or(default_ttl);
+                extend(&mut conn, &format!("{}{}", prefix, task_id), ttl_secs).await;
+            }
+        }
+    });
+}
+
+async fn extend(conn: &mut redis::aio::MultiplexedConnection, result_key: &str, ttl_secs: u64) {
+    let chunks_key = format!("{}::chunks", result_key);
+    let extended: redis::RedisResult<(bool, bool)> = redis::pipe()
+        .expire(result_key, ttl_secs as i64)
+        .expire(&chunks_key, ttl_secs as i64)
+        .query_async(conn)
+        .await;
+    match extended {
+        Ok((true, _)) => log(&format!(
+            "Extended TTL of {} to {}s on request.",
+            result_key, ttl_secs
+        )),
+        Ok((false, _)) => log(&format!(
+            "Touch request for {} ignored: no such result.",
+            result_key
+        )),
+        Err(e) => log(&format!(
+            "[ERROR] Failed to extend TTL of {}: {}",
+            result_key, e
+        )),
+    }
+}
@@ -0,0 +1,254 @@
+use serde::Serialize;
+use serde_json::Value;
+
+/// Result status of a task whose details don't fit its type's schema.
+pub const VALIDATION_ERROR: &str = "VALIDATION_ERROR";
+
+// --- Details Validation ---
+/// What a details field must hold.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    String,
+    Bool,
+    Number,
+    /// A non-negative integer.
+    Unsigned,
+    Object,
+    Array,
+    /// An array of strings.
+    #[cfg_attr(not(any(feature = "docker", feature = "shell")), allow(dead_code))]
+    StringList,
+    /// An object of string values.
+    StringMap,
+    /// One of the listed strings.
+    OneOf(&'static [&'static str]),
+}
+
+/// One field of a task type's schema. Fields a schema doesn't name are
+/// left to the executor.
+pub struct Field {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub required: bool,
+}
+
+pub const fn required(name: &'static str, kind: Kind) -> Field {
+    Field {
+        name,
+        kind,
+        required: true,
+    }
+}
+
+pub const fn optional(name: &'static str, kind: Kind) -> Field {
+    Field {
+        name,
+        kind,
+        required: false,
+    }
+}
+
+/// Fields every task type reads.
+const COMMON: &[Field] = &[
+    optional("limits", Kind::Object),
+    optional("max_retries", Kind::Unsigned),
+];
+
+/// A details field that doesn't fit the schema, such as
+/// `{"field": "details.command", "problem": "is required"}`.
+#[derive(Serialize, Debug)]
+pub struct Problem {
+    pub field: String,
+    pub problem: String,
+}
+
+impl Problem {
+    pub fn new(field: impl Into<String>, problem: impl Into<String>) -> Self {
+        Problem {
+            field: field.into(),
+            problem: problem.into(),
+        }
+    }
+
+    /// The same problem, for details nested under `prefix`.
+    pub fn nested(self, prefix: &str) -> Self {
+        Problem {
+            field: format!("{}.{}", prefix, self.field),
+            problem: self.problem,
+        }
+    }
+}
+
+/// Checks `details` against `schema` and the fields every type shares,
+/// returning every field that doesn't fit rather than just the first.
+pub fn check(details: &Value, schema: &[Field]) -> Result<(), Vec<Problem>> {
+    let empty = serde_json::Map::new();
+    let fields = match details {
+        Value::Object(fields) => fields,
+        Value::Null => &empty,
+        _ => return Err(vec![Problem::new("details", "must be an object")]),
+    };
+    let problems: Vec<Problem> = COMMON
+        .iter()
+        .chain(schema)
+        .filter_map(|field| {
+            let name = format!("details.{}", field.name);
+            match fields.get(field.name).unwrap_or(&Value::Null) {
+                Value::Null if field.required => Some(Problem::new(name, "is required")),
+                Value::Null => None,
+                value => mismatch(field.kind, value).map(|problem| Problem::new(name, problem)),
+            }
+        })
+        .collect();
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems),
+    }
+}
+
+/// Why `value` isn't of `kind`, if it isn't.
+fn mismatch(kind: Kind, value: &Value) -> Option<String> {
+    let (fits, expected) = match kind {
+        Kind::String => (value.is_string(), "a string".to_string()),
+        Kind::Bool => (value.is_boolean(), "a boolean".to_string()),
+        Kind::Number => (value.is_number(), "a number".to_string()),
+        Kind::Unsigned => (value.is_u64(), "a non-negative integer".to_string()),
+        Kind::Object => (value.is_object(), "an object".to_string()),
+        Kind::Array => (value.is_array(), "an array".to_string()),
+        Kind::StringList => (
+            value
+                .as_array()
+                .is_some_and(|items| items.iter().all(Value::is_string)),
+            "an array of strings".to_string(),
+        ),
+        Kind::StringMap => (
+            value
+                .as_object()
+                .is_some_and(|map| map.values().all(Value::is_string)),
+            "an object of strings".to_string(),
+        ),
+        Kind::OneOf(allowed) => (
+            value.as_str().is_some_and(|v| allowed.contains(&v)),
+            format!("one of: {}", allowed.join(", ")),
+        ),
+    };
+    (!fits).then(|| format!("must be {}", expected))
+}
+
+/// The output of a `VALIDATION_ERROR` result.
+pub fn report(problems: &[Problem]) -> String {
+    serde_json::json!({
+        "error": "invalid task details",
+        "fields": problems,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SCHEMA: &[Field] = &[
+        required("command", Kind::String),
+        optional("args", Kind::StringList),
+        optional("env", Kind::StringMap),
+        optional("timeout_secs", Kind::Unsigned),
+        optional("ratio", Kind::Number),
+        optional("structured", Kind::Bool),
+        optional("options", Kind::Object),
+        optional("items", Kind::Array),
+        optional("action", Kind::OneOf(&["start", "stop"])),
+    ];
+
+    /// The `field: problem` pairs `details` fails with.
+    fn problems(details: serde_json::Value) -> Vec<(String, String)> {
+        check(&details, SCHEMA)
+            .err()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|p| (p.field, p.problem))
+            .collect()
+    }
+
+    fn problem(field: &str, problem: &str) -> (String, String) {
+        (field.to_string(), problem.to_string())
+    }
+
+    #[test]
+    fn accepts_details_that_fit() {
+        let details = json!({
+            "command": "df",
+            "args": ["-h"],
+            "env": {"A": "1"},
+            "timeout_secs": 5,
+            "ratio": -0.5,
+            "structured": true,
+            "options": {},
+            "items": [1, "a"],
+            "action": "stop",
+            "limits": {"memory": "1g"},
+            "max_retries": 2,
+            "unknown": "left to the executor",
+        });
+        assert!(problems(details).is_empty());
+        // Null counts as unset.
+        assert!(problems(json!({"command": "df", "args": null})).is_empty());
+    }
+
+    #[test]
+    fn reports_every_field_that_doesnt_fit() {
+        let details = json!({
+            "args": ["-h", 1],
+            "env": {"A": 1},
+            "timeout_secs": -1,
+            "ratio": "fast",
+            "structured": "yes",
+            "options": [],
+            "items": {},
+            "action": "restart",
+            "max_retries": 1.5,
+        });
+        assert_eq!(
+            problems(details),
+            [
+                problem("details.max_retries", "must be a non-negative integer"),
+                problem("details.command", "is required"),
+                problem("details.args", "must be an array of strings"),
+                problem("details.env", "must be an object of strings"),
+                problem("details.timeout_secs", "must be a non-negative integer"),
+                problem("details.ratio", "must be a number"),
+                problem("details.structured", "must be a boolean"),
+                problem("details.options", "must be an object"),
+                problem("details.items", "must be an array"),
+                problem("details.action", "must be one of: start, stop"),
+            ]
+        );
+    }
+
+    #[test]
+    fn details_must_be_an_object() {
+        assert_eq!(
+            problems(json!("df")),
+            [problem("details", "must be an object")]
+        );
+        // Missing details are an empty object, so only required fields fail.
+        assert_eq!(
+            problems(serde_json::Value::Null),
+            [problem("details.command", "is required")]
+        );
+    }
+
+    #[test]
+    fn nests_and_reports_problems() {
+        let nested = Problem::new("details.command", "is required").nested("details.steps[2]");
+        assert_eq!(nested.field, "details.steps[2].details.command");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&report(&[nested])).unwrap(),
+            json!({
+                "error": "invalid task details",
+                "fields": [{"field": "details.steps[2].details.command", "problem": "is required"}],
+            })
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use crate::config::Config;
+use crate::handlers;
+use crate::health;
+use crate::log;
+use crate::ssh;
+use crate::stats::{self, STATS};
+use redis::AsyncCommands;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use tokio::time::{self, Duration};
+
+// --- Worker Info ---
+/// Describes this worker under `mcp::worker::<worker_id>` so operators and
+/// dispatchers can see where it runs and what it accepts. Region and zone
+/// are omitted when not configured.
+#[derive(Serialize, Debug)]
+pub struct WorkerInfo {
+    pub worker_id: String,
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub version: &'static str,
+    pub started_at: String,
+    pub task_types: Vec<&'static str>,
+    pub queues: Vec<String>,
+    /// Hosts this worker runs SHELL tasks on over SSH.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ssh_hosts: Vec<&'static str>,
+    pub max_concurrent_tasks: usize,
+}
+
+impl WorkerInfo {
+    pub fn new(config: &Config) -> Self {
+        WorkerInfo {
+            worker_id: config.worker_id.clone(),
+            host: config.worker_host.clone(),
+            region: config.worker_region.clone(),
+            zone: config.worker_zone.clone(),
+            labels: config.worker_labels.clone(),
+            version: env!("CARGO_PKG_VERSION"),
+            started_at: chrono::Utc::now().to_rfc3339(),
+            task_types: handlers::supported_task_types(config),
+            queues: config.queues.clone(),
+            ssh_hosts: ssh::host_names(),
+            max_concurrent_tasks: config.max_concurrent_tasks,
+        }
+    }
+}
+
+/// The info key's value: the static [`WorkerInfo`] plus the current load.
+#[derive(Serialize)]
+struct Heartbeat<'a> {
+    #[serde(flatten)]
+    info: &'a WorkerInfo,
+    running: u64,
+    uptime_secs: u64,
+    heartbeat_at: String,
+}
+
+pub fn info_key(worker_id: &str) -> String {
+    format!("mcp::worker::{}", worker_id)
+}
+
+/// Publishes the worker info key, expiring after `ttl_secs` (0 keeps it).
+pub async fn publish_info(
+    conn: &mut redis::aio::MultiplexedConnection,
+    info: &WorkerInfo,
+    ttl_secs: u64,
+) {
+    let heartbeat = Heartbeat {
+        info,
+        running: STATS.inflight.load(Ordering::Relaxed),
+        uptime_secs: stats::uptime().as_secs(),
+        heartbeat_at: chrono::Utc::now().to_rfc3339(),
+    };
+    let info_json = match serde_json::to_string(&heartbeat) {
+        Ok(s) => s,
+        Err(e) => {
+            log(&format!("[ERROR] Failed to serialize worker info: {}", e));
+            return;
+        }
+    };
+    let key = info_key(&info.worker_id);
+    let published = match ttl_secs {
+        0 => conn.set::<_, _, ()>(key, info_json).await,
+        ttl => conn.set_ex::<_, _, ()>(key, info_json, ttl).await,
+    };
+    match published {
+        Ok(()) => health::mark_alive(),
+        Err(e) => log(&format!("[ERROR] Failed to publish worker info: {}", e)),
+    }
+}
+
+/// Spawns a loop republishing the info key every `interval_secs`
+/// (`HEARTBEAT_INTERVAL_SECS`) with a `ttl_secs` (`HEARTBEAT_TTL_SECS`)
+/// expiry, so the key disappears soon after the worker does. An interval of
+/// 0 disables the loop.
+pub fn spawn_heartbeat(
+    mut conn: redis::aio::MultiplexedConnection,
+    info: WorkerInfo,
+    interval_secs: u64,
+    ttl_secs: u64,
+) {
+    if interval_secs == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(interval_secs));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            publish_info(&mut conn, &info, ttl_secs).await;
+        }
+    });
+}
+
+/// Deletes the info key, on the way out after a shutdown.
+pub async fn remove_info(conn: &mut redis::aio::MultiplexedConnection, worker_id: &str) {
+    if let Err(e) = conn.del::<_, ()>(info_key(worker_id)).await {
+        log(&format!("[ERROR] Failed to remove worker info: {}", e));
+    }
+}
+
+pub fn config_key(worker_id: &str) -> String {
+    format!("mcp::workers::{}::config", worker_id)
+}
+
+/// Writes the effective config, secrets redacted, to the config key. Config
+/// is only read at startup, so this happens once, right after connecting.
+pub async fn publish_config(conn: &mut redis::aio::MultiplexedConnection, config: &Config) {
+    let config_json = match serde_json::to_string(config) {
+        Ok(s) => s,
+        Err(e) => {
+            log(&format!("[ERROR] Failed to serialize config: {}", e));
+            return;
+        }
+    };
+    if let Err(e) = conn
+        .set::<_, _, ()>(config_key(&config.worker_id), config_json)
+        .await
+    {
+        log(&format!("[ERROR] Failed to publish config: {}", e));
+    }
+}